@@ -0,0 +1,37 @@
+//! Throughput of each of the `bench` subcommand's workloads, for `cargo bench`.
+//! Only applying the transactions is timed, not generating them.
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use std::time::Duration;
+use toy_payments::{Benchmark, Workload};
+
+const TRANSACTIONS: u64 = 200_000;
+
+fn workloads(c: &mut Criterion) {
+    let mut group = c.benchmark_group("workloads");
+    // Each run is a whole workload, so fewer of them than usual
+    group.sample_size(10);
+    group.throughput(Throughput::Elements(TRANSACTIONS));
+    for workload in [
+        Workload::DepositHeavy,
+        Workload::DisputeHeavy,
+        Workload::ManyClients,
+    ] {
+        let benchmark = Benchmark {
+            workload,
+            transactions: TRANSACTIONS,
+            seed: 0,
+        };
+        group.bench_function(workload.to_string(), |b| {
+            b.iter_custom(|runs| {
+                (0..runs)
+                    .map(|_| benchmark.run().expect("workloads apply cleanly").elapsed)
+                    .sum::<Duration>()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, workloads);
+criterion_main!(benches);
@@ -0,0 +1,14 @@
+//! Generates the gRPC service of proto/payments.proto, with the grpc feature
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=proto/payments.proto");
+    #[cfg(feature = "grpc")]
+    {
+        // A protoc of its own, so building doesn't need one installed
+        let protoc = protoc_bin_vendored::protoc_bin_path().expect("No protoc for this platform");
+        std::env::set_var("PROTOC", protoc);
+        tonic_prost_build::compile_protos("proto/payments.proto")
+            .expect("Failed to compile proto/payments.proto");
+    }
+}
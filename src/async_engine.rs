@@ -0,0 +1,147 @@
+use crate::{
+    AccountStore, DisputePolicy, MemoryStore, PaymentsEngine, PaymentsError, StandardDisputePolicy,
+    Transaction, TransactionStore,
+};
+use futures::{Stream, StreamExt};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+/// A `PaymentsEngine` running on a blocking thread of the Tokio runtime, fed
+/// transactions through a bounded channel. Submitting waits while the channel is
+/// full, so a fast source such as a socket or Kafka consumer is held back to the
+/// pace the engine applies transactions at, rather than buffering them in memory.
+pub struct AsyncPaymentsEngine<T = MemoryStore, A = MemoryStore, P = StandardDisputePolicy> {
+    sender: mpsc::Sender<Transaction>,
+    task: JoinHandle<Result<PaymentsEngine<T, A, P>, PaymentsError>>,
+}
+
+impl<T, A, P> AsyncPaymentsEngine<T, A, P>
+where
+    T: TransactionStore + 'static,
+    A: AccountStore + 'static,
+    P: DisputePolicy,
+{
+    /// Moves the engine, with its settings, onto a blocking thread of the current
+    /// Tokio runtime. `capacity` is how many transactions can wait to be applied.
+    pub fn new(
+        mut engine: PaymentsEngine<T, A, P>,
+        capacity: usize,
+    ) -> AsyncPaymentsEngine<T, A, P> {
+        let (sender, mut receiver) = mpsc::channel::<Transaction>(capacity);
+        // Applying blocks on the stores and any file output, so it stays off the
+        // runtime's async workers
+        let task = tokio::task::spawn_blocking(move || {
+            while let Some(row) = receiver.blocking_recv() {
+                engine.process_transaction(row)?;
+            }
+            engine.finish()?;
+            Ok(engine)
+        });
+        AsyncPaymentsEngine { sender, task }
+    }
+
+    /// Queues a transaction, waiting for room in the channel
+    pub async fn submit(&self, transaction: Transaction) -> Result<(), PaymentsError> {
+        self.sender
+            .send(transaction)
+            .await
+            .map_err(|_| PaymentsError::EngineStopped)
+    }
+
+    /// Queues every transaction from the stream as the engine makes room for it
+    pub async fn process_stream<S>(&self, transactions: S) -> Result<(), PaymentsError>
+    where
+        S: Stream<Item = Transaction>,
+    {
+        futures::pin_mut!(transactions);
+        while let Some(transaction) = transactions.next().await {
+            self.submit(transaction).await?;
+        }
+        Ok(())
+    }
+
+    /// Waits for every queued transaction to be applied and hands the engine back,
+    /// or the error that stopped it
+    pub async fn finish(self) -> Result<PaymentsEngine<T, A, P>, PaymentsError> {
+        drop(self.sender);
+        self.task.await.map_err(|_| PaymentsError::EngineStopped)?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DisputeState, TransactionType};
+    use rust_decimal::Decimal;
+    use rust_decimal_macros::dec;
+
+    fn transaction(id: u32, transaction_type: TransactionType, amount: Decimal) -> Transaction {
+        Transaction {
+            id,
+            transaction_type,
+            client_id: 1,
+            amount,
+            to_client_id: None,
+            reference: None,
+            currency: None,
+            timestamp: None,
+            dispute_state: DisputeState::Undisputed,
+        }
+    }
+
+    #[tokio::test]
+    async fn applies_a_stream_through_a_small_channel() {
+        use TransactionType::*;
+        let engine = AsyncPaymentsEngine::new(PaymentsEngine::new(), 1);
+        let deposits = (1..=100).map(|id| transaction(id, Deposit, dec!(1.0000)));
+        engine
+            .process_stream(futures::stream::iter(deposits))
+            .await
+            .unwrap();
+        engine
+            .submit(transaction(101, Withdrawal, dec!(30.0000)))
+            .await
+            .unwrap();
+        engine
+            .submit(transaction(7, Dispute, Decimal::ZERO))
+            .await
+            .unwrap();
+
+        let engine = engine.finish().await.unwrap();
+        let client = engine.client(1).unwrap();
+        assert_eq!(client.available(), dec!(69.0000));
+        assert_eq!(client.held(), dec!(1.0000));
+        assert_eq!(engine.valid_records(), 102);
+    }
+
+    #[tokio::test]
+    async fn runs_an_engine_with_its_own_dispute_policy() {
+        use TransactionType::*;
+        struct OnLockedAccounts;
+        impl crate::DisputePolicy for OnLockedAccounts {
+            fn allowed_on_locked(&self, _transaction_type: &TransactionType) -> bool {
+                true
+            }
+        }
+        let engine = PaymentsEngine::with_dispute_policy(
+            MemoryStore::new(),
+            MemoryStore::new(),
+            OnLockedAccounts,
+        );
+        let engine = AsyncPaymentsEngine::new(engine, 1);
+        for row in [
+            transaction(1, Deposit, dec!(5.0000)),
+            transaction(2, Deposit, dec!(3.0000)),
+            transaction(1, Dispute, Decimal::ZERO),
+            transaction(1, Chargeback, Decimal::ZERO),
+            transaction(2, Dispute, Decimal::ZERO),
+        ] {
+            engine.submit(row).await.unwrap();
+        }
+
+        let engine = engine.finish().await.unwrap();
+        let client = engine.client(1).unwrap();
+        assert!(client.locked());
+        assert_eq!(client.held(), dec!(3.0000));
+    }
+}
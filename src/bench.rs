@@ -0,0 +1,219 @@
+use crate::generate::SplitMix64;
+use crate::{ClientId, DisputeState, PaymentsEngine, PaymentsError, Transaction, TransactionType};
+use rust_decimal::Decimal;
+use std::fmt;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// The mix of transactions a benchmark applies
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Workload {
+    /// Deposits with a withdrawal in ten, over 1000 clients
+    DepositHeavy,
+    /// Deposits, half of them disputed and then resolved, over 1000 clients
+    DisputeHeavy,
+    /// Deposits and withdrawals spread over every client id
+    ManyClients,
+}
+
+impl FromStr for Workload {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Workload, Self::Err> {
+        match s {
+            "deposit-heavy" => Ok(Workload::DepositHeavy),
+            "dispute-heavy" => Ok(Workload::DisputeHeavy),
+            "many-clients" => Ok(Workload::ManyClients),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for Workload {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Workload::DepositHeavy => "deposit-heavy",
+            Workload::DisputeHeavy => "dispute-heavy",
+            Workload::ManyClients => "many-clients",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Applies a synthetic workload to an in-memory engine and times it. The
+/// transactions are generated up front, so only applying them is timed, and every
+/// one applies cleanly, so none are rejected.
+#[derive(Debug, Clone)]
+pub struct Benchmark {
+    pub workload: Workload,
+    /// Transactions to apply
+    pub transactions: u64,
+    /// The same seed always gives the same workload
+    pub seed: u64,
+}
+
+/// How fast a benchmark's transactions were applied
+#[derive(Debug, Clone)]
+pub struct BenchmarkResult {
+    pub transactions: u64,
+    pub elapsed: Duration,
+    /// The process's peak resident memory in bytes, including the generated
+    /// transactions, `None` where it can't be told
+    pub peak_memory: Option<u64>,
+}
+
+impl BenchmarkResult {
+    pub fn transactions_per_sec(&self) -> f64 {
+        match self.elapsed.as_secs_f64() {
+            secs if secs > 0.0 => self.transactions as f64 / secs,
+            _ => 0.0,
+        }
+    }
+}
+
+impl Benchmark {
+    pub fn run(&self) -> Result<BenchmarkResult, PaymentsError> {
+        let rows = self.rows();
+        let transactions = rows.len() as u64;
+        let mut engine = PaymentsEngine::new();
+        let started = Instant::now();
+        for row in rows {
+            engine.process_transaction(row)?;
+        }
+        engine.finish()?;
+        Ok(BenchmarkResult {
+            transactions,
+            elapsed: started.elapsed(),
+            peak_memory: peak_memory(),
+        })
+    }
+
+    /// The transactions to apply, keeping track of each client's funds so every
+    /// withdrawal is covered
+    fn rows(&self) -> Vec<Transaction> {
+        use TransactionType::*;
+        let mut rng = SplitMix64(self.seed);
+        let clients = match self.workload {
+            Workload::ManyClients => ClientId::MAX,
+            _ => 1000,
+        };
+        let mut available = vec![Decimal::ZERO; usize::from(clients) + 1];
+        // Deposits not disputed yet, and those under dispute, as (client, tx)
+        let mut disputable = Vec::new();
+        let mut disputed = Vec::new();
+        let mut rows = Vec::with_capacity(self.transactions as usize);
+        let row = |transaction_type, client_id, id, amount| Transaction {
+            id,
+            transaction_type,
+            client_id,
+            amount,
+            to_client_id: None,
+            reference: None,
+            currency: None,
+            timestamp: None,
+            dispute_state: DisputeState::Undisputed,
+        };
+        for tx in (1..=self.transactions).map_while(|tx| u32::try_from(tx).ok()) {
+            let roll = rng.below(100);
+            if self.workload == Workload::DisputeHeavy {
+                if roll < 25 && !disputed.is_empty() {
+                    let picked = rng.below(disputed.len() as u64) as usize;
+                    let (client, id) = disputed.swap_remove(picked);
+                    rows.push(row(Resolve, client, id, Decimal::ZERO));
+                    continue;
+                }
+                if roll < 50 && !disputable.is_empty() {
+                    let picked = rng.below(disputable.len() as u64) as usize;
+                    let (client, id) = disputable.swap_remove(picked);
+                    disputed.push((client, id));
+                    rows.push(row(Dispute, client, id, Decimal::ZERO));
+                    continue;
+                }
+            }
+            let client = rng.below(u64::from(clients)) as ClientId + 1;
+            let funds = &mut available[usize::from(client)];
+            let withdrawals = match self.workload {
+                Workload::DepositHeavy => 10,
+                Workload::DisputeHeavy => 0,
+                Workload::ManyClients => 40,
+            };
+            let amount = Decimal::new(rng.below(100_000) as i64 + 1, 2);
+            if roll < withdrawals && amount <= *funds {
+                *funds -= amount;
+                rows.push(row(Withdrawal, client, tx, amount));
+            } else {
+                *funds += amount;
+                // Nothing is withdrawn in the dispute heavy workload, so the funds
+                // always cover a dispute
+                if self.workload == Workload::DisputeHeavy {
+                    disputable.push((client, tx));
+                }
+                rows.push(row(Deposit, client, tx, amount));
+            }
+        }
+        rows
+    }
+}
+
+/// The process's peak resident memory in bytes
+#[cfg(unix)]
+fn peak_memory() -> Option<u64> {
+    let mut usage = std::mem::MaybeUninit::<libc::rusage>::zeroed();
+    // SAFETY: getrusage fills in the struct it's given, which is only read if it did
+    if unsafe { libc::getrusage(libc::RUSAGE_SELF, usage.as_mut_ptr()) } != 0 {
+        return None;
+    }
+    let max_rss = unsafe { usage.assume_init() }.ru_maxrss;
+    // In bytes on macOS, kilobytes elsewhere
+    let unit = if cfg!(target_os = "macos") { 1 } else { 1024 };
+    u64::try_from(max_rss).ok().map(|max_rss| max_rss * unit)
+}
+
+#[cfg(not(unix))]
+fn peak_memory() -> Option<u64> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_workload_applies_cleanly() {
+        for workload in [
+            Workload::DepositHeavy,
+            Workload::DisputeHeavy,
+            Workload::ManyClients,
+        ] {
+            let benchmark = Benchmark {
+                workload,
+                transactions: 5000,
+                seed: 3,
+            };
+            let rows = benchmark.rows();
+            assert_eq!(rows.len(), 5000);
+            let mut engine = PaymentsEngine::new();
+            for row in rows.clone() {
+                engine.process_transaction(row).unwrap();
+            }
+            assert!(engine.skipped().is_empty(), "{}", workload);
+            assert_eq!(rows[..100], benchmark.rows()[..100]);
+            if workload == Workload::DisputeHeavy {
+                let disputes = rows
+                    .iter()
+                    .filter(|row| row.transaction_type == TransactionType::Dispute)
+                    .count();
+                assert!(disputes > 1000, "{}", disputes);
+            }
+
+            let result = benchmark.run().unwrap();
+            assert_eq!(result.transactions, 5000);
+            assert!(result.transactions_per_sec() > 0.0);
+        }
+        assert_eq!(
+            "many-clients".parse::<Workload>(),
+            Ok(Workload::ManyClients)
+        );
+        assert_eq!(Workload::DisputeHeavy.to_string(), "dispute-heavy");
+    }
+}
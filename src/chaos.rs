@@ -0,0 +1,193 @@
+use crate::generate::SplitMix64;
+use crate::Transaction;
+use std::str::FromStr;
+use std::thread;
+use std::time::Duration;
+use tracing::debug;
+
+/// Failures injected into the input on its way to being applied, for testing how
+/// tools downstream of the engine cope, see `PaymentsEngine::set_chaos`. Written as
+/// `seed=7,reorder=16,duplicate=0.01,delay=0.001,max-delay-ms=200`, where every key
+/// is optional.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Chaos {
+    /// The same seed and input always give the same failures
+    pub seed: u64,
+    /// Transactions are held back and applied in random order, up to this many at a
+    /// time. 0 or 1 keeps the order.
+    pub reorder_window: usize,
+    /// Share of transactions, from 0 to 1, that are applied twice
+    pub duplicate_rate: f64,
+    /// Share of transactions, from 0 to 1, held up before they're applied
+    pub delay_rate: f64,
+    /// Longest a transaction is held up for
+    pub max_delay: Duration,
+}
+
+impl Default for Chaos {
+    /// Nothing injected
+    fn default() -> Chaos {
+        Chaos {
+            seed: 0,
+            reorder_window: 0,
+            duplicate_rate: 0.0,
+            delay_rate: 0.0,
+            max_delay: Duration::from_millis(100),
+        }
+    }
+}
+
+impl FromStr for Chaos {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Chaos, Self::Err> {
+        let mut chaos = Chaos::default();
+        let rate = |value: &str| {
+            value
+                .parse::<f64>()
+                .ok()
+                .filter(|rate| (0.0..=1.0).contains(rate))
+                .ok_or(())
+        };
+        for setting in s.split(',').filter(|setting| !setting.is_empty()) {
+            let (key, value) = setting.split_once('=').ok_or(())?;
+            match key.trim() {
+                "seed" => chaos.seed = value.parse().map_err(|_| ())?,
+                "reorder" => chaos.reorder_window = value.parse().map_err(|_| ())?,
+                "duplicate" => chaos.duplicate_rate = rate(value)?,
+                "delay" => chaos.delay_rate = rate(value)?,
+                "max-delay-ms" => {
+                    chaos.max_delay = Duration::from_millis(value.parse().map_err(|_| ())?)
+                }
+                _ => return Err(()),
+            }
+        }
+        Ok(chaos)
+    }
+}
+
+/// Where the engine's input stands on its way through the chaos
+pub(crate) struct Injector {
+    chaos: Chaos,
+    rng: SplitMix64,
+    /// Transactions held back to be reordered
+    held: Vec<Transaction>,
+}
+
+impl Injector {
+    pub(crate) fn new(chaos: Chaos) -> Injector {
+        Injector {
+            chaos,
+            rng: SplitMix64(chaos.seed),
+            held: Vec::new(),
+        }
+    }
+
+    /// Takes in the next transaction, returning those to apply now, if any
+    pub(crate) fn admit(&mut self, row: Transaction) -> Vec<Transaction> {
+        if self.rng.chance(self.chaos.duplicate_rate) {
+            debug!(tx = row.id, "Duplicating");
+            self.held.push(row.clone());
+        }
+        self.held.push(row);
+        let mut released = Vec::new();
+        while self.held.len() >= self.chaos.reorder_window.max(1) {
+            let picked = self.rng.below(self.held.len() as u64) as usize;
+            released.push(self.held.remove(picked));
+        }
+        self.delay(&released);
+        released
+    }
+
+    /// Every transaction still held back, in random order, once the input is done
+    pub(crate) fn release(&mut self) -> Vec<Transaction> {
+        let mut released = Vec::with_capacity(self.held.len());
+        while !self.held.is_empty() {
+            let picked = self.rng.below(self.held.len() as u64) as usize;
+            released.push(self.held.remove(picked));
+        }
+        self.delay(&released);
+        released
+    }
+
+    /// Holds up the calling thread for each transaction picked to be delayed
+    fn delay(&mut self, released: &[Transaction]) {
+        let max_ms = self.chaos.max_delay.as_millis() as u64;
+        for row in released {
+            if self.rng.chance(self.chaos.delay_rate) {
+                let delay = Duration::from_millis(self.rng.below(max_ms + 1));
+                debug!(tx = row.id, ?delay, "Delaying");
+                thread::sleep(delay);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Generator, OutputOrder, PaymentsEngine, SkipReason};
+
+    #[test]
+    fn reads_settings() {
+        assert_eq!("".parse(), Ok(Chaos::default()));
+        assert_eq!(
+            "seed=7,reorder=16,duplicate=0.5,max-delay-ms=20".parse(),
+            Ok(Chaos {
+                seed: 7,
+                reorder_window: 16,
+                duplicate_rate: 0.5,
+                max_delay: Duration::from_millis(20),
+                ..Chaos::default()
+            })
+        );
+        for bad in ["seed", "duplicate=2", "delay=x", "reorder=-1", "drop=0.1"] {
+            assert_eq!(bad.parse::<Chaos>(), Err(()), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn seed_decides_what_is_reordered_and_duplicated() {
+        let mut feed = Vec::new();
+        Generator {
+            clients: 10,
+            transactions: 500,
+            seed: 1,
+            malformed_rate: 0.0,
+        }
+        .write(&mut feed)
+        .unwrap();
+        let run = |chaos: Chaos| {
+            let mut engine = PaymentsEngine::new();
+            engine.set_chaos(chaos);
+            engine.process(&feed[..]).unwrap();
+            engine
+        };
+        let chaos = Chaos {
+            seed: 3,
+            reorder_window: 8,
+            duplicate_rate: 0.1,
+            ..Chaos::default()
+        };
+        let (first, second) = (run(chaos), run(chaos));
+        assert_eq!(
+            first.clients(OutputOrder::ClientId),
+            second.clients(OutputOrder::ClientId)
+        );
+        assert_eq!(first.skipped(), second.skipped());
+        assert!(first.valid_records() > 500);
+        let duplicates = first.valid_records() - 500;
+        let rejected_duplicates = first.skipped()[&SkipReason::DuplicateTransaction];
+        assert!(rejected_duplicates > 0 && rejected_duplicates <= duplicates);
+        assert!(first.skipped().len() > 1, "{:?}", first.skipped());
+
+        let calm = run(Chaos::default());
+        let mut plain = PaymentsEngine::new();
+        plain.process(&feed[..]).unwrap();
+        assert_eq!(
+            calm.clients(OutputOrder::FirstSeen),
+            plain.clients(OutputOrder::FirstSeen)
+        );
+        assert_eq!(calm.skipped(), plain.skipped());
+    }
+}
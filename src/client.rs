@@ -0,0 +1,617 @@
+use crate::{ClientId, Currency, Rule, Transaction, TransactionType, TxId};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+use tracing::{debug, info, warn};
+
+/// Why a well formed record was skipped instead of applied
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Ord, PartialOrd, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SkipReason {
+    DuplicateTransaction,
+    /// The tx id was already used by a transaction with other details
+    ConflictingDuplicate,
+    UnknownTransaction,
+    OwnershipMismatch,
+    LockedAccount,
+    InsufficientFunds,
+    NotDisputable,
+    AlreadyDisputed,
+    /// The tx was resolved or charged back and can't be disputed again
+    DisputeClosed,
+    /// A resolve or chargeback of a tx that was never disputed
+    NotDisputed,
+    /// A resolve or chargeback of a tx whose dispute was already resolved or
+    /// charged back
+    DisputeSettled,
+    SelfTransfer,
+    /// A dispute, resolve or chargeback naming another currency than its tx's
+    CurrencyMismatch,
+    /// A dispute filed longer after its tx than the dispute window allows
+    DisputeWindowExpired,
+    ClosedAccount,
+    /// An open for a client that already has an account
+    AccountExists,
+    /// A close or reactivate for a client without an account
+    UnknownAccount,
+    /// A reactivate of an account that isn't closed
+    NotClosed,
+    /// A deposit, withdrawal or transfer breaking one of the engine's rules
+    RejectedByRule(Rule),
+}
+
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let reason = match self {
+            SkipReason::DuplicateTransaction => "duplicate tx id",
+            SkipReason::ConflictingDuplicate => "tx id reused with other details",
+            SkipReason::UnknownTransaction => "unknown tx",
+            SkipReason::OwnershipMismatch => "tx belongs to another client",
+            SkipReason::LockedAccount => "account locked",
+            SkipReason::InsufficientFunds => "insufficient funds",
+            SkipReason::NotDisputable => "tx can't be disputed",
+            SkipReason::AlreadyDisputed => "tx already under dispute",
+            SkipReason::DisputeClosed => "tx dispute already settled",
+            SkipReason::NotDisputed => "tx not under dispute",
+            SkipReason::DisputeSettled => "tx dispute already resolved or charged back",
+            SkipReason::SelfTransfer => "transfer to the same client",
+            SkipReason::CurrencyMismatch => "tx is in another currency",
+            SkipReason::DisputeWindowExpired => "dispute window passed",
+            SkipReason::ClosedAccount => "account closed",
+            SkipReason::AccountExists => "account already open",
+            SkipReason::UnknownAccount => "unknown account",
+            SkipReason::NotClosed => "account not closed",
+            SkipReason::RejectedByRule(rule) => return write!(f, "rejected by rule {}", rule),
+        };
+        write!(f, "{}", reason)
+    }
+}
+
+impl SkipReason {
+    /// Label for the audit trail, saying whether the record was rejected as invalid
+    /// or ignored as having nothing to act on
+    pub fn disposition(&self) -> &'static str {
+        match self {
+            SkipReason::DuplicateTransaction => "rejected-duplicate",
+            SkipReason::ConflictingDuplicate => "rejected-conflicting-duplicate",
+            SkipReason::UnknownTransaction => "ignored-unknown-tx",
+            SkipReason::OwnershipMismatch => "rejected-ownership-mismatch",
+            SkipReason::LockedAccount => "rejected-locked-account",
+            SkipReason::InsufficientFunds => "rejected-insufficient-funds",
+            SkipReason::NotDisputable => "rejected-not-disputable",
+            SkipReason::AlreadyDisputed => "rejected-already-disputed",
+            SkipReason::DisputeClosed => "rejected-dispute-closed",
+            SkipReason::NotDisputed => "ignored-not-disputed",
+            SkipReason::DisputeSettled => "ignored-dispute-settled",
+            SkipReason::SelfTransfer => "rejected-self-transfer",
+            SkipReason::CurrencyMismatch => "rejected-currency-mismatch",
+            SkipReason::DisputeWindowExpired => "rejected-dispute-window-expired",
+            SkipReason::ClosedAccount => "rejected-closed-account",
+            SkipReason::AccountExists => "rejected-account-exists",
+            SkipReason::UnknownAccount => "rejected-unknown-account",
+            SkipReason::NotClosed => "ignored-not-closed",
+            SkipReason::RejectedByRule(rule) => rule.disposition(),
+        }
+    }
+
+    /// Whether the record itself is at fault, rather than the state of an account
+    /// such as it being locked, closed, short of funds or over a limit
+    pub fn is_partner_error(&self) -> bool {
+        !matches!(
+            self,
+            SkipReason::LockedAccount
+                | SkipReason::ClosedAccount
+                | SkipReason::InsufficientFunds
+                | SkipReason::RejectedByRule(_)
+        )
+    }
+}
+
+/// What the engine did with a transaction handed to `process_transaction`
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Outcome {
+    Applied,
+    /// A withdrawal or transfer applied, leaving the client's available funds below
+    /// zero as its overdraft policy allows
+    Overdrawn,
+    /// Held back in buffered mode, to be applied once the input is done
+    Deferred,
+    DuplicateTx,
+    /// The tx id was already used by a transaction with other details
+    ConflictingDuplicate,
+    InsufficientFunds,
+    UnknownTx,
+    /// The tx referred to belongs to another client
+    ClientMismatch,
+    AccountLocked,
+    /// The tx referred to is a type that can't be disputed
+    NotDisputable,
+    AlreadyDisputed,
+    /// The tx was resolved or charged back and can't be disputed again
+    DisputeClosed,
+    /// The tx referred to was never disputed
+    NotDisputed,
+    /// The tx referred to had its dispute resolved or charged back already
+    DisputeSettled,
+    SelfTransfer,
+    /// The tx referred to is in another currency
+    CurrencyMismatch,
+    /// The dispute was filed longer after the tx than the dispute window allows
+    DisputeWindowExpired,
+    AccountClosed,
+    /// An open for a client that already has an account
+    AccountExists,
+    /// A close or reactivate for a client without an account
+    UnknownAccount,
+    /// A reactivate of an account that isn't closed
+    NotClosed,
+    /// A deposit, withdrawal or transfer broke the rule, see `PaymentsEngine::set_rules`
+    RejectedByRule(Rule),
+}
+
+impl From<SkipReason> for Outcome {
+    fn from(reason: SkipReason) -> Outcome {
+        match reason {
+            SkipReason::DuplicateTransaction => Outcome::DuplicateTx,
+            SkipReason::ConflictingDuplicate => Outcome::ConflictingDuplicate,
+            SkipReason::UnknownTransaction => Outcome::UnknownTx,
+            SkipReason::OwnershipMismatch => Outcome::ClientMismatch,
+            SkipReason::LockedAccount => Outcome::AccountLocked,
+            SkipReason::InsufficientFunds => Outcome::InsufficientFunds,
+            SkipReason::NotDisputable => Outcome::NotDisputable,
+            SkipReason::AlreadyDisputed => Outcome::AlreadyDisputed,
+            SkipReason::DisputeClosed => Outcome::DisputeClosed,
+            SkipReason::NotDisputed => Outcome::NotDisputed,
+            SkipReason::DisputeSettled => Outcome::DisputeSettled,
+            SkipReason::SelfTransfer => Outcome::SelfTransfer,
+            SkipReason::CurrencyMismatch => Outcome::CurrencyMismatch,
+            SkipReason::DisputeWindowExpired => Outcome::DisputeWindowExpired,
+            SkipReason::ClosedAccount => Outcome::AccountClosed,
+            SkipReason::AccountExists => Outcome::AccountExists,
+            SkipReason::UnknownAccount => Outcome::UnknownAccount,
+            SkipReason::NotClosed => Outcome::NotClosed,
+            SkipReason::RejectedByRule(rule) => Outcome::RejectedByRule(rule),
+        }
+    }
+}
+
+impl Outcome {
+    /// Label as in the audit trail and metrics: `applied`, overdrawn or not,
+    /// `deferred`, or the disposition of the reason it was skipped
+    pub fn disposition(&self) -> &'static str {
+        let reason = match self {
+            Outcome::Applied | Outcome::Overdrawn => return "applied",
+            Outcome::Deferred => return "deferred",
+            Outcome::DuplicateTx => SkipReason::DuplicateTransaction,
+            Outcome::ConflictingDuplicate => SkipReason::ConflictingDuplicate,
+            Outcome::InsufficientFunds => SkipReason::InsufficientFunds,
+            Outcome::UnknownTx => SkipReason::UnknownTransaction,
+            Outcome::ClientMismatch => SkipReason::OwnershipMismatch,
+            Outcome::AccountLocked => SkipReason::LockedAccount,
+            Outcome::NotDisputable => SkipReason::NotDisputable,
+            Outcome::AlreadyDisputed => SkipReason::AlreadyDisputed,
+            Outcome::DisputeClosed => SkipReason::DisputeClosed,
+            Outcome::NotDisputed => SkipReason::NotDisputed,
+            Outcome::DisputeSettled => SkipReason::DisputeSettled,
+            Outcome::SelfTransfer => SkipReason::SelfTransfer,
+            Outcome::CurrencyMismatch => SkipReason::CurrencyMismatch,
+            Outcome::DisputeWindowExpired => SkipReason::DisputeWindowExpired,
+            Outcome::AccountClosed => SkipReason::ClosedAccount,
+            Outcome::AccountExists => SkipReason::AccountExists,
+            Outcome::UnknownAccount => SkipReason::UnknownAccount,
+            Outcome::NotClosed => SkipReason::NotClosed,
+            Outcome::RejectedByRule(rule) => SkipReason::RejectedByRule(*rule),
+        };
+        reason.disposition()
+    }
+}
+
+/// How far a withdrawal or transfer may take the client's available funds below zero
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum OverdraftPolicy {
+    /// Not at all, the withdrawal is rejected for insufficient funds
+    Reject,
+    AllowNegative,
+    /// Down to minus the limit
+    AllowUpTo(Decimal),
+}
+
+impl OverdraftPolicy {
+    /// Whether the client can be left with `available` after the withdrawal
+    fn allows(&self, available: Decimal) -> bool {
+        match self {
+            OverdraftPolicy::Reject => available >= Decimal::ZERO,
+            OverdraftPolicy::AllowNegative => true,
+            OverdraftPolicy::AllowUpTo(limit) => available >= -*limit,
+        }
+    }
+}
+
+impl FromStr for OverdraftPolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<OverdraftPolicy, Self::Err> {
+        match s.split_once(':') {
+            None if s == "reject" => Ok(OverdraftPolicy::Reject),
+            None if s == "allow-negative" => Ok(OverdraftPolicy::AllowNegative),
+            Some(("allow-up-to", limit)) => match Decimal::from_str(limit) {
+                Ok(limit) if limit >= Decimal::ZERO => Ok(OverdraftPolicy::AllowUpTo(limit)),
+                _ => Err(()),
+            },
+            _ => Err(()),
+        }
+    }
+}
+
+/// Whether an account takes transactions, as the accounts output shows it once any
+/// account was opened, closed or reactivated
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccountStatus {
+    Active,
+    /// Frozen by a chargeback
+    Locked,
+    /// Closed by a close record, until reactivated
+    Closed,
+}
+
+/// A client's funds in one currency
+#[derive(Eq, PartialEq, Clone, Debug, Serialize, Deserialize)]
+pub struct Balance {
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+}
+
+impl Balance {
+    pub(crate) fn new() -> Balance {
+        Balance {
+            available: Decimal::from_str("0.0000").unwrap(),
+            held: Decimal::from_str("0.0000").unwrap(),
+            total: Decimal::from_str("0.0000").unwrap(),
+        }
+    }
+}
+
+/// One row of the accounts output, a client's balances in one currency. The
+/// currency is left out for transactions without one, and the fees unless they're
+/// collected per client.
+#[derive(Eq, PartialEq, Clone, Debug, Serialize)]
+pub struct AccountRow<'a> {
+    /// The delta the row was written in, when only changed accounts are written
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sequence: Option<u64>,
+    pub client: ClientId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub currency: Option<&'a str>,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+    pub locked: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<AccountStatus>,
+    /// Fees the client was charged in the currency
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fees: Option<Decimal>,
+}
+
+/// A client account. Its balances are those of transactions without a currency,
+/// any others are kept by currency apart from them. Serialized as json with the
+/// latter under "currencies", if there are any, while the accounts output is
+/// written from its `rows`.
+#[derive(Eq, PartialEq, Clone, Debug, Serialize)]
+pub struct Client {
+    #[serde(rename(serialize = "client"))]
+    pub(crate) id: ClientId,
+    pub(crate) available: Decimal,
+    pub(crate) held: Decimal,
+    pub(crate) total: Decimal,
+    pub(crate) locked: bool,
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub(crate) closed: bool,
+    #[serde(skip_serializing)]
+    pub(crate) disputes: HashSet<TxId>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) currencies: BTreeMap<Currency, Balance>,
+}
+
+impl Client {
+    pub fn id(&self) -> ClientId {
+        self.id
+    }
+
+    /// Funds the client can use, total - held
+    pub fn available(&self) -> Decimal {
+        self.available
+    }
+
+    /// Funds held for open disputes, total - available
+    pub fn held(&self) -> Decimal {
+        self.held
+    }
+
+    /// available + held
+    pub fn total(&self) -> Decimal {
+        self.total
+    }
+
+    /// Whether a chargeback froze the account
+    pub fn locked(&self) -> bool {
+        self.locked
+    }
+
+    /// Whether a close record closed the account
+    pub fn closed(&self) -> bool {
+        self.closed
+    }
+
+    /// Locked before closed, as a locked account can't be closed or reactivated
+    pub fn status(&self) -> AccountStatus {
+        if self.locked {
+            AccountStatus::Locked
+        } else if self.closed {
+            AccountStatus::Closed
+        } else {
+            AccountStatus::Active
+        }
+    }
+
+    /// Tx ids of the transactions whose funds are held for an open dispute
+    pub fn disputes(&self) -> impl Iterator<Item = TxId> + '_ {
+        self.disputes.iter().copied()
+    }
+
+    /// Funds in the currency, or those of transactions without one for `None`
+    pub fn balance(&self, currency: Option<&str>) -> Balance {
+        match currency {
+            None => Balance {
+                available: self.available,
+                held: self.held,
+                total: self.total,
+            },
+            Some(currency) => self
+                .currencies
+                .get(currency)
+                .cloned()
+                .unwrap_or_else(Balance::new),
+        }
+    }
+
+    /// Balances in named currencies, leaving out those without a currency
+    pub fn currencies(&self) -> &BTreeMap<Currency, Balance> {
+        &self.currencies
+    }
+
+    /// A row per currency the client holds funds in. Funds without a currency get a
+    /// row of their own unless they're all zero while there are named currencies.
+    pub fn rows(&self) -> Vec<AccountRow<'_>> {
+        let mut rows = Vec::new();
+        if self.currencies.is_empty() || !self.total.is_zero() || !self.available.is_zero() {
+            rows.push(self.row(None));
+        }
+        for currency in self.currencies.keys() {
+            rows.push(self.row(Some(currency)));
+        }
+        rows
+    }
+
+    fn row<'a>(&'a self, currency: Option<&'a str>) -> AccountRow<'a> {
+        let balance = self.balance(currency);
+        AccountRow {
+            sequence: None,
+            client: self.id,
+            currency,
+            available: balance.available,
+            held: balance.held,
+            total: balance.total,
+            locked: self.locked,
+            status: None,
+            fees: None,
+        }
+    }
+
+    pub(crate) fn new(id: ClientId) -> Client {
+        Client {
+            id,
+            available: Decimal::from_str("0.0000").unwrap(),
+            held: Decimal::from_str("0.0000").unwrap(),
+            locked: false,
+            closed: false,
+            total: Decimal::from_str("0.0000").unwrap(),
+            disputes: HashSet::<TxId>::new(),
+            currencies: BTreeMap::new(),
+        }
+    }
+
+    /// The available and held funds in the currency, or those of transactions
+    /// without one for `None`
+    fn funds_mut(&mut self, currency: Option<&str>) -> (&mut Decimal, &mut Decimal) {
+        match currency {
+            None => (&mut self.available, &mut self.held),
+            Some(currency) => {
+                let balance = self
+                    .currencies
+                    .entry(currency.to_string())
+                    .or_insert_with(Balance::new);
+                (&mut balance.available, &mut balance.held)
+            }
+        }
+    }
+
+    /// Applies the transaction to the client, in the transaction's currency, or
+    /// returns why the account was left untouched. The engine has already turned
+    /// away rows for locked accounts. Withdrawals and transfers may overdraw the
+    /// account as far as `overdraft` allows, a negative
+    /// adjustment regardless.
+    pub(crate) fn handle_transaction(
+        &mut self,
+        transaction_type: &TransactionType,
+        transaction: &Transaction,
+        overdraft: OverdraftPolicy,
+    ) -> Result<(), SkipReason> {
+        use TransactionType::*;
+        let currency = transaction.currency.as_deref();
+        let applied = match transaction_type {
+            Deposit => self.deposit(currency, transaction.amount),
+            // The sending side, the engine credits the receiving client as a deposit
+            Withdrawal | Transfer => self.withdrawal(currency, transaction.amount, overdraft),
+            Dispute => self.dispute(transaction),
+            Resolve => self.resolve(transaction),
+            Chargeback => self.chargeback(transaction),
+            // The engine opens the account, which starts with the amount
+            Open => self.deposit(currency, transaction.amount),
+            Close => {
+                self.closed = true;
+                info!(client = self.id, "Account closed");
+                Ok(())
+            }
+            Reactivate if self.closed => {
+                self.closed = false;
+                info!(client = self.id, "Account reactivated");
+                Ok(())
+            }
+            Reactivate => Err(SkipReason::NotClosed),
+            // A correction either way, which a negative amount takes out
+            Adjustment => self.deposit(currency, transaction.amount),
+        };
+        self.calculate_total();
+        applied
+    }
+
+    fn deposit(&mut self, currency: Option<&str>, amount: Decimal) -> Result<(), SkipReason> {
+        let (available, _) = self.funds_mut(currency);
+        *available += amount;
+        debug!("Client {} deposited {}", self.id, amount);
+        Ok(())
+    }
+
+    fn calculate_total(&mut self) {
+        self.total = self.available + self.held;
+        for balance in self.currencies.values_mut() {
+            balance.total = balance.available + balance.held;
+        }
+    }
+
+    fn withdrawal(
+        &mut self,
+        currency: Option<&str>,
+        amount: Decimal,
+        overdraft: OverdraftPolicy,
+    ) -> Result<(), SkipReason> {
+        let id = self.id;
+        let (available, _) = self.funds_mut(currency);
+        if overdraft.allows(*available - amount) {
+            *available -= amount;
+            if *available < Decimal::ZERO {
+                debug!(
+                    "Client {} withdrew {}, overdrawn to {}",
+                    id, amount, available
+                );
+            } else {
+                debug!("Client {} withdrew {}", id, amount);
+            }
+            Ok(())
+        } else {
+            warn!(
+                "Skipping withdrawal of {} for client {}, insufficient funds",
+                amount, self.id
+            );
+            Err(SkipReason::InsufficientFunds)
+        }
+    }
+
+    /// A disputed deposit, or a transfer on the receiving side, moves its funds from
+    /// available to held. A disputed withdrawal holds its amount pending credit back
+    /// to the client, leaving available as is.
+    fn dispute(&mut self, transaction: &Transaction) -> Result<(), SkipReason> {
+        let tx_id = transaction.id;
+        if self.disputes.contains(&tx_id) {
+            warn!(
+                "Skipping dispute of tx {} for client {}, already under dispute",
+                tx_id, self.id
+            );
+            return Err(SkipReason::AlreadyDisputed);
+        }
+        let amount = transaction.amount;
+        let (available, held) = self.funds_mut(transaction.currency.as_deref());
+        match transaction.transaction_type {
+            TransactionType::Deposit | TransactionType::Transfer => *available -= amount,
+            TransactionType::Withdrawal => (),
+            _ => {
+                warn!(
+                    "Skipping dispute of {:?} tx {} for client {}",
+                    transaction.transaction_type, tx_id, self.id
+                );
+                return Err(SkipReason::NotDisputable);
+            }
+        }
+        *held += amount;
+        self.disputes.insert(tx_id);
+        debug!("Client {} disputed tx {}", self.id, tx_id);
+        Ok(())
+    }
+
+    /// Ends the dispute with the original transaction standing
+    fn resolve(&mut self, transaction: &Transaction) -> Result<(), SkipReason> {
+        let tx_id = transaction.id;
+        if self.disputes.remove(&tx_id) {
+            let amount = transaction.amount;
+            let (available, held) = self.funds_mut(transaction.currency.as_deref());
+            if transaction.transaction_type != TransactionType::Withdrawal {
+                *available += amount;
+            }
+            *held -= amount;
+            debug!("Client {} resolved tx {}", self.id, tx_id);
+            Ok(())
+        } else {
+            warn!(
+                "Skipping resolve of tx {} for client {}, no open dispute",
+                tx_id, self.id
+            );
+            Err(SkipReason::NotDisputed)
+        }
+    }
+
+    /// Pays a charged back transfer back to its sender, whether or not the sender's
+    /// account is locked
+    pub(crate) fn reverse_transfer(&mut self, transaction: &Transaction) {
+        let (available, _) = self.funds_mut(transaction.currency.as_deref());
+        *available += transaction.amount;
+        self.calculate_total();
+        debug!(
+            "Client {} was paid back transfer tx {}",
+            self.id, transaction.id
+        );
+    }
+
+    /// Ends the dispute by reversing the original transaction and locks the account.
+    /// The funds move the opposite way to the original transaction.
+    fn chargeback(&mut self, transaction: &Transaction) -> Result<(), SkipReason> {
+        let tx_id = transaction.id;
+        if self.disputes.remove(&tx_id) {
+            let amount = transaction.amount;
+            let (available, held) = self.funds_mut(transaction.currency.as_deref());
+            match transaction.transaction_type {
+                // The deposited or transferred funds held for the dispute leave the
+                // account, the engine credits a transfer back to its sender
+                TransactionType::Deposit | TransactionType::Transfer => *held -= amount,
+                // The withdrawn funds held for the dispute are credited back
+                _ => {
+                    *held -= amount;
+                    *available += amount;
+                }
+            }
+            self.locked = true;
+            info!(client = self.id, tx = tx_id, "Account locked by chargeback");
+            Ok(())
+        } else {
+            warn!(
+                "Skipping chargeback of tx {} for client {}, no open dispute",
+                tx_id, self.id
+            );
+            Err(SkipReason::NotDisputed)
+        }
+    }
+}
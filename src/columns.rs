@@ -0,0 +1,517 @@
+use crate::{PaymentsError, TransactionType};
+use csv::{ByteRecord, StringRecord};
+use std::io::{self, BufRead, Read};
+use std::str::FromStr;
+use toml_edit::{DocumentMut, TableLike};
+use tracing::warn;
+
+/// The columns a header read by position starts with, in order
+const POSITIONAL_HEADER: [Column; 4] = [Column::Type, Column::Client, Column::Tx, Column::Amount];
+
+/// The fields of a csv record
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub(crate) enum Column {
+    Type,
+    Client,
+    Tx,
+    Amount,
+    ToClient,
+    Reference,
+    Currency,
+    Timestamp,
+}
+
+impl Column {
+    pub(crate) const ALL: [Column; 8] = [
+        Column::Type,
+        Column::Client,
+        Column::Tx,
+        Column::Amount,
+        Column::ToClient,
+        Column::Reference,
+        Column::Currency,
+        Column::Timestamp,
+    ];
+
+    /// Its name in the usual header, and its key in a column map
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            Column::Type => "type",
+            Column::Client => "client",
+            Column::Tx => "tx",
+            Column::Amount => "amount",
+            Column::ToClient => "to_client",
+            Column::Reference => "reference",
+            Column::Currency => "currency",
+            Column::Timestamp => "timestamp",
+        }
+    }
+
+    /// Whether a header has to have it for any record to be read
+    fn required(self) -> bool {
+        matches!(self, Column::Type | Column::Client | Column::Tx)
+    }
+}
+
+/// Header names of the csv columns, for input whose header differs from the usual
+/// `type,client,tx,amount`, see `PaymentsEngine::set_column_map`. Columns are then
+/// matched by name, in any order and among any others. Read from TOML such as:
+///
+/// ```toml
+/// type = "txn_type"
+/// client = "cust"
+/// tx = "txn_id"
+/// amount = "value"
+/// ```
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ColumnMap {
+    names: [String; 8],
+}
+
+impl Default for ColumnMap {
+    /// Every column under its usual name
+    fn default() -> ColumnMap {
+        ColumnMap {
+            names: Column::ALL.map(|column| column.name().to_string()),
+        }
+    }
+}
+
+impl ColumnMap {
+    /// Parses a map of field to header name, where fields left out keep their usual
+    /// name. The fields are type, client, tx, amount, to_client, reference, currency
+    /// and timestamp.
+    pub fn from_toml(text: &str) -> Result<ColumnMap, PaymentsError> {
+        let document: DocumentMut = text
+            .parse()
+            .map_err(|err| PaymentsError::ColumnMap(format!("{}", err).trim().to_string()))?;
+        ColumnMap::from_table(document.as_table())
+    }
+
+    /// Reads the map from a TOML table, such as a profile's `columns`
+    pub(crate) fn from_table(table: &dyn TableLike) -> Result<ColumnMap, PaymentsError> {
+        let mut map = ColumnMap::default();
+        for (key, item) in table.iter() {
+            let column = Column::ALL
+                .into_iter()
+                .find(|column| column.name() == key)
+                .ok_or_else(|| PaymentsError::ColumnMap(format!("Unknown field {}", key)))?;
+            let name = item.as_str().ok_or_else(|| {
+                PaymentsError::ColumnMap(format!("Invalid {}, expected a column name", key))
+            })?;
+            map.names[column as usize] = name.trim().to_string();
+        }
+        Ok(map)
+    }
+
+    /// The header name the field is read from
+    pub fn name(&self, field: &str) -> Option<&str> {
+        Column::ALL
+            .into_iter()
+            .find(|column| column.name() == field)
+            .map(|column| self.names[column as usize].as_str())
+    }
+
+    /// Where each column is in records under `header`. Fails if the header lacks the
+    /// type, client or tx column.
+    pub(crate) fn resolve(&self, header: &StringRecord) -> Result<Columns, PaymentsError> {
+        let mut indexes = [None; 8];
+        for column in Column::ALL {
+            let name = &self.names[column as usize];
+            indexes[column as usize] = header.iter().position(|field| field.trim() == name);
+            if column.required() && indexes[column as usize].is_none() {
+                return Err(PaymentsError::ColumnMap(format!(
+                    "No {} column {:?} in the header",
+                    column.name(),
+                    name
+                )));
+            }
+        }
+        Ok(Columns::Named(indexes))
+    }
+}
+
+/// Where the columns are in a csv record
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub(crate) enum Columns {
+    /// The usual order, with the receiving client of a transfer or the reference of an
+    /// adjustment after the amount, for input without a header naming any column
+    /// after the amount
+    Positional,
+    /// Matched by name to the header, `None` for columns it doesn't have
+    Named([Option<usize>; 8]),
+}
+
+impl Columns {
+    /// Indexes of the columns matched by name, none for `Positional`
+    #[cfg(feature = "parquet")]
+    pub(crate) fn named(&self) -> impl Iterator<Item = usize> + '_ {
+        let indexes = match self {
+            Columns::Named(indexes) => &indexes[..],
+            Columns::Positional => &[],
+        };
+        indexes.iter().flatten().copied()
+    }
+
+    /// Index of the column in a record of the type
+    pub(crate) fn index(
+        &self,
+        column: Column,
+        transaction_type: &TransactionType,
+    ) -> Option<usize> {
+        match self {
+            Columns::Named(indexes) => indexes[column as usize],
+            Columns::Positional => {
+                // Everything after the amount moves along one for a transfer or an
+                // adjustment
+                let transfer = *transaction_type == TransactionType::Transfer;
+                let adjustment = *transaction_type == TransactionType::Adjustment;
+                let shift = usize::from(transfer || adjustment);
+                match column {
+                    Column::Type => Some(0),
+                    Column::Client => Some(1),
+                    Column::Tx => Some(2),
+                    Column::Amount => Some(3),
+                    Column::ToClient => transfer.then_some(4),
+                    Column::Reference => adjustment.then_some(4),
+                    Column::Currency => Some(4 + shift),
+                    Column::Timestamp => Some(5 + shift),
+                }
+            }
+        }
+    }
+}
+
+/// Where the fields are in each line of fixed-width input, see
+/// `InputFormat::FixedWidth`. Parsed from the fields in the order they're laid out,
+/// each with its width in characters, such as `type=10,client=6,tx=10,amount=16`.
+/// The fields are named as in a column map, with `_` for filler that's skipped, and
+/// need at least the type, client and tx.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct FixedWidths {
+    /// Each field's column, `None` for filler, and width
+    fields: Vec<(Option<Column>, usize)>,
+}
+
+impl FromStr for FixedWidths {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<FixedWidths, Self::Err> {
+        let mut fields = Vec::new();
+        for field in s.split(',') {
+            let (name, width) = field.split_once('=').ok_or(())?;
+            let width = width.trim().parse::<usize>().map_err(|_| ())?;
+            let column = match name.trim() {
+                "_" => None,
+                name => Some(
+                    Column::ALL
+                        .into_iter()
+                        .find(|column| column.name() == name)
+                        .ok_or(())?,
+                ),
+            };
+            let repeated = column.is_some() && fields.iter().any(|(seen, _)| *seen == column);
+            if width == 0 || repeated {
+                return Err(());
+            }
+            fields.push((column, width));
+        }
+        let has = |column| fields.iter().any(|(seen, _)| *seen == Some(column));
+        if !Column::ALL
+            .into_iter()
+            .filter(|column| column.required())
+            .all(has)
+        {
+            return Err(());
+        }
+        Ok(FixedWidths { fields })
+    }
+}
+
+impl FixedWidths {
+    /// Cuts a line into its fields, filler included, trimmed of their padding. A
+    /// short line is missing the fields past its end, as trailing spaces are often
+    /// left off.
+    pub(crate) fn split(&self, line: &str) -> StringRecord {
+        let mut record = StringRecord::new();
+        let mut rest = line;
+        for &(_, width) in &self.fields {
+            if rest.is_empty() {
+                break;
+            }
+            let end = rest
+                .char_indices()
+                .nth(width)
+                .map_or(rest.len(), |(index, _)| index);
+            record.push_field(rest[..end].trim());
+            rest = &rest[end..];
+        }
+        record
+    }
+
+    /// Where each column is in the records `split` gives
+    pub(crate) fn columns(&self) -> Columns {
+        let mut indexes = [None; 8];
+        for (index, (column, _)) in self.fields.iter().enumerate() {
+            if let Some(column) = column {
+                indexes[*column as usize] = Some(index);
+            }
+        }
+        Columns::Named(indexes)
+    }
+
+    /// Whether the line is a header rather than a transaction, going by whether its
+    /// type field is a transaction type
+    pub(crate) fn is_header(&self, line: &str) -> bool {
+        let Some(index) = self
+            .fields
+            .iter()
+            .position(|(column, _)| *column == Some(Column::Type))
+        else {
+            return false;
+        };
+        self.split(line)
+            .get(index)
+            .is_none_or(|field| field.to_lowercase().parse::<TransactionType>().is_err())
+    }
+}
+
+/// Reads csv input, its fields split on `delimiter`, up to its first row that isn't
+/// blank, returning that row along
+/// with the input put back together, so it can be looked at before deciding how the
+/// rest is read. `None` for input without any rows.
+pub(crate) fn peek_first_row<R: Read>(
+    input: R,
+    delimiter: u8,
+) -> Result<(Option<StringRecord>, impl Read), PaymentsError> {
+    let mut input = io::BufReader::new(input);
+    let mut read = Vec::new();
+    loop {
+        let start = read.len();
+        if input.read_until(b'\n', &mut read)? == 0 {
+            return Ok((None, io::Cursor::new(read).chain(input)));
+        }
+        if !read[start..].iter().all(u8::is_ascii_whitespace) {
+            let mut row = StringRecord::new();
+            csv::ReaderBuilder::new()
+                .delimiter(delimiter)
+                .has_headers(false)
+                .flexible(true)
+                .from_reader(&read[start..])
+                .read_record(&mut row)?;
+            return Ok((Some(row), io::Cursor::new(read).chain(input)));
+        }
+    }
+}
+
+/// Where the columns are in csv input read without a column map, going by its first
+/// row, or `None` if that row is a transaction rather than a header. A header has to
+/// start with the type, client, tx and amount columns, and fails with
+/// `PaymentsError::Header` if it names them in another order, as they'd be read as
+/// the wrong fields. The columns after the amount are then matched by name if the
+/// header names any of them, and read by position otherwise. One naming other
+/// columns is still taken as a header, with a warning, its columns read by position,
+/// as is any other row that doesn't start with a transaction type. Input without a
+/// header is read from its first row, also with a warning.
+pub(crate) fn positional_header(row: &StringRecord) -> Result<Option<Columns>, PaymentsError> {
+    let names: Vec<String> = row
+        .iter()
+        .map(|field| field.trim_start_matches('\u{feff}').trim().to_lowercase())
+        .collect();
+    if names
+        .first()
+        .is_some_and(|name| name.parse::<TransactionType>().is_ok())
+    {
+        warn!("No header, reading the first row as a transaction");
+        return Ok(None);
+    }
+    let mut missing = Vec::new();
+    for (expected, column) in POSITIONAL_HEADER.into_iter().enumerate() {
+        match names.iter().position(|name| name == column.name()) {
+            Some(index) if index == expected => (),
+            Some(index) => {
+                return Err(PaymentsError::Header(format!(
+                    "{} is column {} rather than {}, expected {}, or a --column-map",
+                    column.name(),
+                    index + 1,
+                    expected + 1,
+                    POSITIONAL_HEADER.map(Column::name).join(", ")
+                )))
+            }
+            None => missing.push(column.name()),
+        }
+    }
+    if !missing.is_empty() {
+        warn!(
+            "Header {:?} has no {} column, reading its columns by position",
+            names.join(","),
+            missing.join(", ")
+        );
+        return Ok(Some(Columns::Positional));
+    }
+    let mut indexes = [None; 8];
+    for column in Column::ALL {
+        indexes[column as usize] = names.iter().position(|name| name == column.name());
+    }
+    if indexes[POSITIONAL_HEADER.len()..]
+        .iter()
+        .all(Option::is_none)
+    {
+        return Ok(Some(Columns::Positional));
+    }
+    Ok(Some(Columns::Named(indexes)))
+}
+
+/// A csv record whose fields can be read as text
+pub(crate) trait Fields {
+    /// The field at `index`, `None` past the end of the record or if it isn't utf-8
+    fn field(&self, index: usize) -> Option<&str>;
+}
+
+impl Fields for StringRecord {
+    fn field(&self, index: usize) -> Option<&str> {
+        self.get(index)
+    }
+}
+
+/// Read in place, without copying the record into a `String`
+impl Fields for ByteRecord {
+    fn field(&self, index: usize) -> Option<&str> {
+        self.get(index)
+            .and_then(|field| std::str::from_utf8(field).ok())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_columns_by_name() {
+        let map = ColumnMap::from_toml(
+            "type = \"txn_type\"\n\
+             client = \"cust\"\n\
+             tx = \"txn_id\"\n\
+             amount = \"value\"\n",
+        )
+        .unwrap();
+        assert_eq!(map.name("client"), Some("cust"));
+        assert_eq!(map.name("currency"), Some("currency"));
+
+        let columns = map
+            .resolve(&StringRecord::from(vec![
+                "cust",
+                " txn_type",
+                "note",
+                "value",
+                "txn_id",
+            ]))
+            .unwrap();
+        let deposit = TransactionType::Deposit;
+        assert_eq!(columns.index(Column::Type, &deposit), Some(1));
+        assert_eq!(columns.index(Column::Tx, &deposit), Some(4));
+        assert_eq!(columns.index(Column::Amount, &deposit), Some(3));
+        assert_eq!(columns.index(Column::Currency, &deposit), None);
+
+        assert!(matches!(
+            map.resolve(&StringRecord::from(vec!["type", "client", "tx", "amount"])),
+            Err(PaymentsError::ColumnMap(_))
+        ));
+        for bad in ["account = \"acct\"", "tx = 3", "tx = "] {
+            assert!(
+                matches!(ColumnMap::from_toml(bad), Err(PaymentsError::ColumnMap(_))),
+                "{}",
+                bad
+            );
+        }
+    }
+
+    #[test]
+    fn tells_headers_from_transactions() {
+        let is_header = |row: &str| {
+            positional_header(&StringRecord::from(row.split(',').collect::<Vec<_>>()))
+                .map(|columns| columns.is_some())
+        };
+        assert!(is_header("type, client, tx, amount").unwrap());
+        assert!(is_header("\u{feff}Type,Client,Tx,Amount,currency").unwrap());
+        // Other names are read by position, with a warning
+        assert!(is_header("kind,customer,id,value").unwrap());
+        assert!(!is_header("deposit, 1, 1, 1.0").unwrap());
+        assert!(!is_header("dispute,2,3").unwrap());
+        assert!(matches!(
+            is_header("client,type,tx,amount"),
+            Err(PaymentsError::Header(_))
+        ));
+        assert!(matches!(
+            is_header("type,client,amount,tx"),
+            Err(PaymentsError::Header(_))
+        ));
+    }
+
+    #[test]
+    fn matches_the_columns_after_the_amount_by_name() {
+        let columns = |row: &str| {
+            positional_header(&StringRecord::from(row.split(',').collect::<Vec<_>>()))
+                .unwrap()
+                .unwrap()
+        };
+        let named = columns("type,client,tx,amount,currency");
+        let deposit = TransactionType::Deposit;
+        assert_eq!(named.index(Column::Amount, &deposit), Some(3));
+        assert_eq!(named.index(Column::Currency, &deposit), Some(4));
+        assert_eq!(named.index(Column::Timestamp, &deposit), None);
+        let transfer = TransactionType::Transfer;
+        let named = columns("type,client,tx,amount,timestamp,to_client");
+        assert_eq!(named.index(Column::ToClient, &transfer), Some(5));
+        assert_eq!(named.index(Column::Timestamp, &transfer), Some(4));
+        assert_eq!(named.index(Column::Currency, &transfer), None);
+
+        assert_eq!(columns("type,client,tx,amount"), Columns::Positional);
+        assert_eq!(
+            columns("kind,customer,id,value,currency"),
+            Columns::Positional
+        );
+    }
+
+    #[test]
+    fn peeks_past_blank_lines_and_puts_the_input_back() {
+        let input = "\n  \ntype,client,tx,amount\ndeposit,1,1,1.0\n";
+        let (row, mut rest) = peek_first_row(input.as_bytes(), b',').unwrap();
+        assert_eq!(row.unwrap(), vec!["type", "client", "tx", "amount"]);
+        let mut read = String::new();
+        rest.read_to_string(&mut read).unwrap();
+        assert_eq!(read, input);
+
+        let (row, _) = peek_first_row("\n".as_bytes(), b',').unwrap();
+        assert_eq!(row, None);
+    }
+
+    #[test]
+    fn splits_fixed_width_lines() {
+        let widths: FixedWidths = "type=10,client=4,_=2,tx=6,amount=8".parse().unwrap();
+        assert_eq!(
+            widths.split("deposit      1XX    12  1.5000"),
+            vec!["deposit", "1", "XX", "12", "1.5000"]
+        );
+        assert_eq!(
+            widths.split("dispute      1XX    12"),
+            vec!["dispute", "1", "XX", "12"]
+        );
+        let deposit = TransactionType::Deposit;
+        let columns = widths.columns();
+        assert_eq!(columns.index(Column::Tx, &deposit), Some(3));
+        assert_eq!(columns.index(Column::Currency, &deposit), None);
+        assert!(widths.is_header("TYPE      CLNT  TX    AMOUNT"));
+        assert!(!widths.is_header("Deposit      1XX    12  1.5000"));
+
+        for bad in [
+            "",
+            "type=10,client=4",
+            "type=10,client=4,tx=0",
+            "type=1,client=1,tx=1,type=1",
+            "kind=1",
+        ] {
+            assert!(bad.parse::<FixedWidths>().is_err(), "{}", bad);
+        }
+    }
+}
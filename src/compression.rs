@@ -0,0 +1,185 @@
+use flate2::read::MultiGzDecoder;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Read, Seek};
+use std::path::Path;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// How an input file is compressed, told by its extension or, failing that, its
+/// first bytes
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Compression {
+    None,
+    /// `.gz`, possibly several gzip members one after the other as `cat` leaves them
+    Gzip,
+    /// `.zst`
+    Zstd,
+}
+
+impl Compression {
+    /// The compression of the file at `path`, leaving `file` at its start
+    pub fn detect(path: &Path, file: &mut File) -> io::Result<Compression> {
+        match path.extension().and_then(OsStr::to_str) {
+            Some("gz") => return Ok(Compression::Gzip),
+            Some("zst") => return Ok(Compression::Zstd),
+            _ => (),
+        }
+        let mut magic = Vec::with_capacity(ZSTD_MAGIC.len());
+        (&mut *file)
+            .take(ZSTD_MAGIC.len() as u64)
+            .read_to_end(&mut magic)?;
+        file.rewind()?;
+        Ok(if magic.starts_with(GZIP_MAGIC) {
+            Compression::Gzip
+        } else if magic.starts_with(ZSTD_MAGIC) {
+            Compression::Zstd
+        } else {
+            Compression::None
+        })
+    }
+
+    /// Reads `file` decompressed, streaming it through the decompressor as it's
+    /// read rather than decompressing it up front. A file that can't be
+    /// decompressed, such as a truncated one, fails the read once it gets there, or
+    /// this straight away if nothing can be read of it.
+    pub fn decompress(self, file: File) -> io::Result<Box<dyn Read + Send>> {
+        match self {
+            Compression::None => Ok(Box::new(file)),
+            Compression::Gzip => {
+                Decompressed::start("gzip", MultiGzDecoder::new(BufReader::new(file)))
+            }
+            Compression::Zstd => Decompressed::start("zstd", zstd::Decoder::new(file)?),
+        }
+    }
+}
+
+/// The output of a decompressor reading a file, naming the format in its errors
+struct Decompressed<R> {
+    format: &'static str,
+    decoder: R,
+}
+
+impl<R: Read + Send + 'static> Decompressed<R> {
+    fn start(format: &'static str, decoder: R) -> io::Result<Box<dyn Read + Send>> {
+        let mut decompressed = BufReader::new(Decompressed { format, decoder });
+        // Input that isn't compressed at all fails here rather than part way through
+        decompressed.fill_buf()?;
+        Ok(Box::new(decompressed))
+    }
+}
+
+impl<R: Read> Read for Decompressed<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.decoder
+            .read(buf)
+            .map_err(|err| io::Error::new(err.kind(), format!("Invalid {}: {}", self.format, err)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use std::io::Write;
+
+    fn temp_file(name: &str, contents: &[u8]) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "toy-payments-compression-{}-{}",
+            std::process::id(),
+            name
+        ));
+        File::create(&path).unwrap().write_all(contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn detects_compression_by_extension_or_magic_bytes() {
+        for (name, contents, compression) in [
+            (
+                "plain.csv",
+                &b"type,client,tx,amount\n"[..],
+                Compression::None,
+            ),
+            ("archived.csv.gz", b"", Compression::Gzip),
+            ("archived.csv.zst", b"", Compression::Zstd),
+            ("gzipped", &[0x1f, 0x8b, 0x08, 0x00], Compression::Gzip),
+            ("zstd", &[0x28, 0xb5, 0x2f, 0xfd, 0x04], Compression::Zstd),
+            ("short", b"t", Compression::None),
+        ] {
+            let path = temp_file(name, contents);
+            let mut file = File::open(&path).unwrap();
+            assert_eq!(
+                Compression::detect(&path, &mut file).unwrap(),
+                compression,
+                "{}",
+                name
+            );
+            // Left at the start for reading
+            let mut read = Vec::new();
+            file.read_to_end(&mut read).unwrap();
+            assert_eq!(read, contents);
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn streams_concatenated_gzip_and_zstd() {
+        let mut gzipped = Vec::new();
+        for part in [&b"type,client,tx,amount\n"[..], b"deposit,1,1,1.0\n"] {
+            let mut gzip = GzEncoder::new(Vec::new(), flate2::Compression::default());
+            gzip.write_all(part).unwrap();
+            gzipped.extend(gzip.finish().unwrap());
+        }
+        let plain = b"type,client,tx,amount\ndeposit,1,1,1.0\n";
+        let zstd = zstd::encode_all(&plain[..], 0).unwrap();
+
+        for (name, compression, compressed) in [
+            ("cat.csv.gz", Compression::Gzip, gzipped),
+            ("archived.csv.zst", Compression::Zstd, zstd),
+        ] {
+            let path = temp_file(name, &compressed);
+            let mut read = Vec::new();
+            compression
+                .decompress(File::open(&path).unwrap())
+                .unwrap()
+                .read_to_end(&mut read)
+                .unwrap();
+            assert_eq!(read, plain, "{}", name);
+            std::fs::remove_file(path).unwrap();
+        }
+    }
+
+    #[test]
+    fn streams_gzip_and_fails_on_corrupt_input() {
+        let plain = b"type,client,tx,amount\ndeposit,1,1,1.0\n";
+        let mut gzip = GzEncoder::new(Vec::new(), flate2::Compression::default());
+        gzip.write_all(plain).unwrap();
+        let compressed = gzip.finish().unwrap();
+
+        let path = temp_file("round-trip.csv.gz", &compressed);
+        let mut read = Vec::new();
+        Compression::Gzip
+            .decompress(File::open(&path).unwrap())
+            .unwrap()
+            .read_to_end(&mut read)
+            .unwrap();
+        assert_eq!(read, plain);
+
+        // Cut off part way through
+        std::fs::write(&path, &compressed[..compressed.len() / 2]).unwrap();
+        let err = Compression::Gzip
+            .decompress(File::open(&path).unwrap())
+            .unwrap()
+            .read_to_end(&mut Vec::new())
+            .unwrap_err();
+        assert!(err.to_string().starts_with("Invalid gzip: "), "{}", err);
+
+        std::fs::write(&path, plain).unwrap();
+        assert!(Compression::Gzip
+            .decompress(File::open(&path).unwrap())
+            .is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,223 @@
+use crate::{AccountStore, ClientId, DisputePolicy, OutputOrder, PaymentsEngine, TransactionStore};
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tracing::{debug, info, warn};
+
+/// The engine as the control socket answers for it, taken with `ControlState::of`
+/// whenever it should be brought up to date
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct ControlState {
+    /// Header of the accounts csv
+    header: String,
+    /// Each client's lines of the accounts csv, a line per currency
+    rows: BTreeMap<ClientId, String>,
+    /// The answer to `STATS`
+    stats: String,
+}
+
+impl ControlState {
+    /// The engine's accounts and counts as they are now
+    pub fn of<T: TransactionStore, A: AccountStore, P: DisputePolicy>(
+        engine: &PaymentsEngine<T, A, P>,
+    ) -> ControlState {
+        let account_rows = engine.account_rows(OutputOrder::ClientId);
+        let mut writer = csv::Writer::from_writer(Vec::new());
+        for row in &account_rows {
+            // Only fails on io, and this writes to memory
+            let _ = writer.serialize(row);
+        }
+        let csv = writer
+            .into_inner()
+            .map(|csv| String::from_utf8_lossy(&csv).into_owned())
+            .unwrap_or_default();
+        let mut lines = csv.lines();
+        let header = lines.next().unwrap_or_default().to_string();
+        let mut rows = BTreeMap::new();
+        for (row, line) in account_rows.iter().zip(lines) {
+            let client: &mut String = rows.entry(row.client).or_default();
+            client.push_str(line);
+            client.push('\n');
+        }
+
+        let report = engine.report();
+        let skipped: usize = engine.skipped().values().sum();
+        let mut stats = String::new();
+        for (name, count) in [
+            ("transactions", engine.valid_records()),
+            ("applied", engine.applied_records()),
+            ("rejected", skipped),
+            ("invalid records", engine.invalid_records()),
+            ("clients", rows.len()),
+            ("locked accounts", report.locked_accounts),
+            ("open disputes", report.open_disputes),
+        ] {
+            let _ = writeln!(stats, "{}: {}", name, count);
+        }
+        ControlState {
+            header,
+            rows,
+            stats,
+        }
+    }
+
+    /// The answer to a command line, ending with an empty line. Errors start with
+    /// `ERR`.
+    fn answer(&self, command: &str) -> String {
+        let (name, argument) = command
+            .split_once(' ')
+            .map_or((command, ""), |(name, argument)| (name, argument.trim()));
+        let answer = match (name, argument) {
+            ("GET", client) if !client.is_empty() => match client.parse::<ClientId>() {
+                Ok(client) => match self.rows.get(&client) {
+                    Some(rows) => format!("{}\n{}", self.header, rows),
+                    None => format!("ERR Unknown client {}\n", client),
+                },
+                Err(_) => format!("ERR Invalid client {}\n", client),
+            },
+            ("SNAPSHOT", path) if !path.is_empty() => match self.snapshot(Path::new(path)) {
+                Ok(()) => "OK\n".to_string(),
+                Err(err) => format!("ERR Failed to write {} {}\n", path, err),
+            },
+            ("STATS", "") => self.stats.clone(),
+            _ => format!("ERR Unknown command {:?}\n", command),
+        };
+        answer + "\n"
+    }
+
+    /// Writes the accounts csv next to `path` first and then moves it into place
+    fn snapshot(&self, path: &Path) -> io::Result<()> {
+        let mut partial = path.as_os_str().to_owned();
+        partial.push(".tmp");
+        let partial = PathBuf::from(partial);
+        let mut file = io::BufWriter::new(fs::File::create(&partial)?);
+        if !self.rows.is_empty() {
+            writeln!(file, "{}", self.header)?;
+        }
+        for rows in self.rows.values() {
+            file.write_all(rows.as_bytes())?;
+        }
+        file.into_inner()?.sync_all()?;
+        fs::rename(&partial, path)
+    }
+}
+
+/// Answers commands sent to a Unix domain socket bound at `path`, replacing a stale
+/// one, from threads of their own, with whatever `latest` holds, such as the engine
+/// as of the last file `watch` applied. Connections are served at the same time,
+/// each sending a command per line:
+///
+/// - `GET <client>` answers with the client's rows of the accounts csv, header
+///   included
+/// - `SNAPSHOT <path>` writes the accounts csv to `path`, answering `OK`
+/// - `STATS` answers with the counts of transactions, clients and disputes
+///
+/// Every answer ends with an empty line. Fails if `path` can't be bound.
+pub fn serve_control(
+    path: &Path,
+    latest: Arc<Mutex<ControlState>>,
+) -> Result<thread::JoinHandle<()>, Box<dyn Error + Send + Sync>> {
+    let file_type = fs::metadata(path).map(|metadata| metadata.file_type()).ok();
+    if file_type.is_some_and(|file_type| file_type.is_socket()) {
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    info!("Answering control commands on {}", path.display());
+    Ok(thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let latest = Arc::clone(&latest);
+                    thread::spawn(move || {
+                        if let Err(err) = answer_commands(stream, &latest) {
+                            warn!("Control connection failed: {}", err);
+                        }
+                    });
+                }
+                Err(err) => warn!("Failed to accept control connection: {}", err),
+            }
+        }
+    }))
+}
+
+/// Answers every command read from `stream` until it's closed
+fn answer_commands(stream: UnixStream, latest: &Mutex<ControlState>) -> io::Result<()> {
+    debug!("Control client connected");
+    let mut writer = stream.try_clone()?;
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let answer = match latest.lock() {
+            Ok(state) => state.answer(line.trim()),
+            Err(_) => "ERR The engine stopped\n\n".to_string(),
+        };
+        writer.write_all(answer.as_bytes())?;
+    }
+    debug!("Control client disconnected");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use csv::StringRecord;
+
+    #[test]
+    fn answers_commands_from_the_latest_state() {
+        let mut engine = PaymentsEngine::new();
+        for row in [
+            &["deposit", "2", "1", "5.0"][..],
+            &["deposit", "1", "2", "3.0", "USD"],
+            &["dispute", "2", "1"],
+        ] {
+            engine
+                .process_record(StringRecord::from(row.to_vec()))
+                .unwrap();
+        }
+        let dir = std::env::temp_dir().join(format!("toy-payments-control-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let socket = dir.join("control.sock");
+        let latest = Arc::new(Mutex::new(ControlState::default()));
+        serve_control(&socket, Arc::clone(&latest)).unwrap();
+        *latest.lock().unwrap() = ControlState::of(&engine);
+
+        let stream = UnixStream::connect(&socket).unwrap();
+        let mut writer = stream.try_clone().unwrap();
+        let mut reader = BufReader::new(stream);
+        let mut ask = |command: &str| {
+            writeln!(writer, "{}", command).unwrap();
+            let mut answer = String::new();
+            while !answer.ends_with("\n\n") {
+                reader.read_line(&mut answer).unwrap();
+            }
+            answer
+        };
+        assert_eq!(
+            ask("GET 2"),
+            "client,currency,available,held,total,locked\n2,,0.0000,5.0000,5.0000,false\n\n"
+        );
+        assert_eq!(ask("GET 3"), "ERR Unknown client 3\n\n");
+        assert!(ask("STATS").contains("transactions: 3\n"));
+        assert!(ask("STATS").contains("open disputes: 1\n"));
+        assert!(ask("FLUSH").starts_with("ERR Unknown command"));
+
+        let snapshot = dir.join("accounts.csv");
+        assert_eq!(ask(&format!("SNAPSHOT {}", snapshot.display())), "OK\n\n");
+        assert_eq!(
+            fs::read_to_string(&snapshot).unwrap(),
+            "client,currency,available,held,total,locked\n\
+             1,USD,3.0000,0.0000,3.0000,false\n\
+             2,,0.0000,5.0000,5.0000,false\n"
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,42 @@
+use crate::{OutputOrder, PaymentsEngine, PaymentsError};
+
+/// Applies a whole csv input held in memory and returns the accounts csv, as the
+/// command line would write it with the default settings. Touches no files, threads
+/// or clock, so it suits embedding where there are none, such as a browser demo.
+pub fn process_csv(input: &[u8]) -> Result<String, PaymentsError> {
+    let mut engine = PaymentsEngine::new();
+    engine.process(input)?;
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    for row in engine.account_rows(OutputOrder::ClientId) {
+        writer.serialize(row)?;
+    }
+    writer.flush()?;
+    // Flushed already, and written to memory, so there's nothing left to fail
+    let accounts = writer.into_inner().unwrap_or_default();
+    Ok(String::from_utf8_lossy(&accounts).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn returns_the_accounts_csv() {
+        let accounts = process_csv(
+            b"type, client, tx, amount\n\
+              deposit, 1, 9, 1.0\n\
+              deposit, 2, 1, 2.0\n\
+              deposit, 1, 2, 3.0\n\
+              refund, 1, 3, 1.0\n\
+              dispute, 2, 1\n",
+        )
+        .unwrap();
+        assert_eq!(
+            accounts,
+            "client,available,held,total,locked\n\
+             1,4.0000,0.0000,4.0000,false\n\
+             2,0.0000,2.0000,2.0000,false\n"
+        );
+        assert_eq!(process_csv(b"").unwrap(), "");
+    }
+}
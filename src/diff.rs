@@ -0,0 +1,157 @@
+use crate::{ClientId, Currency, PaymentsError};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io;
+
+/// One row of an accounts csv, as written by the accounts output
+#[derive(Debug, Deserialize)]
+struct SnapshotRow {
+    client: ClientId,
+    /// Only there once a client holds a currency, empty for funds without one
+    #[serde(default)]
+    currency: Option<Currency>,
+    available: Decimal,
+    held: Decimal,
+    total: Decimal,
+    locked: bool,
+}
+
+/// How a client's balances in one currency moved from one accounts snapshot to
+/// the next. The currency is left out for funds without one.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct AccountDelta {
+    pub client: ClientId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub currency: Option<Currency>,
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+    pub locked_before: bool,
+    pub locked_after: bool,
+}
+
+impl AccountDelta {
+    /// Whether the account was locked between the snapshots
+    pub fn newly_locked(&self) -> bool {
+        self.locked_after && !self.locked_before
+    }
+}
+
+/// Compares two accounts csvs written by the engine, returning a delta for every
+/// client and currency whose balances or lock changed, by client id. A client
+/// missing from either snapshot counts as holding nothing there.
+pub fn diff_snapshots<B: io::Read, A: io::Read>(
+    before: B,
+    after: A,
+) -> Result<Vec<AccountDelta>, PaymentsError> {
+    let before = read_snapshot(before)?;
+    let after = read_snapshot(after)?;
+    let empty = SnapshotRow {
+        client: 0,
+        currency: None,
+        available: Decimal::ZERO,
+        held: Decimal::ZERO,
+        total: Decimal::ZERO,
+        locked: false,
+    };
+    let mut keys: Vec<_> = before.keys().chain(after.keys()).collect();
+    keys.sort();
+    keys.dedup();
+    let mut deltas: Vec<AccountDelta> = keys
+        .into_iter()
+        .filter_map(|key| {
+            let (was, now) = (before.get(key), after.get(key));
+            let (was_row, now_row) = (was.unwrap_or(&empty), now.unwrap_or(&empty));
+            let delta = AccountDelta {
+                client: key.0,
+                currency: key.1.clone(),
+                available: difference(now_row.available, was_row.available),
+                held: difference(now_row.held, was_row.held),
+                total: difference(now_row.total, was_row.total),
+                locked_before: was.is_some_and(|row| row.locked),
+                locked_after: now.is_some_and(|row| row.locked),
+            };
+            let changed = !delta.available.is_zero()
+                || !delta.held.is_zero()
+                || !delta.total.is_zero()
+                || delta.locked_before != delta.locked_after;
+            changed.then_some(delta)
+        })
+        .collect();
+    // Every row gets the currency column once any has one, as in the accounts output
+    if deltas.iter().any(|delta| delta.currency.is_some()) {
+        for delta in &mut deltas {
+            delta.currency.get_or_insert_with(String::new);
+        }
+    }
+    Ok(deltas)
+}
+
+/// `after - before`, with as many decimal places as the snapshot that has the most
+fn difference(after: Decimal, before: Decimal) -> Decimal {
+    let mut difference = after - before;
+    difference.rescale(after.scale().max(before.scale()));
+    difference
+}
+
+fn read_snapshot<R: io::Read>(
+    input: R,
+) -> Result<BTreeMap<(ClientId, Option<Currency>), SnapshotRow>, PaymentsError> {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_reader(input);
+    let mut rows = BTreeMap::new();
+    for row in reader.deserialize() {
+        let row: SnapshotRow = row?;
+        let currency = row.currency.clone().filter(|currency| !currency.is_empty());
+        rows.insert((row.client, currency), row);
+    }
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn reports_changed_accounts_only() {
+        let before = "client,available,held,total,locked\n\
+                      1,1.0000,0.0000,1.0000,false\n\
+                      2,2.0000,1.0000,3.0000,false\n\
+                      3,5.0000,0.0000,5.0000,false\n";
+        let after = "client,currency,available,held,total,locked\n\
+                     1,,1.0000,0.0000,1.0000,false\n\
+                     2,,2.0000,0.0000,2.0000,true\n\
+                     3,,5.0000,0.0000,5.0000,false\n\
+                     3,USD,4.5000,0.0000,4.5000,false\n";
+
+        let deltas = diff_snapshots(before.as_bytes(), after.as_bytes()).unwrap();
+        assert_eq!(
+            deltas,
+            [
+                AccountDelta {
+                    client: 2,
+                    currency: Some(String::new()),
+                    available: dec!(0.0000),
+                    held: dec!(-1.0000),
+                    total: dec!(-1.0000),
+                    locked_before: false,
+                    locked_after: true,
+                },
+                AccountDelta {
+                    client: 3,
+                    currency: Some("USD".to_string()),
+                    available: dec!(4.5000),
+                    held: dec!(0.0000),
+                    total: dec!(4.5000),
+                    locked_before: false,
+                    locked_after: false,
+                },
+            ]
+        );
+        assert!(deltas[0].newly_locked());
+        assert!(!deltas[1].newly_locked());
+    }
+}
@@ -0,0 +1,114 @@
+use crate::{RedisputePolicy, Transaction, TransactionType};
+use std::str::FromStr;
+
+/// Decides what disputes the engine lets through, where reviewers and partners
+/// disagree on the semantics. The provided methods are the engine's defaults, so a
+/// policy only overrides the decisions it makes differently.
+///
+/// Whatever the policy, an adjustment can't be disputed, a charged back transaction
+/// can't be disputed again, and a resolve or chargeback needs an open dispute.
+pub trait DisputePolicy: Send + Sync + 'static {
+    /// Whether the transaction can be disputed at all. Deposits, and transfers on
+    /// the receiving side, by default.
+    fn disputable(&self, transaction: &Transaction) -> bool {
+        matches!(
+            transaction.transaction_type,
+            TransactionType::Deposit | TransactionType::Transfer
+        )
+    }
+
+    /// Whether a dispute, resolve or chargeback is still applied once its account is
+    /// locked. None are by default.
+    fn allowed_on_locked(&self, _transaction_type: &TransactionType) -> bool {
+        false
+    }
+
+    /// Whether the transaction can be disputed again once its dispute is resolved.
+    /// Not by default, the resolve settles it for good.
+    fn redisputable(&self, _transaction: &Transaction) -> bool {
+        false
+    }
+}
+
+impl<P: DisputePolicy + ?Sized> DisputePolicy for Box<P> {
+    fn disputable(&self, transaction: &Transaction) -> bool {
+        (**self).disputable(transaction)
+    }
+
+    fn allowed_on_locked(&self, transaction_type: &TransactionType) -> bool {
+        (**self).allowed_on_locked(transaction_type)
+    }
+
+    fn redisputable(&self, transaction: &Transaction) -> bool {
+        (**self).redisputable(transaction)
+    }
+}
+
+/// The engine's own policy, each decision a setting. The default is the same as
+/// the trait's, and `FromStr` takes the presets the cli offers with
+/// `--dispute-policy`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct StandardDisputePolicy {
+    /// Let withdrawals be disputed as well as deposits and transfers
+    pub withdrawals: bool,
+    /// Still apply disputes and resolves once an account is locked, so open cases
+    /// can be worked through. Chargebacks are rejected either way.
+    pub on_locked_accounts: bool,
+    pub redispute: RedisputePolicy,
+}
+
+impl Default for StandardDisputePolicy {
+    fn default() -> Self {
+        StandardDisputePolicy {
+            withdrawals: false,
+            on_locked_accounts: false,
+            redispute: RedisputePolicy::Never,
+        }
+    }
+}
+
+impl DisputePolicy for StandardDisputePolicy {
+    fn disputable(&self, transaction: &Transaction) -> bool {
+        match transaction.transaction_type {
+            TransactionType::Deposit | TransactionType::Transfer => true,
+            TransactionType::Withdrawal => self.withdrawals,
+            _ => false,
+        }
+    }
+
+    fn allowed_on_locked(&self, transaction_type: &TransactionType) -> bool {
+        self.on_locked_accounts
+            && matches!(
+                transaction_type,
+                TransactionType::Dispute | TransactionType::Resolve
+            )
+    }
+
+    fn redisputable(&self, _transaction: &Transaction) -> bool {
+        self.redispute == RedisputePolicy::AfterResolve
+    }
+}
+
+impl FromStr for StandardDisputePolicy {
+    type Err = ();
+
+    /// standard, the default; any-tx, which lets withdrawals be disputed too; or
+    /// lenient, which also works disputes on locked accounts and lets resolved
+    /// transactions be disputed again
+    fn from_str(s: &str) -> Result<StandardDisputePolicy, Self::Err> {
+        let standard = StandardDisputePolicy::default();
+        match s {
+            "standard" => Ok(standard),
+            "any-tx" => Ok(StandardDisputePolicy {
+                withdrawals: true,
+                ..standard
+            }),
+            "lenient" => Ok(StandardDisputePolicy {
+                withdrawals: true,
+                on_locked_accounts: true,
+                redispute: RedisputePolicy::AfterResolve,
+            }),
+            _ => Err(()),
+        }
+    }
+}
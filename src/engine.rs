@@ -0,0 +1,3971 @@
+#[cfg(feature = "chaos")]
+use crate::chaos::{Chaos, Injector};
+use crate::columns::{self, Column, Columns, Fields, FixedWidths};
+use crate::invariants;
+use crate::metrics::Metrics;
+use crate::{
+    AccountRow, AccountStore, AmountFormat, Balance, Client, ClientId, ColumnMap, Currency,
+    DisputePolicy, DisputeState, FeeSchedule, InvariantCheck, MemoryStore, Outcome,
+    OverdraftPolicy, PaymentsError, ProcessingMode, RedisputePolicy, RoundingPolicy, Rules,
+    SkipReason, StandardDisputePolicy, Transaction, TransactionStore, TransactionType, TxId,
+    TxIdScope, TxKey, MAX_PRECISION, PRECISION,
+};
+use csv::{ByteRecord, StringRecord};
+#[cfg(feature = "async")]
+use futures::{Stream, StreamExt};
+use rust_decimal::prelude::*;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::mem;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{debug, error, info, warn};
+
+mod alerts;
+mod batch;
+mod changes;
+mod checkpoint;
+mod disputes;
+mod events;
+mod fees;
+mod issues;
+mod journal;
+mod migrate;
+#[cfg(feature = "native")]
+mod parallel;
+#[cfg(feature = "parquet")]
+mod parquet;
+#[cfg(feature = "native")]
+mod pipeline;
+mod progress;
+mod reconcile;
+mod record;
+mod replay;
+mod rules;
+
+pub use alerts::{Alert, AlertHandler, AlertSink, Alerts};
+pub use batch::BatchOutcome;
+use changes::Changes;
+use checkpoint::InputPosition;
+pub use checkpoint::{Checkpoint, CheckpointSink, CHECKPOINT_VERSION};
+pub use disputes::OpenDispute;
+pub use events::{EngineEvent, EventHandler};
+pub use issues::Issue;
+use journal::Journal;
+pub use migrate::{upgrade_state, STATE_VERSION};
+#[cfg(feature = "parquet")]
+pub use parquet::write_parquet;
+use progress::ProgressState;
+pub use progress::{Progress, ProgressSink};
+use reconcile::Ledger;
+pub use reconcile::{Discrepancy, LedgerTotals, Reconciliation};
+use record::{Parsed, RawRecord};
+use rules::Tally;
+
+/// One line of the audit trail: a record and its client's balances either side of it
+#[derive(Debug, Serialize)]
+struct AuditRecord {
+    tx: TxId,
+    client: ClientId,
+    #[serde(rename = "type")]
+    transaction_type: TransactionType,
+    amount: Decimal,
+    /// "applied", or how the record was skipped, see `SkipReason::disposition`
+    disposition: &'static str,
+    available_before: Decimal,
+    held_before: Decimal,
+    total_before: Decimal,
+    locked_before: bool,
+    available_after: Decimal,
+    held_after: Decimal,
+    total_after: Decimal,
+    locked_after: bool,
+    /// Currency of the balances either side, a last column only on records with one,
+    /// so trails of input without currencies keep their columns
+    #[serde(skip_serializing_if = "Option::is_none")]
+    currency: Option<Currency>,
+}
+
+/// Format of the input read by `process`
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum InputFormat {
+    /// Csv with a header row
+    Csv,
+    /// One json transaction per line, see `Transaction`
+    Json,
+    /// One transaction per line with its fields at fixed widths, as mainframe exports
+    /// are written. A first line whose type field isn't a transaction type is taken
+    /// as a header.
+    FixedWidth(FixedWidths),
+    /// A Parquet file, its columns named as in a csv header
+    #[cfg(feature = "parquet")]
+    Parquet,
+}
+
+impl FromStr for InputFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<InputFormat, Self::Err> {
+        match s.split_once(':') {
+            None if s == "csv" => Ok(InputFormat::Csv),
+            None if s == "json" => Ok(InputFormat::Json),
+            #[cfg(feature = "parquet")]
+            None if s == "parquet" => Ok(InputFormat::Parquet),
+            Some(("fixed-width", widths)) => widths.parse().map(InputFormat::FixedWidth),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Format of the audit trail
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum AuditFormat {
+    Csv,
+    /// One json object per line
+    JsonLines,
+}
+
+impl FromStr for AuditFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<AuditFormat, Self::Err> {
+        match s {
+            "csv" => Ok(AuditFormat::Csv),
+            "jsonl" => Ok(AuditFormat::JsonLines),
+            _ => Err(()),
+        }
+    }
+}
+
+enum AuditSink {
+    Csv(Box<csv::Writer<Box<dyn io::Write + Send>>>),
+    JsonLines(Box<dyn io::Write + Send>),
+}
+
+/// Order clients are written out in
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum OutputOrder {
+    /// Sorted by client id
+    ClientId,
+    /// The order clients first appeared in the input
+    FirstSeen,
+}
+
+impl FromStr for OutputOrder {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<OutputOrder, Self::Err> {
+        match s {
+            "client-id" => Ok(OutputOrder::ClientId),
+            "first-seen" => Ok(OutputOrder::FirstSeen),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Which kept transactions the engine lets go of, to bound memory on large inputs.
+/// An evicted transaction can no longer be disputed, nor is its tx id caught as a
+/// duplicate anymore. Transactions under dispute are never evicted.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum EvictionPolicy {
+    /// Keep every transaction
+    KeepAll,
+    /// Keep each client's latest n, dropping the client's oldest past that
+    PerClient(usize),
+    /// Keep a transaction for the n records that follow it. One still under dispute
+    /// by then gets another n.
+    Window(usize),
+    /// Drop a transaction once its dispute is settled for good, i.e. charged back,
+    /// or resolved when it can't be disputed again
+    Settled,
+}
+
+impl FromStr for EvictionPolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<EvictionPolicy, Self::Err> {
+        match s.split_once(':') {
+            None if s == "keep-all" => Ok(EvictionPolicy::KeepAll),
+            None if s == "settled" => Ok(EvictionPolicy::Settled),
+            Some(("per-client", n)) => n.parse().map(EvictionPolicy::PerClient).map_err(|_| ()),
+            Some(("window", n)) => n.parse().map(EvictionPolicy::Window).map_err(|_| ()),
+            _ => Err(()),
+        }
+    }
+}
+
+/// System wide totals across every client, for reconciliation. The funds are those
+/// of transactions without a currency, with any others totalled by currency.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct Report {
+    pub available: Decimal,
+    pub held: Decimal,
+    pub total: Decimal,
+    pub locked_accounts: usize,
+    pub open_disputes: usize,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub currencies: BTreeMap<Currency, Balance>,
+    /// Fees charged on transactions without a currency, when there's a fee schedule
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fees: Option<Decimal>,
+    /// Fees charged in named currencies
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub currency_fees: BTreeMap<Currency, Decimal>,
+}
+
+/// Everything a later run needs to carry on from where this one left off. Per run
+/// counts and the settings are left out.
+#[derive(Debug, Serialize, Deserialize)]
+struct SavedState {
+    clients: Vec<SavedClient>,
+    first_seen: Vec<ClientId>,
+    transactions: Vec<Transaction>,
+    retained: HashMap<ClientId, VecDeque<TxId>>,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    history: HashMap<ClientId, Vec<Transaction>>,
+    /// Fees charged as (client, currency, fees)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    fees: Vec<(ClientId, Option<Currency>, Decimal)>,
+}
+
+/// A client with its open disputes, which the accounts output leaves out
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct SavedClient {
+    id: ClientId,
+    available: Decimal,
+    held: Decimal,
+    locked: bool,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    closed: bool,
+    disputes: Vec<TxId>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    currencies: BTreeMap<Currency, Balance>,
+}
+
+impl From<&Client> for SavedClient {
+    fn from(client: &Client) -> SavedClient {
+        let mut disputes: Vec<TxId> = client.disputes.iter().copied().collect();
+        disputes.sort_unstable();
+        SavedClient {
+            id: client.id,
+            available: client.available,
+            held: client.held,
+            locked: client.locked,
+            closed: client.closed,
+            disputes,
+            currencies: client.currencies.clone(),
+        }
+    }
+}
+
+impl From<SavedClient> for Client {
+    fn from(saved: SavedClient) -> Client {
+        let mut client = Client::new(saved.id);
+        client.available = saved.available;
+        client.held = saved.held;
+        client.total = saved.available + saved.held;
+        client.locked = saved.locked;
+        client.closed = saved.closed;
+        client.disputes = saved.disputes.into_iter().collect();
+        client.currencies = saved.currencies;
+        client
+    }
+}
+
+/// Maintains client accounts from a stream of transactions. Feed it a csv file with
+/// `process`, parsed records with `process_record`, or transactions directly with
+/// `process_transaction`, then query the accounts with `client` and `clients`.
+///
+/// Transactions kept for disputes live in the `TransactionStore`, and accounts are
+/// read from and written back to the `AccountStore`, both in memory by default.
+/// What disputes are let through is up to the `DisputePolicy`, the
+/// `StandardDisputePolicy` by default.
+pub struct PaymentsEngine<T = MemoryStore, A = MemoryStore, P = StandardDisputePolicy> {
+    clients: HashMap<ClientId, Client>,
+    /// Client ids in the order they were first seen, as the client map has no order
+    first_seen: Vec<ClientId>,
+    transaction_store: T,
+    account_store: A,
+    rounding_policy: RoundingPolicy,
+    /// Decimal places amounts are rounded to when parsed and balances when output
+    precision: u32,
+    input_format: InputFormat,
+    /// What csv fields are separated by
+    delimiter: u8,
+    /// Header names csv columns are matched by, if not read in the usual order
+    column_map: Option<ColumnMap>,
+    /// How amounts are written in csv input
+    amount_format: AmountFormat,
+    /// Tx ids of deposits and withdrawals rejected for reusing an existing tx id
+    duplicate_transactions: Vec<TxId>,
+    /// Of `duplicate_transactions`, those whose details differ from the original
+    conflicting_duplicates: Vec<TxId>,
+    /// (client id, tx id) of withdrawals and transfers rejected for insufficient funds
+    rejected_withdrawals: Vec<(ClientId, TxId)>,
+    overdraft_policy: OverdraftPolicy,
+    /// Overdraft policies of clients that don't go by `overdraft_policy`
+    client_overdraft_policies: HashMap<ClientId, OverdraftPolicy>,
+    /// (client id, tx id) of disputes, resolves and chargebacks rejected because the
+    /// row's client doesn't own the transaction
+    ownership_mismatches: Vec<(ClientId, TxId)>,
+    /// Number of records that parsed, whether they were applied or skipped
+    valid_records: usize,
+    /// Number of records that parsed, by transaction type
+    transaction_counts: BTreeMap<TransactionType, usize>,
+    /// Number of records that failed to parse, only counted when skipping invalid records
+    invalid_records: usize,
+    skipped: BTreeMap<SkipReason, usize>,
+    /// Skip records that fail to parse and carry on, rather than stopping at the first
+    skip_invalid_records: bool,
+    /// Stop at the first record that fails to parse or is a partner error
+    strict: bool,
+    /// Line of the record being applied, when it was read from an input
+    line: Option<u64>,
+    /// Threads csv records are parsed on, apart from the one applying them
+    parse_threads: usize,
+    /// Records failing validation, when recording them
+    issues: Option<Vec<Issue>>,
+    /// Hold disputes, resolves and chargebacks back until all deposits and
+    /// withdrawals are in, so they may come before the tx they refer to
+    buffered: bool,
+    deferred: Vec<Transaction>,
+    /// Which transactions can be disputed, on locked accounts and again once resolved,
+    /// shared with the shards of a parallel run
+    dispute_policy: Arc<P>,
+    /// Largest amount a single deposit, withdrawal or transfer may move, if any
+    max_amount: Option<Decimal>,
+    /// Limits deposits, withdrawals and transfers are checked against, if any
+    rules: Option<Rules>,
+    /// What the rules have counted so far, by client
+    rule_tallies: HashMap<ClientId, Tally>,
+    /// How long after its tx a dispute may be filed, if there's a limit
+    dispute_window: Option<Duration>,
+    tx_id_scope: TxIdScope,
+    eviction_policy: EvictionPolicy,
+    /// Per client tx ids kept in the store, oldest first, only tracked per client
+    retained: HashMap<ClientId, VecDeque<TxId>>,
+    /// Kept tx ids with the record count they were kept at, oldest first, only
+    /// tracked with a window
+    window: VecDeque<(usize, TxKey)>,
+    /// Tx ids evicted and not yet taken, only collected for a shard of
+    /// `process_parallel` to report back
+    evicted: Option<Vec<TxId>>,
+    /// Keep every applied transaction per client for `history`
+    record_history: bool,
+    /// Applied transactions per client in the order they were applied, only kept
+    /// when recording history
+    history: HashMap<ClientId, Vec<Transaction>>,
+    /// Where every applied or skipped record is written along with its client's
+    /// balances before and after, kept apart from the accounts on stdout
+    audit: Option<AuditSink>,
+    /// Where the double entry journal of applied transactions is written, if anywhere
+    journal: Option<Journal>,
+    /// What to do about an applied transaction leaving an account breaking an invariant
+    invariant_check: InvariantCheck,
+    /// Also check that available funds don't go below zero
+    available_not_negative: bool,
+    /// Invariants found broken, when checking them without aborting
+    invariant_violations: usize,
+    /// How far into the input the engine got, for checkpoints
+    position: InputPosition,
+    /// Where a resumed run's checkpoint left off, until the input gets there
+    resume_at: Option<InputPosition>,
+    /// Where a checkpoint is handed every `checkpoint_every` records, if anywhere
+    checkpoint_sink: Option<CheckpointSink>,
+    checkpoint_every: usize,
+    /// Records read since the last checkpoint
+    since_checkpoint: usize,
+    /// Once set, reading the input stops before the next record
+    stop: Option<Arc<AtomicBool>>,
+    /// Where progress through the input is reported, if anywhere
+    progress: Option<ProgressState>,
+    /// Called with every `EngineEvent`, in the order they were added
+    event_handlers: Vec<EventHandler>,
+    /// What accounts are alerted on and where to, if anywhere
+    alerts: Option<Alerts>,
+    /// When each client last changed, when tracking changes
+    changes: Option<Changes>,
+    /// Counts and timings of applied transactions, when exposing metrics
+    metrics: Option<Metrics>,
+    /// What deposits and withdrawals are charged, if anything
+    fee_schedule: Option<FeeSchedule>,
+    /// Fees charged by client and currency
+    fees: BTreeMap<(ClientId, Option<Currency>), Decimal>,
+    /// Funds moved by applied transactions, when reconciling
+    ledger: Option<Ledger>,
+    /// What input is delayed, reordered or duplicated before it's applied, if any
+    #[cfg(feature = "chaos")]
+    chaos: Option<Injector>,
+}
+
+impl Default for PaymentsEngine {
+    fn default() -> Self {
+        PaymentsEngine::new()
+    }
+}
+
+impl PaymentsEngine {
+    pub fn new() -> PaymentsEngine {
+        PaymentsEngine::with_stores(MemoryStore::new(), MemoryStore::new())
+    }
+}
+
+impl<T: TransactionStore, A: AccountStore> PaymentsEngine<T, A> {
+    pub fn with_stores(transaction_store: T, account_store: A) -> PaymentsEngine<T, A> {
+        PaymentsEngine::with_dispute_policy(
+            transaction_store,
+            account_store,
+            StandardDisputePolicy::default(),
+        )
+    }
+
+    pub fn set_withdrawal_disputes(&mut self, withdrawal_disputes: bool) {
+        Arc::make_mut(&mut self.dispute_policy).withdrawals = withdrawal_disputes;
+    }
+
+    pub fn set_disputes_on_locked_accounts(&mut self, disputes_on_locked_accounts: bool) {
+        Arc::make_mut(&mut self.dispute_policy).on_locked_accounts = disputes_on_locked_accounts;
+    }
+
+    pub fn set_redispute_policy(&mut self, redispute_policy: RedisputePolicy) {
+        Arc::make_mut(&mut self.dispute_policy).redispute = redispute_policy;
+    }
+}
+
+impl<T: TransactionStore, A: AccountStore, P: DisputePolicy> PaymentsEngine<T, A, P> {
+    pub fn with_dispute_policy(
+        transaction_store: T,
+        account_store: A,
+        dispute_policy: P,
+    ) -> PaymentsEngine<T, A, P> {
+        PaymentsEngine::with_shared_dispute_policy(
+            transaction_store,
+            account_store,
+            Arc::new(dispute_policy),
+        )
+    }
+
+    fn with_shared_dispute_policy(
+        transaction_store: T,
+        account_store: A,
+        dispute_policy: Arc<P>,
+    ) -> PaymentsEngine<T, A, P> {
+        let clients = HashMap::<ClientId, Client>::new();
+        PaymentsEngine {
+            clients,
+            first_seen: Vec::new(),
+            transaction_store,
+            account_store,
+            rounding_policy: RoundingPolicy::HalfEven,
+            precision: PRECISION,
+            input_format: InputFormat::Csv,
+            delimiter: b',',
+            column_map: None,
+            amount_format: AmountFormat::Plain,
+            duplicate_transactions: Vec::new(),
+            conflicting_duplicates: Vec::new(),
+            rejected_withdrawals: Vec::new(),
+            overdraft_policy: OverdraftPolicy::Reject,
+            client_overdraft_policies: HashMap::new(),
+            ownership_mismatches: Vec::new(),
+            valid_records: 0,
+            transaction_counts: BTreeMap::new(),
+            invalid_records: 0,
+            skipped: BTreeMap::new(),
+            skip_invalid_records: true,
+            strict: false,
+            line: None,
+            parse_threads: 1,
+            issues: None,
+            buffered: false,
+            deferred: Vec::new(),
+            dispute_policy,
+            max_amount: None,
+            rules: None,
+            rule_tallies: HashMap::new(),
+            dispute_window: None,
+            tx_id_scope: TxIdScope::Global,
+            eviction_policy: EvictionPolicy::KeepAll,
+            retained: HashMap::new(),
+            window: VecDeque::new(),
+            evicted: None,
+            record_history: false,
+            history: HashMap::new(),
+            audit: None,
+            journal: None,
+            invariant_check: InvariantCheck::Off,
+            available_not_negative: false,
+            invariant_violations: 0,
+            position: InputPosition::default(),
+            resume_at: None,
+            checkpoint_sink: None,
+            checkpoint_every: 0,
+            since_checkpoint: 0,
+            stop: None,
+            progress: None,
+            event_handlers: Vec::new(),
+            alerts: None,
+            changes: None,
+            metrics: None,
+            fee_schedule: None,
+            fees: BTreeMap::new(),
+            ledger: None,
+            #[cfg(feature = "chaos")]
+            chaos: None,
+        }
+    }
+
+    pub fn set_rounding_policy(&mut self, rounding_policy: RoundingPolicy) {
+        self.rounding_policy = rounding_policy;
+    }
+
+    /// Decimal places amounts are rounded to with the rounding policy as they're
+    /// parsed, and balances in the accounts output, `PRECISION` by default and at
+    /// most `MAX_PRECISION`
+    pub fn set_precision(&mut self, precision: u32) {
+        self.precision = precision.min(MAX_PRECISION);
+    }
+
+    /// Decimal places balances are output with
+    pub fn precision(&self) -> u32 {
+        self.precision
+    }
+
+    pub fn input_format(&self) -> &InputFormat {
+        &self.input_format
+    }
+
+    pub fn set_input_format(&mut self, input_format: InputFormat) {
+        self.input_format = input_format;
+    }
+
+    /// What csv fields are separated by, a comma by default, such as `b'|'` for pipe
+    /// delimited input
+    pub fn set_delimiter(&mut self, delimiter: u8) {
+        self.delimiter = delimiter;
+    }
+
+    /// Plain by default, so amounts with thousands separators or currency symbols are
+    /// malformed
+    pub fn set_amount_format(&mut self, amount_format: AmountFormat) {
+        self.amount_format = amount_format;
+    }
+
+    /// Reads csv columns by their name in each part's header instead of by position,
+    /// so they can come in any order, among any others
+    pub fn set_column_map(&mut self, column_map: ColumnMap) {
+        self.column_map = Some(column_map);
+    }
+
+    /// On by default, malformed records are then counted and logged instead of failing
+    /// the run
+    pub fn set_skip_invalid_records(&mut self, skip_invalid_records: bool) {
+        self.skip_invalid_records = skip_invalid_records;
+    }
+
+    /// Stops processing at the first record that fails to parse or is skipped as a
+    /// partner error, see `SkipReason::is_partner_error`, with an error naming its
+    /// line. Off by default, such records are then counted and processing carries on.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    pub fn set_buffered(&mut self, buffered: bool) {
+        self.buffered = buffered;
+    }
+
+    /// Replaces the policy deciding which disputes are let through
+    pub fn set_dispute_policy(&mut self, dispute_policy: P) {
+        self.dispute_policy = Arc::new(dispute_policy);
+    }
+
+    /// Whether tx ids are unique across every client, the default, or only within
+    /// each client's own transactions. Duplicates are then only caught within a
+    /// client, and a dispute always refers to its own client's tx. Transfers, which
+    /// are disputed by the receiving client, aren't supported with `Client`.
+    pub fn set_tx_id_scope(&mut self, tx_id_scope: TxIdScope) {
+        self.tx_id_scope = tx_id_scope;
+    }
+
+    /// What the client's transaction `tx` is kept by, under the tx id scope
+    pub(crate) fn tx_key(&self, client: ClientId, tx: TxId) -> TxKey {
+        match self.tx_id_scope {
+            TxIdScope::Global => TxKey::from(tx),
+            TxIdScope::Client => TxKey {
+                client: Some(client),
+                tx,
+            },
+        }
+    }
+
+    /// What a kept transaction is kept by
+    pub(crate) fn key_of(&self, transaction: &Transaction) -> TxKey {
+        self.tx_key(transaction.client_id, transaction.id)
+    }
+
+    /// How far withdrawals and transfers may overdraw an account, `Reject` by default
+    pub fn set_overdraft_policy(&mut self, overdraft_policy: OverdraftPolicy) {
+        self.overdraft_policy = overdraft_policy;
+    }
+
+    /// Overrides the overdraft policy for one client, e.g. to let a trusted client
+    /// go negative
+    pub fn set_client_overdraft_policy(&mut self, id: ClientId, overdraft_policy: OverdraftPolicy) {
+        self.client_overdraft_policies.insert(id, overdraft_policy);
+    }
+
+    fn overdraft_policy(&self, id: ClientId) -> OverdraftPolicy {
+        self.client_overdraft_policies
+            .get(&id)
+            .copied()
+            .unwrap_or(self.overdraft_policy)
+    }
+
+    /// Disputes filed more than `window` after their tx are rejected. Only enforced
+    /// when both the dispute and its tx carry a timestamp.
+    pub fn set_dispute_window(&mut self, window: Option<Duration>) {
+        self.dispute_window = window;
+    }
+
+    /// Deposits, withdrawals and transfers over `max` are turned away as malformed
+    pub fn set_max_amount(&mut self, max: Option<Decimal>) {
+        self.max_amount = max;
+    }
+
+    pub fn set_eviction_policy(&mut self, eviction_policy: EvictionPolicy) {
+        self.eviction_policy = eviction_policy;
+    }
+
+    /// Shorthand for `EvictionPolicy::PerClient`, or keeping everything with `None`
+    pub fn set_max_transactions_per_client(&mut self, cap: Option<usize>) {
+        self.eviction_policy = cap.map_or(EvictionPolicy::KeepAll, EvictionPolicy::PerClient);
+    }
+
+    /// Moves the transactions kept so far into `store`, which keeps them from then on
+    /// Keeps every transaction applied to a client, for `history`. Off by default, as
+    /// this holds the whole input in memory and in the saved state.
+    pub fn set_record_history(&mut self, record_history: bool) {
+        self.record_history = record_history;
+    }
+
+    pub fn set_transaction_store(&mut self, mut store: T) -> Result<(), PaymentsError> {
+        for transaction in self.transaction_store.transactions()? {
+            store.put_tx(self.key_of(&transaction), transaction)?;
+        }
+        self.transaction_store = store;
+        Ok(())
+    }
+
+    /// Accounts the engine hasn't seen yet are read from `store` from then on
+    pub fn set_account_store(&mut self, store: A) {
+        self.account_store = store;
+    }
+
+    /// Hands the stores back, e.g. to read the accounts written once the input is done
+    pub fn into_stores(self) -> (T, A) {
+        (self.transaction_store, self.account_store)
+    }
+
+    /// Checks the balances of every account a transaction touched once it's applied.
+    /// Off by default. With `available_not_negative`, available funds going below
+    /// zero count as broken too.
+    pub fn set_invariant_check(&mut self, check: InvariantCheck, available_not_negative: bool) {
+        self.invariant_check = check;
+        self.available_not_negative = available_not_negative;
+    }
+
+    /// Stops `process`, `process_part` and `process_parallel` reading once `stop`
+    /// is set, before the next record, with `PaymentsError::Interrupted`. Records
+    /// read by then are applied, apart from disputes held back by `set_buffered`,
+    /// and a checkpoint taken then resumes at the next record.
+    pub fn set_stop_flag(&mut self, stop: Arc<AtomicBool>) {
+        self.stop = Some(stop);
+    }
+
+    /// Lines are only ever added to the sink. With `append` it already holds an
+    /// earlier trail, so no csv header is written.
+    pub fn set_audit_sink(
+        &mut self,
+        sink: Box<dyn io::Write + Send>,
+        format: AuditFormat,
+        append: bool,
+    ) {
+        self.audit = Some(match format {
+            AuditFormat::Csv => AuditSink::Csv(Box::new(
+                csv::WriterBuilder::new()
+                    .has_headers(!append)
+                    .flexible(true)
+                    .from_writer(sink),
+            )),
+            AuditFormat::JsonLines => AuditSink::JsonLines(sink),
+        });
+    }
+
+    /// Applies every record read from `input`, such as a file or stdin, in the
+    /// engine's input format
+    pub fn process<R: io::Read>(&mut self, input: R) -> Result<(), PaymentsError> {
+        self.process_part(input)?;
+        self.finish()
+    }
+
+    /// Applies the records of one part of a larger input, such as one of several
+    /// files, with a header of its own if csv. Call `finish` once every part is in.
+    pub fn process_part<R: io::Read>(&mut self, input: R) -> Result<(), PaymentsError> {
+        self.read_input(input, false, Self::process_parsed)
+    }
+
+    /// Applies the records of one part held in memory, such as a `MappedFile`, the
+    /// same as `process_part`. Csv records are parsed where they are in `input`,
+    /// without copying each one into strings first.
+    pub fn process_mapped_part(&mut self, input: &[u8]) -> Result<(), PaymentsError> {
+        self.read_input(input, true, Self::process_parsed)
+    }
+
+    /// Applies anything held back in buffered mode and logs what was rejected along
+    /// the way, once the input is done
+    pub fn finish(&mut self) -> Result<(), PaymentsError> {
+        self.apply_deferred()?;
+        self.warn_rejected();
+        self.store_clients()
+    }
+
+    /// Writes every account to the account store and flushes both stores
+    fn store_clients(&mut self) -> Result<(), PaymentsError> {
+        for client in self.clients.values() {
+            self.account_store.put_client(client)?;
+        }
+        self.account_store.flush()?;
+        self.transaction_store.flush()
+    }
+
+    fn warn_rejected(&self) {
+        if !self.duplicate_transactions.is_empty() {
+            warn!(
+                "Rejected {} transactions with duplicate tx ids {:?}",
+                self.duplicate_transactions.len(),
+                self.duplicate_transactions
+            );
+        }
+        if !self.conflicting_duplicates.is_empty() {
+            warn!(
+                "{} duplicate tx ids {:?} differ from their original transaction, input may be corrupt",
+                self.conflicting_duplicates.len(),
+                self.conflicting_duplicates
+            );
+        }
+        if !self.rejected_withdrawals.is_empty() {
+            warn!(
+                "Rejected {} withdrawals for insufficient funds, (client, tx): {:?}",
+                self.rejected_withdrawals.len(),
+                self.rejected_withdrawals
+            );
+        }
+        if !self.ownership_mismatches.is_empty() {
+            warn!(
+                "Skipped {} records referencing another client's tx, (client, tx): {:?}",
+                self.ownership_mismatches.len(),
+                self.ownership_mismatches
+            );
+        }
+    }
+
+    /// Parses every record of `input` in the engine's input format and hands it to
+    /// `handle`, whether it parsed or not. Csv records are read `in_place` as byte
+    /// records rather than copied into strings.
+    fn read_input<R, F>(
+        &mut self,
+        input: R,
+        in_place: bool,
+        mut handle: F,
+    ) -> Result<(), PaymentsError>
+    where
+        R: io::Read,
+        F: FnMut(&mut Self, Result<Transaction, PaymentsError>) -> Result<(), PaymentsError>,
+    {
+        // A resumed run reads past what its checkpoint already covers
+        let Some(skip) = self.records_to_skip() else {
+            return Ok(());
+        };
+        match &self.input_format {
+            InputFormat::Csv => {
+                // Columns matched by name always need a header, while input read by
+                // position only skips its first row if it is one, and matches the
+                // columns after the amount by name if the header names them
+                let (first_row, input) = columns::peek_first_row(input, self.delimiter)?;
+                let (has_header, header_columns) = match (&self.column_map, &first_row) {
+                    (None, Some(row)) => match columns::positional_header(row)? {
+                        Some(columns) => (true, Some(columns)),
+                        None => (false, None),
+                    },
+                    _ => (true, None),
+                };
+                let mut reader = csv::ReaderBuilder::new()
+                    .delimiter(self.delimiter)
+                    .flexible(true)
+                    .has_headers(has_header)
+                    .from_reader(input);
+                let columns = match &self.column_map {
+                    Some(column_map) => column_map.resolve(reader.headers()?)?,
+                    None => header_columns.unwrap_or(Columns::Positional),
+                };
+                if self.parse_threads > 1 {
+                    // Only ever set above 1 with the native feature
+                    #[cfg(feature = "native")]
+                    self.read_csv_pipelined(
+                        &mut reader,
+                        &columns,
+                        |engine, (position, parsed)| {
+                            engine.take_csv_record(position.as_ref(), skip, &mut handle, |_| parsed)
+                        },
+                    )?;
+                } else if in_place {
+                    // One record read into over and over, its fields parsed where
+                    // they are
+                    let mut record = ByteRecord::new();
+                    loop {
+                        let result = reader.read_byte_record(&mut record);
+                        let position = match &result {
+                            Ok(false) => break,
+                            Ok(true) => record.position().cloned(),
+                            Err(err) => err.position().cloned(),
+                        };
+                        self.take_csv_record(position.as_ref(), skip, &mut handle, |engine| {
+                            result?;
+                            std::str::from_utf8(record.as_slice())?;
+                            engine.transaction_from_columns(&record, &columns)
+                        })?;
+                    }
+                } else {
+                    for result in reader.records() {
+                        let position = match &result {
+                            Ok(record) => record.position(),
+                            Err(err) => err.position(),
+                        };
+                        self.take_csv_record(
+                            position.cloned().as_ref(),
+                            skip,
+                            &mut handle,
+                            |engine| {
+                                result.map_err(PaymentsError::from).and_then(|record| {
+                                    engine.transaction_from_columns(&record, &columns)
+                                })
+                            },
+                        )?;
+                    }
+                }
+                self.progress_part_done(reader.position().byte());
+            }
+            // Blank lines are ignored, there is no header
+            InputFormat::Json => self.read_lines(
+                input,
+                skip,
+                &mut handle,
+                |_| false,
+                Self::transaction_from_json,
+            )?,
+            InputFormat::FixedWidth(widths) => {
+                let widths = widths.clone();
+                let columns = widths.columns();
+                self.read_lines(
+                    input,
+                    skip,
+                    &mut handle,
+                    |line| widths.is_header(line),
+                    |engine, line| engine.transaction_from_columns(&widths.split(line), &columns),
+                )?
+            }
+            #[cfg(feature = "parquet")]
+            InputFormat::Parquet => self.read_parquet_buffered(input, skip, &mut handle)?,
+        }
+        self.part_done();
+        Ok(())
+    }
+
+    /// Moves on to the next part once one is read
+    fn part_done(&mut self) {
+        self.line = None;
+        self.position.part += 1;
+        self.position.records = 0;
+        self.rules_part_done();
+    }
+
+    /// Parses every line of `input` that isn't blank and hands it to `handle`, for
+    /// the formats with a record per line. The first of them is skipped if `header`
+    /// says it's a header, without counting it as a record.
+    fn read_lines<R, F, H, G>(
+        &mut self,
+        input: R,
+        skip: u64,
+        handle: &mut F,
+        header: H,
+        parse: G,
+    ) -> Result<(), PaymentsError>
+    where
+        R: io::Read,
+        F: FnMut(&mut Self, Result<Transaction, PaymentsError>) -> Result<(), PaymentsError>,
+        H: Fn(&str) -> bool,
+        G: Fn(&Self, &str) -> Result<Transaction, PaymentsError>,
+    {
+        let mut offset = 0;
+        let mut first = true;
+        for (index, line) in io::BufReader::new(input).lines().enumerate() {
+            if let Ok(line) = &line {
+                offset += line.len() as u64 + 1;
+                if line.trim().is_empty() || (mem::take(&mut first) && header(line)) {
+                    continue;
+                }
+            }
+            self.stop_if_asked()?;
+            self.progress_if_due(offset);
+            self.position.records += 1;
+            if self.position.records <= skip {
+                continue;
+            }
+            self.line = Some(index as u64 + 1);
+            let parsed = match line {
+                Ok(line) => parse(self, &line),
+                Err(err) => Err(PaymentsError::from(err)),
+            };
+            handle(self, parsed)?;
+            self.checkpoint_if_due()?;
+        }
+        self.progress_part_done(offset);
+        Ok(())
+    }
+
+    /// Counts a csv record read at `position` and, unless a resumed run has already
+    /// applied it, hands it to `handle` once `parse`d
+    fn take_csv_record<F, G>(
+        &mut self,
+        position: Option<&csv::Position>,
+        skip: u64,
+        handle: &mut F,
+        parse: G,
+    ) -> Result<(), PaymentsError>
+    where
+        F: FnMut(&mut Self, Result<Transaction, PaymentsError>) -> Result<(), PaymentsError>,
+        G: FnOnce(&Self) -> Result<Transaction, PaymentsError>,
+    {
+        self.stop_if_asked()?;
+        self.progress_if_due(position.map_or(0, csv::Position::byte));
+        self.position.records += 1;
+        if self.position.records <= skip {
+            return Ok(());
+        }
+        self.line = position.map(csv::Position::line);
+        let parsed = parse(self);
+        handle(self, parsed)?;
+        self.checkpoint_if_due()
+    }
+
+    /// Fails once the stop flag is set, before the record about to be read is
+    /// counted, with the accounts as they stand written to the account store
+    fn stop_if_asked(&mut self) -> Result<(), PaymentsError> {
+        match &self.stop {
+            Some(stop) if stop.load(Ordering::SeqCst) => {
+                self.store_clients()?;
+                Err(PaymentsError::Interrupted)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Fails with `PaymentsError::Unsupported` at the first feature turned on that
+    /// records can't be applied with in `mode`
+    fn check_supported(&self, mode: ProcessingMode) -> Result<(), PaymentsError> {
+        use ProcessingMode::*;
+        let window = matches!(self.eviction_policy, EvictionPolicy::Window(_));
+        let per_client = matches!(self.eviction_policy, EvictionPolicy::PerClient(_));
+        let checkpointing = self.checkpoint_sink.is_some() || self.resume_at.is_some();
+        let scoped = self.tx_id_scope != TxIdScope::Global;
+        let shared = self.account_store.writes_through();
+        // Whether each feature is on, and the modes that can't support it
+        let features: &[(bool, &'static str, &[ProcessingMode])] = &[
+            (
+                self.audit.is_some(),
+                "The audit trail",
+                &[Parallel, Batch, Shared],
+            ),
+            (
+                self.journal.is_some(),
+                "The ledger export",
+                &[Parallel, Batch, Shared],
+            ),
+            (self.rules.is_some(), "Rules", &[Parallel, Batch, Shared]),
+            (checkpointing, "Checkpointing", &[Parallel]),
+            (
+                !self.event_handlers.is_empty(),
+                "Event handlers",
+                &[Parallel, Batch, Shared],
+            ),
+            (self.alerts.is_some(), "Alerts", &[Parallel, Batch, Shared]),
+            (self.changes.is_some(), "Tracking changes", &[Parallel]),
+            (self.issues.is_some(), "Recording issues", &[Parallel]),
+            (scoped, "Client scoped tx ids", &[Parallel]),
+            (self.buffered, "Buffered disputes", &[Batch]),
+            (self.ledger.is_some(), "Reconciliation", &[Batch, Shared]),
+            (window, "Window eviction", &[Parallel, Batch, Shared]),
+            (per_client, "Per client eviction", &[Batch, Shared]),
+            #[cfg(feature = "chaos")]
+            (self.chaos.is_some(), "Chaos", &[Parallel]),
+            (
+                shared,
+                "A store shared between instances",
+                &[Parallel, Batch],
+            ),
+        ];
+        match features
+            .iter()
+            .find(|(on, _, modes)| *on && modes.contains(&mode))
+        {
+            Some(&(_, feature, _)) => Err(PaymentsError::Unsupported { mode, feature }),
+            None => Ok(()),
+        }
+    }
+
+    /// Applies every record from the stream, awaiting each one as it arrives.
+    /// Unlike `process` no header is skipped.
+    #[cfg(feature = "async")]
+    pub async fn process_stream<S>(&mut self, records: S) -> Result<(), PaymentsError>
+    where
+        S: Stream<Item = StringRecord>,
+    {
+        futures::pin_mut!(records);
+        while let Some(record) = records.next().await {
+            let parsed = self.transaction_from_record(record);
+            self.process_parsed(parsed)?;
+        }
+        self.apply_deferred()?;
+        Ok(())
+    }
+
+    /// Applies a parsed record, or skips it if it was malformed and skipping is on
+    pub(crate) fn process_parsed(
+        &mut self,
+        parsed: Result<Transaction, PaymentsError>,
+    ) -> Result<(), PaymentsError> {
+        match parsed {
+            #[cfg(feature = "chaos")]
+            Ok(row) if self.chaos.is_some() => {
+                let released = self
+                    .chaos
+                    .as_mut()
+                    .map_or_else(Vec::new, |chaos| chaos.admit(row));
+                for row in released {
+                    self.process_transaction(row)?;
+                }
+                Ok(())
+            }
+            Ok(row) => self.process_transaction(row).map(|_| ()),
+            Err(err) => self.skip_malformed(err),
+        }
+    }
+
+    /// Delays, reorders and duplicates the records read from the input from here on,
+    /// as `chaos` says, to test how tools downstream of the engine cope. Records held
+    /// back to reorder are applied by `apply_deferred` at the latest, so they aren't
+    /// in checkpoints, and it isn't supported with `process_parallel`.
+    #[cfg(feature = "chaos")]
+    pub fn set_chaos(&mut self, chaos: Chaos) {
+        self.chaos = Some(Injector::new(chaos));
+    }
+
+    /// Counts a record that failed to parse and carries on, unless skipping is off.
+    /// The input failing to read, such as a truncated compressed file, isn't a
+    /// record that can be passed over.
+    fn skip_malformed(&mut self, err: PaymentsError) -> Result<(), PaymentsError> {
+        let read_failed = match &err {
+            PaymentsError::Io(err) => err.kind() != io::ErrorKind::InvalidData,
+            PaymentsError::Csv(err) => matches!(err.kind(), csv::ErrorKind::Io(_)),
+            _ => false,
+        };
+        if self.strict || read_failed {
+            return Err(self.at_line(err));
+        }
+        if !self.skip_invalid_records {
+            return Err(err);
+        }
+        warn!(line = self.line, "Skipped malformed record: {}", err);
+        self.record_invalid(&err);
+        self.invalid_records += 1;
+        Ok(())
+    }
+
+    /// Parses and applies a single record, without a header. Malformed records are
+    /// always returned as errors here, the caller decides whether to carry on.
+    pub fn process_record(&mut self, record: StringRecord) -> Result<Outcome, PaymentsError> {
+        let row = self.transaction_from_record(record)?;
+        self.process_transaction(row)
+    }
+
+    /// Applies a transaction for the client it names. Disputes, resolves and
+    /// chargebacks refer to an earlier deposit or withdrawal by its id. The amount is
+    /// taken as is, without the rounding applied to parsed records, but a deposit,
+    /// withdrawal or transfer whose amount isn't positive or is over the maximum is
+    /// returned as an error without being counted.
+    ///
+    /// Returns what was done with the transaction. Errors are left for when
+    /// processing can't carry on, such as a store or audit trail failing.
+    pub fn process_transaction(&mut self, row: Transaction) -> Result<Outcome, PaymentsError> {
+        use TransactionType::*;
+        if matches!(row.transaction_type, Deposit | Withdrawal | Transfer) {
+            self.check_amount(row.id, row.amount)?;
+        } else if row.transaction_type == Adjustment {
+            self.check_amount(row.id, row.amount.abs())?;
+        }
+        self.count_valid(&row.transaction_type);
+        match row.transaction_type {
+            Dispute | Resolve | Chargeback if self.buffered => {
+                self.deferred.push(row);
+                Ok(Outcome::Deferred)
+            }
+            _ => self.apply_or_retry(row),
+        }
+    }
+
+    /// Applies the disputes, resolves and chargebacks held back in buffered mode,
+    /// now that every deposit and withdrawal they could refer to has been seen, after
+    /// any records chaos held back
+    pub fn apply_deferred(&mut self) -> Result<(), PaymentsError> {
+        #[cfg(feature = "chaos")]
+        if let Some(chaos) = self.chaos.as_mut() {
+            for row in chaos.release() {
+                self.process_transaction(row)?;
+            }
+        }
+        for row in mem::take(&mut self.deferred) {
+            self.apply_or_retry(row)?;
+        }
+        Ok(())
+    }
+
+    /// Applies the transaction, again if it conflicts with another instance's when
+    /// the store is shared between instances
+    fn apply_or_retry(&mut self, row: Transaction) -> Result<Outcome, PaymentsError> {
+        #[cfg(feature = "redis")]
+        if self.account_store.writes_through() {
+            return self.apply_retrying(row);
+        }
+        self.apply_transaction(row)
+    }
+
+    fn apply_transaction(&mut self, row: Transaction) -> Result<Outcome, PaymentsError> {
+        use TransactionType::*;
+        if row.transaction_type == Transfer && self.tx_id_scope == TxIdScope::Client {
+            return Err(PaymentsError::ScopedTransfer(row.id));
+        }
+        let started = self.metrics.is_some().then(Instant::now);
+        self.load_client(row.client_id)?;
+        if let Some(to_client_id) = row.to_client_id {
+            self.load_client(to_client_id)?;
+        }
+        // Only snapshot the client when there is an audit trail to write it to
+        let before = match self.audit {
+            Some(_) => self.clients.get(&row.client_id).cloned(),
+            None => None,
+        };
+        // Every account the transaction may touch, for the invariant checks
+        let mut touched = vec![row.client_id];
+        touched.extend(row.to_client_id);
+        let (amount, outcome) = match row.transaction_type {
+            Deposit | Withdrawal | Transfer | Adjustment => {
+                let fee = self.fee(&row);
+                // If not unique assume partner error
+                let outcome = if let Some(reason) = self.duplicate_of(&row)? {
+                    warn!(
+                        "Rejecting {:?}, {} {}",
+                        row.transaction_type, reason, row.id
+                    );
+                    self.duplicate_transactions.push(row.id);
+                    if reason == SkipReason::ConflictingDuplicate {
+                        self.conflicting_duplicates.push(row.id);
+                    }
+                    Err(reason)
+                } else if row.transaction_type == Adjustment {
+                    // Back-office corrections aren't held to the rules, and are made
+                    // however far they take the funds, so the policy has no say
+                    self.ensure_unlocked(row.client_id, &row).and_then(|()| {
+                        let client = self.client_entry(row.client_id);
+                        client.handle_transaction(&Adjustment, &row, OverdraftPolicy::Reject)
+                    })
+                } else if let Err(reason) = self.check_rules(&row) {
+                    Err(reason)
+                } else if row.transaction_type == Transfer {
+                    self.transfer(&row)
+                } else {
+                    let outcome = self.ensure_unlocked(row.client_id, &row).and_then(|()| {
+                        let overdraft = self.overdraft_policy(row.client_id);
+                        let client = self.client_entry(row.client_id);
+                        let charged = fees::charged(&row, fee);
+                        client.handle_transaction(&row.transaction_type, &charged, overdraft)
+                    });
+                    if outcome.is_ok() {
+                        self.collect_fee(&row, fee);
+                    }
+                    outcome
+                };
+                self.alert_funds(&row, outcome);
+                // Only applied transactions can be looked up for disputes later on
+                if outcome.is_ok() {
+                    let mut kept = row.clone();
+                    kept.dispute_state = DisputeState::Undisputed;
+                    self.post(&kept, None, fee);
+                    self.journal(&kept, None, fee)?;
+                    self.tally_for_rules(&kept);
+                    self.record_applied(&kept, None);
+                    self.retain_transaction(kept)?;
+                }
+                (row.amount, outcome)
+            }
+            Open | Close | Reactivate => {
+                let outcome = self.lifecycle(&row);
+                if outcome.is_ok() {
+                    self.post(&row, None, Decimal::ZERO);
+                    self.journal(&row, None, Decimal::ZERO)?;
+                    self.record_applied(&row, None);
+                }
+                (row.amount, outcome)
+            }
+            Dispute | Resolve | Chargeback => match self.referenced_transaction(&row)? {
+                Ok(t) if t.transaction_type == Adjustment => {
+                    warn!(
+                        "Skipping {:?} of adjustment tx {} for client {}",
+                        row.transaction_type, t.id, t.client_id
+                    );
+                    (t.amount, Err(SkipReason::NotDisputable))
+                }
+                Ok(t) if row.transaction_type == Dispute && !self.dispute_policy.disputable(&t) => {
+                    warn!(
+                        "Skipping dispute of {:?} tx {} for client {}, not disputable under the dispute policy",
+                        t.transaction_type, t.id, t.client_id
+                    );
+                    (t.amount, Err(SkipReason::NotDisputable))
+                }
+                Ok(t) if row.transaction_type == Dispute && self.past_dispute_window(&row, &t) => {
+                    warn!(
+                        "Skipping dispute of tx {} for client {}, filed after the dispute window",
+                        t.id, row.client_id
+                    );
+                    (t.amount, Err(SkipReason::DisputeWindowExpired))
+                }
+                Ok(t) if row.currency.is_some() && row.currency != t.currency => {
+                    warn!(
+                        "Skipping {:?} of tx {} for client {}, tx is in {:?} not {:?}",
+                        row.transaction_type, t.id, row.client_id, t.currency, row.currency
+                    );
+                    (t.amount, Err(SkipReason::CurrencyMismatch))
+                }
+                Ok(mut t) => {
+                    let client_id = t.disputed_client_id();
+                    if !touched.contains(&client_id) {
+                        touched.push(client_id);
+                    }
+                    self.load_client(client_id)?;
+                    let redisputable = self.dispute_policy.redisputable(&t);
+                    let next = self
+                        .ensure_unlocked(client_id, &row)
+                        .and_then(|()| {
+                            t.dispute_state
+                                .next(&row.transaction_type, redisputable)
+                                .inspect_err(|reason| {
+                                    warn!(
+                                        "Skipping {:?} of tx {} for client {}, {}",
+                                        row.transaction_type, t.id, client_id, reason
+                                    )
+                                })
+                        })
+                        .and_then(|next| {
+                            let client = self.client_entry(client_id);
+                            // Disputes never overdraw, so the policy has no say
+                            client.handle_transaction(
+                                &row.transaction_type,
+                                &t,
+                                OverdraftPolicy::Reject,
+                            )?;
+                            Ok(next)
+                        });
+                    let outcome = next.map(|next| t.dispute_state = next);
+                    if outcome.is_ok() {
+                        self.emit_settlement(&row.transaction_type, client_id, &t);
+                        self.alert_settlement(&row.transaction_type, client_id, &t);
+                        if row.transaction_type == Chargeback && t.transaction_type == Transfer {
+                            if !touched.contains(&t.client_id) {
+                                touched.push(t.client_id);
+                            }
+                            let sender = self.client_entry(t.client_id);
+                            sender.reverse_transfer(&t);
+                        }
+                        let key = self.key_of(&t);
+                        if self.settled_for_good(&t) {
+                            self.transaction_store.remove_tx(key)?;
+                            if let Some(evicted) = self.evicted.as_mut() {
+                                evicted.push(t.id);
+                            }
+                            debug!("Evicted settled tx {}", key);
+                        } else {
+                            self.transaction_store.put_tx(key, t.clone())?;
+                        }
+                        self.post(&row, Some(&t), Decimal::ZERO);
+                        self.journal(&row, Some(&t), Decimal::ZERO)?;
+                        self.record_applied(&row, Some(&t));
+                    }
+                    (t.amount, outcome)
+                }
+                Err(reason) => (row.amount, Err(reason)),
+            },
+        };
+        match outcome {
+            Ok(()) => debug!(
+                tx = row.id,
+                client = row.client_id,
+                kind = ?row.transaction_type,
+                amount = %amount,
+                "Applied transaction"
+            ),
+            Err(reason) => debug!(
+                tx = row.id,
+                client = row.client_id,
+                disposition = reason.disposition(),
+                "Skipped transaction"
+            ),
+        }
+        if let (Some(metrics), Some(started)) = (self.metrics.as_mut(), started) {
+            let disposition = outcome.map_or_else(|reason| reason.disposition(), |()| "applied");
+            metrics.record(&row.transaction_type, disposition, started.elapsed());
+        }
+        if let Err(reason) = outcome {
+            self.emit(EngineEvent::TransactionRejected {
+                client: row.client_id,
+                tx: row.id,
+                reason,
+            });
+            self.skip(reason);
+            self.record_rejected(&row, reason);
+            if reason == SkipReason::InsufficientFunds {
+                self.rejected_withdrawals.push((row.client_id, row.id));
+            }
+        }
+        let overdrawn = outcome.is_ok()
+            && matches!(row.transaction_type, Withdrawal | Transfer)
+            && self.clients.get(&row.client_id).is_some_and(|client| {
+                client.balance(row.currency.as_deref()).available < Decimal::ZERO
+            });
+        self.audit(&row, amount, before, outcome)?;
+        if outcome.is_ok() {
+            self.mark_changed(&touched);
+            self.check_invariants(&touched, row.id)?;
+            self.write_through(&touched)?;
+        }
+        match outcome {
+            Err(reason) if self.strict && reason.is_partner_error() => {
+                Err(self.at_line(PaymentsError::Rejected(row.id, reason)))
+            }
+            _ if overdrawn => Ok(Outcome::Overdrawn),
+            _ => Ok(outcome.map_or_else(Outcome::from, |()| Outcome::Applied)),
+        }
+    }
+
+    /// Puts the clients an applied transaction touched and flushes both stores, when
+    /// the account store writes through. Fails if the stores can't commit them, such
+    /// as when another instance changed one of the clients first, leaving the engine
+    /// ahead of the stores.
+    fn write_through(&mut self, ids: &[ClientId]) -> Result<(), PaymentsError> {
+        if !self.account_store.writes_through() {
+            return Ok(());
+        }
+        let written = ids
+            .iter()
+            .filter_map(|id| self.clients.get(id))
+            .try_for_each(|client| self.account_store.put_client(client))
+            .and_then(|()| self.account_store.flush())
+            .and_then(|()| self.transaction_store.flush());
+        written.map_err(|err| self.at_line(err))
+    }
+
+    /// Logs or fails on any invariant the clients' balances break after tx `tx`
+    fn check_invariants(&mut self, ids: &[ClientId], tx: TxId) -> Result<(), PaymentsError> {
+        if self.invariant_check == InvariantCheck::Off {
+            return Ok(());
+        }
+        for id in ids {
+            let Some(client) = self.clients.get(id) else {
+                continue;
+            };
+            for (currency, invariant) in invariants::broken(client, self.available_not_negative) {
+                if self.invariant_check == InvariantCheck::Abort {
+                    return Err(self.at_line(PaymentsError::InvariantBroken {
+                        client: *id,
+                        tx,
+                        invariant,
+                    }));
+                }
+                error!(
+                    "Invariant {} broken for client {} by tx {}, in {}",
+                    invariant,
+                    id,
+                    tx,
+                    currency.unwrap_or("funds without a currency")
+                );
+                self.invariant_violations += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Adds the line of the record being applied to its error, if it came from an input
+    fn at_line(&self, err: PaymentsError) -> PaymentsError {
+        match self.line {
+            Some(line) => PaymentsError::AtLine {
+                line,
+                source: Box::new(err),
+            },
+            None => err,
+        }
+    }
+
+    /// Reads the client from the account store the first time the engine sees it
+    fn load_client(&mut self, id: ClientId) -> Result<(), PaymentsError> {
+        if !self.clients.contains_key(&id) {
+            if let Some(client) = self.account_store.get_client(id)? {
+                debug!("Client {} loaded from the account store", id);
+                self.first_seen.push(id);
+                self.clients.insert(id, client);
+            }
+        }
+        Ok(())
+    }
+
+    /// The client with the given id, added with empty balances if it's new
+    fn client_entry(&mut self, id: ClientId) -> &mut Client {
+        self.open_ledger_account(id);
+        if !self.clients.contains_key(&id) {
+            self.first_seen.push(id);
+            self.emit(EngineEvent::AccountCreated { client: id });
+        }
+        self.clients.entry(id).or_insert_with(|| Client::new(id))
+    }
+
+    /// Debits the row's client and credits the receiving client, or neither if
+    /// either account can't take part
+    fn transfer(&mut self, row: &Transaction) -> Result<(), SkipReason> {
+        let to_client_id = row.disputed_client_id();
+        if to_client_id == row.client_id {
+            warn!(
+                "Skipping transfer tx {} from client {} to itself",
+                row.id, row.client_id
+            );
+            return Err(SkipReason::SelfTransfer);
+        }
+        self.ensure_unlocked(row.client_id, row)?;
+        self.ensure_unlocked(to_client_id, row)?;
+        let overdraft = self.overdraft_policy(row.client_id);
+        self.client_entry(row.client_id).handle_transaction(
+            &TransactionType::Transfer,
+            row,
+            overdraft,
+        )?;
+        self.client_entry(to_client_id).handle_transaction(
+            &TransactionType::Deposit,
+            row,
+            overdraft,
+        )
+    }
+
+    /// Turns away rows for a locked account, apart from adjustments and whatever the
+    /// dispute policy allows on locked accounts, and rows for a closed account apart
+    /// from reactivating it
+    fn ensure_unlocked(&self, client_id: ClientId, row: &Transaction) -> Result<(), SkipReason> {
+        use TransactionType::*;
+        let client = self.clients.get(&client_id);
+        let locked = client.is_some_and(|c| c.locked);
+        let allowed = row.transaction_type == Adjustment
+            || matches!(row.transaction_type, Dispute | Resolve | Chargeback)
+                && self.dispute_policy.allowed_on_locked(&row.transaction_type);
+        if locked && !allowed {
+            warn!(
+                "Rejecting {:?} tx {} for locked client {}",
+                row.transaction_type, row.id, client_id
+            );
+            return Err(SkipReason::LockedAccount);
+        }
+        if client.is_some_and(|c| c.closed) && row.transaction_type != Reactivate {
+            warn!(
+                "Rejecting {:?} tx {} for closed client {}",
+                row.transaction_type, row.id, client_id
+            );
+            return Err(SkipReason::ClosedAccount);
+        }
+        Ok(())
+    }
+
+    /// Opens an account for the row's client, which mustn't have one yet, or closes
+    /// or reactivates the one it has
+    fn lifecycle(&mut self, row: &Transaction) -> Result<(), SkipReason> {
+        let exists = self.clients.contains_key(&row.client_id);
+        match row.transaction_type {
+            TransactionType::Open if exists => {
+                warn!("Skipping open of client {}, already open", row.client_id);
+                Err(SkipReason::AccountExists)
+            }
+            TransactionType::Open => Ok(()),
+            _ if !exists => {
+                warn!(
+                    "Skipping {:?} of client {}, no such account",
+                    row.transaction_type, row.client_id
+                );
+                Err(SkipReason::UnknownAccount)
+            }
+            _ => self.ensure_unlocked(row.client_id, row),
+        }?;
+        self.client_entry(row.client_id).handle_transaction(
+            &row.transaction_type,
+            row,
+            OverdraftPolicy::Reject,
+        )
+    }
+
+    /// Writes the client's balances before and after the row to the audit sink, if any
+    fn audit(
+        &mut self,
+        row: &Transaction,
+        amount: Decimal,
+        before: Option<Client>,
+        outcome: Result<(), SkipReason>,
+    ) -> Result<(), PaymentsError> {
+        let audit = match self.audit.as_mut() {
+            Some(audit) => audit,
+            None => return Ok(()),
+        };
+        let new = Client::new(row.client_id);
+        let before = before.as_ref().unwrap_or(&new);
+        let after = self.clients.get(&row.client_id).unwrap_or(&new);
+        let currency = row.currency.as_deref();
+        let (balance_before, balance_after) = (before.balance(currency), after.balance(currency));
+        let record = AuditRecord {
+            tx: row.id,
+            client: row.client_id,
+            transaction_type: row.transaction_type.clone(),
+            amount,
+            disposition: match outcome {
+                Ok(()) => "applied",
+                Err(reason) => reason.disposition(),
+            },
+            available_before: balance_before.available,
+            held_before: balance_before.held,
+            total_before: balance_before.total,
+            locked_before: before.locked,
+            available_after: balance_after.available,
+            held_after: balance_after.held,
+            total_after: balance_after.total,
+            locked_after: after.locked,
+            currency: row.currency.clone(),
+        };
+        match audit {
+            AuditSink::Csv(writer) => {
+                writer.serialize(record)?;
+                writer.flush()?;
+            }
+            AuditSink::JsonLines(writer) => {
+                serde_json::to_writer(&mut *writer, &record)?;
+                writeln!(writer)?;
+                writer.flush()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Keeps the transaction for later disputes. Past the per client cap the client's
+    /// oldest transaction that isn't under dispute is dropped, after which it can't be
+    /// disputed, nor is its tx id caught as a duplicate anymore.
+    fn retain_transaction(&mut self, transaction: Transaction) -> Result<(), PaymentsError> {
+        if let EvictionPolicy::PerClient(cap) = self.eviction_policy {
+            let scoped = self.key_of(&transaction);
+            let key = move |tx| TxKey { tx, ..scoped };
+            let retained = self.retained.entry(transaction.client_id).or_default();
+            retained.push_back(transaction.id);
+            if retained.len() > cap {
+                let mut evicted = None;
+                for (i, id) in retained.iter().enumerate() {
+                    if *id == transaction.id {
+                        continue;
+                    }
+                    let disputed = self
+                        .transaction_store
+                        .get_tx(key(*id))?
+                        .is_some_and(|t| t.dispute_state == DisputeState::Disputed);
+                    if !disputed {
+                        evicted = Some(i);
+                        break;
+                    }
+                }
+                if let Some(evicted) = evicted.and_then(|i| retained.remove(i)) {
+                    self.transaction_store.remove_tx(key(evicted))?;
+                    if let Some(ids) = self.evicted.as_mut() {
+                        ids.push(evicted);
+                    }
+                    debug!("Client {} evicted tx {}", transaction.client_id, evicted);
+                }
+            }
+        }
+        let key = self.key_of(&transaction);
+        self.transaction_store.put_tx(key, transaction)?;
+        if let EvictionPolicy::Window(records) = self.eviction_policy {
+            self.window.push_back((self.valid_records, key));
+            self.evict_window(records)?;
+        }
+        Ok(())
+    }
+
+    /// Drops the transactions kept for more than `records` records, giving those
+    /// under dispute another window
+    fn evict_window(&mut self, records: usize) -> Result<(), PaymentsError> {
+        while let Some(&(kept_at, key)) = self.window.front() {
+            if kept_at + records >= self.valid_records {
+                break;
+            }
+            self.window.pop_front();
+            match self.transaction_store.get_tx(key)? {
+                Some(t) if t.dispute_state == DisputeState::Disputed => {
+                    self.window.push_back((self.valid_records, key));
+                }
+                Some(_) => {
+                    self.transaction_store.remove_tx(key)?;
+                    debug!("Evicted tx {} past the window", key);
+                }
+                None => (),
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the dispute `row` of `transaction` came too long after it
+    fn past_dispute_window(&self, row: &Transaction, transaction: &Transaction) -> bool {
+        match (self.dispute_window, row.timestamp, transaction.timestamp) {
+            (Some(window), Some(filed), Some(happened)) => {
+                filed.saturating_sub(happened) > window.as_secs()
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether the transaction's dispute is over for good and the eviction policy
+    /// lets it go
+    fn settled_for_good(&self, transaction: &Transaction) -> bool {
+        self.eviction_policy == EvictionPolicy::Settled
+            && match transaction.dispute_state {
+                DisputeState::ChargedBack => true,
+                DisputeState::Resolved => !self.dispute_policy.redisputable(transaction),
+                DisputeState::Undisputed | DisputeState::Disputed => false,
+            }
+    }
+
+    fn count_valid(&mut self, transaction_type: &TransactionType) {
+        self.valid_records += 1;
+        *self
+            .transaction_counts
+            .entry(transaction_type.clone())
+            .or_insert(0) += 1;
+    }
+
+    fn skip(&mut self, reason: SkipReason) {
+        *self.skipped.entry(reason).or_insert(0) += 1;
+    }
+
+    /// Looks up the transaction a dispute, resolve or chargeback row refers to.
+    /// The row's client must own the transaction, otherwise the row is a partner
+    /// error and is recorded in `ownership_mismatches`. Only fails if the store does.
+    fn referenced_transaction(
+        &mut self,
+        row: &Transaction,
+    ) -> Result<Result<Transaction, SkipReason>, PaymentsError> {
+        let key = self.tx_key(row.client_id, row.id);
+        Ok(match self.transaction_store.get_tx(key)? {
+            // No matching transaction, assume partner error
+            None => {
+                warn!(
+                    "Skipping {:?}, no matching tx {}",
+                    row.transaction_type, row.id
+                );
+                Err(SkipReason::UnknownTransaction)
+            }
+            Some(t) if t.client_id != row.client_id => {
+                warn!(
+                    "Skipping {:?}, tx {} belongs to client {} not client {}",
+                    row.transaction_type, row.id, t.client_id, row.client_id
+                );
+                self.ownership_mismatches.push((row.client_id, row.id));
+                Err(SkipReason::OwnershipMismatch)
+            }
+            Some(t) => Ok(t),
+        })
+    }
+
+    /// Clears the lock a chargeback put on the account, so operators can remediate it
+    /// after manual review. With `require_no_disputes` the account stays locked
+    /// while any of its disputes are open.
+    pub fn unlock_client(
+        &mut self,
+        id: ClientId,
+        require_no_disputes: bool,
+    ) -> Result<(), PaymentsError> {
+        let client = self
+            .clients
+            .get_mut(&id)
+            .ok_or(PaymentsError::UnknownClient(id))?;
+        if require_no_disputes && !client.disputes.is_empty() {
+            return Err(PaymentsError::OpenDisputes(id, client.disputes.len()));
+        }
+        client.locked = false;
+        self.mark_changed(&[id]);
+        info!(client = id, "Account unlocked");
+        Ok(())
+    }
+
+    pub fn client(&self, id: ClientId) -> Option<&Client> {
+        self.clients.get(&id)
+    }
+
+    /// Every client account keyed by client id
+    pub fn accounts(&self) -> &HashMap<ClientId, Client> {
+        &self.clients
+    }
+
+    pub fn clients(&self, order: OutputOrder) -> Vec<&Client> {
+        match order {
+            OutputOrder::ClientId => {
+                let mut clients: Vec<&Client> = self.clients.values().collect();
+                clients.sort_unstable_by_key(|c| c.id);
+                clients
+            }
+            OutputOrder::FirstSeen => self
+                .first_seen
+                .iter()
+                .filter_map(|id| self.clients.get(id))
+                .collect(),
+        }
+    }
+
+    /// The rows of the accounts output, see `Client::rows`. Once any client holds a
+    /// currency, rows without one get an empty currency so every row has the column.
+    pub fn account_rows(&self, order: OutputOrder) -> Vec<AccountRow<'_>> {
+        self.rows_of(self.clients(order))
+    }
+
+    /// The rows of just these clients, in the order given, with the same columns as
+    /// `account_rows`. For writing out the clients `changed_since` the last time.
+    pub fn account_rows_of(&self, clients: &[ClientId]) -> Vec<AccountRow<'_>> {
+        self.rows_of(
+            clients
+                .iter()
+                .filter_map(|id| self.clients.get(id))
+                .collect(),
+        )
+    }
+
+    fn rows_of<'a>(&'a self, clients: Vec<&'a Client>) -> Vec<AccountRow<'a>> {
+        let mut rows: Vec<AccountRow> = clients.into_iter().flat_map(Client::rows).collect();
+        let currencies = self
+            .clients
+            .values()
+            .any(|client| !client.currencies.is_empty());
+        let fees_per_client = self.fees_per_client();
+        // Statuses are only told apart by their own column once accounts can be closed
+        let statuses = self
+            .transaction_counts
+            .keys()
+            .any(TransactionType::is_lifecycle)
+            || self.clients.values().any(Client::closed);
+        for row in &mut rows {
+            if statuses {
+                row.status = self.clients.get(&row.client).map(Client::status);
+            }
+            if fees_per_client {
+                let key = (row.client, row.currency.map(str::to_string));
+                let fee = self.fees.get(&key).copied().unwrap_or(Decimal::ZERO);
+                row.fees = Some(self.rescale(fee));
+            }
+            if currencies {
+                row.currency.get_or_insert("");
+            }
+            row.available = self.rescale(row.available);
+            row.held = self.rescale(row.held);
+            row.total = self.rescale(row.total);
+        }
+        rows
+    }
+
+    /// Writes the clients, their open disputes and the transactions kept for disputes
+    /// as json, for `load_state` to pick up in a later run
+    pub fn save_state<W: io::Write>(&self, writer: W) -> Result<(), PaymentsError> {
+        #[derive(Serialize)]
+        struct Versioned {
+            version: u32,
+            #[serde(flatten)]
+            state: SavedState,
+        }
+        let state = Versioned {
+            version: STATE_VERSION,
+            state: self.saved_state()?,
+        };
+        serde_json::to_writer(writer, &state)?;
+        Ok(())
+    }
+
+    fn saved_state(&self) -> Result<SavedState, PaymentsError> {
+        let mut clients: Vec<SavedClient> = self.clients.values().map(SavedClient::from).collect();
+        clients.sort_by_key(|client| client.id);
+        let mut transactions = self.transaction_store.transactions()?;
+        transactions.sort_by_key(|transaction| transaction.id);
+        Ok(SavedState {
+            clients,
+            first_seen: self.first_seen.clone(),
+            transactions,
+            retained: self.retained.clone(),
+            history: self.history.clone(),
+            fees: self
+                .fees
+                .iter()
+                .map(|((client, currency), fee)| (*client, currency.clone(), *fee))
+                .collect(),
+        })
+    }
+
+    /// Replaces the clients with those saved by `save_state` and adds the saved
+    /// transactions to the store. States saved by earlier versions are migrated, see
+    /// `STATE_VERSION`.
+    pub fn load_state<R: io::Read>(&mut self, reader: R) -> Result<(), PaymentsError> {
+        let state = migrate::migrate(serde_json::from_reader(reader)?)?;
+        self.restore_state(serde_json::from_value(state)?)
+    }
+
+    fn restore_state(&mut self, state: SavedState) -> Result<(), PaymentsError> {
+        self.clients = state
+            .clients
+            .into_iter()
+            .map(|saved| (saved.id, Client::from(saved)))
+            .collect();
+        self.first_seen = state.first_seen;
+        for transaction in state.transactions {
+            // Saved transactions count as kept before this run's first record
+            let key = self.key_of(&transaction);
+            if let EvictionPolicy::Window(_) = self.eviction_policy {
+                self.window.push_back((0, key));
+            }
+            self.transaction_store.put_tx(key, transaction)?;
+        }
+        self.retained = state.retained;
+        self.history = state.history;
+        self.fees = state
+            .fees
+            .into_iter()
+            .map(|(client, currency, fee)| ((client, currency), fee))
+            .collect();
+        Ok(())
+    }
+
+    /// Every transaction applied to the client, oldest first, as recorded with
+    /// `set_record_history`. Deposits, withdrawals and transfers carry their current
+    /// dispute state, and transfers, along with their chargebacks, show up for both
+    /// clients.
+    pub fn history(&self, client_id: ClientId) -> impl Iterator<Item = &Transaction> {
+        self.history.get(&client_id).into_iter().flatten()
+    }
+
+    /// Adds an applied transaction to the history of every client it touched
+    fn record_applied(&mut self, row: &Transaction, disputed: Option<&Transaction>) {
+        if !self.record_history {
+            return;
+        }
+        let mut client_ids = vec![row.client_id];
+        client_ids.extend(row.to_client_id);
+        client_ids.extend(disputed.map(Transaction::disputed_client_id));
+        // The sender of a transfer charged back is paid back
+        client_ids.extend(
+            disputed
+                .filter(|t| {
+                    row.transaction_type == TransactionType::Chargeback
+                        && t.transaction_type == TransactionType::Transfer
+                })
+                .map(|t| t.client_id),
+        );
+        client_ids.sort_unstable();
+        client_ids.dedup();
+        for client_id in client_ids {
+            let history = self.history.entry(client_id).or_default();
+            if let Some(t) = disputed {
+                // The disputed transaction is listed with its new dispute state
+                if let Some(entry) = history
+                    .iter_mut()
+                    .rev()
+                    .find(|entry| entry.id == t.id && entry.transaction_type == t.transaction_type)
+                {
+                    entry.dispute_state = t.dispute_state;
+                }
+            }
+            history.push(row.clone());
+        }
+    }
+
+    /// Sums the current client balances, no reprocessing required
+    /// Starts counting transactions by type and disposition and timing how long each
+    /// takes to apply, for `metrics`
+    pub fn enable_metrics(&mut self) {
+        self.metrics.get_or_insert_with(Metrics::default);
+    }
+
+    /// The counts and timings since `enable_metrics`, along with the funds, locked
+    /// accounts and open disputes of `report`, in the Prometheus text format
+    pub fn metrics(&self) -> Option<String> {
+        let metrics = self.metrics.as_ref()?;
+        Some(metrics.render(&self.report()))
+    }
+
+    pub fn report(&self) -> Report {
+        let mut report = Report {
+            available: Decimal::ZERO,
+            held: Decimal::ZERO,
+            total: Decimal::ZERO,
+            locked_accounts: 0,
+            open_disputes: 0,
+            currencies: BTreeMap::new(),
+            fees: None,
+            currency_fees: BTreeMap::new(),
+        };
+        if self.fee_schedule.is_some() {
+            for (currency, fee) in self.house_fees() {
+                match currency {
+                    None => report.fees = Some(fee),
+                    Some(currency) => {
+                        report.currency_fees.insert(currency.to_string(), fee);
+                    }
+                }
+            }
+            report.fees.get_or_insert(self.rescale(Decimal::ZERO));
+        }
+        for client in self.clients.values() {
+            report.available += client.available;
+            report.held += client.held;
+            report.total += client.total;
+            for (currency, balance) in &client.currencies {
+                let totals = report
+                    .currencies
+                    .entry(currency.clone())
+                    .or_insert_with(Balance::new);
+                totals.available += balance.available;
+                totals.held += balance.held;
+                totals.total += balance.total;
+            }
+            report.locked_accounts += client.locked as usize;
+            report.open_disputes += client.disputes.len();
+        }
+        report
+    }
+
+    /// Number of records that parsed, whether they were applied or skipped
+    pub fn valid_records(&self) -> usize {
+        self.valid_records
+    }
+
+    /// Number of records that failed to parse, see `set_skip_invalid_records`
+    pub fn invalid_records(&self) -> usize {
+        self.invalid_records
+    }
+
+    /// How many records were skipped for each reason
+    pub fn skipped(&self) -> &BTreeMap<SkipReason, usize> {
+        &self.skipped
+    }
+
+    /// Number of invariants found broken, when checking them with `InvariantCheck::Log`
+    pub fn invariant_violations(&self) -> usize {
+        self.invariant_violations
+    }
+
+    /// Tx ids of deposits and withdrawals rejected for reusing an existing tx id
+    pub fn duplicate_transactions(&self) -> &[TxId] {
+        &self.duplicate_transactions
+    }
+
+    /// Tx ids of deposits and withdrawals rejected for reusing the tx id of a
+    /// transaction with another type, client, amount or currency
+    pub fn conflicting_duplicates(&self) -> &[TxId] {
+        &self.conflicting_duplicates
+    }
+
+    /// (client id, tx id) of withdrawals and transfers rejected for insufficient funds
+    pub fn rejected_withdrawals(&self) -> &[(ClientId, TxId)] {
+        &self.rejected_withdrawals
+    }
+
+    /// (client id, tx id) of records that referred to another client's tx
+    pub fn ownership_mismatches(&self) -> &[(ClientId, TxId)] {
+        &self.ownership_mismatches
+    }
+
+    /// Number of records that parsed, by transaction type
+    pub fn transaction_counts(&self) -> &BTreeMap<TransactionType, usize> {
+        &self.transaction_counts
+    }
+
+    /// Number of records applied, those that parsed and weren't skipped. Records
+    /// held back in buffered mode count once `finish` has applied them.
+    pub fn applied_records(&self) -> usize {
+        let skipped: usize = self.skipped.values().sum();
+        self.valid_records - skipped - self.deferred.len()
+    }
+
+    /// Why the transaction can't be applied under its tx id, if the id was already
+    /// used. A resubmission of the same transaction is a plain duplicate, anything
+    /// else reusing the id a conflicting one.
+    fn duplicate_of(&self, transaction: &Transaction) -> Result<Option<SkipReason>, PaymentsError> {
+        Ok(self
+            .transaction_store
+            .get_tx(self.key_of(transaction))?
+            .map(|original| {
+                if original.fingerprint() == transaction.fingerprint() {
+                    SkipReason::DuplicateTransaction
+                } else {
+                    SkipReason::ConflictingDuplicate
+                }
+            }))
+    }
+
+    /// Parses a record into a transaction for the record's client. Dispute, resolve
+    /// and chargeback records carry no amount, so theirs is zero. A transfer names
+    /// its receiving client in a fifth column.
+    pub(crate) fn transaction_from_record(
+        &self,
+        record: StringRecord,
+    ) -> Result<Transaction, PaymentsError> {
+        self.transaction_from_columns(&record, &Columns::Positional)
+    }
+
+    /// Parses a csv record with its columns where `columns` says
+    fn transaction_from_columns<R: Fields>(
+        &self,
+        record: &R,
+        columns: &Columns,
+    ) -> Result<Transaction, PaymentsError> {
+        use TransactionType::*;
+        let raw = RawRecord::read(record, columns)?;
+        let value = raw.r#type.ok_or(PaymentsError::MissingField("type"))?;
+        let transaction_type = value
+            .parse::<TransactionType>()
+            .map_err(|_| PaymentsError::UnknownTransactionType(value.to_string()))?;
+        let get = |column| {
+            columns
+                .index(column, &transaction_type)
+                .and_then(|index| record.field(index))
+        };
+        let field = |column, name| get(column).ok_or(PaymentsError::MissingField(name));
+        let client_id = raw
+            .client
+            .ok_or(PaymentsError::MissingField("client"))?
+            .into_value("client_id")?;
+        let tx = raw
+            .tx
+            .ok_or(PaymentsError::MissingField("tx"))?
+            .into_value("tx")?;
+        let amount = raw.amount.filter(|amount| !amount.text.is_empty());
+        let amount = match transaction_type {
+            Deposit | Withdrawal | Transfer => {
+                let amount = amount.ok_or(PaymentsError::MissingAmount(tx))?;
+                self.parsed_amount(tx, self.csv_amount(amount)?)?
+            }
+            Adjustment => {
+                let amount = amount.ok_or(PaymentsError::MissingAmount(tx))?;
+                self.adjustment_amount(tx, self.csv_amount(amount)?)?
+            }
+            // The starting balance, which may be left out
+            Open => match amount {
+                None => Decimal::ZERO,
+                Some(amount) => self.opening_amount(tx, self.csv_amount(amount)?)?,
+            },
+            Dispute | Resolve | Chargeback | Close | Reactivate => Decimal::ZERO,
+        };
+        let to_client_id = match transaction_type {
+            Transfer => Some(parse_field(
+                field(Column::ToClient, "to_client")?,
+                "to_client",
+            )?),
+            _ => None,
+        };
+        let reference = match transaction_type {
+            Adjustment => Some(
+                get(Column::Reference)
+                    .map(str::trim)
+                    .filter(|value| !value.is_empty())
+                    .ok_or(PaymentsError::MissingField("reference"))?
+                    .to_string(),
+            ),
+            _ => None,
+        };
+        // Optional last columns, left empty or out for input without currencies or
+        // timestamps
+        let optional = |column| get(column).map(str::trim).filter(|value| !value.is_empty());
+        let currency = optional(Column::Currency).map(str::to_string);
+        let timestamp = optional(Column::Timestamp)
+            .map(|value| parse_field(value, "timestamp"))
+            .transpose()?;
+        Ok(Transaction {
+            id: tx,
+            transaction_type,
+            client_id,
+            amount,
+            to_client_id,
+            reference,
+            currency,
+            timestamp,
+            dispute_state: DisputeState::Undisputed,
+        })
+    }
+
+    /// Parses a json line into a transaction, rounding its amount the same as a csv
+    /// record's. Any amount on a dispute, resolve or chargeback is ignored.
+    pub(crate) fn transaction_from_json(&self, line: &str) -> Result<Transaction, PaymentsError> {
+        use TransactionType::*;
+        let mut row: Transaction = serde_json::from_str(line)?;
+        row.amount = match row.transaction_type {
+            Deposit | Withdrawal | Transfer => self.parsed_amount(row.id, row.amount)?,
+            Open => self.opening_amount(row.id, row.amount)?,
+            Adjustment => self.adjustment_amount(row.id, row.amount)?,
+            Dispute | Resolve | Chargeback | Close | Reactivate => Decimal::ZERO,
+        };
+        match row.transaction_type {
+            Transfer if row.to_client_id.is_none() => {
+                return Err(PaymentsError::MissingField("to_client"))
+            }
+            Transfer => (),
+            _ => row.to_client_id = None,
+        }
+        row.reference = match row.transaction_type {
+            Adjustment => Some(
+                row.reference
+                    .map(|reference| reference.trim().to_string())
+                    .filter(|reference| !reference.is_empty())
+                    .ok_or(PaymentsError::MissingField("reference"))?,
+            ),
+            _ => None,
+        };
+        row.currency = row.currency.filter(|currency| !currency.trim().is_empty());
+        row.dispute_state = DisputeState::Undisputed;
+        Ok(row)
+    }
+
+    /// Parses a single transaction arriving on its own, such as a message or a line
+    /// of a stream: a json object if it starts with `{`, otherwise a csv record
+    /// without a header
+    #[cfg_attr(not(feature = "native"), allow(dead_code))]
+    pub(crate) fn transaction_from_payload(
+        &self,
+        payload: &[u8],
+    ) -> Result<Transaction, PaymentsError> {
+        let text = String::from_utf8_lossy(payload);
+        if text.trim_start().starts_with('{') {
+            return self.transaction_from_json(&text);
+        }
+        let mut reader = csv::ReaderBuilder::new()
+            .delimiter(self.delimiter)
+            .has_headers(false)
+            .flexible(true)
+            .from_reader(payload);
+        match reader.records().next() {
+            Some(record) => self.transaction_from_record(record?),
+            None => Err(PaymentsError::MissingField("type")),
+        }
+    }
+
+    /// The amount of a csv record, written in the engine's amount format
+    fn csv_amount(&self, amount: Parsed<Decimal>) -> Result<Decimal, PaymentsError> {
+        match self.amount_format {
+            AmountFormat::Plain => amount.into_value("amount"),
+            format => Parsed::amount(&format.normalize(amount.text)).into_value("amount"),
+        }
+    }
+
+    /// Rounds the amount of a parsed deposit, withdrawal or transfer and checks it
+    fn parsed_amount(&self, tx: TxId, amount: Decimal) -> Result<Decimal, PaymentsError> {
+        let amount = self.round_amount(amount)?;
+        self.check_amount(tx, amount)?;
+        Ok(amount)
+    }
+
+    /// Rounds the starting balance of an open and checks it, which may be zero
+    fn opening_amount(&self, tx: TxId, amount: Decimal) -> Result<Decimal, PaymentsError> {
+        let amount = self.round_amount(amount)?;
+        if !amount.is_zero() {
+            self.check_amount(tx, amount)?;
+        }
+        Ok(amount)
+    }
+
+    /// Rounds the amount of an adjustment and checks it, which may be negative but
+    /// not zero
+    fn adjustment_amount(&self, tx: TxId, amount: Decimal) -> Result<Decimal, PaymentsError> {
+        let amount = self.round_amount(amount)?;
+        self.check_amount(tx, amount.abs())?;
+        Ok(amount)
+    }
+
+    /// Only positive amounts, up to the maximum if there is one, can be moved
+    fn check_amount(&self, tx: TxId, amount: Decimal) -> Result<(), PaymentsError> {
+        if amount <= Decimal::ZERO {
+            return Err(PaymentsError::NonPositiveAmount(tx, amount));
+        }
+        match self.max_amount {
+            Some(max) if amount > max => Err(PaymentsError::AmountOverMax(tx, amount, max)),
+            _ => Ok(()),
+        }
+    }
+
+    fn round_amount(&self, amount: Decimal) -> Result<Decimal, PaymentsError> {
+        if self.rounding_policy == RoundingPolicy::Reject
+            && amount.normalize().scale() > self.precision
+        {
+            return Err(PaymentsError::ExcessPrecision(amount, self.precision));
+        }
+        Ok(self.rescale(amount))
+    }
+
+    /// The amount rounded with the rounding policy and written with exactly
+    /// `precision` decimal places. Balances are only ever rounded for output, as
+    /// the reject policy has nothing to reject there, half-even stands in for it.
+    fn rescale(&self, amount: Decimal) -> Decimal {
+        use RoundingPolicy::*;
+        let strategy = match self.rounding_policy {
+            Reject | HalfEven => RoundingStrategy::MidpointNearestEven,
+            HalfUp => RoundingStrategy::MidpointAwayFromZero,
+            Truncate => RoundingStrategy::ToZero,
+        };
+        let mut amount = amount.round_dp_with_strategy(self.precision, strategy);
+        amount.rescale(self.precision);
+        amount
+    }
+}
+
+/// Parses a trimmed column of a record, naming the field it was meant for on failure
+fn parse_field<T>(value: &str, field: &'static str) -> Result<T, PaymentsError>
+where
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    value
+        .trim()
+        .parse::<T>()
+        .map_err(|err| PaymentsError::ParseError {
+            field,
+            value: value.to_string(),
+            reason: err.to_string(),
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{AccountStatus, Invariant};
+    use proptest::prelude::*;
+    use rust_decimal_macros::dec;
+
+    fn apply<P: DisputePolicy>(
+        engine: &mut PaymentsEngine<MemoryStore, MemoryStore, P>,
+        rows: &[&[&str]],
+    ) {
+        for row in rows {
+            engine
+                .process_record(StringRecord::from(row.to_vec()))
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn overdraft_withdrawal_is_not_disputable() {
+        let mut engine = PaymentsEngine::new();
+        apply(
+            &mut engine,
+            &[
+                &["deposit", "1", "1", "1.0"],
+                &["withdrawal", "1", "2", "5.0"],
+                &["dispute", "1", "2"],
+            ],
+        );
+
+        let client = engine.accounts().get(&1).unwrap();
+        assert_eq!(client.available, dec!(1.0000));
+        assert_eq!(client.held, dec!(0.0000));
+        assert_eq!(client.total, dec!(1.0000));
+        assert!(client.disputes.is_empty());
+        assert_eq!(engine.transaction_store.get_tx(2.into()).unwrap(), None);
+    }
+
+    #[test]
+    fn withdrawal_disputes_are_off_by_default() {
+        let mut engine = PaymentsEngine::new();
+        apply(
+            &mut engine,
+            &[
+                &["deposit", "1", "1", "5.0"],
+                &["withdrawal", "1", "2", "2.0"],
+                &["dispute", "1", "2"],
+            ],
+        );
+
+        let client = engine.accounts().get(&1).unwrap();
+        assert_eq!(client.available, dec!(3.0000));
+        assert_eq!(client.held, dec!(0.0000));
+        assert_eq!(engine.skipped[&SkipReason::NotDisputable], 1);
+    }
+
+    #[test]
+    fn withdrawal_dispute_resolve() {
+        let mut engine = PaymentsEngine::new();
+        engine.set_withdrawal_disputes(true);
+        apply(
+            &mut engine,
+            &[
+                &["deposit", "1", "1", "5.0"],
+                &["withdrawal", "1", "2", "2.0"],
+                &["dispute", "1", "2"],
+            ],
+        );
+        let client = engine.accounts().get(&1).unwrap();
+        assert_eq!(client.available, dec!(3.0000));
+        assert_eq!(client.held, dec!(2.0000));
+        assert_eq!(client.total, dec!(5.0000));
+
+        apply(&mut engine, &[&["resolve", "1", "2"]]);
+        let client = engine.accounts().get(&1).unwrap();
+        assert_eq!(client.available, dec!(3.0000));
+        assert_eq!(client.held, dec!(0.0000));
+        assert_eq!(client.total, dec!(3.0000));
+        assert!(!client.locked);
+    }
+
+    /// Lets small deposits be disputed over and over, and charged back on locked
+    /// accounts
+    struct SmallDeposits;
+
+    impl DisputePolicy for SmallDeposits {
+        fn disputable(&self, transaction: &Transaction) -> bool {
+            transaction.transaction_type == TransactionType::Deposit
+                && transaction.amount <= dec!(10)
+        }
+
+        fn allowed_on_locked(&self, _transaction_type: &TransactionType) -> bool {
+            true
+        }
+
+        fn redisputable(&self, _transaction: &Transaction) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn dispute_policy_decides_what_disputes_go_through() {
+        let mut engine = PaymentsEngine::with_dispute_policy(
+            MemoryStore::new(),
+            MemoryStore::new(),
+            SmallDeposits,
+        );
+        apply(
+            &mut engine,
+            &[
+                &["deposit", "1", "1", "5.0"],
+                &["deposit", "1", "2", "50.0"],
+                &["deposit", "1", "3", "1.0"],
+                &["dispute", "1", "2"],
+                &["dispute", "1", "1"],
+                &["resolve", "1", "1"],
+                &["dispute", "1", "1"],
+                &["dispute", "1", "3"],
+                &["chargeback", "1", "3"],
+                &["chargeback", "1", "1"],
+            ],
+        );
+
+        let client = engine.accounts().get(&1).unwrap();
+        assert!(client.locked);
+        assert_eq!(client.available, dec!(50.0000));
+        assert_eq!(client.held, dec!(0.0000));
+        assert_eq!(engine.skipped[&SkipReason::NotDisputable], 1);
+        assert_eq!(engine.skipped.get(&SkipReason::LockedAccount), None);
+    }
+
+    #[test]
+    fn dispute_policy_presets() {
+        let lenient: StandardDisputePolicy = "lenient".parse().unwrap();
+        let mut engine = PaymentsEngine::new();
+        engine.set_dispute_policy(lenient);
+        apply(
+            &mut engine,
+            &[
+                &["deposit", "1", "1", "5.0"],
+                &["withdrawal", "1", "2", "2.0"],
+                &["dispute", "1", "2"],
+                &["resolve", "1", "2"],
+                &["dispute", "1", "2"],
+            ],
+        );
+        assert_eq!(engine.accounts()[&1].held, dec!(2.0000));
+        assert!(engine.skipped.is_empty());
+
+        assert_eq!(
+            "standard".parse::<StandardDisputePolicy>(),
+            Ok(StandardDisputePolicy::default())
+        );
+        assert!(
+            "any-tx"
+                .parse::<StandardDisputePolicy>()
+                .unwrap()
+                .withdrawals
+        );
+        assert!("deposits".parse::<StandardDisputePolicy>().is_err());
+    }
+
+    #[test]
+    fn withdrawal_dispute_chargeback() {
+        let mut engine = PaymentsEngine::new();
+        engine.set_withdrawal_disputes(true);
+        apply(
+            &mut engine,
+            &[
+                &["deposit", "1", "1", "5.0"],
+                &["withdrawal", "1", "2", "2.0"],
+                &["dispute", "1", "2"],
+                &["chargeback", "1", "2"],
+            ],
+        );
+
+        let client = engine.accounts().get(&1).unwrap();
+        assert_eq!(client.available, dec!(5.0000));
+        assert_eq!(client.held, dec!(0.0000));
+        assert_eq!(client.total, dec!(5.0000));
+        assert!(client.locked);
+    }
+
+    #[test]
+    fn chargeback_direction_depends_on_original_type() {
+        let mut engine = PaymentsEngine::new();
+        engine.set_withdrawal_disputes(true);
+        apply(
+            &mut engine,
+            &[
+                &["deposit", "1", "1", "5.0"],
+                &["deposit", "1", "2", "3.0"],
+                &["deposit", "2", "3", "5.0"],
+                &["withdrawal", "2", "4", "3.0"],
+                &["dispute", "1", "2"],
+                &["dispute", "2", "4"],
+                &["chargeback", "1", "2"],
+                &["chargeback", "2", "4"],
+            ],
+        );
+
+        // The deposit is taken back out, the withdrawal is paid back in
+        assert_eq!(engine.client(1).unwrap().total(), dec!(5.0000));
+        assert_eq!(engine.client(2).unwrap().total(), dec!(5.0000));
+        assert_eq!(engine.client(2).unwrap().available(), dec!(5.0000));
+    }
+
+    #[test]
+    fn transfer_moves_funds_or_nothing() {
+        let mut engine = PaymentsEngine::new();
+        apply(
+            &mut engine,
+            &[
+                &["deposit", "1", "1", "5.0"],
+                &["transfer", "1", "2", "3.0", "2"],
+                &["transfer", "1", "3", "3.0", "2"],
+                &["transfer", "1", "4", "1.0", "1"],
+                &["deposit", "3", "5", "1.0"],
+                &["dispute", "3", "5"],
+                &["chargeback", "3", "5"],
+                &["transfer", "2", "6", "1.0", "3"],
+            ],
+        );
+
+        assert_eq!(engine.client(1).unwrap().available(), dec!(2.0000));
+        assert_eq!(engine.client(2).unwrap().available(), dec!(3.0000));
+        assert_eq!(engine.skipped()[&SkipReason::InsufficientFunds], 1);
+        assert_eq!(engine.skipped()[&SkipReason::SelfTransfer], 1);
+        assert_eq!(engine.skipped()[&SkipReason::LockedAccount], 1);
+    }
+
+    #[test]
+    fn disputed_transfer_holds_funds_on_receiving_side() {
+        let mut engine = PaymentsEngine::new();
+        apply(
+            &mut engine,
+            &[
+                &["deposit", "1", "1", "5.0"],
+                &["transfer", "1", "2", "3.0", "2"],
+                &["dispute", "1", "2"],
+            ],
+        );
+        let receiver = engine.client(2).unwrap();
+        assert_eq!(receiver.available(), dec!(0.0000));
+        assert_eq!(receiver.held(), dec!(3.0000));
+
+        apply(&mut engine, &[&["chargeback", "1", "2"]]);
+        let sender = engine.client(1).unwrap();
+        assert_eq!(sender.available(), dec!(5.0000));
+        assert!(!sender.locked());
+        let receiver = engine.client(2).unwrap();
+        assert_eq!(receiver.total(), dec!(0.0000));
+        assert!(receiver.locked());
+    }
+
+    #[test]
+    fn locked_account_rejects_all_but_allowed_disputes() {
+        let rows: &[&[&str]] = &[
+            &["deposit", "1", "1", "5.0"],
+            &["deposit", "1", "2", "3.0"],
+            &["dispute", "1", "1"],
+            &["chargeback", "1", "1"],
+            &["deposit", "1", "3", "1.0"],
+            &["dispute", "1", "2"],
+            &["resolve", "1", "2"],
+            &["dispute", "1", "2"],
+            &["chargeback", "1", "2"],
+        ];
+        let mut engine = PaymentsEngine::new();
+        apply(&mut engine, rows);
+        assert_eq!(engine.skipped()[&SkipReason::LockedAccount], 5);
+        assert_eq!(engine.client(1).unwrap().available(), dec!(3.0000));
+
+        let mut engine = PaymentsEngine::new();
+        engine.set_disputes_on_locked_accounts(true);
+        engine.set_redispute_policy(RedisputePolicy::AfterResolve);
+        apply(&mut engine, rows);
+        assert_eq!(engine.skipped()[&SkipReason::LockedAccount], 2);
+        let client = engine.client(1).unwrap();
+        assert_eq!(client.available(), dec!(0.0000));
+        assert_eq!(client.held(), dec!(3.0000));
+    }
+
+    #[test]
+    fn closed_account_turns_transactions_away_until_reactivated() {
+        let mut engine = PaymentsEngine::new();
+        apply(
+            &mut engine,
+            &[
+                &["open", "1", "1", "5.0"],
+                &["open", "2", "2", ""],
+                &["close", "2", "3"],
+                &["deposit", "2", "4", "1.0"],
+                &["transfer", "1", "5", "1.0", "2"],
+                &["reactivate", "1", "6"],
+            ],
+        );
+        let record = |engine: &mut PaymentsEngine, row: &[&str]| {
+            engine
+                .process_record(StringRecord::from(row.to_vec()))
+                .unwrap()
+        };
+        assert_eq!(
+            record(&mut engine, &["open", "1", "7", "1.0"]),
+            Outcome::AccountExists
+        );
+        assert_eq!(
+            record(&mut engine, &["close", "3", "8"]),
+            Outcome::UnknownAccount
+        );
+        assert_eq!(engine.skipped()[&SkipReason::ClosedAccount], 2);
+        assert_eq!(engine.skipped()[&SkipReason::NotClosed], 1);
+        assert_eq!(engine.client(1).unwrap().available(), dec!(5.0000));
+        assert_eq!(engine.client(2).unwrap().status(), AccountStatus::Closed);
+        let statuses: Vec<_> = engine
+            .account_rows(OutputOrder::ClientId)
+            .iter()
+            .map(|row| row.status)
+            .collect();
+        assert_eq!(
+            statuses,
+            [Some(AccountStatus::Active), Some(AccountStatus::Closed)]
+        );
+
+        apply(
+            &mut engine,
+            &[&["reactivate", "2", "9"], &["deposit", "2", "10", "1.0"]],
+        );
+        let client = engine.client(2).unwrap();
+        assert_eq!(client.status(), AccountStatus::Active);
+        assert_eq!(client.available(), dec!(1.0000));
+    }
+
+    #[test]
+    fn adjustments_correct_locked_accounts_and_cant_be_disputed() {
+        let mut engine = PaymentsEngine::new();
+        engine.set_record_history(true);
+        apply(
+            &mut engine,
+            &[
+                &["deposit", "1", "1", "5.0"],
+                &["deposit", "1", "2", "3.0"],
+                &["dispute", "1", "1"],
+                &["chargeback", "1", "1"],
+                &["adjustment", "1", "3", "-4.5", "ops-17"],
+                &["adjustment", "1", "4", "0.25", "ops-18", "", "1700000000"],
+                &["dispute", "1", "3"],
+            ],
+        );
+        let client = engine.client(1).unwrap();
+        assert!(client.locked());
+        assert_eq!(client.available(), dec!(-1.2500));
+        assert_eq!(client.total(), dec!(-1.2500));
+        assert_eq!(engine.skipped()[&SkipReason::NotDisputable], 1);
+        let history: Vec<_> = engine.history(1).collect();
+        assert_eq!(history[4].reference.as_deref(), Some("ops-17"));
+        assert_eq!(history[4].dispute_state, DisputeState::Undisputed);
+        assert_eq!(history[5].timestamp, Some(1_700_000_000));
+
+        for (row, err) in [
+            (
+                &["adjustment", "1", "5", "1.0"][..],
+                PaymentsError::MissingField("reference"),
+            ),
+            (
+                &["adjustment", "1", "5", "1.0", " "],
+                PaymentsError::MissingField("reference"),
+            ),
+            (
+                &["adjustment", "1", "5", "0", "ops-19"],
+                PaymentsError::NonPositiveAmount(5, dec!(0.0000)),
+            ),
+        ] {
+            let parsed = engine.transaction_from_record(StringRecord::from(row.to_vec()));
+            assert_eq!(
+                parsed.unwrap_err().to_string(),
+                err.to_string(),
+                "{:?}",
+                row
+            );
+        }
+        let json = engine
+            .transaction_from_json(
+                r#"{"type":"adjustment","client":1,"tx":5,"amount":"-1","reference":"ops-19"}"#,
+            )
+            .unwrap();
+        assert_eq!(json.amount, dec!(-1.0000));
+        assert!(engine
+            .transaction_from_json(r#"{"type":"adjustment","client":1,"tx":5,"amount":"1"}"#)
+            .is_err());
+    }
+
+    #[test]
+    fn unlocked_client_takes_transactions_again() {
+        let mut engine = PaymentsEngine::new();
+        engine.set_disputes_on_locked_accounts(true);
+        apply(
+            &mut engine,
+            &[
+                &["deposit", "1", "1", "5.0"],
+                &["deposit", "1", "2", "3.0"],
+                &["dispute", "1", "1"],
+                &["chargeback", "1", "1"],
+                &["dispute", "1", "2"],
+            ],
+        );
+
+        assert!(matches!(
+            engine.unlock_client(2, false),
+            Err(PaymentsError::UnknownClient(2))
+        ));
+        assert!(matches!(
+            engine.unlock_client(1, true),
+            Err(PaymentsError::OpenDisputes(1, 1))
+        ));
+        assert!(engine.client(1).unwrap().locked());
+
+        apply(&mut engine, &[&["resolve", "1", "2"]]);
+        engine.unlock_client(1, true).unwrap();
+        apply(&mut engine, &[&["deposit", "1", "3", "1.0"]]);
+        let client = engine.client(1).unwrap();
+        assert!(!client.locked());
+        assert_eq!(client.available(), dec!(4.0000));
+    }
+
+    #[test]
+    fn accounts_come_from_and_go_back_to_the_account_store() {
+        let mut accounts = MemoryStore::new();
+        let mut locked = Client::new(1);
+        locked.locked = true;
+        accounts.put_client(&locked).unwrap();
+        let mut engine = PaymentsEngine::with_stores(MemoryStore::new(), accounts);
+        for row in [["deposit", "2", "1", "2.0"], ["deposit", "1", "2", "1.0"]] {
+            engine
+                .process_record(StringRecord::from(row.to_vec()))
+                .unwrap();
+        }
+        engine.finish().unwrap();
+
+        assert_eq!(engine.skipped().get(&SkipReason::LockedAccount), Some(&1));
+        let (_, accounts) = engine.into_stores();
+        assert!(accounts.get_client(1).unwrap().unwrap().locked());
+        assert_eq!(
+            accounts.get_client(2).unwrap().unwrap().available(),
+            dec!(2.0000)
+        );
+    }
+
+    #[test]
+    fn each_transaction_reports_its_outcome() {
+        let mut engine = PaymentsEngine::new();
+        let mut outcome = |row: &[&str]| {
+            engine
+                .process_record(StringRecord::from(row.to_vec()))
+                .unwrap()
+        };
+        assert_eq!(outcome(&["deposit", "1", "1", "5.0"]), Outcome::Applied);
+        assert_eq!(outcome(&["deposit", "1", "1", "5.0"]), Outcome::DuplicateTx);
+        assert_eq!(
+            outcome(&["withdrawal", "1", "2", "9.0"]),
+            Outcome::InsufficientFunds
+        );
+        assert_eq!(outcome(&["dispute", "1", "3"]), Outcome::UnknownTx);
+        assert_eq!(outcome(&["dispute", "2", "1"]), Outcome::ClientMismatch);
+        assert_eq!(outcome(&["resolve", "1", "1"]), Outcome::NotDisputed);
+        assert_eq!(outcome(&["dispute", "1", "1"]), Outcome::Applied);
+        assert_eq!(outcome(&["dispute", "1", "1"]), Outcome::AlreadyDisputed);
+        assert_eq!(outcome(&["chargeback", "1", "1"]), Outcome::Applied);
+        assert_eq!(
+            outcome(&["deposit", "1", "4", "1.0"]),
+            Outcome::AccountLocked
+        );
+        assert_eq!(outcome(&["deposit", "2", "5", "1.0"]), Outcome::Applied);
+        assert_eq!(outcome(&["dispute", "2", "5"]), Outcome::Applied);
+        assert_eq!(outcome(&["resolve", "2", "5"]), Outcome::Applied);
+        assert_eq!(outcome(&["resolve", "2", "5"]), Outcome::DisputeSettled);
+        assert_eq!(outcome(&["chargeback", "2", "5"]), Outcome::DisputeSettled);
+        assert_eq!(outcome(&["resolve", "2", "6"]), Outcome::UnknownTx);
+        assert_eq!(outcome(&["resolve", "1", "5"]), Outcome::ClientMismatch);
+
+        let client = engine.client(1).unwrap();
+        assert_eq!(client.held(), dec!(0.0000));
+        assert_eq!(client.total(), dec!(0.0000));
+    }
+
+    #[test]
+    fn dispute_lifecycle_follows_the_redispute_policy() {
+        let rows: &[&[&str]] = &[
+            &["deposit", "1", "1", "5.0"],
+            &["deposit", "1", "2", "3.0"],
+            &["dispute", "1", "1"],
+            &["resolve", "1", "1"],
+            &["dispute", "1", "1"],
+            &["resolve", "1", "1"],
+            &["dispute", "1", "2"],
+            &["chargeback", "1", "2"],
+        ];
+        let mut engine = PaymentsEngine::new();
+        apply(&mut engine, rows);
+        assert_eq!(engine.skipped()[&SkipReason::DisputeClosed], 1);
+        assert_eq!(engine.skipped()[&SkipReason::DisputeSettled], 1);
+        let tx = engine.transaction_store.get_tx(1.into()).unwrap().unwrap();
+        assert_eq!(tx.dispute_state, DisputeState::Resolved);
+        let tx = engine.transaction_store.get_tx(2.into()).unwrap().unwrap();
+        assert_eq!(tx.dispute_state, DisputeState::ChargedBack);
+
+        let mut engine = PaymentsEngine::new();
+        engine.set_redispute_policy(RedisputePolicy::AfterResolve);
+        engine.set_disputes_on_locked_accounts(true);
+        apply(&mut engine, rows);
+        apply(&mut engine, &[&["dispute", "1", "2"]]);
+        assert!(!engine.skipped().contains_key(&SkipReason::DisputeSettled));
+        assert_eq!(engine.skipped()[&SkipReason::DisputeClosed], 1);
+        let client = engine.client(1).unwrap();
+        assert_eq!(client.available(), dec!(5.0000));
+        assert_eq!(client.held(), dec!(0.0000));
+    }
+
+    #[test]
+    fn disputes_past_the_window_are_rejected() {
+        let mut engine = PaymentsEngine::new();
+        engine.set_dispute_window(Some(Duration::from_secs(100)));
+        let mut outcome = |row: &[&str]| {
+            engine
+                .process_record(StringRecord::from(row.to_vec()))
+                .unwrap()
+        };
+        assert_eq!(
+            outcome(&["deposit", "1", "1", "5.0", "", "1000"]),
+            Outcome::Applied
+        );
+        assert_eq!(outcome(&["deposit", "1", "2", "5.0"]), Outcome::Applied);
+        assert_eq!(
+            outcome(&["dispute", "1", "1", "", "", "1101"]),
+            Outcome::DisputeWindowExpired
+        );
+        assert_eq!(
+            outcome(&["dispute", "1", "2", "", "", "5000"]),
+            Outcome::Applied
+        );
+        assert_eq!(
+            outcome(&["dispute", "1", "1", "", "", "1100"]),
+            Outcome::Applied
+        );
+        assert!(engine
+            .process_record(StringRecord::from(vec!["dispute", "1", "1", "", "", "x"]))
+            .is_err());
+        assert_eq!(engine.skipped()[&SkipReason::DisputeWindowExpired], 1);
+        let tx = engine.transaction_store.get_tx(1.into()).unwrap().unwrap();
+        assert_eq!(tx.timestamp, Some(1000));
+    }
+
+    #[test]
+    fn buffered_disputes_are_deferred() {
+        let mut engine = PaymentsEngine::new();
+        engine.set_buffered(true);
+        let dispute = StringRecord::from(vec!["dispute", "1", "1"]);
+        assert_eq!(engine.process_record(dispute).unwrap(), Outcome::Deferred);
+    }
+
+    #[test]
+    fn duplicate_tx_id_is_rejected_and_reported() {
+        let mut engine = PaymentsEngine::new();
+        apply(
+            &mut engine,
+            &[&["deposit", "1", "7", "1.0"], &["deposit", "1", "7", "5.0"]],
+        );
+
+        let client = engine.accounts().get(&1).unwrap();
+        assert_eq!(client.available, dec!(1.0000));
+        assert_eq!(client.total, dec!(1.0000));
+        assert_eq!(engine.duplicate_transactions, vec![7]);
+    }
+
+    #[test]
+    fn client_scoped_tx_ids_only_clash_within_a_client() {
+        let mut engine = PaymentsEngine::new();
+        engine.set_tx_id_scope(TxIdScope::Client);
+        apply(
+            &mut engine,
+            &[
+                &["deposit", "1", "7", "1.0"],
+                &["deposit", "2", "7", "5.0"],
+                &["deposit", "2", "7", "5.0"],
+                &["dispute", "2", "7"],
+                &["dispute", "1", "7"],
+                &["resolve", "1", "7"],
+            ],
+        );
+
+        let client = engine.accounts().get(&1).unwrap();
+        assert_eq!((client.available, client.held), (dec!(1), dec!(0)));
+        let client = engine.accounts().get(&2).unwrap();
+        assert_eq!((client.available, client.held), (dec!(0), dec!(5)));
+        assert_eq!(engine.duplicate_transactions, vec![7]);
+        assert!(engine.ownership_mismatches.is_empty());
+        assert!(matches!(
+            engine.process_record(StringRecord::from(vec!["transfer", "1", "8", "1.0", "2"])),
+            Err(PaymentsError::ScopedTransfer(8))
+        ));
+    }
+
+    #[test]
+    fn dispute_by_other_client_is_recorded_and_skipped() {
+        let mut engine = PaymentsEngine::new();
+        apply(
+            &mut engine,
+            &[
+                &["deposit", "1", "1", "1.0"],
+                &["deposit", "2", "2", "2.0"],
+                &["dispute", "2", "1"],
+            ],
+        );
+
+        let client = engine.accounts().get(&1).unwrap();
+        assert_eq!(client.available, dec!(1.0000));
+        assert_eq!(client.held, dec!(0.0000));
+        let client = engine.accounts().get(&2).unwrap();
+        assert_eq!(client.available, dec!(2.0000));
+        assert_eq!(client.held, dec!(0.0000));
+        assert_eq!(engine.ownership_mismatches, vec![(2, 1)]);
+    }
+
+    #[test]
+    fn report_sums_across_clients() {
+        let mut engine = PaymentsEngine::new();
+        apply(
+            &mut engine,
+            &[
+                &["deposit", "1", "1", "1.5"],
+                &["deposit", "2", "2", "2.0"],
+                &["deposit", "2", "3", "3.0"],
+                &["deposit", "3", "4", "4.25"],
+                &["deposit", "3", "5", "1.0"],
+                &["dispute", "2", "2"],
+                &["dispute", "3", "4"],
+                &["chargeback", "3", "4"],
+            ],
+        );
+
+        assert_eq!(
+            engine.report(),
+            Report {
+                available: dec!(5.5000),
+                held: dec!(2.0000),
+                total: dec!(7.5000),
+                locked_accounts: 1,
+                open_disputes: 1,
+                currencies: BTreeMap::new(),
+                fees: None,
+                currency_fees: BTreeMap::new(),
+            }
+        );
+    }
+
+    #[test]
+    fn balances_are_kept_per_currency() {
+        let mut engine = PaymentsEngine::new();
+        apply(
+            &mut engine,
+            &[
+                &["deposit", "1", "1", "10.0", "USD"],
+                &["deposit", "1", "2", "2.0", "EUR"],
+                &["withdrawal", "1", "3", "3.0", "EUR"],
+                &["withdrawal", "1", "4", "4.0", "USD"],
+                &["dispute", "1", "1", "", "EUR"],
+                &["dispute", "1", "2"],
+                &["deposit", "2", "5", "1.0"],
+                &["transfer", "1", "6", "1.0", "2", "USD"],
+            ],
+        );
+
+        assert_eq!(engine.skipped()[&SkipReason::InsufficientFunds], 1);
+        assert_eq!(engine.skipped()[&SkipReason::CurrencyMismatch], 1);
+        let client = engine.client(1).unwrap();
+        assert_eq!(client.available(), dec!(0.0000));
+        assert_eq!(client.balance(Some("USD")).available, dec!(5.0000));
+        let eur = client.balance(Some("EUR"));
+        assert_eq!((eur.available, eur.held), (dec!(0.0000), dec!(2.0000)));
+
+        let rows: Vec<_> = engine
+            .account_rows(OutputOrder::ClientId)
+            .into_iter()
+            .map(|row| (row.client, row.currency, row.total))
+            .collect();
+        assert_eq!(
+            rows,
+            [
+                (1, Some("EUR"), dec!(2.0000)),
+                (1, Some("USD"), dec!(5.0000)),
+                (2, Some(""), dec!(1.0000)),
+                (2, Some("USD"), dec!(1.0000)),
+            ]
+        );
+        assert_eq!(engine.report().currencies["USD"].total, dec!(6.0000));
+
+        let mut saved = Vec::new();
+        engine.save_state(&mut saved).unwrap();
+        let mut loaded = PaymentsEngine::new();
+        loaded.load_state(saved.as_slice()).unwrap();
+        assert_eq!(loaded.accounts(), engine.accounts());
+    }
+
+    #[test]
+    fn history_lists_applied_transactions_with_their_dispute_state() {
+        let mut engine = PaymentsEngine::new();
+        apply(
+            &mut engine,
+            &[
+                &["deposit", "1", "1", "5.0"],
+                &["deposit", "2", "2", "1.0"],
+                &["withdrawal", "1", "3", "9.0"],
+                &["transfer", "1", "4", "2.0", "2"],
+                &["dispute", "1", "1"],
+            ],
+        );
+        assert_eq!(engine.history(1).count(), 0);
+
+        let mut engine = PaymentsEngine::new();
+        engine.set_record_history(true);
+        apply(
+            &mut engine,
+            &[
+                &["deposit", "1", "1", "5.0"],
+                &["deposit", "2", "2", "1.0"],
+                &["withdrawal", "1", "3", "9.0"],
+                &["transfer", "1", "4", "2.0", "2"],
+                &["dispute", "1", "1"],
+            ],
+        );
+        let history: Vec<_> = engine
+            .history(1)
+            .map(|t| (t.id, t.transaction_type.clone(), t.dispute_state))
+            .collect();
+        assert_eq!(
+            history,
+            [
+                (1, TransactionType::Deposit, DisputeState::Disputed),
+                (4, TransactionType::Transfer, DisputeState::Undisputed),
+                (1, TransactionType::Dispute, DisputeState::Undisputed),
+            ]
+        );
+        let ids: Vec<TxId> = engine.history(2).map(|t| t.id).collect();
+        assert_eq!(ids, [2, 4]);
+
+        let mut saved = Vec::new();
+        engine.save_state(&mut saved).unwrap();
+        let mut loaded = PaymentsEngine::new();
+        loaded.load_state(saved.as_slice()).unwrap();
+        assert!(loaded.history(1).eq(engine.history(1)));
+    }
+
+    #[test]
+    fn output_order() {
+        let mut engine = PaymentsEngine::new();
+        apply(
+            &mut engine,
+            &[
+                &["deposit", "4", "1", "1.0"],
+                &["deposit", "2", "2", "1.0"],
+                &["deposit", "9", "3", "1.0"],
+                &["deposit", "2", "4", "1.0"],
+            ],
+        );
+        let ids = |order: OutputOrder| -> Vec<ClientId> {
+            engine.clients(order).iter().map(|c| c.id).collect()
+        };
+
+        assert_eq!(ids(OutputOrder::ClientId), vec![2, 4, 9]);
+        assert_eq!(ids(OutputOrder::FirstSeen), vec![4, 2, 9]);
+    }
+
+    #[test]
+    fn buffered_mode_applies_dispute_before_its_deposit() {
+        let rows: &[&[&str]] = &[&["dispute", "1", "1"], &["deposit", "1", "1", "1.0"]];
+
+        let mut engine = PaymentsEngine::new();
+        apply(&mut engine, rows);
+        engine.apply_deferred().unwrap();
+        assert_eq!(engine.accounts().get(&1).unwrap().held, dec!(0.0000));
+
+        let mut engine = PaymentsEngine::new();
+        engine.buffered = true;
+        apply(&mut engine, rows);
+        engine.apply_deferred().unwrap();
+        let client = engine.accounts().get(&1).unwrap();
+        assert_eq!(client.available, dec!(0.0000));
+        assert_eq!(client.held, dec!(1.0000));
+        assert_eq!(client.total, dec!(1.0000));
+    }
+
+    #[test]
+    fn capped_transactions_evict_oldest() {
+        let mut engine = PaymentsEngine::new();
+        engine.set_max_transactions_per_client(Some(2));
+        apply(
+            &mut engine,
+            &[
+                &["deposit", "1", "1", "1.0"],
+                &["deposit", "1", "2", "2.0"],
+                &["deposit", "2", "3", "5.0"],
+                &["deposit", "1", "4", "4.0"],
+                &["dispute", "1", "1"],
+                &["dispute", "1", "2"],
+                &["dispute", "2", "3"],
+            ],
+        );
+
+        let client = engine.accounts().get(&1).unwrap();
+        assert_eq!(client.held, dec!(2.0000));
+        assert_eq!(client.available, dec!(5.0000));
+        assert_eq!(engine.accounts().get(&2).unwrap().held, dec!(5.0000));
+        assert_eq!(engine.skipped[&SkipReason::UnknownTransaction], 1);
+    }
+
+    #[test]
+    fn capped_transactions_keep_open_disputes() {
+        let mut engine = PaymentsEngine::new();
+        engine.set_max_transactions_per_client(Some(1));
+        apply(
+            &mut engine,
+            &[
+                &["deposit", "1", "1", "1.0"],
+                &["dispute", "1", "1"],
+                &["deposit", "1", "2", "2.0"],
+                &["resolve", "1", "1"],
+            ],
+        );
+
+        let client = engine.accounts().get(&1).unwrap();
+        assert_eq!(client.held, dec!(0.0000));
+        assert_eq!(client.available, dec!(3.0000));
+    }
+
+    #[test]
+    fn capped_transactions_never_evict_the_incoming_tx() {
+        let mut engine = PaymentsEngine::new();
+        engine.set_max_transactions_per_client(Some(1));
+        apply(
+            &mut engine,
+            &[
+                &["deposit", "1", "1", "1.0"],
+                &["dispute", "1", "1"],
+                &["deposit", "1", "2", "2.0"],
+                &["resolve", "1", "1"],
+                &["deposit", "1", "3", "3.0"],
+                &["deposit", "1", "4", "4.0"],
+                &["dispute", "1", "2"],
+            ],
+        );
+
+        let client = engine.accounts().get(&1).unwrap();
+        assert_eq!(client.held, dec!(0.0000));
+        assert_eq!(client.available, dec!(10.0000));
+    }
+
+    #[test]
+    fn window_eviction_keeps_open_disputes() {
+        let mut engine = PaymentsEngine::new();
+        engine.set_eviction_policy(EvictionPolicy::Window(2));
+        apply(
+            &mut engine,
+            &[
+                &["deposit", "1", "1", "1.0"],
+                &["deposit", "1", "2", "2.0"],
+                &["dispute", "1", "2"],
+                &["deposit", "2", "3", "3.0"],
+                &["deposit", "2", "4", "4.0"],
+                &["deposit", "2", "5", "5.0"],
+                &["dispute", "1", "1"],
+                &["resolve", "1", "2"],
+            ],
+        );
+
+        assert_eq!(engine.skipped[&SkipReason::UnknownTransaction], 1);
+        let client = engine.accounts().get(&1).unwrap();
+        assert_eq!(client.held, dec!(0.0000));
+        assert_eq!(client.available, dec!(3.0000));
+        assert_eq!(engine.transaction_store.get_tx(1.into()).unwrap(), None);
+        assert!(engine.transaction_store.get_tx(5.into()).unwrap().is_some());
+    }
+
+    #[test]
+    fn settled_eviction_drops_finished_disputes() {
+        let mut engine = PaymentsEngine::new();
+        engine.set_eviction_policy(EvictionPolicy::Settled);
+        apply(
+            &mut engine,
+            &[
+                &["deposit", "1", "1", "1.0"],
+                &["deposit", "1", "2", "2.0"],
+                &["dispute", "1", "1"],
+                &["resolve", "1", "1"],
+                &["dispute", "1", "2"],
+            ],
+        );
+        assert_eq!(engine.transaction_store.get_tx(1.into()).unwrap(), None);
+        assert!(engine.transaction_store.get_tx(2.into()).unwrap().is_some());
+
+        let mut engine = PaymentsEngine::new();
+        engine.set_eviction_policy(EvictionPolicy::Settled);
+        engine.set_redispute_policy(RedisputePolicy::AfterResolve);
+        apply(
+            &mut engine,
+            &[
+                &["deposit", "1", "1", "1.0"],
+                &["dispute", "1", "1"],
+                &["resolve", "1", "1"],
+                &["dispute", "1", "1"],
+                &["chargeback", "1", "1"],
+            ],
+        );
+        assert_eq!(engine.transaction_store.get_tx(1.into()).unwrap(), None);
+        assert!(engine.accounts().get(&1).unwrap().locked);
+    }
+
+    #[test]
+    fn parses_eviction_policies() {
+        assert_eq!("keep-all".parse(), Ok(EvictionPolicy::KeepAll));
+        assert_eq!("settled".parse(), Ok(EvictionPolicy::Settled));
+        assert_eq!("window:10".parse(), Ok(EvictionPolicy::Window(10)));
+        assert_eq!("per-client:3".parse(), Ok(EvictionPolicy::PerClient(3)));
+        assert_eq!("window".parse::<EvictionPolicy>(), Err(()));
+        assert_eq!("window:x".parse::<EvictionPolicy>(), Err(()));
+    }
+
+    /// Audit sink the test can still read from after handing it to the engine
+    #[derive(Clone, Default)]
+    struct SharedBuffer(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl io::Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn overdraft_policy_decides_how_far_withdrawals_go() {
+        let buffer = SharedBuffer::default();
+        let mut engine = PaymentsEngine::new();
+        engine.set_audit_sink(Box::new(buffer.clone()), AuditFormat::Csv, false);
+        engine.set_client_overdraft_policy(2, OverdraftPolicy::AllowUpTo(dec!(5)));
+        engine.set_client_overdraft_policy(3, OverdraftPolicy::AllowNegative);
+        let mut outcome = |row: &[&str]| {
+            engine
+                .process_record(StringRecord::from(row.to_vec()))
+                .unwrap()
+        };
+        assert_eq!(outcome(&["deposit", "1", "1", "1.0"]), Outcome::Applied);
+        assert_eq!(
+            outcome(&["withdrawal", "1", "2", "2.0"]),
+            Outcome::InsufficientFunds
+        );
+        assert_eq!(
+            outcome(&["withdrawal", "2", "3", "4.0"]),
+            Outcome::Overdrawn
+        );
+        assert_eq!(
+            outcome(&["withdrawal", "2", "4", "2.0"]),
+            Outcome::InsufficientFunds
+        );
+        assert_eq!(
+            outcome(&["withdrawal", "2", "5", "1.0"]),
+            Outcome::Overdrawn
+        );
+        assert_eq!(
+            outcome(&["withdrawal", "3", "6", "1000"]),
+            Outcome::Overdrawn
+        );
+        assert_eq!(outcome(&["deposit", "3", "7", "1500"]), Outcome::Applied);
+        assert_eq!(outcome(&["withdrawal", "3", "8", "100"]), Outcome::Applied);
+
+        assert_eq!(engine.client(1).unwrap().available(), dec!(1.0000));
+        assert_eq!(engine.client(2).unwrap().available(), dec!(-5.0000));
+        assert_eq!(engine.client(3).unwrap().available(), dec!(400.0000));
+        assert_eq!(engine.rejected_withdrawals(), &[(1, 2), (2, 4)]);
+        let audit = String::from_utf8(mem::take(&mut *buffer.0.lock().unwrap())).unwrap();
+        assert!(audit.contains("2,1,withdrawal,2.0000,rejected-insufficient-funds,"));
+        assert!(audit.contains("3,2,withdrawal,4.0000,applied,"));
+    }
+
+    #[test]
+    fn audit_trail_snapshots_balances() {
+        let buffer = SharedBuffer::default();
+        let mut engine = PaymentsEngine::new();
+        engine.set_audit_sink(Box::new(buffer.clone()), AuditFormat::Csv, false);
+        apply(
+            &mut engine,
+            &[
+                &["deposit", "1", "1", "2.5"],
+                &["dispute", "1", "1"],
+                &["resolve", "1", "9"],
+            ],
+        );
+
+        let audit = String::from_utf8(mem::take(&mut *buffer.0.lock().unwrap())).unwrap();
+        assert_eq!(
+            audit,
+            "tx,client,type,amount,disposition,\
+             available_before,held_before,total_before,locked_before,\
+             available_after,held_after,total_after,locked_after\n\
+             1,1,deposit,2.5000,applied,\
+             0.0000,0.0000,0.0000,false,2.5000,0.0000,2.5000,false\n\
+             1,1,dispute,2.5000,applied,\
+             2.5000,0.0000,2.5000,false,0.0000,2.5000,2.5000,false\n\
+             9,1,resolve,0,ignored-unknown-tx,\
+             0.0000,2.5000,2.5000,false,0.0000,2.5000,2.5000,false\n"
+        );
+    }
+
+    #[test]
+    fn appended_json_audit_trail() {
+        let buffer = SharedBuffer::default();
+        let mut engine = PaymentsEngine::new();
+        engine.set_audit_sink(Box::new(buffer.clone()), AuditFormat::JsonLines, true);
+        apply(
+            &mut engine,
+            &[
+                &["deposit", "1", "1", "2.5"],
+                &["deposit", "1", "1", "2.5"],
+                &["withdrawal", "1", "2", "9.0"],
+            ],
+        );
+
+        let audit = String::from_utf8(mem::take(&mut *buffer.0.lock().unwrap())).unwrap();
+        let dispositions: Vec<String> = audit
+            .lines()
+            .map(|line| {
+                let record: serde_json::Value = serde_json::from_str(line).unwrap();
+                record["disposition"].as_str().unwrap().to_string()
+            })
+            .collect();
+        assert_eq!(
+            dispositions,
+            [
+                "applied",
+                "rejected-duplicate",
+                "rejected-insufficient-funds"
+            ]
+        );
+        assert!(audit.contains(r#""available_after":"2.5000""#));
+    }
+
+    #[derive(Debug, Clone)]
+    enum Op {
+        Deposit(ClientId, i64),
+        Withdrawal(ClientId, i64),
+        // Indexes wrap around the deposits or open disputes seen so far
+        Dispute(usize),
+        Resolve(usize),
+        Chargeback(usize),
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        prop_oneof![
+            4 => (1..5u16, 1..100_000i64).prop_map(|(c, a)| Op::Deposit(c, a)),
+            3 => (1..5u16, 1..100_000i64).prop_map(|(c, a)| Op::Withdrawal(c, a)),
+            2 => any::<usize>().prop_map(Op::Dispute),
+            1 => any::<usize>().prop_map(Op::Resolve),
+            1 => any::<usize>().prop_map(Op::Chargeback),
+        ]
+    }
+
+    /// Turns ops into rows where disputes only target earlier deposits and
+    /// resolves/chargebacks only target disputes still open
+    fn records(ops: Vec<Op>) -> Vec<StringRecord> {
+        let mut deposits = Vec::<(ClientId, TxId)>::new();
+        let mut disputed = Vec::<(ClientId, TxId)>::new();
+        let mut records = Vec::new();
+        for (tx, op) in (1..).zip(ops) {
+            let amount = |a: i64| Decimal::new(a, PRECISION).to_string();
+            let row = match op {
+                Op::Deposit(client, a) => {
+                    deposits.push((client, tx));
+                    vec![
+                        "deposit".into(),
+                        client.to_string(),
+                        tx.to_string(),
+                        amount(a),
+                    ]
+                }
+                Op::Withdrawal(client, a) => {
+                    vec![
+                        "withdrawal".into(),
+                        client.to_string(),
+                        tx.to_string(),
+                        amount(a),
+                    ]
+                }
+                Op::Dispute(i) if !deposits.is_empty() => {
+                    let (client, tx) = deposits[i % deposits.len()];
+                    disputed.push((client, tx));
+                    vec!["dispute".into(), client.to_string(), tx.to_string()]
+                }
+                Op::Resolve(i) if !disputed.is_empty() => {
+                    let (client, tx) = disputed.swap_remove(i % disputed.len());
+                    vec!["resolve".into(), client.to_string(), tx.to_string()]
+                }
+                Op::Chargeback(i) if !disputed.is_empty() => {
+                    let (client, tx) = disputed.swap_remove(i % disputed.len());
+                    vec!["chargeback".into(), client.to_string(), tx.to_string()]
+                }
+                _ => continue,
+            };
+            records.push(StringRecord::from(row));
+        }
+        records
+    }
+
+    proptest! {
+        #[test]
+        fn ledger_invariants_hold(ops in proptest::collection::vec(op(), 0..200)) {
+            let mut engine = PaymentsEngine::new();
+            // Fails the record that leaves its accounts breaking an invariant
+            engine.set_invariant_check(InvariantCheck::Abort, false);
+            let mut charged_back = Decimal::ZERO;
+            for record in records(ops) {
+                let is_chargeback = &record[0] == "chargeback";
+                let client_id = record[1].parse::<ClientId>().unwrap();
+                let tx = record[2].parse::<TxId>().unwrap();
+                let was_locked = engine.accounts().get(&client_id).is_some_and(|c| c.locked);
+
+                engine.process_record(record).unwrap();
+
+                for client in engine.accounts().values() {
+                    prop_assert_eq!(client.total, client.available + client.held);
+                }
+                let locked = engine.accounts().get(&client_id).is_some_and(|c| c.locked);
+                if is_chargeback && !was_locked && locked {
+                    charged_back += engine.transaction_store.get_tx(tx.into()).unwrap().unwrap().amount;
+                }
+            }
+
+            let transactions = engine.transaction_store.transactions().unwrap();
+            let deposited: Decimal = transactions
+                .iter()
+                .filter(|t| t.transaction_type == TransactionType::Deposit)
+                .map(|t| t.amount)
+                .sum();
+            let withdrawn: Decimal = transactions
+                .iter()
+                .filter(|t| t.transaction_type == TransactionType::Withdrawal)
+                .map(|t| t.amount)
+                .sum();
+            let totals: Decimal = engine.accounts().values().map(|c| c.total).sum();
+            prop_assert_eq!(totals, deposited - withdrawn - charged_back);
+        }
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn process_stream_applies_records() {
+        let records = futures::stream::iter(vec![
+            StringRecord::from(vec!["deposit", "1", "1", "3.0"]),
+            StringRecord::from(vec!["deposit", "2", "2", "2.0"]),
+            StringRecord::from(vec!["withdrawal", "1", "3", "1.5"]),
+            StringRecord::from(vec!["dispute", "2", "2"]),
+        ]);
+        let mut engine = PaymentsEngine::new();
+        futures::executor::block_on(engine.process_stream(records)).unwrap();
+
+        let client = engine.accounts().get(&1).unwrap();
+        assert_eq!(client.available, dec!(1.5000));
+        assert_eq!(client.total, dec!(1.5000));
+        let client = engine.accounts().get(&2).unwrap();
+        assert_eq!(client.available, dec!(0.0000));
+        assert_eq!(client.held, dec!(2.0000));
+    }
+
+    fn parse_amount(rounding_policy: RoundingPolicy, amount: &str) -> Option<Decimal> {
+        let mut engine = PaymentsEngine::new();
+        engine.rounding_policy = rounding_policy;
+        let record = StringRecord::from(vec!["deposit", "1", "1", amount]);
+        engine
+            .transaction_from_record(record)
+            .ok()
+            .map(|t| t.amount)
+    }
+
+    #[test]
+    fn malformed_records_are_skipped_and_counted() {
+        let input = "type, client, tx, amount\n\
+                     deposit, 1, 2, 5.0\n\
+                     deposit, 1, 3, abc\n\
+                     deposit, x, 4, 1.0\n\
+                     refund, 1, 5, 1.0\n\
+                     withdrawal, 1, 6\n\
+                     deposit, 1\n\
+                     withdrawal, 1, 8, 2.0\n";
+        let mut engine = PaymentsEngine::new();
+        engine.process(input.as_bytes()).unwrap();
+        assert_eq!(engine.valid_records(), 2);
+        assert_eq!(engine.invalid_records(), 5);
+        assert_eq!(engine.client(1).unwrap().available(), dec!(3.0000));
+
+        engine.set_skip_invalid_records(false);
+        let err = engine.process(input.as_bytes()).unwrap_err();
+        assert!(matches!(
+            err,
+            PaymentsError::ParseError {
+                field: "amount",
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn strict_mode_stops_at_partner_errors_with_their_line() {
+        let input = "type, client, tx, amount\n\
+                     deposit, 1, 1, 1.0\n\
+                     deposit, 1, 2, 2.0\n\
+                     withdrawal, 1, 3, 5.0\n\
+                     dispute, 1, 9\n\
+                     deposit, 1, 4, 1.0\n";
+        let mut engine = PaymentsEngine::new();
+        engine.set_strict(true);
+        let err = engine.process(input.as_bytes()).unwrap_err();
+        assert!(
+            matches!(
+                &err,
+                PaymentsError::AtLine { line: 5, source }
+                    if matches!(**source, PaymentsError::Rejected(9, SkipReason::UnknownTransaction))
+            ),
+            "{}",
+            err
+        );
+        // Insufficient funds is down to the account, not the record
+        assert_eq!(engine.skipped[&SkipReason::InsufficientFunds], 1);
+
+        let mut engine = PaymentsEngine::new();
+        engine.set_strict(true);
+        let err = engine.process_parallel([input.as_bytes()], 2).unwrap_err();
+        assert_eq!(err.to_string(), "Line 5: Rejected tx 9, unknown tx");
+    }
+
+    #[test]
+    fn counts_records_by_type_and_applied() {
+        let mut engine = PaymentsEngine::new();
+        engine.buffered = true;
+        apply(
+            &mut engine,
+            &[
+                &["deposit", "1", "1", "1.0"],
+                &["withdrawal", "1", "2", "5.0"],
+                &["dispute", "1", "1"],
+                &["resolve", "1", "1"],
+                &["deposit", "1", "3", "1.0"],
+            ],
+        );
+        assert_eq!(engine.applied_records(), 2);
+        engine.apply_deferred().unwrap();
+        assert_eq!(engine.applied_records(), 4);
+        assert_eq!(engine.valid_records(), 5);
+        let counts: Vec<_> = engine
+            .transaction_counts()
+            .iter()
+            .map(|(transaction_type, count)| (transaction_type.to_string(), *count))
+            .collect();
+        assert_eq!(
+            counts,
+            [
+                ("deposit".to_string(), 2),
+                ("withdrawal".to_string(), 1),
+                ("dispute".to_string(), 1),
+                ("resolve".to_string(), 1)
+            ]
+        );
+    }
+
+    #[test]
+    fn malformed_record_errors() {
+        let engine = PaymentsEngine::new();
+        let parse = |row: &[&str]| engine.transaction_from_record(StringRecord::from(row.to_vec()));
+        assert!(matches!(
+            parse(&["refund", "1", "1", "1.0"]),
+            Err(PaymentsError::UnknownTransactionType(t)) if t == "refund"
+        ));
+        assert!(matches!(
+            parse(&["deposit", "x", "1", "1.0"]),
+            Err(PaymentsError::ParseError {
+                field: "client_id",
+                ..
+            })
+        ));
+        assert!(matches!(
+            parse(&["deposit", "1"]),
+            Err(PaymentsError::MissingField("tx"))
+        ));
+        assert!(matches!(
+            parse(&["withdrawal", "1", "7", " "]),
+            Err(PaymentsError::MissingAmount(7))
+        ));
+        assert!(parse(&["dispute", "1", "7"]).is_ok());
+    }
+
+    #[test]
+    fn payloads_are_csv_or_json() {
+        let engine = PaymentsEngine::new();
+        let csv = engine
+            .transaction_from_payload(b"deposit, 1, 2, 1.5")
+            .unwrap();
+        let json = engine
+            .transaction_from_payload(br#"{"type":"deposit","client":1,"tx":2,"amount":"1.5"}"#)
+            .unwrap();
+        assert_eq!(csv, json);
+        assert_eq!(csv.transaction_type, TransactionType::Deposit);
+        assert_eq!(csv.amount, dec!(1.5));
+
+        assert!(engine.transaction_from_payload(b"").is_err());
+        assert!(engine
+            .transaction_from_payload(b"type, client, tx, amount")
+            .is_err());
+    }
+
+    #[test]
+    fn json_lines_match_csv() {
+        let csv = "type, client, tx, amount\n\
+                   deposit, 1, 2, 2.12345\n\
+                   deposit, 2, 3, 3.0\n\
+                   withdrawal, 1, 4, 0.5\n\
+                   dispute, 2, 3\n\
+                   chargeback, 2, 3\n";
+        let json = r#"{"type":"deposit","client":1,"tx":2,"amount":"2.12345"}
+            {"type":"deposit","client":2,"tx":3,"amount":"3.0"}
+
+            {"type":"withdrawal","client":1,"tx":4,"amount":"0.5"}
+            {"type":"dispute","client":2,"tx":3}
+            {"type":"chargeback","client":2,"tx":3}
+            {"type":"refund","client":2,"tx":5,"amount":"1.0"}"#;
+        let mut from_csv = PaymentsEngine::new();
+        from_csv.process(csv.as_bytes()).unwrap();
+        let mut from_json = PaymentsEngine::new();
+        from_json.set_input_format(InputFormat::Json);
+        from_json.process(json.as_bytes()).unwrap();
+
+        assert_eq!(
+            from_json.clients(OutputOrder::ClientId),
+            from_csv.clients(OutputOrder::ClientId)
+        );
+        assert_eq!(from_json.client(1).unwrap().available(), dec!(1.6234));
+        assert!(from_json.client(2).unwrap().locked());
+        assert_eq!(from_json.valid_records(), 5);
+        assert_eq!(from_json.invalid_records(), 1);
+    }
+
+    #[test]
+    fn pipe_delimited_and_fixed_width_lines_match_csv() {
+        let csv = "type, client, tx, amount, currency\n\
+                   deposit, 1, 2, 2.12345, EUR\n\
+                   deposit, 2, 3, 3.0\n\
+                   withdrawal, 1, 4, 0.5, EUR\n\
+                   dispute, 2, 3\n\
+                   chargeback, 2, 3\n";
+        let piped = "type|client|tx|amount|currency\n\
+                     deposit|1|2|2.12345|EUR\n\
+                     deposit|2|3|3.0\n\
+                     withdrawal|1|4|0.5|EUR\n\
+                     dispute|2|3\n\
+                     chargeback|2|3\n";
+        let fixed = "TYPE        CLIENT    TX    AMOUNT CCY\n\
+                     deposit          1     2   2.12345EUR\n\
+                     deposit          2     3       3.0\n\
+                     \n\
+                     withdrawal       1     4       0.5EUR\n\
+                     dispute          2     3\n\
+                     chargeback       2     3\n\
+                     refund           2     5       1.0\n";
+        let mut from_csv = PaymentsEngine::new();
+        from_csv.process(csv.as_bytes()).unwrap();
+        let mut from_piped = PaymentsEngine::new();
+        from_piped.set_delimiter(b'|');
+        from_piped.process(piped.as_bytes()).unwrap();
+        let mut from_fixed = PaymentsEngine::new();
+        from_fixed.set_input_format(
+            "fixed-width:type=12,client=6,_=2,tx=4,amount=10,currency=3"
+                .parse()
+                .unwrap(),
+        );
+        from_fixed.process(fixed.as_bytes()).unwrap();
+
+        let accounts = from_csv.clients(OutputOrder::ClientId);
+        assert_eq!(from_piped.clients(OutputOrder::ClientId), accounts);
+        assert_eq!(from_fixed.clients(OutputOrder::ClientId), accounts);
+        assert_eq!(
+            from_fixed.client(1).unwrap().balance(Some("EUR")).available,
+            dec!(1.6234)
+        );
+        assert!(from_fixed.client(2).unwrap().locked());
+        assert_eq!(from_fixed.valid_records(), 5);
+        assert_eq!(from_fixed.invalid_records(), 1);
+    }
+
+    #[test]
+    fn amounts_with_separators_and_symbols_parse_outside_the_plain_format() {
+        let mut engine = PaymentsEngine::new();
+        let mut amount = |format, value: &str| {
+            engine.set_amount_format(format);
+            engine
+                .transaction_from_record(StringRecord::from(vec!["deposit", "1", "1", value]))
+                .map(|row| row.amount)
+        };
+        assert_eq!(
+            amount(AmountFormat::En, "$1,234.56").unwrap(),
+            dec!(1234.56)
+        );
+        assert_eq!(amount(AmountFormat::En, " 25.00 $").unwrap(), dec!(25.00));
+        assert_eq!(amount(AmountFormat::En, "1234567").unwrap(), dec!(1234567));
+        assert_eq!(
+            amount(AmountFormat::Eu, "1.234,56 €").unwrap(),
+            dec!(1234.56)
+        );
+        assert_eq!(amount(AmountFormat::Eu, "£0,5").unwrap(), dec!(0.5));
+        assert!(matches!(
+            amount(AmountFormat::En, "-$5"),
+            Err(PaymentsError::NonPositiveAmount(1, _))
+        ));
+        for (format, value) in [
+            (AmountFormat::En, "1,5"),
+            (AmountFormat::En, "12,34,567"),
+            (AmountFormat::En, "1.234,56"),
+            (AmountFormat::Eu, "1,234.56"),
+            (AmountFormat::En, "USD 5"),
+            (AmountFormat::Plain, "$25.00"),
+            (AmountFormat::Plain, "1,234.56"),
+        ] {
+            assert!(
+                matches!(
+                    amount(format, value),
+                    Err(PaymentsError::ParseError {
+                        field: "amount",
+                        ..
+                    })
+                ),
+                "{:?} {}",
+                format,
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn mapped_columns_match_positional_ones() {
+        let positional = "type, client, tx, amount\n\
+                          deposit, 1, 9, 1.0\n\
+                          deposit, 1, 1, 5.0, USD\n\
+                          transfer, 1, 2, 2.0, 2, USD\n\
+                          withdrawal, 2, 3, 0.5, USD\n\
+                          dispute, 1, 1\n";
+        let mapped = "note,txn_id,cust,txn_type,ccy,value,payee\n\
+                      x,9,1,deposit,,1.0,\n\
+                      ,1,1,deposit,USD,5.0,\n\
+                      ,2,1,transfer,USD,2.0,2\n\
+                      ,3,2,withdrawal,USD,0.5,\n\
+                      ,1,1,dispute,,,\n";
+        let mut from_positional = PaymentsEngine::new();
+        from_positional.process(positional.as_bytes()).unwrap();
+        let mut from_mapped = PaymentsEngine::new();
+        from_mapped.set_column_map(
+            ColumnMap::from_toml(
+                "type = \"txn_type\"\nclient = \"cust\"\ntx = \"txn_id\"\n\
+                 amount = \"value\"\nto_client = \"payee\"\ncurrency = \"ccy\"\n",
+            )
+            .unwrap(),
+        );
+        from_mapped.process(mapped.as_bytes()).unwrap();
+
+        assert_eq!(
+            from_mapped.clients(OutputOrder::ClientId),
+            from_positional.clients(OutputOrder::ClientId)
+        );
+        assert_eq!(
+            from_mapped
+                .client(2)
+                .unwrap()
+                .balance(Some("USD"))
+                .available,
+            dec!(1.5)
+        );
+        assert_eq!(from_mapped.invalid_records(), 0);
+
+        let mut unmapped = PaymentsEngine::new();
+        unmapped.set_column_map(ColumnMap::default());
+        assert!(matches!(
+            unmapped.process(mapped.as_bytes()),
+            Err(PaymentsError::ColumnMap(_))
+        ));
+    }
+
+    #[test]
+    fn resubmitted_tx_is_a_duplicate_and_a_changed_one_conflicts() {
+        let mut engine = PaymentsEngine::new();
+        let mut outcome = |row: &[&str]| {
+            engine
+                .process_record(StringRecord::from(row.to_vec()))
+                .unwrap()
+        };
+        assert_eq!(outcome(&["deposit", "1", "1", "5.0"]), Outcome::Applied);
+        assert_eq!(
+            outcome(&["deposit", "1", "1", "5.00"]),
+            Outcome::DuplicateTx
+        );
+        assert_eq!(
+            outcome(&["deposit", "1", "1", "6.0"]),
+            Outcome::ConflictingDuplicate
+        );
+        assert_eq!(
+            outcome(&["withdrawal", "1", "1", "5.0"]),
+            Outcome::ConflictingDuplicate
+        );
+        assert_eq!(
+            outcome(&["deposit", "2", "1", "5.0"]),
+            Outcome::ConflictingDuplicate
+        );
+
+        assert_eq!(engine.duplicate_transactions(), &[1, 1, 1, 1]);
+        assert_eq!(engine.conflicting_duplicates(), &[1, 1, 1]);
+        assert_eq!(engine.skipped()[&SkipReason::DuplicateTransaction], 1);
+        assert_eq!(engine.skipped()[&SkipReason::ConflictingDuplicate], 3);
+    }
+
+    #[test]
+    fn invariant_checks_log_or_abort() {
+        let rows: &[&[&str]] = &[
+            &["deposit", "1", "1", "5.0"],
+            &["deposit", "1", "2", "5.0"],
+            &["withdrawal", "1", "3", "8.0"],
+            &["dispute", "1", "2"],
+        ];
+        let mut unchecked = PaymentsEngine::new();
+        unchecked.set_invariant_check(InvariantCheck::Log, false);
+        apply(&mut unchecked, rows);
+        assert_eq!(unchecked.invariant_violations(), 0);
+
+        let mut logged = PaymentsEngine::new();
+        logged.set_invariant_check(InvariantCheck::Log, true);
+        apply(&mut logged, rows);
+        assert_eq!(logged.invariant_violations(), 1);
+        assert_eq!(logged.client(1).unwrap().available(), dec!(-3.0000));
+
+        let mut aborted = PaymentsEngine::new();
+        aborted.set_invariant_check(InvariantCheck::Abort, true);
+        let mut results = rows
+            .iter()
+            .map(|row| aborted.process_record(StringRecord::from(row.to_vec())));
+        assert!(results.by_ref().take(3).all(|result| result.is_ok()));
+        let err = results.next().unwrap().unwrap_err();
+        assert!(matches!(
+            err,
+            PaymentsError::InvariantBroken {
+                client: 1,
+                tx: 2,
+                invariant: Invariant::AvailableNotNegative
+            }
+        ));
+    }
+
+    #[test]
+    fn parts_share_tx_ids_and_deferred_records() {
+        let first = "type, client, tx, amount\n\
+                     dispute, 1, 3\n\
+                     deposit, 1, 2, 1.0\n";
+        let second = "type, client, tx, amount\n\
+                      deposit, 1, 2, 5.0\n\
+                      deposit, 1, 3, 2.0\n";
+        let mut engine = PaymentsEngine::new();
+        engine.set_buffered(true);
+        engine.process_part(first.as_bytes()).unwrap();
+        engine.process_part(second.as_bytes()).unwrap();
+        engine.finish().unwrap();
+
+        assert_eq!(engine.duplicate_transactions(), &[2]);
+        let client = engine.client(1).unwrap();
+        assert_eq!(client.available(), dec!(1.0000));
+        assert_eq!(client.held(), dec!(2.0000));
+    }
+
+    #[test]
+    fn saved_state_resumes_the_run() {
+        let mut engine = PaymentsEngine::new();
+        apply(
+            &mut engine,
+            &[
+                &["deposit", "1", "1", "5.0"],
+                &["deposit", "2", "2", "3.0"],
+                &["dispute", "1", "1"],
+            ],
+        );
+        let mut saved = Vec::new();
+        engine.save_state(&mut saved).unwrap();
+
+        let mut resumed = PaymentsEngine::new();
+        resumed.load_state(saved.as_slice()).unwrap();
+        apply(
+            &mut resumed,
+            &[
+                &["deposit", "2", "1", "1.0"],
+                &["deposit", "3", "3", "1.0"],
+                &["resolve", "1", "1"],
+            ],
+        );
+
+        assert_eq!(resumed.duplicate_transactions(), &[1]);
+        assert_eq!(resumed.client(1).unwrap().available(), dec!(5.0000));
+        assert_eq!(resumed.client(1).unwrap().held(), dec!(0.0000));
+        assert_eq!(resumed.client(2).unwrap().total(), dec!(3.0000));
+        let order: Vec<ClientId> = resumed
+            .clients(OutputOrder::FirstSeen)
+            .iter()
+            .map(|c| c.id)
+            .collect();
+        assert_eq!(order, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn amounts_must_be_positive_and_within_the_maximum() {
+        let mut engine = PaymentsEngine::new();
+        engine.set_max_amount(Some(dec!(100)));
+        let parse = |engine: &PaymentsEngine, amount: &str| {
+            engine.transaction_from_record(StringRecord::from(vec!["deposit", "1", "1", amount]))
+        };
+        assert!(matches!(
+            parse(&engine, "-50.0"),
+            Err(PaymentsError::NonPositiveAmount(1, _))
+        ));
+        assert!(matches!(
+            parse(&engine, "0.00001"),
+            Err(PaymentsError::NonPositiveAmount(1, _))
+        ));
+        assert!(matches!(
+            parse(&engine, "100.0001"),
+            Err(PaymentsError::AmountOverMax(1, _, _))
+        ));
+        assert!(parse(&engine, "100").is_ok());
+
+        let withdrawal = Transaction {
+            id: 2,
+            transaction_type: TransactionType::Withdrawal,
+            client_id: 1,
+            amount: dec!(-5),
+            to_client_id: None,
+            reference: None,
+            currency: None,
+            timestamp: None,
+            dispute_state: DisputeState::Undisputed,
+        };
+        assert!(matches!(
+            engine.process_transaction(withdrawal),
+            Err(PaymentsError::NonPositiveAmount(2, _))
+        ));
+        assert_eq!(engine.valid_records(), 0);
+        assert!(engine.client(1).is_none());
+    }
+
+    #[test]
+    fn rounding_policies() {
+        use RoundingPolicy::*;
+        assert_eq!(parse_amount(HalfEven, "1.23456"), Some(dec!(1.2346)));
+        assert_eq!(parse_amount(HalfEven, "1.23445"), Some(dec!(1.2344)));
+        assert_eq!(parse_amount(HalfUp, "1.23456"), Some(dec!(1.2346)));
+        assert_eq!(parse_amount(HalfUp, "1.23445"), Some(dec!(1.2345)));
+        assert_eq!(parse_amount(Truncate, "1.23456"), Some(dec!(1.2345)));
+        assert_eq!(parse_amount(Reject, "1.23456"), None);
+        assert_eq!(parse_amount(Reject, "1.2345"), Some(dec!(1.2345)));
+        assert_eq!(parse_amount(Reject, "1.23450"), Some(dec!(1.2345)));
+    }
+
+    #[test]
+    fn precision_applies_to_input_and_output() {
+        let input = "type, client, tx, amount\n\
+                     deposit, 1, 2, 0.125\n\
+                     deposit, 2, 3, 0.123456789\n";
+        let rows = |precision: u32, rounding_policy: RoundingPolicy| {
+            let mut engine = PaymentsEngine::new();
+            engine.set_precision(precision);
+            engine.set_rounding_policy(rounding_policy);
+            engine.process(input.as_bytes()).unwrap();
+            engine
+                .account_rows(OutputOrder::ClientId)
+                .iter()
+                .map(|row| row.total.to_string())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(rows(2, RoundingPolicy::HalfEven), ["0.12", "0.12"]);
+        assert_eq!(rows(2, RoundingPolicy::HalfUp), ["0.13", "0.12"]);
+        assert_eq!(
+            rows(8, RoundingPolicy::Truncate),
+            ["0.12500000", "0.12345678"]
+        );
+        // Rounded down to nothing, which isn't a positive amount
+        assert!(rows(0, RoundingPolicy::HalfEven).is_empty());
+
+        let mut engine = PaymentsEngine::new();
+        engine.set_precision(8);
+        engine.set_rounding_policy(RoundingPolicy::Reject);
+        let record = StringRecord::from(vec!["deposit", "1", "1", "0.123456789"]);
+        assert!(matches!(
+            engine.transaction_from_record(record),
+            Err(PaymentsError::ExcessPrecision(_, 8))
+        ));
+    }
+}
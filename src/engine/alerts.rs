@@ -0,0 +1,334 @@
+use super::PaymentsEngine;
+use crate::{
+    AccountStore, ClientId, Currency, DisputePolicy, SkipReason, Transaction, TransactionStore,
+    TransactionType, TxId,
+};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::fmt;
+#[cfg(feature = "webhook")]
+use tracing::warn;
+
+/// Handed every alert as the engine raises it, see `AlertSink::Callback`
+pub type AlertHandler = Box<dyn FnMut(&Alert) + Send>;
+
+/// A client account risk monitoring should look at, raised as the transaction
+/// behind it is applied. Serialized as json tagged with its kind, e.g.
+/// `{"alert":"account-locked",...}`.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+#[serde(tag = "alert", rename_all = "kebab-case")]
+pub enum Alert {
+    /// A withdrawal, transfer, negative adjustment or dispute left the client's
+    /// available funds below zero, or would have if the tx hadn't been rejected for
+    /// insufficient funds
+    NegativeAvailable {
+        client: ClientId,
+        tx: TxId,
+        available: Decimal,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        currency: Option<Currency>,
+        rejected: bool,
+    },
+    /// A dispute of the tx left the client's held funds over the threshold
+    HeldOverThreshold {
+        client: ClientId,
+        tx: TxId,
+        held: Decimal,
+        threshold: Decimal,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        currency: Option<Currency>,
+    },
+    /// A chargeback of the tx locked the account
+    AccountLocked { client: ClientId, tx: TxId },
+}
+
+impl fmt::Display for Alert {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let of = |currency: &Option<Currency>| match currency {
+            Some(currency) => format!(" in {}", currency),
+            None => String::new(),
+        };
+        match self {
+            Alert::NegativeAvailable {
+                client,
+                tx,
+                available,
+                currency,
+                rejected: false,
+            } => write!(
+                f,
+                "Client {}'s available funds{} went to {} with tx {}",
+                client,
+                of(currency),
+                available,
+                tx
+            ),
+            Alert::NegativeAvailable {
+                client,
+                tx,
+                available,
+                currency,
+                rejected: true,
+            } => write!(
+                f,
+                "Client {}'s available funds{} would have gone to {} with tx {}, which was rejected",
+                client,
+                of(currency),
+                available,
+                tx
+            ),
+            Alert::HeldOverThreshold {
+                client,
+                tx,
+                held,
+                threshold,
+                currency,
+            } => write!(
+                f,
+                "Client {}'s held funds{} went to {} with tx {}, over {}",
+                client,
+                of(currency),
+                held,
+                tx,
+                threshold
+            ),
+            Alert::AccountLocked { client, tx } => {
+                write!(f, "Client {} was locked by a chargeback of tx {}", client, tx)
+            }
+        }
+    }
+}
+
+/// Where alerts go
+pub enum AlertSink {
+    /// A line per alert on stderr
+    Stderr,
+    /// Each alert posted to the url as json, with the webhook feature. A failed post
+    /// is logged rather than failing the run.
+    #[cfg(feature = "webhook")]
+    Webhook(String),
+    Callback(AlertHandler),
+}
+
+/// What the engine raises alerts on and where they go, see `set_alerts`
+pub struct Alerts {
+    pub sink: AlertSink,
+    /// Alert when a client's available funds go below zero
+    pub negative_available: bool,
+    /// Alert when a dispute takes a client's held funds over this, if set
+    pub held_over: Option<Decimal>,
+    /// Alert when a chargeback locks an account
+    pub locked: bool,
+}
+
+impl Alerts {
+    /// Alerts on negative available funds and locked accounts, but not on held funds
+    /// until there's a threshold
+    pub fn new(sink: AlertSink) -> Alerts {
+        Alerts {
+            sink,
+            negative_available: true,
+            held_over: None,
+            locked: true,
+        }
+    }
+
+    fn raise(&mut self, alert: Alert) {
+        match &mut self.sink {
+            AlertSink::Stderr => eprintln!("Alert: {}", alert),
+            #[cfg(feature = "webhook")]
+            AlertSink::Webhook(url) => {
+                let posted = serde_json::to_string(&alert)
+                    .map_err(|err| err.to_string())
+                    .and_then(|body| {
+                        ureq::post(url.as_str())
+                            .header("Content-Type", "application/json")
+                            .send(body)
+                            .map_err(|err| err.to_string())
+                    });
+                if let Err(err) = posted {
+                    warn!("Failed to post alert to {} {}", url, err);
+                }
+            }
+            AlertSink::Callback(handler) => handler(&alert),
+        }
+    }
+}
+
+impl<T: TransactionStore, A: AccountStore, P: DisputePolicy> PaymentsEngine<T, A, P> {
+    /// Raises alerts on risky accounts from here on, in place of any set before. Not
+    /// supported with `process_parallel`.
+    pub fn set_alerts(&mut self, alerts: Option<Alerts>) {
+        self.alerts = alerts;
+    }
+
+    /// Alerts on where a deposit, withdrawal, transfer or adjustment left, or would
+    /// have left, the funds of the row's client
+    pub(super) fn alert_funds(&mut self, row: &Transaction, outcome: Result<(), SkipReason>) {
+        use TransactionType::*;
+        if !self.alerts.as_ref().is_some_and(|a| a.negative_available) {
+            return;
+        }
+        let debit = match row.transaction_type {
+            Withdrawal | Transfer => true,
+            Adjustment => row.amount < Decimal::ZERO,
+            _ => false,
+        };
+        let currency = row.currency.as_deref();
+        let Some(balance) = self
+            .clients
+            .get(&row.client_id)
+            .map(|client| client.balance(currency))
+        else {
+            return;
+        };
+        let (available, rejected) = match outcome {
+            Ok(()) if debit => (balance.available, false),
+            Err(SkipReason::InsufficientFunds) => (balance.available - row.amount, true),
+            _ => return,
+        };
+        if available < Decimal::ZERO {
+            self.raise(Alert::NegativeAvailable {
+                client: row.client_id,
+                tx: row.id,
+                available,
+                currency: row.currency.clone(),
+                rejected,
+            });
+        }
+    }
+
+    /// Alerts on what an applied dispute or chargeback of `t` did to `client`
+    pub(super) fn alert_settlement(
+        &mut self,
+        transaction_type: &TransactionType,
+        client: ClientId,
+        t: &Transaction,
+    ) {
+        let Some(alerts) = &self.alerts else {
+            return;
+        };
+        let (negative_available, held_over, locked) =
+            (alerts.negative_available, alerts.held_over, alerts.locked);
+        let Some(balance) = self
+            .clients
+            .get(&client)
+            .map(|c| c.balance(t.currency.as_deref()))
+        else {
+            return;
+        };
+        let tx = t.id;
+        match transaction_type {
+            TransactionType::Dispute => {
+                if negative_available && balance.available < Decimal::ZERO {
+                    self.raise(Alert::NegativeAvailable {
+                        client,
+                        tx,
+                        available: balance.available,
+                        currency: t.currency.clone(),
+                        rejected: false,
+                    });
+                }
+                if let Some(threshold) = held_over.filter(|threshold| balance.held > *threshold) {
+                    self.raise(Alert::HeldOverThreshold {
+                        client,
+                        tx,
+                        held: balance.held,
+                        threshold,
+                        currency: t.currency.clone(),
+                    });
+                }
+            }
+            TransactionType::Chargeback if locked => {
+                self.raise(Alert::AccountLocked { client, tx });
+            }
+            _ => (),
+        }
+    }
+
+    fn raise(&mut self, alert: Alert) {
+        if let Some(alerts) = &mut self.alerts {
+            alerts.raise(alert);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::OverdraftPolicy;
+    use rust_decimal_macros::dec;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn alerts_on_negative_available_held_funds_and_locks() {
+        let raised = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&raised);
+        let mut engine = PaymentsEngine::new();
+        engine.set_client_overdraft_policy(2, OverdraftPolicy::AllowNegative);
+        engine.set_alerts(Some(Alerts {
+            held_over: Some(dec!(4)),
+            ..Alerts::new(AlertSink::Callback(Box::new(move |alert| {
+                seen.lock().unwrap().push(alert.clone())
+            })))
+        }));
+        engine
+            .process_part(
+                "type, client, tx, amount\n\
+                 deposit, 1, 1, 3.0\n\
+                 deposit, 1, 2, 5.0\n\
+                 withdrawal, 1, 3, 6.0\n\
+                 withdrawal, 1, 4, 9.0\n\
+                 dispute, 1, 1\n\
+                 dispute, 1, 2\n\
+                 chargeback, 1, 2\n\
+                 withdrawal, 2, 5, 1.0\n\
+                 deposit, 2, 6, 0.5\n"
+                    .as_bytes(),
+            )
+            .unwrap();
+
+        use Alert::*;
+        assert_eq!(
+            *raised.lock().unwrap(),
+            [
+                NegativeAvailable {
+                    client: 1,
+                    tx: 4,
+                    available: dec!(-7.0000),
+                    currency: None,
+                    rejected: true,
+                },
+                NegativeAvailable {
+                    client: 1,
+                    tx: 1,
+                    available: dec!(-1.0000),
+                    currency: None,
+                    rejected: false,
+                },
+                NegativeAvailable {
+                    client: 1,
+                    tx: 2,
+                    available: dec!(-6.0000),
+                    currency: None,
+                    rejected: false,
+                },
+                HeldOverThreshold {
+                    client: 1,
+                    tx: 2,
+                    held: dec!(8.0000),
+                    threshold: dec!(4),
+                    currency: None,
+                },
+                AccountLocked { client: 1, tx: 2 },
+                NegativeAvailable {
+                    client: 2,
+                    tx: 5,
+                    available: dec!(-1.0000),
+                    currency: None,
+                    rejected: false,
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,385 @@
+use super::PaymentsEngine;
+use crate::metrics::Metrics;
+use crate::{
+    AccountStore, Client, ClientId, Currency, DisputePolicy, Outcome, PaymentsError,
+    ProcessingMode, SkipReason, Transaction, TransactionStore, TransactionType, TxKey,
+};
+use rust_decimal::Decimal;
+use std::collections::{BTreeMap, HashMap};
+use tracing::debug;
+
+/// What `apply_batch` did with a batch of transactions
+#[derive(Debug)]
+pub struct BatchOutcome {
+    /// What was done with each transaction in turn, up to and including the first
+    /// that failed. Those before it were applied and then rolled back.
+    pub results: Vec<Result<Outcome, PaymentsError>>,
+    /// Whether a transaction failed and the batch was rolled back
+    pub rolled_back: bool,
+}
+
+impl BatchOutcome {
+    /// Whether every transaction in the batch was applied
+    pub fn is_applied(&self) -> bool {
+        !self.rolled_back
+    }
+
+    /// Index in the batch of the transaction that failed, if one did
+    pub fn failed_at(&self) -> Option<usize> {
+        self.rolled_back.then(|| self.results.len() - 1)
+    }
+}
+
+/// Times a transaction is applied again after conflicting with another instance's
+#[cfg(feature = "redis")]
+const CONFLICT_RETRIES: usize = 3;
+
+/// The engine as it was before a batch, or a transaction applied with a shared
+/// store, as far as they changed it so far
+struct Savepoint {
+    /// Clients the batch touched, `None` for those the engine didn't hold yet
+    clients: HashMap<ClientId, Option<Client>>,
+    /// Kept transactions the batch touched by tx id, `None` for those not kept
+    transactions: HashMap<TxKey, Option<Transaction>>,
+    /// Histories of the clients the batch touched, when recording history
+    history: HashMap<ClientId, Option<Vec<Transaction>>>,
+    /// Fees the clients the batch touched were charged, by currency
+    fees: HashMap<(ClientId, Option<Currency>), Option<Decimal>>,
+    /// When the clients the batch touched last changed, when tracking changes
+    last_changed: HashMap<ClientId, Option<u64>>,
+    change_stamp: u64,
+    metrics: Option<Metrics>,
+    first_seen: usize,
+    duplicate_transactions: usize,
+    conflicting_duplicates: usize,
+    rejected_withdrawals: usize,
+    ownership_mismatches: usize,
+    valid_records: usize,
+    invariant_violations: usize,
+    /// Issues recorded, when recording them
+    issues: Option<usize>,
+    transaction_counts: BTreeMap<TransactionType, usize>,
+    skipped: BTreeMap<SkipReason, usize>,
+}
+
+impl<T: TransactionStore, A: AccountStore, P: DisputePolicy> PaymentsEngine<T, A, P> {
+    /// Applies the transactions in order as `process_transaction` would, but all
+    /// or none of them: at the first that is skipped or errors, those before it are
+    /// rolled back and the rest aren't tried. Overdrawn counts as applied.
+    ///
+    /// Rolling back restores the accounts, the kept transactions, the counts and
+    /// metrics, the issues recorded and which clients changed. The audit trail,
+    /// ledger export, rules, event handlers, alerts, buffered disputes,
+    /// reconciliation and per client or window eviction can't be undone, so they
+    /// aren't supported here.
+    /// Errors are left for when the batch can't be tried or rolled back, such as a
+    /// store failing.
+    pub fn apply_batch(
+        &mut self,
+        transactions: &[Transaction],
+    ) -> Result<BatchOutcome, PaymentsError> {
+        self.check_supported(ProcessingMode::Batch)?;
+        let mut savepoint = self.savepoint();
+        let mut results = Vec::with_capacity(transactions.len());
+        for transaction in transactions {
+            self.save(&mut savepoint, transaction)?;
+            let result = self.process_transaction(transaction.clone());
+            let failed = !matches!(result, Ok(Outcome::Applied | Outcome::Overdrawn));
+            results.push(result);
+            if failed {
+                self.roll_back(savepoint)?;
+                debug!("Rolled back batch");
+                return Ok(BatchOutcome {
+                    results,
+                    rolled_back: true,
+                });
+            }
+        }
+        Ok(BatchOutcome {
+            results,
+            rolled_back: false,
+        })
+    }
+
+    /// Applies a transaction with a store shared between instances, which only
+    /// commits it if no other instance changed what it read since. On a conflict
+    /// the engine is rolled back as for a batch, and the transaction applied again
+    /// to the accounts and transactions read afresh, up to `CONFLICT_RETRIES` times.
+    #[cfg(feature = "redis")]
+    pub(super) fn apply_retrying(&mut self, row: Transaction) -> Result<Outcome, PaymentsError> {
+        self.check_supported(ProcessingMode::Shared)?;
+        let mut retries = 0;
+        loop {
+            let mut savepoint = self.savepoint();
+            self.save(&mut savepoint, &row)?;
+            match self.apply_transaction(row.clone()) {
+                Err(err) if err.is_store_conflict() && retries < CONFLICT_RETRIES => {
+                    debug!(tx = row.id, "Applying again after a conflict: {}", err);
+                    retries += 1;
+                    // The stores dropped the transaction's writes, so there's nothing
+                    // of it kept to restore, and the clients it read are out of date
+                    savepoint.transactions.clear();
+                    for (id, client) in &mut savepoint.clients {
+                        if client.is_some() {
+                            if let Some(fresh) = self.account_store.get_client(*id)? {
+                                *client = Some(fresh);
+                            }
+                        }
+                    }
+                    self.roll_back(savepoint)?;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// A savepoint of the engine as it is, before anything is saved in it
+    fn savepoint(&self) -> Savepoint {
+        Savepoint {
+            clients: HashMap::new(),
+            transactions: HashMap::new(),
+            history: HashMap::new(),
+            fees: HashMap::new(),
+            last_changed: HashMap::new(),
+            change_stamp: self.change_stamp(),
+            metrics: self.metrics.clone(),
+            first_seen: self.first_seen.len(),
+            duplicate_transactions: self.duplicate_transactions.len(),
+            conflicting_duplicates: self.conflicting_duplicates.len(),
+            rejected_withdrawals: self.rejected_withdrawals.len(),
+            ownership_mismatches: self.ownership_mismatches.len(),
+            valid_records: self.valid_records,
+            invariant_violations: self.invariant_violations,
+            issues: self.issues.as_ref().map(Vec::len),
+            transaction_counts: self.transaction_counts.clone(),
+            skipped: self.skipped.clone(),
+        }
+    }
+
+    /// Adds what applying `transaction` may change to the savepoint, unless an
+    /// earlier transaction of the batch already did
+    fn save(
+        &mut self,
+        savepoint: &mut Savepoint,
+        transaction: &Transaction,
+    ) -> Result<(), PaymentsError> {
+        let key = self.key_of(transaction);
+        let kept = match savepoint.transactions.get(&key) {
+            Some(kept) => kept.clone(),
+            None => {
+                let kept = self.transaction_store.get_tx(key)?;
+                savepoint.transactions.insert(key, kept.clone());
+                kept
+            }
+        };
+        let key = (transaction.client_id, transaction.currency.clone());
+        savepoint
+            .fees
+            .entry(key)
+            .or_insert_with_key(|key| self.fees.get(key).copied());
+        let mut client_ids = vec![transaction.client_id];
+        client_ids.extend(transaction.to_client_id);
+        if let Some(kept) = kept {
+            client_ids.push(kept.client_id);
+            client_ids.extend(kept.to_client_id);
+        }
+        for id in client_ids {
+            // A client read from the account store during the batch is dropped again
+            // on rollback, to be read afresh
+            savepoint
+                .clients
+                .entry(id)
+                .or_insert_with(|| self.clients.get(&id).cloned());
+            if self.record_history {
+                savepoint
+                    .history
+                    .entry(id)
+                    .or_insert_with(|| self.history.get(&id).cloned());
+            }
+            savepoint
+                .last_changed
+                .entry(id)
+                .or_insert_with(|| self.last_changed(id));
+        }
+        Ok(())
+    }
+
+    fn roll_back(&mut self, savepoint: Savepoint) -> Result<(), PaymentsError> {
+        for (id, client) in savepoint.clients {
+            match client {
+                Some(client) => self.clients.insert(id, client),
+                None => self.clients.remove(&id),
+            };
+        }
+        for (key, transaction) in savepoint.transactions {
+            match transaction {
+                Some(transaction) => self.transaction_store.put_tx(key, transaction)?,
+                None => {
+                    self.transaction_store.remove_tx(key)?;
+                }
+            }
+        }
+        for (key, fee) in savepoint.fees {
+            match fee {
+                Some(fee) => self.fees.insert(key, fee),
+                None => self.fees.remove(&key),
+            };
+        }
+        for (id, history) in savepoint.history {
+            match history {
+                Some(history) => self.history.insert(id, history),
+                None => self.history.remove(&id),
+            };
+        }
+        self.restore_changes(savepoint.change_stamp, savepoint.last_changed);
+        self.metrics = savepoint.metrics;
+        self.first_seen.truncate(savepoint.first_seen);
+        self.duplicate_transactions
+            .truncate(savepoint.duplicate_transactions);
+        self.conflicting_duplicates
+            .truncate(savepoint.conflicting_duplicates);
+        self.rejected_withdrawals
+            .truncate(savepoint.rejected_withdrawals);
+        self.ownership_mismatches
+            .truncate(savepoint.ownership_mismatches);
+        self.valid_records = savepoint.valid_records;
+        self.invariant_violations = savepoint.invariant_violations;
+        if let (Some(issues), Some(len)) = (self.issues.as_mut(), savepoint.issues) {
+            issues.truncate(len);
+        }
+        self.transaction_counts = savepoint.transaction_counts;
+        self.skipped = savepoint.skipped;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DisputeState, OutputOrder};
+    use csv::StringRecord;
+    use rust_decimal_macros::dec;
+
+    fn batch(engine: &PaymentsEngine, rows: &[&[&str]]) -> Vec<Transaction> {
+        rows.iter()
+            .map(|row| {
+                engine
+                    .transaction_from_record(StringRecord::from(row.to_vec()))
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn applies_all_or_none() {
+        let mut engine = PaymentsEngine::new();
+        engine.set_record_history(true);
+        let first = batch(
+            &engine,
+            &[&["deposit", "1", "1", "5.0"], &["deposit", "2", "2", "2.0"]],
+        );
+        let outcome = engine.apply_batch(&first).unwrap();
+        assert!(outcome.is_applied());
+        assert_eq!(outcome.results.len(), 2);
+        let accounts: Vec<Client> = engine
+            .clients(OutputOrder::FirstSeen)
+            .into_iter()
+            .cloned()
+            .collect();
+
+        let second = batch(
+            &engine,
+            &[
+                &["deposit", "1", "3", "1.0"],
+                &["deposit", "3", "4", "1.0"],
+                &["dispute", "1", "1"],
+                &["withdrawal", "2", "5", "9.0"],
+                &["deposit", "2", "6", "1.0"],
+            ],
+        );
+        let outcome = engine.apply_batch(&second).unwrap();
+        assert!(!outcome.is_applied());
+        assert_eq!(outcome.failed_at(), Some(3));
+        assert!(matches!(outcome.results[3], Ok(Outcome::InsufficientFunds)));
+
+        assert_eq!(
+            engine.clients(OutputOrder::FirstSeen),
+            accounts.iter().collect::<Vec<_>>()
+        );
+        assert!(engine.client(3).is_none());
+        assert_eq!(engine.valid_records(), 2);
+        assert!(engine.skipped().is_empty());
+        assert!(engine.rejected_withdrawals().is_empty());
+        assert!(engine.transaction_store.get_tx(3.into()).unwrap().is_none());
+        let tx = engine.transaction_store.get_tx(1.into()).unwrap().unwrap();
+        assert_eq!(tx.dispute_state, DisputeState::Undisputed);
+        assert_eq!(engine.history(1).count(), 1);
+        assert_eq!(engine.client(1).unwrap().available(), dec!(5.0000));
+    }
+
+    #[test]
+    fn rolls_back_the_issues_recorded() {
+        let mut engine = PaymentsEngine::new();
+        engine.set_record_issues(true);
+        let rows = batch(
+            &engine,
+            &[&["deposit", "1", "1", "5.0"], &["deposit", "2", "1", "1.0"]],
+        );
+        let outcome = engine.apply_batch(&rows).unwrap();
+        assert_eq!(outcome.failed_at(), Some(1));
+        assert!(engine.issues().is_empty());
+    }
+
+    #[test]
+    fn rolls_back_metrics_and_changes() {
+        let mut engine = PaymentsEngine::new();
+        engine.enable_metrics();
+        engine.set_track_changes(true);
+        let first = batch(&engine, &[&["deposit", "1", "1", "5.0"]]);
+        engine.apply_batch(&first).unwrap();
+        let metrics = engine.metrics.clone();
+        let stamp = engine.change_stamp();
+
+        let second = batch(
+            &engine,
+            &[
+                &["deposit", "2", "2", "1.0"],
+                &["withdrawal", "1", "3", "1.0"],
+                &["withdrawal", "1", "4", "9.0"],
+            ],
+        );
+        assert!(!engine.apply_batch(&second).unwrap().is_applied());
+        assert_eq!(engine.metrics, metrics);
+        assert_eq!(engine.change_stamp(), stamp);
+        assert_eq!(engine.changed_since(0), [1]);
+    }
+
+    #[test]
+    fn errors_roll_back_too() {
+        let mut engine = PaymentsEngine::new();
+        let mut rows = batch(
+            &engine,
+            &[&["deposit", "1", "1", "5.0"], &["deposit", "1", "2", "1.0"]],
+        );
+        rows[1].amount = dec!(-1);
+        let outcome = engine.apply_batch(&rows).unwrap();
+        assert!(matches!(
+            outcome.results[..],
+            [
+                Ok(Outcome::Applied),
+                Err(PaymentsError::NonPositiveAmount(2, _))
+            ]
+        ));
+        assert!(engine.client(1).is_none());
+
+        engine.on_event(|_| ());
+        let err = engine.apply_batch(&rows).unwrap_err();
+        assert!(matches!(
+            err,
+            PaymentsError::Unsupported {
+                mode: ProcessingMode::Batch,
+                feature: "Event handlers"
+            }
+        ));
+    }
+}
@@ -0,0 +1,112 @@
+use super::PaymentsEngine;
+use crate::{AccountStore, ClientId, DisputePolicy, TransactionStore};
+use std::collections::HashMap;
+
+/// When each client last changed, counted in transactions applied since tracking
+/// started, so continuous runs can write out only the accounts that moved
+#[derive(Debug, Default)]
+pub(super) struct Changes {
+    stamp: u64,
+    last_changed: HashMap<ClientId, u64>,
+}
+
+impl<T: TransactionStore, A: AccountStore, P: DisputePolicy> PaymentsEngine<T, A, P> {
+    /// Keeps track of which clients each applied transaction changes, from here on,
+    /// for `changed_since`. Off by default. Not supported with `process_parallel`.
+    pub fn set_track_changes(&mut self, track: bool) {
+        if track != self.changes.is_some() {
+            self.changes = track.then(Changes::default);
+        }
+    }
+
+    /// How far the engine has got, to hand to `changed_since` the next time round.
+    /// Zero unless tracking changes.
+    pub fn change_stamp(&self) -> u64 {
+        self.changes.as_ref().map_or(0, |changes| changes.stamp)
+    }
+
+    /// Clients changed since `stamp` was taken, such as since the accounts were last
+    /// written out, by client id. Empty unless tracking changes.
+    pub fn changed_since(&self, stamp: u64) -> Vec<ClientId> {
+        let Some(changes) = &self.changes else {
+            return Vec::new();
+        };
+        let mut changed: Vec<ClientId> = changes
+            .last_changed
+            .iter()
+            .filter(|(_, changed)| **changed > stamp)
+            .map(|(client, _)| *client)
+            .collect();
+        changed.sort_unstable();
+        changed
+    }
+
+    /// When `client` last changed, for a batch's savepoint
+    pub(super) fn last_changed(&self, client: ClientId) -> Option<u64> {
+        self.changes.as_ref()?.last_changed.get(&client).copied()
+    }
+
+    /// Puts the stamp and when the clients last changed back as they were before a
+    /// batch that's rolled back
+    pub(super) fn restore_changes(
+        &mut self,
+        stamp: u64,
+        last_changed: HashMap<ClientId, Option<u64>>,
+    ) {
+        let Some(changes) = &mut self.changes else {
+            return;
+        };
+        changes.stamp = stamp;
+        for (client, changed) in last_changed {
+            match changed {
+                Some(changed) => changes.last_changed.insert(client, changed),
+                None => changes.last_changed.remove(&client),
+            };
+        }
+    }
+
+    pub(super) fn mark_changed(&mut self, clients: &[ClientId]) {
+        if let Some(changes) = &mut self.changes {
+            changes.stamp += 1;
+            for client in clients {
+                changes.last_changed.insert(*client, changes.stamp);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::PaymentsEngine;
+
+    #[test]
+    fn tracks_clients_changed_since_a_stamp() {
+        let mut engine = PaymentsEngine::new();
+        engine.set_track_changes(true);
+        engine
+            .process_part(
+                "type, client, tx, amount\n\
+                 deposit, 1, 1, 2.0\n\
+                 deposit, 2, 2, 2.0\n"
+                    .as_bytes(),
+            )
+            .unwrap();
+        assert_eq!(engine.changed_since(0), [1, 2]);
+        let stamp = engine.change_stamp();
+        assert!(engine.changed_since(stamp).is_empty());
+
+        engine
+            .process_part(
+                "type, client, tx, amount\n\
+                 transfer, 2, 3, 1.0, 3\n\
+                 withdrawal, 1, 4, 5.0\n\
+                 dispute, 4, 2\n"
+                    .as_bytes(),
+            )
+            .unwrap();
+        // Only applied transactions change a client
+        assert_eq!(engine.changed_since(stamp), [2, 3]);
+        engine.unlock_client(2, false).unwrap();
+        assert_eq!(engine.changed_since(engine.change_stamp() - 1), [2]);
+    }
+}
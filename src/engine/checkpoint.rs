@@ -0,0 +1,245 @@
+use super::{PaymentsEngine, SavedState, Tally};
+use crate::{
+    AccountStore, ClientId, DisputePolicy, PaymentsError, SkipReason, Transaction,
+    TransactionStore, TransactionType,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::io;
+use tracing::debug;
+
+/// Version of the checkpoint format, bumped whenever it changes so a checkpoint
+/// from another version is refused rather than misread. 3 counts a csv part's first
+/// record, which was dropped along with the header before.
+pub const CHECKPOINT_VERSION: u32 = 3;
+
+/// Handed every checkpoint as it is taken, e.g. to write it to a file
+pub type CheckpointSink = Box<dyn FnMut(&Checkpoint) -> Result<(), PaymentsError> + Send>;
+
+/// How far into the input the engine got, counted in records so stdin can be
+/// resumed as well as files
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub(crate) struct InputPosition {
+    /// Parts of the input done with, see `process_part`
+    pub(crate) part: usize,
+    /// Records read of the current part, whether they parsed or not
+    pub(crate) records: u64,
+}
+
+/// The engine state part way through the input, along with how far it got, for
+/// `PaymentsEngine::resume` to carry on from after a crash
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Checkpoint {
+    version: u32,
+    position: InputPosition,
+    state: SavedState,
+    /// Records held back in buffered mode
+    deferred: Vec<Transaction>,
+    valid_records: usize,
+    invalid_records: usize,
+    transaction_counts: BTreeMap<TransactionType, usize>,
+    /// As pairs, since a rule's reason can't be a json key
+    skipped: Vec<(SkipReason, usize)>,
+    rule_tallies: BTreeMap<ClientId, Tally>,
+}
+
+impl Checkpoint {
+    pub fn write<W: io::Write>(&self, writer: W) -> Result<(), PaymentsError> {
+        serde_json::to_writer(writer, self)?;
+        Ok(())
+    }
+
+    /// Reads a checkpoint written by `write`, refusing one of another version
+    pub fn read<R: io::Read>(reader: R) -> Result<Checkpoint, PaymentsError> {
+        let value: serde_json::Value = serde_json::from_reader(reader)?;
+        let version = value["version"].as_u64().unwrap_or(0);
+        if version != u64::from(CHECKPOINT_VERSION) {
+            return Err(PaymentsError::CheckpointVersion(version));
+        }
+        Ok(serde_json::from_value(value)?)
+    }
+}
+
+impl<T: TransactionStore, A: AccountStore, P: DisputePolicy> PaymentsEngine<T, A, P> {
+    /// Hands a checkpoint to `sink` every `every` records read by `process` or
+    /// `process_part`. Not supported with `process_parallel`.
+    pub fn set_checkpoints(&mut self, every: usize, sink: CheckpointSink) {
+        self.checkpoint_every = every.max(1);
+        self.checkpoint_sink = Some(sink);
+    }
+
+    /// The engine as it stands, with how far into the input it got
+    pub fn checkpoint(&self) -> Result<Checkpoint, PaymentsError> {
+        Ok(Checkpoint {
+            version: CHECKPOINT_VERSION,
+            position: self.position,
+            state: self.saved_state()?,
+            deferred: self.deferred.clone(),
+            valid_records: self.valid_records,
+            invalid_records: self.invalid_records,
+            transaction_counts: self.transaction_counts.clone(),
+            skipped: self.skipped.clone().into_iter().collect(),
+            rule_tallies: self.rule_tallies.clone().into_iter().collect(),
+        })
+    }
+
+    /// Restores the engine from the checkpoint, in place of loading a saved state.
+    /// The same input has to be processed again: the parts and records the
+    /// checkpoint already covers are read past without being applied.
+    pub fn resume(&mut self, checkpoint: Checkpoint) -> Result<(), PaymentsError> {
+        self.restore_state(checkpoint.state)?;
+        self.deferred = checkpoint.deferred;
+        self.valid_records = checkpoint.valid_records;
+        self.invalid_records = checkpoint.invalid_records;
+        self.transaction_counts = checkpoint.transaction_counts;
+        self.skipped = checkpoint.skipped.into_iter().collect();
+        self.rule_tallies = checkpoint.rule_tallies.into_iter().collect();
+        self.resume_at = Some(checkpoint.position);
+        debug!(
+            "Resuming at record {} of input part {}",
+            checkpoint.position.records, checkpoint.position.part
+        );
+        Ok(())
+    }
+
+    /// Records of the part about to be read that the checkpoint resumed from already
+    /// covers, or `None` when the whole part is, which is then counted as read
+    pub(super) fn records_to_skip(&mut self) -> Option<u64> {
+        match self.resume_at {
+            Some(at) if at.part > self.position.part => {
+                self.position.part += 1;
+                None
+            }
+            Some(at) => {
+                self.resume_at = None;
+                Some(at.records)
+            }
+            None => Some(0),
+        }
+    }
+
+    /// Hands the sink a checkpoint once enough records were read since the last one
+    pub(super) fn checkpoint_if_due(&mut self) -> Result<(), PaymentsError> {
+        self.since_checkpoint += 1;
+        if self.checkpoint_sink.is_none() || self.since_checkpoint < self.checkpoint_every {
+            return Ok(());
+        }
+        self.since_checkpoint = 0;
+        let checkpoint = self.checkpoint()?;
+        if let Some(sink) = self.checkpoint_sink.as_mut() {
+            sink(&checkpoint)?;
+        }
+        debug!("Checkpoint at record {}", checkpoint.position.records);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{EngineEvent, OutputOrder};
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    const FIRST: &str = "type, client, tx, amount\n\
+                         deposit, 1, 2, 5.0\n\
+                         deposit, 2, 3, 3.0\n\
+                         dispute, 1, 2\n";
+    const SECOND: &str = "type, client, tx, amount\n\
+                          withdrawal, 2, 4, 1.0\n\
+                          malformed, 2, 5, 1.0\n\
+                          deposit, 2, 3, 3.0\n\
+                          chargeback, 1, 2\n";
+
+    /// Runs both parts with a checkpoint every record, keeping the checkpoints written
+    fn checkpointed(buffered: bool) -> (PaymentsEngine, Vec<Vec<u8>>) {
+        let written = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&written);
+        let mut engine = PaymentsEngine::new();
+        engine.set_buffered(buffered);
+        engine.set_checkpoints(
+            1,
+            Box::new(move |checkpoint| {
+                let mut bytes = Vec::new();
+                checkpoint.write(&mut bytes)?;
+                sink.lock().unwrap().push(bytes);
+                Ok(())
+            }),
+        );
+        engine.process_part(FIRST.as_bytes()).unwrap();
+        engine.process_part(SECOND.as_bytes()).unwrap();
+        engine.finish().unwrap();
+        let written = written.lock().unwrap().clone();
+        (engine, written)
+    }
+
+    #[test]
+    fn resuming_from_any_checkpoint_matches_the_uninterrupted_run() {
+        for buffered in [false, true] {
+            let (uninterrupted, checkpoints) = checkpointed(buffered);
+            assert_eq!(checkpoints.len(), 7);
+            for bytes in checkpoints {
+                let mut resumed = PaymentsEngine::new();
+                resumed.set_buffered(buffered);
+                resumed
+                    .resume(Checkpoint::read(bytes.as_slice()).unwrap())
+                    .unwrap();
+                resumed.process_part(FIRST.as_bytes()).unwrap();
+                resumed.process_part(SECOND.as_bytes()).unwrap();
+                resumed.finish().unwrap();
+
+                assert_eq!(
+                    resumed.clients(OutputOrder::FirstSeen),
+                    uninterrupted.clients(OutputOrder::FirstSeen)
+                );
+                assert_eq!(resumed.valid_records(), uninterrupted.valid_records());
+                assert_eq!(resumed.invalid_records(), uninterrupted.invalid_records());
+                assert_eq!(resumed.skipped(), uninterrupted.skipped());
+            }
+        }
+    }
+
+    #[test]
+    fn a_stopped_run_resumes_from_its_checkpoint() {
+        let (uninterrupted, _) = checkpointed(false);
+        let stop = Arc::new(AtomicBool::new(false));
+        let mut engine = PaymentsEngine::new();
+        engine.set_stop_flag(Arc::clone(&stop));
+        let stopping = Arc::clone(&stop);
+        engine.on_event(move |event| {
+            if *event == (EngineEvent::AccountCreated { client: 2 }) {
+                stopping.store(true, Ordering::SeqCst);
+            }
+        });
+        let err = engine.process_part(FIRST.as_bytes()).unwrap_err();
+        assert!(matches!(err, PaymentsError::Interrupted));
+        // The dispute after client 2's deposit wasn't read
+        assert_eq!(engine.valid_records(), 2);
+
+        let mut resumed = PaymentsEngine::new();
+        resumed.resume(engine.checkpoint().unwrap()).unwrap();
+        resumed.process_part(FIRST.as_bytes()).unwrap();
+        resumed.process_part(SECOND.as_bytes()).unwrap();
+        resumed.finish().unwrap();
+        assert_eq!(
+            resumed.clients(OutputOrder::FirstSeen),
+            uninterrupted.clients(OutputOrder::FirstSeen)
+        );
+        assert_eq!(resumed.valid_records(), uninterrupted.valid_records());
+    }
+
+    #[test]
+    fn checkpoints_of_another_version_are_refused() {
+        let mut bytes = Vec::new();
+        PaymentsEngine::new()
+            .checkpoint()
+            .unwrap()
+            .write(&mut bytes)
+            .unwrap();
+        let other = String::from_utf8(bytes)
+            .unwrap()
+            .replace("\"version\":3", "\"version\":2");
+        let err = Checkpoint::read(other.as_bytes()).unwrap_err();
+        assert!(matches!(err, PaymentsError::CheckpointVersion(2)));
+    }
+}
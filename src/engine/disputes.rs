@@ -0,0 +1,102 @@
+use super::PaymentsEngine;
+use crate::{
+    AccountStore, ClientId, Currency, DisputePolicy, PaymentsError, Timestamp, TransactionStore,
+    TransactionType, TxId,
+};
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// A transaction under dispute, for chasing partners to resolve it. The currency is
+/// left out for transactions without one.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct OpenDispute {
+    /// Whose funds are held, the receiving client for a transfer
+    pub client: ClientId,
+    pub tx: TxId,
+    #[serde(rename = "type")]
+    pub transaction_type: TransactionType,
+    pub amount: Decimal,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub currency: Option<Currency>,
+    /// Whole days since the tx's timestamp, `None` if it has none
+    pub age: Option<u64>,
+}
+
+impl<T: TransactionStore, A: AccountStore, P: DisputePolicy> PaymentsEngine<T, A, P> {
+    /// Every transaction under dispute, by client and then tx id, aged as of `now`.
+    /// Only fails if the transaction store does.
+    pub fn open_disputes(&self, now: Timestamp) -> Result<Vec<OpenDispute>, PaymentsError> {
+        let mut disputed: Vec<(ClientId, TxId)> = self
+            .clients
+            .values()
+            .flat_map(|client| client.disputes().map(|tx| (client.id(), tx)))
+            .collect();
+        disputed.sort_unstable();
+        let mut disputes = Vec::with_capacity(disputed.len());
+        for (client, tx) in disputed {
+            // Kept for as long as it's under dispute, whatever the eviction policy
+            let Some(transaction) = self.transaction_store.get_tx(self.tx_key(client, tx))? else {
+                continue;
+            };
+            disputes.push(OpenDispute {
+                client,
+                tx,
+                transaction_type: transaction.transaction_type,
+                amount: self.rescale(transaction.amount),
+                currency: transaction.currency,
+                age: transaction
+                    .timestamp
+                    .map(|timestamp| now.saturating_sub(timestamp) / SECS_PER_DAY),
+            });
+        }
+        // Every row gets the currency column once any has one, as in the accounts output
+        if disputes.iter().any(|dispute| dispute.currency.is_some()) {
+            for dispute in &mut disputes {
+                dispute.currency.get_or_insert_with(String::new);
+            }
+        }
+        Ok(disputes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use csv::StringRecord;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn lists_open_disputes_with_their_age() {
+        let mut engine = PaymentsEngine::new();
+        for row in [
+            &["deposit", "2", "1", "5.0", "", "0"][..],
+            &["deposit", "1", "2", "3.0"],
+            &["deposit", "1", "3", "1.0", "USD"],
+            &["deposit", "1", "4", "1.0"],
+            &["dispute", "2", "1"],
+            &["dispute", "1", "3"],
+            &["dispute", "1", "2"],
+            &["dispute", "1", "4"],
+            &["resolve", "1", "4"],
+        ] {
+            engine
+                .process_record(StringRecord::from(row.to_vec()))
+                .unwrap();
+        }
+
+        let disputes = engine.open_disputes(3 * SECS_PER_DAY - 1).unwrap();
+        let currency = |dispute: &OpenDispute| dispute.currency.clone();
+        assert_eq!(
+            disputes
+                .iter()
+                .map(|dispute| (dispute.client, dispute.tx, dispute.age))
+                .collect::<Vec<_>>(),
+            [(1, 2, None), (1, 3, None), (2, 1, Some(2))]
+        );
+        assert_eq!(disputes[1].amount, dec!(1.0000));
+        assert_eq!(currency(&disputes[1]), Some("USD".to_string()));
+        assert_eq!(currency(&disputes[0]), Some(String::new()));
+    }
+}
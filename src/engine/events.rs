@@ -0,0 +1,151 @@
+use super::PaymentsEngine;
+use crate::{
+    AccountStore, ClientId, Currency, DisputePolicy, SkipReason, Transaction, TransactionStore,
+    TransactionType, TxId,
+};
+use rust_decimal::Decimal;
+use serde::Serialize;
+
+/// Handed every event as the engine applies transactions, see `on_event`
+pub type EventHandler = Box<dyn FnMut(&EngineEvent) + Send>;
+
+/// Something that happened to a client account, for notifications and metrics.
+/// Serialized as json tagged with its kind, e.g. `{"event":"account-locked",...}`.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum EngineEvent {
+    /// A client seen for the first time
+    AccountCreated { client: ClientId },
+    /// A dispute held the amount of the tx
+    FundsHeld {
+        client: ClientId,
+        tx: TxId,
+        amount: Decimal,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        currency: Option<Currency>,
+    },
+    /// A resolve released the amount the tx's dispute held
+    FundsReleased {
+        client: ClientId,
+        tx: TxId,
+        amount: Decimal,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        currency: Option<Currency>,
+    },
+    /// A chargeback of the tx locked the account
+    AccountLocked { client: ClientId, tx: TxId },
+    /// A well formed record was skipped instead of applied
+    TransactionRejected {
+        client: ClientId,
+        tx: TxId,
+        reason: SkipReason,
+    },
+}
+
+impl<T: TransactionStore, A: AccountStore, P: DisputePolicy> PaymentsEngine<T, A, P> {
+    /// Calls `handler` with every event from here on, after any handlers already
+    /// added. Not supported with `process_parallel`.
+    pub fn on_event<F>(&mut self, handler: F)
+    where
+        F: FnMut(&EngineEvent) + Send + 'static,
+    {
+        self.event_handlers.push(Box::new(handler));
+    }
+
+    pub(super) fn emit(&mut self, event: EngineEvent) {
+        for handler in &mut self.event_handlers {
+            handler(&event);
+        }
+    }
+
+    /// Emits what an applied dispute, resolve or chargeback of `t` did to `client`
+    pub(super) fn emit_settlement(
+        &mut self,
+        transaction_type: &TransactionType,
+        client: ClientId,
+        t: &Transaction,
+    ) {
+        if self.event_handlers.is_empty() {
+            return;
+        }
+        let (tx, amount, currency) = (t.id, t.amount, t.currency.clone());
+        let event = match transaction_type {
+            TransactionType::Dispute => EngineEvent::FundsHeld {
+                client,
+                tx,
+                amount,
+                currency,
+            },
+            TransactionType::Resolve => EngineEvent::FundsReleased {
+                client,
+                tx,
+                amount,
+                currency,
+            },
+            TransactionType::Chargeback => EngineEvent::AccountLocked { client, tx },
+            _ => return,
+        };
+        self.emit(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use csv::StringRecord;
+    use rust_decimal_macros::dec;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn handlers_see_every_account_event() {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&events);
+        let mut engine = PaymentsEngine::new();
+        engine.on_event(move |event| seen.lock().unwrap().push(event.clone()));
+        for row in [
+            &["deposit", "1", "1", "5.0"][..],
+            &["deposit", "1", "2", "1.0"],
+            &["dispute", "1", "1"],
+            &["resolve", "1", "1"],
+            &["dispute", "1", "2"],
+            &["chargeback", "1", "2"],
+            &["withdrawal", "1", "3", "1.0"],
+        ] {
+            engine
+                .process_record(StringRecord::from(row.to_vec()))
+                .unwrap();
+        }
+
+        use EngineEvent::*;
+        assert_eq!(
+            *events.lock().unwrap(),
+            [
+                AccountCreated { client: 1 },
+                FundsHeld {
+                    client: 1,
+                    tx: 1,
+                    amount: dec!(5.0000),
+                    currency: None,
+                },
+                FundsReleased {
+                    client: 1,
+                    tx: 1,
+                    amount: dec!(5.0000),
+                    currency: None,
+                },
+                FundsHeld {
+                    client: 1,
+                    tx: 2,
+                    amount: dec!(1.0000),
+                    currency: None,
+                },
+                AccountLocked { client: 1, tx: 2 },
+                TransactionRejected {
+                    client: 1,
+                    tx: 3,
+                    reason: SkipReason::LockedAccount,
+                },
+            ]
+        );
+    }
+}
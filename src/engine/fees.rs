@@ -0,0 +1,138 @@
+use super::PaymentsEngine;
+use crate::{
+    AccountStore, ClientId, Currency, DisputePolicy, FeeCollection, FeeSchedule, Transaction,
+    TransactionStore, TransactionType,
+};
+use rust_decimal::Decimal;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use tracing::debug;
+
+impl<T: TransactionStore, A: AccountStore, P: DisputePolicy> PaymentsEngine<T, A, P> {
+    /// Charges deposits and withdrawals from here on as the schedule says
+    pub fn set_fee_schedule(&mut self, schedule: FeeSchedule) {
+        self.fee_schedule = Some(schedule);
+    }
+
+    pub fn fee_schedule(&self) -> Option<&FeeSchedule> {
+        self.fee_schedule.as_ref()
+    }
+
+    /// Fees charged so far, by client and currency, `None` for transactions without
+    /// one, however they're collected
+    pub fn fees(&self) -> &BTreeMap<(ClientId, Option<Currency>), Decimal> {
+        &self.fees
+    }
+
+    /// Fees charged so far across every client, by currency, as kept in the house
+    /// balance when collecting them there
+    pub fn house_fees(&self) -> BTreeMap<Option<&str>, Decimal> {
+        let mut house = BTreeMap::new();
+        for ((_, currency), fee) in &self.fees {
+            *house.entry(currency.as_deref()).or_insert(Decimal::ZERO) += fee;
+        }
+        for fee in house.values_mut() {
+            *fee = self.rescale(*fee);
+        }
+        house
+    }
+
+    /// Whether the accounts output carries each client's fees
+    pub(super) fn fees_per_client(&self) -> bool {
+        self.fee_schedule
+            .as_ref()
+            .is_some_and(|schedule| schedule.collect == FeeCollection::PerClient)
+    }
+
+    /// The fee `row` is charged, rounded to the engine's precision. A deposit is
+    /// never charged more than its amount.
+    pub(super) fn fee(&self, row: &Transaction) -> Decimal {
+        let Some(schedule) = &self.fee_schedule else {
+            return Decimal::ZERO;
+        };
+        let fee = self.rescale(schedule.fee(&row.transaction_type, row.amount));
+        match row.transaction_type {
+            TransactionType::Deposit => fee.min(row.amount),
+            _ => fee,
+        }
+    }
+
+    /// Keeps the fee the applied `row` was charged
+    pub(super) fn collect_fee(&mut self, row: &Transaction, fee: Decimal) {
+        if fee.is_zero() {
+            return;
+        }
+        debug!(client = row.client_id, tx = row.id, fee = %fee, "Charged fee");
+        *self
+            .fees
+            .entry((row.client_id, row.currency.clone()))
+            .or_insert(Decimal::ZERO) += fee;
+    }
+}
+
+/// `row` as it moves the client's funds once charged `fee`: a deposit credits its
+/// amount less the fee, a withdrawal debits its amount plus the fee
+pub(super) fn charged(row: &Transaction, fee: Decimal) -> Cow<'_, Transaction> {
+    if fee.is_zero() {
+        return Cow::Borrowed(row);
+    }
+    let mut charged = row.clone();
+    match row.transaction_type {
+        TransactionType::Deposit => charged.amount -= fee,
+        _ => charged.amount += fee,
+    }
+    Cow::Owned(charged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Fee, OutputOrder};
+    use csv::StringRecord;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn deposits_and_withdrawals_pay_their_fees() {
+        let mut engine = PaymentsEngine::new();
+        engine.set_fee_schedule(FeeSchedule {
+            deposit: Fee {
+                flat: dec!(0),
+                percent: dec!(1),
+            },
+            withdrawal: Fee {
+                flat: dec!(0.5),
+                percent: dec!(0),
+            },
+            collect: FeeCollection::PerClient,
+        });
+        for row in [
+            &["deposit", "1", "1", "10.0"][..],
+            &["withdrawal", "1", "2", "9.0"],
+            &["withdrawal", "1", "3", "4.0"],
+            &["deposit", "2", "4", "5.0", "USD"],
+            &["dispute", "1", "1"],
+        ] {
+            engine
+                .process_record(StringRecord::from(row.to_vec()))
+                .unwrap();
+        }
+
+        // 10 less 0.1, less 9 plus 0.5, leaving too little for 4 plus 0.5, and the
+        // dispute holds the whole deposit
+        let client = engine.client(1).unwrap();
+        assert_eq!(client.available(), dec!(-9.6));
+        assert_eq!(client.held(), dec!(10));
+        assert_eq!(engine.fees()[&(1, None)], dec!(0.6000));
+        assert_eq!(engine.fees()[&(2, Some("USD".to_string()))], dec!(0.0500));
+        let fees: Vec<_> = engine
+            .account_rows(OutputOrder::ClientId)
+            .iter()
+            .map(|row| row.fees)
+            .collect();
+        assert_eq!(fees, [Some(dec!(0.6000)), Some(dec!(0.0500))]);
+        assert_eq!(
+            engine.house_fees(),
+            BTreeMap::from([(None, dec!(0.6000)), (Some("USD"), dec!(0.0500))])
+        );
+    }
+}
@@ -0,0 +1,109 @@
+use super::PaymentsEngine;
+use crate::{
+    AccountStore, ClientId, DisputePolicy, PaymentsError, SkipReason, Transaction,
+    TransactionStore, TxId,
+};
+use serde::Serialize;
+
+/// A record failing validation, see `PaymentsEngine::set_record_issues`
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct Issue {
+    /// Line of the record in its input, `None` for records not read from one or held
+    /// back until the input was done
+    pub line: Option<u64>,
+    /// `invalid` for a record that failed to parse, otherwise the disposition of a
+    /// partner error, such as `rejected-duplicate`
+    pub kind: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub client: Option<ClientId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tx: Option<TxId>,
+    pub message: String,
+}
+
+impl<T: TransactionStore, A: AccountStore, P: DisputePolicy> PaymentsEngine<T, A, P> {
+    /// Keep an `Issue` for every record that fails to parse or is skipped as a
+    /// partner error, for checking a feed. Records skipped for the state of an
+    /// account, locked or short of funds, aren't issues. Not supported with
+    /// `process_parallel`.
+    pub fn set_record_issues(&mut self, record_issues: bool) {
+        self.issues = record_issues.then(Vec::new);
+    }
+
+    /// Issues found so far in the order their records were read, empty unless
+    /// recording them
+    pub fn issues(&self) -> &[Issue] {
+        self.issues.as_deref().unwrap_or_default()
+    }
+
+    pub(super) fn record_invalid(&mut self, err: &PaymentsError) {
+        let line = self.line;
+        if let Some(issues) = self.issues.as_mut() {
+            issues.push(Issue {
+                line,
+                kind: "invalid",
+                client: None,
+                tx: None,
+                message: err.to_string(),
+            });
+        }
+    }
+
+    pub(super) fn record_rejected(&mut self, row: &Transaction, reason: SkipReason) {
+        let line = self.line;
+        if let (Some(issues), true) = (self.issues.as_mut(), reason.is_partner_error()) {
+            issues.push(Issue {
+                line,
+                kind: reason.disposition(),
+                client: Some(row.client_id),
+                tx: Some(row.id),
+                message: reason.to_string(),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn records_parse_failures_and_partner_errors_with_their_line() {
+        let mut engine = PaymentsEngine::new();
+        engine.set_record_issues(true);
+        engine
+            .process(
+                &b"type, client, tx, amount\n\
+                   deposit, 1, 9, 1.0\n\
+                   deposit, 1, 1, 5.0\n\
+                   withdrawal, 1, 2, 9.0\n\
+                   deposit, 1, 3, abc\n\
+                   deposit, 2, 1, 1.0\n\
+                   dispute, 2, 1\n\
+                   dispute, 1, 7\n"[..],
+            )
+            .unwrap();
+        let issues: Vec<_> = engine
+            .issues()
+            .iter()
+            .map(|issue| (issue.line, issue.kind, issue.tx))
+            .collect();
+        assert_eq!(
+            issues,
+            [
+                (Some(5), "invalid", None),
+                (Some(6), "rejected-conflicting-duplicate", Some(1)),
+                (Some(7), "rejected-ownership-mismatch", Some(1)),
+                (Some(8), "ignored-unknown-tx", Some(7)),
+            ]
+        );
+        assert_eq!(
+            engine.issues()[1].message,
+            "tx id reused with other details"
+        );
+
+        let mut quiet = PaymentsEngine::new();
+        quiet.process(&b"type, client, tx, amount\n"[..]).unwrap();
+        assert!(quiet.issues().is_empty());
+    }
+}
@@ -0,0 +1,209 @@
+use super::PaymentsEngine;
+use crate::{
+    AccountStore, DisputePolicy, PaymentsError, Transaction, TransactionStore, TransactionType,
+    TxId,
+};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::io;
+
+/// Funds paid in and out through the payment provider
+const SETTLEMENT: &str = "settlement";
+/// Fees charged on deposits and withdrawals
+const FEES: &str = "fees";
+/// Back-office corrections, the other side of every adjustment
+const ADJUSTMENTS: &str = "adjustments";
+/// Withdrawn funds disputed back into an account, owed by the payment provider
+const WITHDRAWAL_DISPUTES: &str = "withdrawal-disputes";
+
+/// The double entry journal, written line by line as transactions are applied
+pub(super) struct Journal {
+    writer: csv::Writer<Box<dyn io::Write + Send>>,
+    entries: u64,
+}
+
+/// One side of a journal entry. Each entry's debits add up to its credits.
+#[derive(Debug, Serialize)]
+struct JournalLine<'a> {
+    entry: u64,
+    tx: TxId,
+    #[serde(rename = "type")]
+    transaction_type: &'a TransactionType,
+    account: &'a str,
+    debit: Option<Decimal>,
+    credit: Option<Decimal>,
+    currency: Option<&'a str>,
+}
+
+impl<T: TransactionStore, A: AccountStore, P: DisputePolicy> PaymentsEngine<T, A, P> {
+    /// Writes a double entry journal of every applied transaction to `sink`, as csv
+    /// lines of debits and credits, including holds and their reversals. Not
+    /// supported with `process_parallel` or `apply_batch`.
+    pub fn set_journal_sink(&mut self, sink: Box<dyn io::Write + Send>) {
+        self.journal = Some(Journal {
+            writer: csv::Writer::from_writer(sink),
+            entries: 0,
+        });
+    }
+
+    /// Journals the funds an applied `row` moved, charged `fee`, with the transaction
+    /// it refers to for a dispute, resolve or chargeback
+    pub(super) fn journal(
+        &mut self,
+        row: &Transaction,
+        disputed: Option<&Transaction>,
+        fee: Decimal,
+    ) -> Result<(), PaymentsError> {
+        use TransactionType::*;
+        let Some(journal) = self.journal.as_mut() else {
+            return Ok(());
+        };
+        let transaction = disputed.unwrap_or(row);
+        let amount = transaction.amount;
+        // The client whose funds a dispute holds
+        let owner = transaction.disputed_client_id();
+        let available = |client| format!("clients:{}:available", client);
+        let held = |client| format!("clients:{}:held", client);
+        // Debited with a positive amount, credited with a negative one
+        let postings = match (&row.transaction_type, &transaction.transaction_type) {
+            (Deposit | Open, _) => vec![
+                (SETTLEMENT.to_string(), amount),
+                (available(row.client_id), fee - amount),
+                (FEES.to_string(), -fee),
+            ],
+            (Withdrawal, _) => vec![
+                (available(row.client_id), amount + fee),
+                (SETTLEMENT.to_string(), -amount),
+                (FEES.to_string(), -fee),
+            ],
+            (Adjustment, _) => vec![
+                (ADJUSTMENTS.to_string(), amount),
+                (available(row.client_id), -amount),
+            ],
+            (Transfer, _) => vec![
+                (available(row.client_id), amount),
+                (available(row.disputed_client_id()), -amount),
+            ],
+            (Dispute, Withdrawal) => vec![
+                (WITHDRAWAL_DISPUTES.to_string(), amount),
+                (held(owner), -amount),
+            ],
+            (Dispute, _) => vec![(available(owner), amount), (held(owner), -amount)],
+            (Resolve, Withdrawal) => vec![
+                (held(owner), amount),
+                (WITHDRAWAL_DISPUTES.to_string(), -amount),
+            ],
+            (Resolve, _) => vec![(held(owner), amount), (available(owner), -amount)],
+            (Chargeback, Deposit) => vec![(held(owner), amount), (SETTLEMENT.to_string(), -amount)],
+            // Paid back to the sender
+            (Chargeback, Transfer) => vec![
+                (held(owner), amount),
+                (available(transaction.client_id), -amount),
+            ],
+            // The held funds are credited back, and recovered from the payment provider
+            (Chargeback, _) => vec![
+                (held(owner), amount),
+                (available(owner), -amount),
+                (SETTLEMENT.to_string(), amount),
+                (WITHDRAWAL_DISPUTES.to_string(), -amount),
+            ],
+            _ => return Ok(()),
+        };
+        if postings.iter().all(|(_, amount)| amount.is_zero()) {
+            return Ok(());
+        }
+        journal.entries += 1;
+        for (account, amount) in postings.iter().filter(|(_, amount)| !amount.is_zero()) {
+            journal.writer.serialize(JournalLine {
+                entry: journal.entries,
+                tx: row.id,
+                transaction_type: &row.transaction_type,
+                account,
+                debit: (*amount > Decimal::ZERO).then_some(*amount),
+                credit: (*amount < Decimal::ZERO).then_some(-*amount),
+                currency: transaction.currency.as_deref(),
+            })?;
+        }
+        journal.writer.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Fee, FeeSchedule};
+    use csv::StringRecord;
+    use rust_decimal_macros::dec;
+    use std::collections::BTreeMap;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn journals_balanced_entries_as_transactions_apply() {
+        let buffer = Buffer::default();
+        let mut engine = PaymentsEngine::new();
+        engine.set_journal_sink(Box::new(buffer.clone()));
+        engine.set_withdrawal_disputes(true);
+        engine.set_fee_schedule(FeeSchedule {
+            deposit: Fee {
+                flat: dec!(0.5),
+                percent: dec!(0),
+            },
+            ..FeeSchedule::default()
+        });
+        for row in [
+            &["deposit", "1", "1", "10.0"][..],
+            &["withdrawal", "1", "2", "3.0"],
+            &["dispute", "1", "1"],
+            &["resolve", "1", "1"],
+            &["withdrawal", "1", "3", "9.0"],
+            &["dispute", "1", "2"],
+            &["chargeback", "1", "2"],
+        ] {
+            engine
+                .process_record(StringRecord::from(row.to_vec()))
+                .unwrap();
+        }
+
+        let journal = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = journal.lines().collect();
+        assert_eq!(
+            lines[..7],
+            [
+                "entry,tx,type,account,debit,credit,currency",
+                "1,1,deposit,settlement,10.0000,,",
+                "1,1,deposit,clients:1:available,,9.5000,",
+                "1,1,deposit,fees,,0.5000,",
+                "2,2,withdrawal,clients:1:available,3.0000,,",
+                "2,2,withdrawal,settlement,,3.0000,",
+                "3,1,dispute,clients:1:available,10.0000,,",
+            ]
+        );
+        // The overdrawn withdrawal was rejected, so isn't journaled
+        assert!(!journal.contains(",3,withdrawal,"));
+        assert!(journal.contains("6,2,chargeback,withdrawal-disputes,,3.0000,"));
+
+        let mut entries: BTreeMap<u64, Decimal> = BTreeMap::new();
+        for line in &lines[1..] {
+            let fields: Vec<&str> = line.split(',').collect();
+            let debit: Decimal = fields[4].parse().unwrap_or_default();
+            let credit: Decimal = fields[5].parse().unwrap_or_default();
+            *entries.entry(fields[0].parse().unwrap()).or_default() += debit - credit;
+        }
+        assert_eq!(entries.len(), 6);
+        assert!(entries.values().all(Decimal::is_zero), "{:?}", entries);
+    }
+}
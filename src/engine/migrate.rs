@@ -0,0 +1,117 @@
+use crate::PaymentsError;
+use serde_json::Value;
+use std::collections::HashSet;
+use std::io;
+use tracing::info;
+
+/// Version of the state file format written by `PaymentsEngine::save_state`. State
+/// files of earlier versions are migrated as they're loaded, while later ones are
+/// refused. Files from before it was written down are version 1.
+pub const STATE_VERSION: u32 = 2;
+
+/// Brings a state of the version after the index up to the next one
+const MIGRATIONS: [fn(&mut Value); STATE_VERSION as usize - 1] = [dispute_states];
+
+/// The version of a saved state, 1 if it has none
+fn version(state: &Value) -> u64 {
+    state.get("version").and_then(Value::as_u64).unwrap_or(1)
+}
+
+/// Brings a saved state of any earlier version up to `STATE_VERSION`, refusing one
+/// of a later version
+pub(super) fn migrate(mut state: Value) -> Result<Value, PaymentsError> {
+    let from = version(&state);
+    if from == 0 || from > u64::from(STATE_VERSION) {
+        return Err(PaymentsError::StateVersion(from));
+    }
+    for (to, migration) in MIGRATIONS.iter().enumerate().skip(from as usize - 1) {
+        migration(&mut state);
+        info!("Migrated the state to version {}", to + 2);
+    }
+    if let Some(fields) = state.as_object_mut() {
+        fields.insert("version".to_string(), STATE_VERSION.into());
+    }
+    Ok(state)
+}
+
+/// Rewrites a state file as `STATE_VERSION`, migrating it if it's of an earlier
+/// version, and returns the version it was
+pub fn upgrade_state<R: io::Read, W: io::Write>(
+    reader: R,
+    writer: W,
+) -> Result<u32, PaymentsError> {
+    let state: Value = serde_json::from_reader(reader)?;
+    let from = version(&state);
+    serde_json::to_writer(writer, &migrate(state)?)?;
+    // Checked against `STATE_VERSION` by `migrate`
+    Ok(from as u32)
+}
+
+/// 1 to 2: version 1 kept no dispute state with the transactions, only the tx ids
+/// under dispute with each client
+fn dispute_states(state: &mut Value) {
+    let disputed: HashSet<u64> = state["clients"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|client| client["disputes"].as_array())
+        .flatten()
+        .filter_map(Value::as_u64)
+        .collect();
+    let transactions = state.get_mut("transactions").and_then(Value::as_array_mut);
+    for transaction in transactions.into_iter().flatten() {
+        let open = transaction["tx"]
+            .as_u64()
+            .is_some_and(|tx| disputed.contains(&tx));
+        if let (true, Some(fields)) = (open, transaction.as_object_mut()) {
+            fields
+                .entry("dispute_state")
+                .or_insert_with(|| "disputed".into());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DisputeState, PaymentsEngine, TransactionStore};
+
+    const VERSION_1: &str = r#"{
+        "clients": [{"id": 1, "available": "2", "held": "3", "locked": false, "disputes": [7]}],
+        "first_seen": [1],
+        "transactions": [
+            {"tx": 7, "type": "deposit", "client": 1, "amount": "3"},
+            {"tx": 8, "type": "deposit", "client": 1, "amount": "2"}
+        ],
+        "retained": {}
+    }"#;
+
+    #[test]
+    fn migrates_earlier_versions_and_refuses_later_ones() {
+        let mut upgraded = Vec::new();
+        assert_eq!(
+            upgrade_state(VERSION_1.as_bytes(), &mut upgraded).unwrap(),
+            1
+        );
+        let state: Value = serde_json::from_slice(&upgraded).unwrap();
+        assert_eq!(state["version"], 2);
+        assert_eq!(state["transactions"][0]["dispute_state"], "disputed");
+        assert!(state["transactions"][1].get("dispute_state").is_none());
+
+        // Loading migrates too, and an upgraded state is left as it is
+        for saved in [VERSION_1.as_bytes(), &upgraded[..]] {
+            let mut engine = PaymentsEngine::new();
+            engine.load_state(saved).unwrap();
+            let tx = engine.transaction_store.get_tx(7.into()).unwrap().unwrap();
+            assert_eq!(tx.dispute_state, DisputeState::Disputed);
+            assert_eq!(engine.client(1).unwrap().held(), "3".parse().unwrap());
+        }
+        assert_eq!(upgrade_state(&upgraded[..], io::sink()).unwrap(), 2);
+
+        let later = VERSION_1.replacen('{', "{\"version\": 3,", 1);
+        assert!(matches!(
+            PaymentsEngine::new().load_state(later.as_bytes()),
+            Err(PaymentsError::StateVersion(3))
+        ));
+    }
+}
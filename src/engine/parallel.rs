@@ -0,0 +1,521 @@
+use super::{EvictionPolicy, Ledger, PaymentsEngine};
+use crate::{
+    AccountStore, Client, ClientId, DisputePolicy, MemoryStore, Outcome, PaymentsError,
+    ProcessingMode, SkipReason, Transaction, TransactionStore, TransactionType, TxId,
+};
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::mem;
+use std::sync::mpsc::{self, Receiver, SyncSender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use tracing::warn;
+
+/// Records queued per worker before the reader waits for it to catch up
+const SHARD_QUEUE: usize = 1024;
+
+type Worker<P> = JoinHandle<Result<PaymentsEngine<MemoryStore, MemoryStore, P>, PaymentsError>>;
+
+/// What the reader hands a shard's worker
+enum ShardInput {
+    /// An account read from the engine's account store, ahead of its first record
+    Client(Client),
+    /// A record, with its line in the input for strict mode's errors, and whether it
+    /// claims its tx id, so the worker reports back if it was applied
+    Record(Transaction, Option<u64>, bool),
+    /// A request to report back once every input before it is applied
+    Sync,
+}
+
+/// What a shard's worker tells the reader
+enum Report {
+    /// Whether the row claiming a tx id was applied
+    Claimed(TxId, bool),
+    /// A tx id the shard let go of, which is free for any client again
+    Evicted(TxId),
+    /// Every input before a `ShardInput::Sync` is applied
+    Synced,
+}
+
+/// Which client each tx id belongs to, across the shards. A deposit, withdrawal or
+/// adjustment only takes its tx id once its shard has applied it, as a rejected one
+/// doesn't when processing serially, so the reader waits on the shard's report
+/// before checking a row against a tx id another client has claimed. A tx id the
+/// shard evicts is forgotten again, and as the rows handed to a shard can still
+/// evict one, a row checked against another client's tx id waits for that shard to
+/// catch up when evicting.
+struct Owners {
+    applied: HashMap<TxId, ClientId>,
+    /// Tx ids claimed by rows their shard hasn't reported on yet
+    pending: HashMap<TxId, ClientId>,
+    /// Each shard's reports on the tx ids it claimed or evicted
+    reports: Vec<Receiver<Report>>,
+    /// Whether the shards evict transactions
+    evicting: bool,
+}
+
+impl Owners {
+    /// The client whose applied transaction `row` refers to, if any, settling any
+    /// claim on its tx id by another client first
+    fn owner(
+        &mut self,
+        row: &Transaction,
+        senders: &[SyncSender<ShardInput>],
+    ) -> Result<Option<ClientId>, PaymentsError> {
+        for shard in 0..self.reports.len() {
+            while let Ok(report) = self.reports[shard].try_recv() {
+                self.settle(report);
+            }
+        }
+        match self.pending.get(&row.id).copied() {
+            Some(claimant) if claimant == row.client_id => return Ok(Some(claimant)),
+            Some(claimant) => {
+                let shard = claimant as usize % self.reports.len();
+                while self.pending.contains_key(&row.id) {
+                    self.next_report(shard)?;
+                }
+            }
+            None => (),
+        }
+        match self.applied.get(&row.id).copied() {
+            Some(owner) if owner != row.client_id && self.evicting => {
+                let shard = owner as usize % self.reports.len();
+                senders[shard]
+                    .send(ShardInput::Sync)
+                    .map_err(|_| PaymentsError::ShardStopped(shard))?;
+                while !self.next_report(shard)? {}
+                Ok(self.applied.get(&row.id).copied())
+            }
+            owner => Ok(owner),
+        }
+    }
+
+    /// Waits for the shard's next report and settles it, returning whether it was
+    /// `Report::Synced`
+    fn next_report(&mut self, shard: usize) -> Result<bool, PaymentsError> {
+        let report = self.reports[shard]
+            .recv()
+            .map_err(|_| PaymentsError::ShardStopped(shard))?;
+        let synced = matches!(report, Report::Synced);
+        self.settle(report);
+        Ok(synced)
+    }
+
+    fn settle(&mut self, report: Report) {
+        match report {
+            Report::Claimed(id, applied) => {
+                if let Some(client) = self.pending.remove(&id) {
+                    if applied {
+                        self.applied.insert(id, client);
+                    }
+                }
+            }
+            Report::Evicted(id) => {
+                self.applied.remove(&id);
+            }
+            Report::Synced => (),
+        }
+    }
+}
+
+impl<T: TransactionStore, A: AccountStore, P: DisputePolicy> PaymentsEngine<T, A, P> {
+    /// Applies every record of the inputs, in order, on `threads` worker threads.
+    /// Records are sharded by `client_id % threads`, each shard with its own clients
+    /// and transactions, and merged back in once the input is done.
+    ///
+    /// Parsing and the checks that span clients stay on the calling thread: a tx id
+    /// reused by another client is rejected as a duplicate, and a dispute of another
+    /// client's tx is an ownership mismatch, as when processing serially. A row whose
+    /// tx id another client has just used waits for that client's shard to say if it
+    /// was applied, to check it against the same transactions it would be serially.
+    /// Transfers move funds between shards, the audit trail and ledger export have to
+    /// be written in order and a window of records to evict by spans the shards, so
+    /// none of them is supported here. The kept transactions are handed to the
+    /// shards in memory, and put back in the engine's store once they're done.
+    /// Accounts are read from the account store on the calling thread too.
+    pub fn process_parallel<I, R>(&mut self, inputs: I, threads: usize) -> Result<(), PaymentsError>
+    where
+        I: IntoIterator<Item = R>,
+        R: io::Read,
+    {
+        self.check_supported(ProcessingMode::Parallel)?;
+        let threads = threads.max(1);
+        let kept = self.transaction_store.transactions()?;
+        let mut applied = HashMap::new();
+        for transaction in &kept {
+            if transaction.transaction_type == TransactionType::Transfer {
+                return Err(PaymentsError::Unsupported {
+                    mode: ProcessingMode::Parallel,
+                    feature: "Transfers",
+                });
+            }
+            applied.insert(transaction.id, transaction.client_id);
+        }
+        let mut seen: HashSet<ClientId> = self.first_seen.iter().copied().collect();
+        let mut known: HashSet<ClientId> = self.clients.keys().copied().collect();
+
+        let mut sharded: Vec<Vec<Transaction>> = vec![Vec::new(); threads];
+        for transaction in kept {
+            self.transaction_store
+                .remove_tx(self.key_of(&transaction))?;
+            sharded[transaction.client_id as usize % threads].push(transaction);
+        }
+        let mut senders = Vec::with_capacity(threads);
+        let mut workers = Vec::with_capacity(threads);
+        let mut owners = Owners {
+            applied,
+            pending: HashMap::new(),
+            reports: Vec::with_capacity(threads),
+            evicting: self.eviction_policy != EvictionPolicy::KeepAll,
+        };
+        for (index, transactions) in sharded.into_iter().enumerate() {
+            let (sender, reports, worker) = self.spawn_shard(index, threads, transactions);
+            senders.push(sender);
+            owners.reports.push(reports);
+            workers.push(worker);
+        }
+        let mut routed = Ok(());
+        for input in inputs {
+            routed = self.read_input(input, false, |engine, parsed| {
+                let row = match parsed {
+                    Ok(row) => row,
+                    Err(err) => return engine.skip_malformed(err),
+                };
+                let shard = row.client_id as usize % threads;
+                // A worker only hangs up once it has failed, its error is picked up below
+                let send = |input| {
+                    senders[shard]
+                        .send(input)
+                        .map_err(|_| PaymentsError::ShardStopped(shard))
+                };
+                if known.insert(row.client_id) {
+                    if let Some(client) = engine.account_store.get_client(row.client_id)? {
+                        if seen.insert(row.client_id) {
+                            engine.first_seen.push(row.client_id);
+                        }
+                        send(ShardInput::Client(client))?;
+                    }
+                }
+                match engine.route(row, &mut owners, &senders, &mut seen)? {
+                    Some(input) => send(input),
+                    None => Ok(()),
+                }
+            });
+            if routed.is_err() {
+                break;
+            }
+        }
+        drop(senders);
+
+        for (index, worker) in workers.into_iter().enumerate() {
+            let shard = worker
+                .join()
+                .map_err(|_| PaymentsError::ShardStopped(index))??;
+            self.merge(shard)?;
+        }
+        // The merged accounts are what an interrupted run has to show for it
+        if let Err(PaymentsError::Interrupted) = routed {
+            self.store_clients()?;
+        }
+        routed?;
+        self.warn_rejected();
+        self.store_clients()
+    }
+
+    /// Takes the clients sharded to `index` out of the engine and starts a worker
+    /// applying that shard's records to them and its kept `transactions`, reporting
+    /// on the records that claim a tx id
+    fn spawn_shard(
+        &mut self,
+        index: usize,
+        threads: usize,
+        transactions: Vec<Transaction>,
+    ) -> (SyncSender<ShardInput>, Receiver<Report>, Worker<P>) {
+        let in_shard = |id: &ClientId| *id as usize % threads == index;
+        let mut shard = PaymentsEngine::with_shared_dispute_policy(
+            MemoryStore::new(),
+            MemoryStore::new(),
+            Arc::clone(&self.dispute_policy),
+        );
+        shard.strict = self.strict;
+        shard.buffered = self.buffered;
+        shard.max_amount = self.max_amount;
+        shard.dispute_window = self.dispute_window;
+        shard.fee_schedule = self.fee_schedule.clone();
+        shard.overdraft_policy = self.overdraft_policy;
+        shard.client_overdraft_policies = self.client_overdraft_policies.clone();
+        shard.eviction_policy = self.eviction_policy;
+        shard.evicted = Some(Vec::new());
+        shard.record_history = self.record_history;
+        shard.invariant_check = self.invariant_check;
+        shard.available_not_negative = self.available_not_negative;
+        shard.metrics = self.metrics.as_ref().map(|_| Default::default());
+        shard.ledger = self.ledger.as_ref().map(|_| Ledger::default());
+        shard.history = self.history.extract_if(|id, _| in_shard(id)).collect();
+        shard.clients = self.clients.extract_if(|id, _| in_shard(id)).collect();
+        shard.retained = self.retained.extract_if(|id, _| in_shard(id)).collect();
+
+        let (sender, receiver) = mpsc::sync_channel::<ShardInput>(SHARD_QUEUE);
+        let (reporter, reports) = mpsc::channel();
+        let worker = thread::spawn(move || {
+            for transaction in transactions {
+                shard
+                    .transaction_store
+                    .put_tx(shard.key_of(&transaction), transaction)?;
+            }
+            for input in receiver {
+                match input {
+                    ShardInput::Client(client) => {
+                        shard.clients.insert(client.id, client);
+                    }
+                    ShardInput::Record(row, line, claim) => {
+                        shard.line = line;
+                        let id = row.id;
+                        let outcome = shard.process_transaction(row)?;
+                        // The reader only stops listening once it's done or failed
+                        if claim {
+                            let applied = matches!(outcome, Outcome::Applied | Outcome::Overdrawn);
+                            let _ = reporter.send(Report::Claimed(id, applied));
+                        }
+                        for evicted in shard.evicted.iter_mut().flat_map(|ids| ids.drain(..)) {
+                            let _ = reporter.send(Report::Evicted(evicted));
+                        }
+                    }
+                    ShardInput::Sync => {
+                        let _ = reporter.send(Report::Synced);
+                    }
+                }
+            }
+            shard.apply_deferred()?;
+            Ok(shard)
+        });
+        (sender, reports, worker)
+    }
+
+    /// Checks a row against every shard's transactions, returning what to hand its
+    /// shard, or nothing if it's rejected here. Rows rejected here are counted as if
+    /// a shard had.
+    fn route(
+        &mut self,
+        row: Transaction,
+        owners: &mut Owners,
+        senders: &[SyncSender<ShardInput>],
+        seen: &mut HashSet<ClientId>,
+    ) -> Result<Option<ShardInput>, PaymentsError> {
+        use TransactionType::*;
+        let owner = owners.owner(&row, senders)?;
+        let mut claim = false;
+        let rejected = match row.transaction_type {
+            Transfer => {
+                return Err(PaymentsError::Unsupported {
+                    mode: ProcessingMode::Parallel,
+                    feature: "Transfers",
+                })
+            }
+            Deposit | Withdrawal | Adjustment => match owner {
+                // Another client's transaction under the same tx id can't be a
+                // resubmission, so it conflicts with the original
+                Some(owner) if owner != row.client_id => {
+                    warn!(
+                        "Rejecting {:?}, {} {}",
+                        row.transaction_type,
+                        SkipReason::ConflictingDuplicate,
+                        row.id
+                    );
+                    self.duplicate_transactions.push(row.id);
+                    self.conflicting_duplicates.push(row.id);
+                    Some(SkipReason::ConflictingDuplicate)
+                }
+                _ => {
+                    if owner.is_none() {
+                        owners.pending.insert(row.id, row.client_id);
+                        claim = true;
+                    }
+                    if seen.insert(row.client_id) {
+                        self.first_seen.push(row.client_id);
+                    }
+                    None
+                }
+            },
+            Open => {
+                if seen.insert(row.client_id) {
+                    self.first_seen.push(row.client_id);
+                }
+                None
+            }
+            Close | Reactivate => None,
+            Dispute | Resolve | Chargeback => match owner {
+                Some(owner) if owner != row.client_id => {
+                    warn!(
+                        "Skipping {:?}, tx {} belongs to client {} not client {}",
+                        row.transaction_type, row.id, owner, row.client_id
+                    );
+                    self.ownership_mismatches.push((row.client_id, row.id));
+                    Some(SkipReason::OwnershipMismatch)
+                }
+                _ => None,
+            },
+        };
+        match rejected {
+            Some(reason) => {
+                self.count_valid(&row.transaction_type);
+                self.skip(reason);
+                if self.strict && reason.is_partner_error() {
+                    return Err(self.at_line(PaymentsError::Rejected(row.id, reason)));
+                }
+                Ok(None)
+            }
+            None => Ok(Some(ShardInput::Record(row, self.line, claim))),
+        }
+    }
+
+    /// Folds a finished shard's clients, transactions and counts into the engine
+    fn merge(
+        &mut self,
+        mut shard: PaymentsEngine<MemoryStore, MemoryStore, P>,
+    ) -> Result<(), PaymentsError> {
+        self.clients.extend(mem::take(&mut shard.clients));
+        for transaction in shard.transaction_store.transactions()? {
+            self.transaction_store
+                .put_tx(self.key_of(&transaction), transaction)?;
+        }
+        self.retained.extend(mem::take(&mut shard.retained));
+        self.history.extend(mem::take(&mut shard.history));
+        self.fees.extend(mem::take(&mut shard.fees));
+        self.valid_records += shard.valid_records;
+        self.invariant_violations += shard.invariant_violations;
+        for (transaction_type, count) in shard.transaction_counts {
+            *self.transaction_counts.entry(transaction_type).or_insert(0) += count;
+        }
+        for (reason, count) in shard.skipped {
+            *self.skipped.entry(reason).or_insert(0) += count;
+        }
+        self.duplicate_transactions
+            .extend(shard.duplicate_transactions);
+        self.conflicting_duplicates
+            .extend(shard.conflicting_duplicates);
+        self.rejected_withdrawals.extend(shard.rejected_withdrawals);
+        self.ownership_mismatches.extend(shard.ownership_mismatches);
+        if let (Some(metrics), Some(shard_metrics)) = (self.metrics.as_mut(), shard.metrics) {
+            metrics.merge(shard_metrics);
+        }
+        if let (Some(ledger), Some(shard_ledger)) = (self.ledger.as_mut(), shard.ledger) {
+            ledger.merge(shard_ledger);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::OutputOrder;
+    use rust_decimal_macros::dec;
+
+    const INPUT: &str = "type, client, tx, amount\n\
+                         deposit, 1, 1, 1.0\n\
+                         deposit, 1, 2, 5.0\n\
+                         deposit, 2, 3, 3.0\n\
+                         deposit, 3, 4, 2.0\n\
+                         withdrawal, 2, 5, 1.0\n\
+                         deposit, 4, 2, 7.0\n\
+                         deposit, 1, 2, 7.0\n\
+                         dispute, 2, 4\n\
+                         dispute, 3, 4\n\
+                         withdrawal, 1, 6, 9.0\n\
+                         chargeback, 3, 4\n\
+                         deposit, 3, 7, 1.0\n\
+                         deposit, 4, 8, 1.0\n";
+
+    #[test]
+    fn parallel_matches_serial() {
+        let mut serial = PaymentsEngine::new();
+        serial.process(INPUT.as_bytes()).unwrap();
+        let mut parallel = PaymentsEngine::new();
+        parallel.process_parallel([INPUT.as_bytes()], 3).unwrap();
+
+        assert_eq!(
+            parallel.clients(OutputOrder::FirstSeen),
+            serial.clients(OutputOrder::FirstSeen)
+        );
+        assert_eq!(parallel.valid_records(), serial.valid_records());
+        assert_eq!(parallel.skipped(), serial.skipped());
+        let mut duplicates = parallel.duplicate_transactions().to_vec();
+        duplicates.sort_unstable();
+        assert_eq!(duplicates, serial.duplicate_transactions());
+        assert_eq!(
+            parallel.ownership_mismatches(),
+            serial.ownership_mismatches()
+        );
+    }
+
+    #[test]
+    fn rejected_rows_dont_claim_their_tx_id() {
+        let input = "type, client, tx, amount\n\
+                     withdrawal, 1, 5, 100.0\n\
+                     deposit, 2, 5, 3.0\n\
+                     dispute, 2, 5\n";
+        let mut serial = PaymentsEngine::new();
+        serial.process(input.as_bytes()).unwrap();
+        let mut parallel = PaymentsEngine::new();
+        parallel.process_parallel([input.as_bytes()], 2).unwrap();
+
+        assert_eq!(
+            parallel.clients(OutputOrder::FirstSeen),
+            serial.clients(OutputOrder::FirstSeen)
+        );
+        assert_eq!(parallel.skipped(), serial.skipped());
+        assert!(parallel.duplicate_transactions().is_empty());
+        assert!(parallel.ownership_mismatches().is_empty());
+        let client = parallel.accounts().get(&2).unwrap();
+        assert_eq!(client.held, dec!(3.0000));
+    }
+
+    #[test]
+    fn evicted_tx_ids_are_free_for_other_clients() {
+        let per_client = "type, client, tx, amount\n\
+                          deposit, 1, 1, 1.0\n\
+                          deposit, 1, 2, 1.0\n\
+                          deposit, 2, 1, 1.0\n";
+        let settled = "type, client, tx, amount\n\
+                       deposit, 1, 1, 1.0\n\
+                       dispute, 1, 1\n\
+                       chargeback, 1, 1\n\
+                       deposit, 2, 1, 1.0\n";
+        for (input, policy) in [
+            (per_client, EvictionPolicy::PerClient(1)),
+            (settled, EvictionPolicy::Settled),
+        ] {
+            let mut serial = PaymentsEngine::new();
+            serial.set_eviction_policy(policy);
+            serial.process(input.as_bytes()).unwrap();
+            let mut parallel = PaymentsEngine::new();
+            parallel.set_eviction_policy(policy);
+            parallel.process_parallel([input.as_bytes()], 2).unwrap();
+
+            assert_eq!(
+                parallel.clients(OutputOrder::FirstSeen),
+                serial.clients(OutputOrder::FirstSeen)
+            );
+            assert_eq!(parallel.skipped(), serial.skipped());
+            assert!(parallel.duplicate_transactions().is_empty());
+            assert_eq!(parallel.accounts()[&2].available, dec!(1.0000));
+        }
+    }
+
+    #[test]
+    fn transfers_are_unsupported() {
+        let input = "type, client, tx, amount, to_client\n\
+                     deposit, 1, 1, 1.0\n\
+                     transfer, 1, 2, 1.0, 2\n";
+        let mut engine = PaymentsEngine::new();
+        let err = engine.process_parallel([input.as_bytes()], 2).unwrap_err();
+        assert!(matches!(
+            err,
+            PaymentsError::Unsupported {
+                mode: ProcessingMode::Parallel,
+                feature: "Transfers",
+            }
+        ));
+    }
+}
@@ -0,0 +1,362 @@
+use super::PaymentsEngine;
+use crate::{
+    AccountRow, AccountStatus, AccountStore, DisputePolicy, PaymentsError, Transaction,
+    TransactionStore,
+};
+use arrow::array::{
+    Array, ArrayRef, AsArray, BooleanArray, Decimal128Array, StringArray, UInt16Array, UInt64Array,
+};
+use arrow::compute::cast;
+use arrow::datatypes::{DataType, TimeUnit};
+use arrow::error::ArrowError;
+use arrow::record_batch::RecordBatch;
+use bytes::Bytes;
+use csv::StringRecord;
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use parquet::arrow::ArrowWriter;
+use parquet::basic::Compression;
+use parquet::file::properties::WriterProperties;
+use parquet::file::reader::ChunkReader;
+use rust_decimal::Decimal;
+use std::fs::File;
+use std::io::{self, Write};
+use std::sync::Arc;
+
+impl<T: TransactionStore, A: AccountStore, P: DisputePolicy> PaymentsEngine<T, A, P> {
+    /// Applies the records of a Parquet file as `process_part` does, reading each
+    /// row group where it is in the file rather than loading the file whole, as
+    /// `process_part` has to for input that can't seek, whatever the input format.
+    pub fn process_parquet_file(&mut self, file: File) -> Result<(), PaymentsError> {
+        if let Some(skip) = self.records_to_skip() {
+            self.read_parquet(file, skip, &mut Self::process_parsed)?;
+            self.part_done();
+        }
+        Ok(())
+    }
+
+    /// Reads the rows of a Parquet file held in memory, for input that can't seek
+    pub(super) fn read_parquet_buffered<R, F>(
+        &mut self,
+        mut input: R,
+        skip: u64,
+        handle: &mut F,
+    ) -> Result<(), PaymentsError>
+    where
+        R: io::Read,
+        F: FnMut(&mut Self, Result<Transaction, PaymentsError>) -> Result<(), PaymentsError>,
+    {
+        // The schema is in the footer, so there's no reading a row before the end
+        let mut file = Vec::new();
+        input.read_to_end(&mut file)?;
+        self.read_parquet(Bytes::from(file), skip, handle)
+    }
+
+    /// Reads the rows of a Parquet file and hands each to `handle`, for
+    /// `InputFormat::Parquet`. Its columns are matched by name as a csv header is,
+    /// through the column map if there is one, and each value is read as the csv
+    /// field would be, so amounts can be decimals or strings and timestamps unix
+    /// seconds or timestamps. Records are numbered by row in place of lines.
+    fn read_parquet<C, F>(
+        &mut self,
+        input: C,
+        skip: u64,
+        handle: &mut F,
+    ) -> Result<(), PaymentsError>
+    where
+        C: ChunkReader + 'static,
+        F: FnMut(&mut Self, Result<Transaction, PaymentsError>) -> Result<(), PaymentsError>,
+    {
+        let size = input.len();
+        let builder = ParquetRecordBatchReaderBuilder::try_new(input)?;
+        let rows = builder.metadata().file_metadata().num_rows().max(1) as u64;
+        let header: StringRecord = builder
+            .schema()
+            .fields()
+            .iter()
+            .map(|field| field.name())
+            .collect();
+        let columns = self
+            .column_map
+            .clone()
+            .unwrap_or_default()
+            .resolve(&header)?;
+        let mut row = 0;
+        for batch in builder.build()? {
+            let batch = batch?;
+            // Only the columns read are cast, any others can be of any type
+            let mut fields = vec![None; batch.num_columns()];
+            for index in columns.named() {
+                fields[index] = Some(as_strings(batch.column(index))?);
+            }
+            for index in 0..batch.num_rows() {
+                row += 1;
+                self.stop_if_asked()?;
+                self.progress_if_due(size * row / rows);
+                self.position.records += 1;
+                if self.position.records <= skip {
+                    continue;
+                }
+                self.line = Some(row);
+                let record: StringRecord = fields
+                    .iter()
+                    .map(|field| match field {
+                        Some(values) if values.is_valid(index) => values.value(index),
+                        _ => "",
+                    })
+                    .collect();
+                let parsed = self.transaction_from_columns(&record, &columns);
+                handle(self, parsed)?;
+                self.checkpoint_if_due()?;
+            }
+        }
+        self.progress_part_done(size);
+        Ok(())
+    }
+}
+
+/// A column's values as csv fields, nulls left empty
+fn as_strings(column: &ArrayRef) -> Result<StringArray, ArrowError> {
+    let column = match column.data_type() {
+        DataType::Timestamp(_, zone) => cast(
+            &cast(column, &DataType::Timestamp(TimeUnit::Second, zone.clone()))?,
+            &DataType::Int64,
+        )?,
+        _ => Arc::clone(column),
+    };
+    Ok(cast(&column, &DataType::Utf8)?.as_string::<i32>().clone())
+}
+
+/// Writes the accounts to `writer` as Parquet, for `--output-format parquet`, with
+/// the columns of the csv output: sequence, currency, status and fees only if a row
+/// has them. Balances and fees are decimal(38, precision), with the engine's
+/// `precision`, so no precision is lost.
+pub fn write_parquet<W: Write + Send>(
+    rows: &[AccountRow],
+    precision: u32,
+    writer: W,
+) -> Result<(), PaymentsError> {
+    let any = |has: fn(&AccountRow) -> bool| rows.iter().any(has);
+    let amounts = |amount: fn(&AccountRow) -> Option<Decimal>| -> Result<ArrayRef, ArrowError> {
+        let amounts: Decimal128Array = rows
+            .iter()
+            .map(|row| amount(row).map(|amount| units(amount, precision)))
+            .collect();
+        Ok(Arc::new(
+            amounts.with_precision_and_scale(38, precision as i8)?,
+        ))
+    };
+    let mut columns: Vec<(&str, ArrayRef, bool)> = Vec::new();
+    if any(|row| row.sequence.is_some()) {
+        let sequences: UInt64Array = rows.iter().map(|row| row.sequence).collect();
+        columns.push(("sequence", Arc::new(sequences), true));
+    }
+    let clients = UInt16Array::from_iter_values(rows.iter().map(|row| row.client));
+    columns.push(("client", Arc::new(clients), false));
+    if any(|row| row.currency.is_some()) {
+        let currencies: StringArray = rows.iter().map(|row| row.currency).collect();
+        columns.push(("currency", Arc::new(currencies), true));
+    }
+    columns.push(("available", amounts(|row| Some(row.available))?, false));
+    columns.push(("held", amounts(|row| Some(row.held))?, false));
+    columns.push(("total", amounts(|row| Some(row.total))?, false));
+    let locked: BooleanArray = rows.iter().map(|row| Some(row.locked)).collect();
+    columns.push(("locked", Arc::new(locked), false));
+    if any(|row| row.status.is_some()) {
+        let statuses: StringArray = rows.iter().map(|row| row.status.map(status_name)).collect();
+        columns.push(("status", Arc::new(statuses), true));
+    }
+    if any(|row| row.fees.is_some()) {
+        columns.push(("fees", amounts(|row| row.fees)?, true));
+    }
+    let batch = RecordBatch::try_from_iter_with_nullable(columns)?;
+    let properties = WriterProperties::builder()
+        .set_compression(Compression::SNAPPY)
+        .build();
+    let mut writer = ArrowWriter::try_new(writer, batch.schema(), Some(properties))?;
+    writer.write(&batch)?;
+    writer.close()?;
+    Ok(())
+}
+
+/// An amount in units of the last decimal place written
+fn units(mut amount: Decimal, precision: u32) -> i128 {
+    amount.rescale(precision);
+    amount.mantissa()
+}
+
+/// The status as the csv output writes it
+fn status_name(status: AccountStatus) -> &'static str {
+    match status {
+        AccountStatus::Active => "active",
+        AccountStatus::Locked => "locked",
+        AccountStatus::Closed => "closed",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{InputFormat, OutputOrder};
+    use arrow::array::UInt32Array;
+
+    /// The csv fields of every row of a Parquet file, under a header of its columns
+    fn read_back(file: Vec<u8>) -> String {
+        let reader = ParquetRecordBatchReaderBuilder::try_new(Bytes::from(file))
+            .unwrap()
+            .build()
+            .unwrap();
+        let mut text = String::new();
+        for batch in reader {
+            let batch = batch.unwrap();
+            if text.is_empty() {
+                let names: Vec<_> = batch
+                    .schema()
+                    .fields()
+                    .iter()
+                    .map(|f| f.name().clone())
+                    .collect();
+                text = names.join(",") + "\n";
+            }
+            let columns: Vec<_> = batch
+                .columns()
+                .iter()
+                .map(|c| as_strings(c).unwrap())
+                .collect();
+            for index in 0..batch.num_rows() {
+                let fields: Vec<_> = columns.iter().map(|c| c.value(index)).collect();
+                text += &(fields.join(",") + "\n");
+            }
+        }
+        text
+    }
+
+    #[test]
+    fn round_trips_transactions_and_accounts() {
+        let csv = "type, client, tx, amount\n\
+                   deposit, 1, 1, 5.0\n\
+                   deposit, 2, 2, 3.25\n\
+                   withdrawal, 1, 3, 1.5\n\
+                   dispute, 2, 2,\n\
+                   chargeback, 2, 2,\n\
+                   withdrawal, 1, 4, 10.0\n";
+        let types = [
+            "deposit",
+            "deposit",
+            "withdrawal",
+            "dispute",
+            "chargeback",
+            "withdrawal",
+        ];
+        let amounts = [
+            Some(50_000),
+            Some(32_500),
+            Some(15_000),
+            None,
+            None,
+            Some(100_000),
+        ];
+        let amounts = amounts
+            .into_iter()
+            .collect::<Decimal128Array>()
+            .with_precision_and_scale(28, 4)
+            .unwrap();
+        let transactions = RecordBatch::try_from_iter([
+            (
+                "tx",
+                Arc::new(UInt32Array::from(vec![1, 2, 3, 2, 2, 4])) as ArrayRef,
+            ),
+            ("type", Arc::new(StringArray::from(types.to_vec()))),
+            (
+                "client",
+                Arc::new(UInt16Array::from(vec![1, 2, 1, 2, 2, 1])),
+            ),
+            ("amount", Arc::new(amounts)),
+        ])
+        .unwrap();
+        let mut file = Vec::new();
+        let mut writer = ArrowWriter::try_new(&mut file, transactions.schema(), None).unwrap();
+        writer.write(&transactions).unwrap();
+        writer.close().unwrap();
+
+        let mut from_csv = PaymentsEngine::new();
+        from_csv.process(csv.as_bytes()).unwrap();
+        let mut from_parquet = PaymentsEngine::new();
+        from_parquet.set_input_format(InputFormat::Parquet);
+        from_parquet.process(file.as_slice()).unwrap();
+        let rows = from_parquet.account_rows(OutputOrder::ClientId);
+        assert_eq!(rows, from_csv.account_rows(OutputOrder::ClientId));
+
+        let mut accounts = Vec::new();
+        write_parquet(&rows, 4, &mut accounts).unwrap();
+        assert_eq!(
+            read_back(accounts),
+            "client,available,held,total,locked\n\
+             1,3.5000,0.0000,3.5000,false\n\
+             2,0.0000,0.0000,0.0000,true\n"
+        );
+    }
+
+    #[test]
+    fn reads_files_where_they_are_and_keeps_the_precision() {
+        let transactions = RecordBatch::try_from_iter([
+            (
+                "type",
+                Arc::new(StringArray::from(vec!["deposit"])) as ArrayRef,
+            ),
+            ("client", Arc::new(UInt16Array::from(vec![1]))),
+            ("tx", Arc::new(UInt32Array::from(vec![1]))),
+            ("amount", Arc::new(StringArray::from(vec!["1.12345678"]))),
+        ])
+        .unwrap();
+        let path = std::env::temp_dir().join(format!(
+            "toy-payments-parquet-{}.parquet",
+            std::process::id()
+        ));
+        let mut writer =
+            ArrowWriter::try_new(File::create(&path).unwrap(), transactions.schema(), None)
+                .unwrap();
+        writer.write(&transactions).unwrap();
+        writer.close().unwrap();
+
+        let mut engine = PaymentsEngine::new();
+        engine.set_precision(8);
+        engine
+            .process_parquet_file(File::open(&path).unwrap())
+            .unwrap();
+        std::fs::remove_file(&path).unwrap();
+        engine.finish().unwrap();
+        let mut accounts = Vec::new();
+        write_parquet(
+            &engine.account_rows(OutputOrder::ClientId),
+            engine.precision(),
+            &mut accounts,
+        )
+        .unwrap();
+        assert_eq!(
+            read_back(accounts),
+            "client,available,held,total,locked\n\
+             1,1.12345678,0.00000000,1.12345678,false\n"
+        );
+    }
+
+    #[test]
+    fn needs_the_type_client_and_tx_columns() {
+        let batch = RecordBatch::try_from_iter([(
+            "type",
+            Arc::new(StringArray::from(vec!["deposit"])) as ArrayRef,
+        )])
+        .unwrap();
+        let mut file = Vec::new();
+        let mut writer = ArrowWriter::try_new(&mut file, batch.schema(), None).unwrap();
+        writer.write(&batch).unwrap();
+        writer.close().unwrap();
+
+        let mut engine = PaymentsEngine::new();
+        engine.set_input_format(InputFormat::Parquet);
+        let err = engine.process(file.as_slice()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Column map: No client column \"client\" in the header"
+        );
+    }
+}
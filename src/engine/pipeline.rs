@@ -0,0 +1,187 @@
+use super::PaymentsEngine;
+use crate::columns::Columns;
+use crate::{
+    AccountStore, AmountFormat, DisputePolicy, PaymentsError, RoundingPolicy, Transaction,
+    TransactionStore,
+};
+use csv::{Position, StringRecord};
+use rayon::prelude::*;
+use rust_decimal::Decimal;
+use std::io;
+use std::sync::mpsc;
+use std::thread;
+
+/// Records parsed together by one of the parsing threads at a time
+const CHUNK: usize = 1024;
+
+/// Windows of records, a chunk per parsing thread, read ahead of the one being
+/// applied, which bounds how far reading gets ahead of applying
+const WINDOWS_IN_FLIGHT: usize = 4;
+
+/// A record as read from the input, with where it was
+type Read = (Option<Position>, csv::Result<StringRecord>);
+/// A record once parsed, with where it was
+type Parsed = (Option<Position>, Result<Transaction, PaymentsError>);
+
+/// What parsing a record takes from the engine, handed to the parsing threads
+#[derive(Clone, Copy)]
+struct ParseSettings {
+    rounding_policy: RoundingPolicy,
+    precision: u32,
+    amount_format: AmountFormat,
+    max_amount: Option<Decimal>,
+}
+
+impl ParseSettings {
+    /// An engine parsing records the same as the one the settings were taken from
+    fn parser(self) -> PaymentsEngine {
+        let mut parser = PaymentsEngine::new();
+        parser.rounding_policy = self.rounding_policy;
+        parser.precision = self.precision;
+        parser.amount_format = self.amount_format;
+        parser.max_amount = self.max_amount;
+        parser
+    }
+}
+
+impl<T: TransactionStore, A: AccountStore, P: DisputePolicy> PaymentsEngine<T, A, P> {
+    /// Parses csv records on a pool of this many threads, while the calling thread
+    /// reads the input and applies the parsed transactions in their input order. The
+    /// outcome is the same as parsing them in turn. 0 or 1 parse on the calling
+    /// thread, which is the default.
+    pub fn set_parse_threads(&mut self, threads: usize) {
+        self.parse_threads = threads.max(1);
+    }
+
+    /// Reads the csv records after the first a window at a time and hands each
+    /// window to a thread of its own, which parses it on the parsing threads and
+    /// hands it back, so windows are parsed while those before them are applied.
+    /// Each record parsed is `take`n in input order.
+    pub(super) fn read_csv_pipelined<R, F>(
+        &mut self,
+        reader: &mut csv::Reader<R>,
+        columns: &Columns,
+        mut take: F,
+    ) -> Result<(), PaymentsError>
+    where
+        R: io::Read,
+        F: FnMut(&mut Self, Parsed) -> Result<(), PaymentsError>,
+    {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(self.parse_threads)
+            .build()
+            .map_err(io::Error::other)?;
+        let settings = ParseSettings {
+            rounding_policy: self.rounding_policy,
+            precision: self.precision,
+            amount_format: self.amount_format,
+            max_amount: self.max_amount,
+        };
+        let window = self.parse_threads * CHUNK;
+        let mut records = reader.records();
+        thread::scope(|scope| {
+            let (to_parse, reads) = mpsc::sync_channel::<Vec<Read>>(WINDOWS_IN_FLIGHT);
+            let (to_apply, parsed) = mpsc::sync_channel::<Vec<Parsed>>(WINDOWS_IN_FLIGHT);
+            scope.spawn(move || {
+                // Windows come and go in turn, so they're handed back in input order
+                for read in reads {
+                    // Collecting an indexed parallel iterator keeps the input order
+                    let window: Vec<Parsed> = pool.install(|| {
+                        read.into_par_iter()
+                            .with_min_len(CHUNK)
+                            .map_init(
+                                || settings.parser(),
+                                |parser, (position, result)| {
+                                    let transaction =
+                                        result.map_err(PaymentsError::from).and_then(|record| {
+                                            parser.transaction_from_columns(&record, columns)
+                                        });
+                                    (position, transaction)
+                                },
+                            )
+                            .collect()
+                    });
+                    // Stopped applying, after an error
+                    if to_apply.send(window).is_err() {
+                        return;
+                    }
+                }
+            });
+            let mut in_flight = 0;
+            loop {
+                let read: Vec<Read> = records
+                    .by_ref()
+                    .take(window)
+                    .map(|result| {
+                        let position = match &result {
+                            Ok(record) => record.position(),
+                            Err(err) => err.position(),
+                        };
+                        (position.cloned(), result)
+                    })
+                    .collect();
+                let done = read.len() < window;
+                if !read.is_empty() {
+                    // Never blocks, as no more windows are in flight than there's room
+                    // for on either channel
+                    let _ = to_parse.send(read);
+                    in_flight += 1;
+                }
+                while in_flight == WINDOWS_IN_FLIGHT || (done && in_flight > 0) {
+                    // Only fails if the parsing thread panicked, which the scope
+                    // passes on once it ends
+                    let Ok(window) = parsed.recv() else {
+                        return Ok(());
+                    };
+                    in_flight -= 1;
+                    for record in window {
+                        take(self, record)?;
+                    }
+                }
+                if done {
+                    return Ok(());
+                }
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Generator, OutputOrder, PaymentsEngine};
+
+    #[test]
+    fn parses_on_workers_with_the_same_outcome() {
+        let mut feed = Vec::new();
+        Generator {
+            clients: 50,
+            transactions: 10_000,
+            seed: 4,
+            malformed_rate: 0.01,
+        }
+        .write(&mut feed)
+        .unwrap();
+        let run = |threads| {
+            let mut engine = PaymentsEngine::new();
+            engine.set_record_issues(true);
+            engine.set_parse_threads(threads);
+            engine.process(&feed[..]).unwrap();
+            engine
+        };
+        let (plain, pipelined) = (run(1), run(2));
+        assert_eq!(
+            plain.clients(OutputOrder::FirstSeen),
+            pipelined.clients(OutputOrder::FirstSeen)
+        );
+        assert_eq!(plain.skipped(), pipelined.skipped());
+        assert_eq!(plain.invalid_records(), pipelined.invalid_records());
+        assert!(plain.invalid_records() > 0);
+        assert_eq!(plain.issues(), pipelined.issues());
+
+        // Failing part way through stops the workers too
+        let mut strict = PaymentsEngine::new();
+        strict.set_strict(true);
+        strict.set_parse_threads(2);
+        assert!(strict.process(&feed[..]).is_err());
+    }
+}
@@ -0,0 +1,131 @@
+use super::PaymentsEngine;
+use crate::{AccountStore, DisputePolicy, TransactionStore};
+use std::time::{Duration, Instant};
+
+/// Handed every progress update as it is made, e.g. to write it to stderr
+pub type ProgressSink = Box<dyn FnMut(&Progress) + Send>;
+
+/// Records read between looks at the clock, so timing costs next to nothing
+const RECORDS_PER_CLOCK_CHECK: u64 = 1024;
+
+/// How far into the input the engine got, across every part read so far
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Progress {
+    /// Bytes of input consumed, headers and blank lines included
+    pub bytes: u64,
+    /// Records read, whether they parsed or not
+    pub records: u64,
+    /// Since the first record was read
+    pub elapsed: Duration,
+}
+
+impl Progress {
+    /// Records read per second so far
+    pub fn records_per_sec(&self) -> f64 {
+        match self.elapsed.as_secs_f64() {
+            secs if secs > 0.0 => self.records as f64 / secs,
+            _ => 0.0,
+        }
+    }
+}
+
+/// Where the engine is in reporting progress, see `set_progress`
+pub(super) struct ProgressState {
+    sink: ProgressSink,
+    every: Duration,
+    started: Instant,
+    last: Instant,
+    /// Bytes of the parts done with
+    bytes_done: u64,
+    records: u64,
+}
+
+impl<T: TransactionStore, A: AccountStore, P: DisputePolicy> PaymentsEngine<T, A, P> {
+    /// Hands `sink` the progress through the input every `every` while `process`,
+    /// `process_part` or `process_parallel` read it, and once at the end of each part
+    pub fn set_progress(&mut self, every: Duration, sink: ProgressSink) {
+        let now = Instant::now();
+        self.progress = Some(ProgressState {
+            sink,
+            every,
+            started: now,
+            last: now,
+            bytes_done: 0,
+            records: 0,
+        });
+    }
+
+    /// Counts a record read, `offset` bytes into the current part, updating the sink
+    /// if it's been long enough since the last update
+    pub(super) fn progress_if_due(&mut self, offset: u64) {
+        let Some(progress) = self.progress.as_mut() else {
+            return;
+        };
+        progress.records += 1;
+        if progress.records % RECORDS_PER_CLOCK_CHECK != 0 {
+            return;
+        }
+        let now = Instant::now();
+        if now.duration_since(progress.last) >= progress.every {
+            progress.last = now;
+            progress.report(offset, now);
+        }
+    }
+
+    /// Updates the sink once a part of `bytes` bytes is read to the end
+    pub(super) fn progress_part_done(&mut self, bytes: u64) {
+        if let Some(progress) = self.progress.as_mut() {
+            let now = Instant::now();
+            progress.last = now;
+            progress.bytes_done += bytes;
+            progress.report(0, now);
+        }
+    }
+}
+
+impl ProgressState {
+    fn report(&mut self, offset: u64, now: Instant) {
+        let progress = Progress {
+            bytes: self.bytes_done + offset,
+            records: self.records,
+            elapsed: now.duration_since(self.started),
+        };
+        (self.sink)(&progress);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::InputFormat;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn counts_bytes_and_records_across_parts() {
+        const PART: &str = "type, client, tx, amount\n\
+                            deposit, 1, 1, 1.0\n\
+                            deposit, 1, 2, 5.0\n";
+        const JSON: &str = "{\"type\":\"deposit\",\"client\":2,\"tx\":3,\"amount\":\"1.0\"}\n\
+                            \n\
+                            {\"type\":\"deposit\",\"client\":2,\"tx\":4,\"amount\":\"1.0\"}\n";
+        let updates = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&updates);
+        let mut engine = PaymentsEngine::new();
+        engine.set_progress(
+            Duration::ZERO,
+            Box::new(move |progress| sink.lock().unwrap().push(*progress)),
+        );
+        engine.process_part(PART.as_bytes()).unwrap();
+        engine.set_input_format(InputFormat::Json);
+        engine.process_part(JSON.as_bytes()).unwrap();
+
+        let updates: Vec<_> = updates
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|progress| (progress.bytes, progress.records))
+            .collect();
+        let csv = PART.len() as u64;
+        assert_eq!(updates, [(csv, 2), (csv + JSON.len() as u64, 4)]);
+    }
+}
@@ -0,0 +1,313 @@
+use super::PaymentsEngine;
+use crate::{
+    AccountStore, ClientId, Currency, DisputePolicy, Transaction, TransactionStore, TransactionType,
+};
+use rust_decimal::Decimal;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+
+/// Funds moved through the accounts in one currency since reconciling was turned
+/// on, see `PaymentsEngine::set_reconcile`. They add up when `opening + deposits -
+/// withdrawals + adjustments + disputed_withdrawals - chargebacks - fees ==
+/// closing`.
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize)]
+pub struct LedgerTotals {
+    /// Totals of the accounts as they were when first touched
+    pub opening: Decimal,
+    pub deposits: Decimal,
+    pub withdrawals: Decimal,
+    /// Net of the back-office corrections, negative if more was taken out
+    pub adjustments: Decimal,
+    /// Withdrawals under dispute, held in the accounts pending credit back
+    pub disputed_withdrawals: Decimal,
+    /// Funds charged back out of the accounts, less withdrawals charged back to them
+    pub chargebacks: Decimal,
+    pub fees: Decimal,
+    /// Totals of the same accounts now
+    pub closing: Decimal,
+}
+
+/// An account whose total isn't what the transactions applied to it add up to
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct Discrepancy {
+    pub client: ClientId,
+    pub currency: Option<Currency>,
+    pub expected: Decimal,
+    pub actual: Decimal,
+}
+
+/// The ledger checked against the accounts, from `PaymentsEngine::reconcile`
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize)]
+pub struct Reconciliation {
+    /// By currency, `None` for transactions without one
+    pub totals: BTreeMap<Option<Currency>, LedgerTotals>,
+    /// By client and then currency
+    pub discrepancies: Vec<Discrepancy>,
+}
+
+impl Reconciliation {
+    /// Whether every account's total is what the ledger says
+    pub fn is_balanced(&self) -> bool {
+        self.discrepancies.is_empty()
+    }
+}
+
+/// Running counters of the funds every applied transaction moved, kept apart from
+/// the accounts so the two can be checked against each other
+#[derive(Debug, Default)]
+pub(super) struct Ledger {
+    /// Clients whose opening balances are taken
+    opened: HashSet<ClientId>,
+    /// Each account's total when the ledger first saw its client
+    opening: BTreeMap<(ClientId, Option<Currency>), Decimal>,
+    /// What was moved in or out of each account since
+    flows: BTreeMap<(ClientId, Option<Currency>), Decimal>,
+    /// Deposits, withdrawals, adjustments, chargebacks and fees by currency
+    totals: BTreeMap<Option<Currency>, LedgerTotals>,
+}
+
+impl Ledger {
+    /// Adds in a shard's ledger, whose clients no other shard touched
+    #[cfg(feature = "native")]
+    pub(super) fn merge(&mut self, shard: Ledger) {
+        self.opened.extend(shard.opened);
+        self.opening.extend(shard.opening);
+        self.flows.extend(shard.flows);
+        for (currency, shard_totals) in shard.totals {
+            let totals = self.totals.entry(currency).or_default();
+            totals.deposits += shard_totals.deposits;
+            totals.withdrawals += shard_totals.withdrawals;
+            totals.adjustments += shard_totals.adjustments;
+            totals.disputed_withdrawals += shard_totals.disputed_withdrawals;
+            totals.chargebacks += shard_totals.chargebacks;
+            totals.fees += shard_totals.fees;
+        }
+    }
+}
+
+impl<T: TransactionStore, A: AccountStore, P: DisputePolicy> PaymentsEngine<T, A, P> {
+    /// Keep running counters of the funds applied transactions move from here on,
+    /// for `reconcile`. Not supported with `apply_batch`.
+    pub fn set_reconcile(&mut self, reconcile: bool) {
+        self.ledger = reconcile.then(Ledger::default);
+    }
+
+    /// Checks every account touched since reconciling was turned on against what the
+    /// transactions applied to it add up to, `None` unless reconciling
+    pub fn reconcile(&self) -> Option<Reconciliation> {
+        let ledger = self.ledger.as_ref()?;
+        let mut reconciliation = Reconciliation {
+            totals: ledger.totals.clone(),
+            discrepancies: Vec::new(),
+        };
+        let mut clients: Vec<ClientId> = ledger.opened.iter().copied().collect();
+        clients.sort_unstable();
+        for id in clients {
+            let Some(client) = self.clients.get(&id) else {
+                continue;
+            };
+            let mut currencies: BTreeSet<Option<Currency>> = BTreeSet::from([None]);
+            currencies.extend(client.currencies().keys().cloned().map(Some));
+            for accounts in [&ledger.opening, &ledger.flows] {
+                let of_client = accounts
+                    .range((id, None)..)
+                    .take_while(|(key, _)| key.0 == id);
+                currencies.extend(of_client.map(|(key, _)| key.1.clone()));
+            }
+            for currency in currencies {
+                let key = (id, currency);
+                let opening = ledger.opening.get(&key).copied().unwrap_or_default();
+                let expected = opening + ledger.flows.get(&key).copied().unwrap_or_default();
+                let actual = client.balance(key.1.as_deref()).total;
+                let totals = reconciliation.totals.entry(key.1.clone()).or_default();
+                totals.opening += opening;
+                totals.closing += actual;
+                if expected != actual {
+                    reconciliation.discrepancies.push(Discrepancy {
+                        client: id,
+                        currency: key.1,
+                        expected: self.rescale(expected),
+                        actual: self.rescale(actual),
+                    });
+                }
+            }
+        }
+        // Clients holding only named currencies leave nothing without one
+        reconciliation
+            .totals
+            .retain(|_, totals| *totals != LedgerTotals::default());
+        for totals in reconciliation.totals.values_mut() {
+            for amount in [
+                &mut totals.opening,
+                &mut totals.deposits,
+                &mut totals.withdrawals,
+                &mut totals.adjustments,
+                &mut totals.disputed_withdrawals,
+                &mut totals.chargebacks,
+                &mut totals.fees,
+                &mut totals.closing,
+            ] {
+                *amount = self.rescale(*amount);
+            }
+        }
+        Some(reconciliation)
+    }
+
+    /// Takes the client's balances as its opening ones the first time the ledger
+    /// sees it, before anything is applied to it
+    pub(super) fn open_ledger_account(&mut self, id: ClientId) {
+        let Some(ledger) = self.ledger.as_mut() else {
+            return;
+        };
+        if !ledger.opened.insert(id) {
+            return;
+        }
+        if let Some(client) = self.clients.get(&id) {
+            ledger.opening.insert((id, None), client.total());
+            for (currency, balance) in client.currencies() {
+                ledger
+                    .opening
+                    .insert((id, Some(currency.clone())), balance.total);
+            }
+        }
+    }
+
+    /// Counts the funds an applied `row` moved, charged `fee`, with the transaction
+    /// it refers to for a dispute, resolve or chargeback
+    pub(super) fn post(&mut self, row: &Transaction, disputed: Option<&Transaction>, fee: Decimal) {
+        use TransactionType::*;
+        let Some(ledger) = self.ledger.as_mut() else {
+            return;
+        };
+        let transaction = disputed.unwrap_or(row);
+        let currency = &transaction.currency;
+        let amount = transaction.amount;
+        let totals = ledger.totals.entry(currency.clone()).or_default();
+        let mut flow = |client: ClientId, amount: Decimal| {
+            *ledger.flows.entry((client, currency.clone())).or_default() += amount;
+        };
+        match (&row.transaction_type, &transaction.transaction_type) {
+            (Deposit | Open, _) => {
+                totals.deposits += amount;
+                totals.fees += fee;
+                flow(row.client_id, amount - fee);
+            }
+            (Withdrawal, _) => {
+                totals.withdrawals += amount;
+                totals.fees += fee;
+                flow(row.client_id, -(amount + fee));
+            }
+            (Adjustment, _) => {
+                totals.adjustments += amount;
+                flow(row.client_id, amount);
+            }
+            (Transfer, _) => {
+                flow(row.client_id, -amount);
+                flow(row.disputed_client_id(), amount);
+            }
+            (Chargeback, Deposit) => {
+                totals.chargebacks += amount;
+                flow(transaction.client_id, -amount);
+            }
+            // A disputed withdrawal's funds are held in the account, and stay there
+            // once charged back
+            (Dispute, Withdrawal) => {
+                totals.disputed_withdrawals += amount;
+                flow(transaction.client_id, amount);
+            }
+            (Resolve, Withdrawal) => {
+                totals.disputed_withdrawals -= amount;
+                flow(transaction.client_id, -amount);
+            }
+            (Chargeback, Withdrawal) => {
+                totals.disputed_withdrawals -= amount;
+                totals.chargebacks -= amount;
+            }
+            (Chargeback, Transfer) => {
+                flow(transaction.disputed_client_id(), -amount);
+                flow(transaction.client_id, amount);
+            }
+            // Other disputes and resolves only move funds between available and held
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Fee, FeeCollection, FeeSchedule};
+    use csv::StringRecord;
+    use rust_decimal_macros::dec;
+
+    fn process(engine: &mut PaymentsEngine, rows: &[&[&str]]) {
+        for row in rows {
+            engine
+                .process_record(StringRecord::from(row.to_vec()))
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn totals_add_up_to_the_accounts() {
+        let mut engine = PaymentsEngine::new();
+        engine.set_withdrawal_disputes(true);
+        engine.set_fee_schedule(FeeSchedule {
+            deposit: Fee::default(),
+            withdrawal: Fee {
+                flat: dec!(0.5),
+                percent: dec!(0),
+            },
+            collect: FeeCollection::PerClient,
+        });
+        process(&mut engine, &[&["deposit", "3", "9", "4.0"]]);
+        engine.set_reconcile(true);
+        process(
+            &mut engine,
+            &[
+                &["deposit", "1", "1", "10.0"],
+                &["withdrawal", "1", "2", "2.0"],
+                &["deposit", "2", "3", "5.0", "USD"],
+                &["transfer", "1", "4", "3.0", "2"],
+                &["dispute", "2", "3"],
+                &["chargeback", "2", "3"],
+                &["withdrawal", "3", "5", "1.0"],
+                &["dispute", "3", "5"],
+                &["chargeback", "3", "5"],
+                &["withdrawal", "1", "6", "1.0"],
+                &["dispute", "1", "6"],
+                &["adjustment", "1", "7", "-0.5", "ops-1"],
+            ],
+        );
+
+        let reconciliation = engine.reconcile().unwrap();
+        assert!(reconciliation.is_balanced(), "{:?}", reconciliation);
+        let plain = &reconciliation.totals[&None];
+        assert_eq!(
+            (plain.opening, plain.deposits, plain.withdrawals),
+            (dec!(4), dec!(10), dec!(4))
+        );
+        assert_eq!((plain.chargebacks, plain.fees), (dec!(-1), dec!(1.5)));
+        assert_eq!(
+            (plain.adjustments, plain.disputed_withdrawals, plain.closing),
+            (dec!(-0.5), dec!(1), dec!(10))
+        );
+        let usd = &reconciliation.totals[&Some("USD".to_string())];
+        assert_eq!(
+            (usd.deposits, usd.chargebacks, usd.closing),
+            (dec!(5), dec!(5), dec!(0))
+        );
+
+        engine.clients.get_mut(&2).unwrap().total += dec!(1);
+        assert_eq!(
+            engine.reconcile().unwrap().discrepancies,
+            [Discrepancy {
+                client: 2,
+                currency: None,
+                expected: dec!(3),
+                actual: dec!(4),
+            }]
+        );
+        assert_eq!(PaymentsEngine::new().reconcile(), None);
+    }
+}
@@ -0,0 +1,135 @@
+use crate::columns::{Column, Columns, Fields};
+use crate::{ClientId, PaymentsError, TransactionType, TxId};
+use rust_decimal::Decimal;
+use serde::de::value::{self, BorrowedStrDeserializer, MapDeserializer};
+use serde::{Deserialize, Deserializer};
+use std::fmt;
+use std::str::FromStr;
+
+/// The columns every csv record starts with, whatever its type
+const LEADING: [Column; 4] = [Column::Type, Column::Client, Column::Tx, Column::Amount];
+
+/// The leading fields of a csv record, deserialized with serde before they're
+/// checked against its type. Fields the record lacks are `None`.
+#[derive(Debug, Deserialize)]
+pub(crate) struct RawRecord<'a> {
+    #[serde(rename = "type", default, deserialize_with = "trimmed")]
+    pub(crate) r#type: Option<&'a str>,
+    #[serde(default, deserialize_with = "parsed")]
+    pub(crate) client: Option<Parsed<'a, ClientId>>,
+    #[serde(default, deserialize_with = "parsed")]
+    pub(crate) tx: Option<Parsed<'a, TxId>>,
+    #[serde(default, deserialize_with = "exact_amount")]
+    pub(crate) amount: Option<Parsed<'a, Decimal>>,
+}
+
+impl<'a> RawRecord<'a> {
+    /// Deserializes the leading fields of `record` from where `columns` has them,
+    /// borrowing rather than copying them
+    pub(crate) fn read<R: Fields>(
+        record: &'a R,
+        columns: &Columns,
+    ) -> Result<RawRecord<'a>, PaymentsError> {
+        // The leading columns are in the same place for every type
+        let fields = LEADING.into_iter().filter_map(|column| {
+            let index = columns.index(column, &TransactionType::Deposit)?;
+            Some((
+                BorrowedStrDeserializer::new(column.name()),
+                BorrowedStrDeserializer::new(record.field(index)?),
+            ))
+        });
+        RawRecord::deserialize(MapDeserializer::<_, value::Error>::new(fields)).map_err(|err| {
+            PaymentsError::ParseError {
+                field: "record",
+                value: String::new(),
+                reason: err.to_string(),
+            }
+        })
+    }
+}
+
+/// A trimmed field along with what it parsed to, kept apart so a field that
+/// doesn't parse can be reported with its column's name and text
+#[derive(Debug)]
+pub(crate) struct Parsed<'a, T> {
+    pub(crate) text: &'a str,
+    value: Result<T, String>,
+}
+
+impl<'a, T> Parsed<'a, T> {
+    fn new<E: fmt::Display>(text: &'a str, value: Result<T, E>) -> Parsed<'a, T> {
+        Parsed {
+            text,
+            value: value.map_err(|err| err.to_string()),
+        }
+    }
+
+    /// The value, or the error for setting `field` from the text
+    pub(crate) fn into_value(self, field: &'static str) -> Result<T, PaymentsError> {
+        self.value.map_err(|reason| PaymentsError::ParseError {
+            field,
+            value: self.text.to_string(),
+            reason,
+        })
+    }
+}
+
+impl<'a> Parsed<'a, Decimal> {
+    /// Parses an amount without rounding it, so one with more digits than a decimal
+    /// holds is malformed rather than rounded before the rounding policy sees it
+    pub(crate) fn amount(text: &'a str) -> Parsed<'a, Decimal> {
+        let text = text.trim();
+        Parsed::new(text, Decimal::from_str_exact(text))
+    }
+}
+
+fn trimmed<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<&'de str>, D::Error> {
+    Ok(Some(<&str>::deserialize(deserializer)?.trim()))
+}
+
+fn parsed<'de, D, T>(deserializer: D) -> Result<Option<Parsed<'de, T>>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    let text = <&str>::deserialize(deserializer)?.trim();
+    Ok(Some(Parsed::new(text, text.parse::<T>())))
+}
+
+fn exact_amount<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Parsed<'de, Decimal>>, D::Error> {
+    Ok(Some(Parsed::amount(<&str>::deserialize(deserializer)?)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use csv::{ByteRecord, StringRecord};
+
+    #[test]
+    fn reads_trimmed_fields_keeping_their_text() {
+        let record = StringRecord::from(vec![" deposit", " 1", " x ", " 1.5 ", "ignored"]);
+        let raw = RawRecord::read(&record, &Columns::Positional).unwrap();
+        assert_eq!(raw.r#type, Some("deposit"));
+        assert_eq!(raw.client.unwrap().into_value("client_id").unwrap(), 1);
+        assert!(matches!(
+            raw.tx.unwrap().into_value("tx"),
+            Err(PaymentsError::ParseError { field: "tx", value, .. }) if value == "x"
+        ));
+        assert_eq!(
+            raw.amount.unwrap().into_value("amount").unwrap(),
+            Decimal::new(15, 1)
+        );
+
+        let record = ByteRecord::from(vec!["dispute", "1", "2"]);
+        let raw = RawRecord::read(&record, &Columns::Positional).unwrap();
+        assert_eq!(raw.tx.unwrap().into_value("tx").unwrap(), 2);
+        assert!(raw.amount.is_none());
+
+        // Too many digits to hold without rounding
+        let amount = Parsed::amount("0.12345678901234567890123456789");
+        assert!(amount.into_value("amount").is_err());
+    }
+}
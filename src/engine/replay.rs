@@ -0,0 +1,103 @@
+use super::{fees, PaymentsEngine};
+use crate::{
+    AccountStore, Client, ClientId, DisputePolicy, OverdraftPolicy, Transaction, TransactionStore,
+    TransactionType, TxId,
+};
+
+impl<T: TransactionStore, A: AccountStore, P: DisputePolicy> PaymentsEngine<T, A, P> {
+    /// The client's account as it stood right after tx `tx_id` posted, rebuilt by
+    /// replaying its history up to there. `None` if the history, as recorded with
+    /// `set_record_history`, doesn't have the tx for the client. Fees are charged
+    /// as the engine's fee schedule has them, so it has to be the one they were
+    /// charged with.
+    pub fn balance_at(&self, client_id: ClientId, tx_id: TxId) -> Option<Client> {
+        use TransactionType::*;
+        let history = self.history.get(&client_id)?;
+        let posted = history.iter().position(|t| {
+            t.id == tx_id && !matches!(t.transaction_type, Dispute | Resolve | Chargeback)
+        })?;
+        let mut client = Client::new(client_id);
+        for (index, row) in history[..=posted].iter().enumerate() {
+            // Replayed as it was applied, so nothing is turned away this time
+            let overdraft = OverdraftPolicy::AllowNegative;
+            let _ = match row.transaction_type {
+                Deposit | Withdrawal => {
+                    let charged = fees::charged(row, self.fee(row));
+                    client.handle_transaction(&row.transaction_type, &charged, overdraft)
+                }
+                // The receiving client is credited as a deposit
+                Transfer if row.client_id != client_id => {
+                    client.handle_transaction(&Deposit, row, overdraft)
+                }
+                Dispute | Resolve | Chargeback => {
+                    let Some(t) = disputed(&history[..index], row) else {
+                        continue;
+                    };
+                    if t.disputed_client_id() == client_id {
+                        client.handle_transaction(&row.transaction_type, t, overdraft)
+                    } else {
+                        // The sender of a transfer charged back is paid back
+                        if row.transaction_type == Chargeback {
+                            client.reverse_transfer(t);
+                        }
+                        Ok(())
+                    }
+                }
+                _ => client.handle_transaction(&row.transaction_type, row, overdraft),
+            };
+        }
+        Some(client)
+    }
+}
+
+/// The transaction `row` disputes, resolves or charges back, among those before it
+fn disputed<'a>(earlier: &'a [Transaction], row: &Transaction) -> Option<&'a Transaction> {
+    use TransactionType::*;
+    earlier
+        .iter()
+        .rev()
+        .find(|t| t.id == row.id && matches!(t.transaction_type, Deposit | Withdrawal | Transfer))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{OutputOrder, PaymentsEngine};
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn balance_at_replays_the_history_up_to_the_tx() {
+        let input = "type, client, tx, amount, to_client\n\
+                     deposit, 1, 1, 10.0\n\
+                     withdrawal, 1, 2, 4.0\n\
+                     deposit, 2, 3, 1.0\n\
+                     transfer, 1, 4, 2.0, 2\n\
+                     dispute, 1, 4\n\
+                     deposit, 1, 5, 3.0\n\
+                     chargeback, 1, 4\n\
+                     deposit, 1, 6, 1.0\n";
+        let mut engine = PaymentsEngine::new();
+        engine.set_record_history(true);
+        engine.process(input.as_bytes()).unwrap();
+
+        let at = |client, tx| {
+            engine
+                .balance_at(client, tx)
+                .map(|client| (client.available(), client.held(), client.locked()))
+        };
+        assert_eq!(at(1, 1), Some((dec!(10.0000), dec!(0.0000), false)));
+        assert_eq!(at(1, 2), Some((dec!(6.0000), dec!(0.0000), false)));
+        assert_eq!(at(1, 4), Some((dec!(4.0000), dec!(0.0000), false)));
+        assert_eq!(at(2, 4), Some((dec!(3.0000), dec!(0.0000), false)));
+        // By then the transfer was disputed, and receiving client 2 held its funds
+        assert_eq!(at(1, 5), Some((dec!(7.0000), dec!(0.0000), false)));
+        // The charged back transfer was paid back to client 1
+        assert_eq!(at(1, 6), Some((dec!(10.0000), dec!(0.0000), false)));
+        assert_eq!(at(2, 3), Some((dec!(1.0000), dec!(0.0000), false)));
+        assert_eq!(at(1, 3), None);
+        assert_eq!(at(3, 1), None);
+
+        // Replaying the whole history gives the account as it is
+        let last = engine.balance_at(1, 6).unwrap();
+        assert_eq!(&last, engine.clients(OutputOrder::FirstSeen)[0]);
+    }
+}
@@ -0,0 +1,167 @@
+use super::PaymentsEngine;
+use crate::{
+    AccountStore, DisputePolicy, Rule, Rules, SkipReason, Transaction, TransactionStore,
+    TransactionType,
+};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use tracing::warn;
+
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// What the rules have counted of a client's accepted transactions
+#[derive(Debug, Clone, Default, Eq, PartialEq, Serialize, Deserialize)]
+pub(super) struct Tally {
+    /// Deposits, withdrawals and transfers in the current input part
+    transactions: usize,
+    /// Withdrawn by day since the epoch
+    withdrawn: BTreeMap<u64, Decimal>,
+    /// Withdrawn by records without a timestamp
+    withdrawn_untimed: Decimal,
+}
+
+impl Tally {
+    fn withdrawn_on(&mut self, row: &Transaction) -> &mut Decimal {
+        match row.timestamp {
+            Some(timestamp) => self.withdrawn.entry(timestamp / SECS_PER_DAY).or_default(),
+            None => &mut self.withdrawn_untimed,
+        }
+    }
+}
+
+impl<T: TransactionStore, A: AccountStore, P: DisputePolicy> PaymentsEngine<T, A, P> {
+    /// Rejects deposits, withdrawals and transfers breaking the rules from then on,
+    /// as `SkipReason::RejectedByRule`. Only accepted transactions count towards
+    /// the limits. Not supported with `process_parallel` or `apply_batch`.
+    pub fn set_rules(&mut self, rules: Rules) {
+        self.rules = Some(rules);
+    }
+
+    pub fn rules(&self) -> Option<&Rules> {
+        self.rules.as_ref()
+    }
+
+    /// The first rule the deposit, withdrawal or transfer breaks, if any
+    pub(super) fn check_rules(&mut self, row: &Transaction) -> Result<(), SkipReason> {
+        let Some(rules) = &self.rules else {
+            return Ok(());
+        };
+        let tally = self.rule_tallies.entry(row.client_id).or_default();
+        let broken = if rules
+            .max_transactions_per_client
+            .is_some_and(|max| tally.transactions >= max)
+        {
+            Some(Rule::MaxTransactionsPerClient)
+        } else if row.transaction_type == TransactionType::Deposit
+            && rules.max_deposit.is_some_and(|max| row.amount > max)
+        {
+            Some(Rule::MaxDeposit)
+        } else if row.transaction_type == TransactionType::Withdrawal
+            && rules
+                .max_daily_withdrawal
+                .is_some_and(|max| *tally.withdrawn_on(row) + row.amount > max)
+        {
+            Some(Rule::MaxDailyWithdrawal)
+        } else {
+            None
+        };
+        match broken {
+            Some(rule) => {
+                warn!(
+                    "Rejecting {:?} of tx {} for client {}, breaks rule {}",
+                    row.transaction_type, row.id, row.client_id, rule
+                );
+                Err(SkipReason::RejectedByRule(rule))
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Counts an accepted deposit, withdrawal or transfer towards the rules
+    pub(super) fn tally_for_rules(&mut self, row: &Transaction) {
+        if self.rules.is_none() || row.transaction_type == TransactionType::Adjustment {
+            return;
+        }
+        let tally = self.rule_tallies.entry(row.client_id).or_default();
+        tally.transactions += 1;
+        if row.transaction_type == TransactionType::Withdrawal {
+            *tally.withdrawn_on(row) += row.amount;
+        }
+    }
+
+    /// Starts the per file counts afresh for the next input part
+    pub(super) fn rules_part_done(&mut self) {
+        for tally in self.rule_tallies.values_mut() {
+            tally.transactions = 0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Outcome;
+    use csv::StringRecord;
+    use rust_decimal_macros::dec;
+
+    fn apply(engine: &mut PaymentsEngine, row: &[&str]) -> Outcome {
+        engine
+            .process_record(StringRecord::from(row.to_vec()))
+            .unwrap()
+    }
+
+    #[test]
+    fn transactions_breaking_a_rule_are_rejected() {
+        let mut engine = PaymentsEngine::new();
+        engine.set_rules(Rules {
+            max_daily_withdrawal: Some(dec!(10)),
+            max_transactions_per_client: Some(4),
+            max_deposit: Some(dec!(100)),
+        });
+        let rejected = |rule| Outcome::RejectedByRule(rule);
+        let day = 1_700_006_400;
+        let (today, tomorrow) = (day.to_string(), (day + SECS_PER_DAY).to_string());
+        for (row, outcome) in [
+            (
+                &["deposit", "1", "1", "100.5"][..],
+                rejected(Rule::MaxDeposit),
+            ),
+            (&["deposit", "1", "2", "100"], Outcome::Applied),
+            (&["withdrawal", "1", "3", "6", "", &today], Outcome::Applied),
+            (
+                &["withdrawal", "1", "4", "5", "", &today],
+                rejected(Rule::MaxDailyWithdrawal),
+            ),
+            (
+                &["withdrawal", "1", "5", "5", "", &tomorrow],
+                Outcome::Applied,
+            ),
+            // Untimed withdrawals count as a day of their own
+            (&["withdrawal", "1", "6", "10"], Outcome::Applied),
+            (
+                &["deposit", "1", "7", "1"],
+                rejected(Rule::MaxTransactionsPerClient),
+            ),
+            (&["deposit", "2", "8", "1"], Outcome::Applied),
+            // Disputes aren't counted or limited
+            (&["dispute", "1", "2"], Outcome::Applied),
+        ] {
+            assert_eq!(apply(&mut engine, row), outcome, "{:?}", row);
+        }
+        assert_eq!(
+            engine.skipped()[&SkipReason::RejectedByRule(Rule::MaxDeposit)],
+            1
+        );
+        assert_eq!(engine.client(1).unwrap().available(), dec!(-21));
+
+        // The count per client starts afresh with each input part
+        engine
+            .process_part("type,client,tx,amount\n".as_bytes())
+            .unwrap();
+        assert_eq!(
+            apply(&mut engine, &["deposit", "1", "9", "1"]),
+            Outcome::Applied
+        );
+    }
+}
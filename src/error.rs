@@ -0,0 +1,195 @@
+use crate::{ClientId, Invariant, SkipReason, TxId};
+use rust_decimal::Decimal;
+use std::fmt;
+use std::io;
+use thiserror::Error;
+
+/// Why a record couldn't be processed, or processing couldn't carry on
+#[derive(Debug, Error)]
+pub enum PaymentsError {
+    /// The csv reader couldn't read a record, or the audit sink couldn't be written
+    #[error("Csv error: {0}")]
+    Csv(#[from] csv::Error),
+    #[error("Io error: {0}")]
+    Io(#[from] io::Error),
+    /// A json line that isn't a transaction
+    #[error("Json error: {0}")]
+    Json(#[from] serde_json::Error),
+    /// A csv record read in place that isn't utf-8
+    #[error("Invalid utf-8 in record: {0}")]
+    InvalidUtf8(#[from] std::str::Utf8Error),
+    #[error("Unknown transaction type {0}")]
+    UnknownTransactionType(String),
+    /// The record ends before the named column
+    #[error("Missing {0}")]
+    MissingField(&'static str),
+    /// A deposit or withdrawal without an amount
+    #[error("Missing amount for tx {0}")]
+    MissingAmount(TxId),
+    #[error("Failed to set {field} from {value} {reason}")]
+    ParseError {
+        field: &'static str,
+        value: String,
+        reason: String,
+    },
+    /// A record skipped as a partner error, only raised in strict mode
+    #[error("Rejected tx {0}, {1}")]
+    Rejected(TxId, SkipReason),
+    /// Strict mode's error for a record, with its line in the input
+    #[error("Line {line}: {source}")]
+    AtLine {
+        line: u64,
+        source: Box<PaymentsError>,
+    },
+    /// A checkpoint written by another version of the format, see `CHECKPOINT_VERSION`
+    #[error("Unsupported checkpoint version {0}")]
+    CheckpointVersion(u64),
+    /// A state saved by a later version of the format, see `STATE_VERSION`
+    #[error("Unsupported state version {0}")]
+    StateVersion(u64),
+    /// Raised when invariants are checked with `InvariantCheck::Abort`
+    #[error("Invariant {invariant} broken for client {client} by tx {tx}")]
+    InvariantBroken {
+        client: ClientId,
+        tx: TxId,
+        invariant: Invariant,
+    },
+    #[error("Unknown client {0}")]
+    UnknownClient(ClientId),
+    /// An account can't be unlocked while disputes are still open, when that's required
+    #[error("Client {0} still has {1} open disputes")]
+    OpenDisputes(ClientId, usize),
+    /// A feature turned on that the way records are being applied can't support
+    #[error("{feature} isn't supported {mode}")]
+    Unsupported {
+        mode: ProcessingMode,
+        feature: &'static str,
+    },
+    /// A fee schedule that can't be read, see `FeeSchedule::from_toml`
+    #[error("Fee schedule: {0}")]
+    FeeSchedule(String),
+    /// Rules that can't be read, see `Rules::from_toml`
+    #[error("Rules: {0}")]
+    Rules(String),
+    /// A column map that can't be read, see `ColumnMap::from_toml`, or a header
+    /// missing a column it names
+    #[error("Column map: {0}")]
+    ColumnMap(String),
+    /// A csv header naming the usual columns out of order, see `PaymentsEngine::process`
+    #[error("Header: {0}")]
+    Header(String),
+    /// A partner profile that can't be read, see `Profile::from_toml`
+    #[error("Profile: {0}")]
+    Profile(String),
+    /// A transfer with client scoped tx ids, where the receiving client couldn't
+    /// name the tx to dispute it, see `TxIdScope`
+    #[error("Transfer tx {0} isn't supported with client scoped tx ids")]
+    ScopedTransfer(TxId),
+    /// The worker panicked, or stopped on an error of its own, which is reported
+    /// instead when there is one
+    #[error("Worker for shard {0} stopped")]
+    ShardStopped(usize),
+    /// Reading stopped part way through the input, see `PaymentsEngine::set_stop_flag`
+    #[error("Interrupted before the end of the input")]
+    Interrupted,
+    /// The `AsyncPaymentsEngine` task panicked, or stopped on an error, which `finish`
+    /// returns, or the `SharedPaymentsEngine` thread panicked or was finished
+    #[error("The engine task stopped")]
+    EngineStopped,
+    /// A deposit, withdrawal or transfer of zero or less
+    #[error("Amount {1} for tx {0} isn't positive")]
+    NonPositiveAmount(TxId, Decimal),
+    /// Over the maximum set with `set_max_amount`
+    #[error("Amount {1} for tx {0} is over the maximum of {2}")]
+    AmountOverMax(TxId, Decimal, Decimal),
+    /// Only raised with the reject rounding policy
+    #[error("Amount {0} has more than {1} decimal places")]
+    ExcessPrecision(Decimal, u32),
+    #[cfg(feature = "sqlite")]
+    #[error("Sqlite error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    /// An `s3://` uri that doesn't name an object, or a failed request
+    #[cfg(feature = "s3")]
+    #[error("S3 error: {0}")]
+    S3(String),
+    #[cfg(feature = "redis")]
+    #[error("Redis error: {0}")]
+    Redis(#[from] redis::RedisError),
+    /// Another instance sharing the store wrote the account or tx since it was
+    /// read, so nothing of the transaction was written
+    #[cfg(feature = "redis")]
+    #[error("{0} was changed by another instance")]
+    StoreConflict(String),
+    /// A Parquet file that can't be read or written, or a column of it that can't be
+    /// read as text
+    #[cfg(feature = "parquet")]
+    #[error("Parquet error: {0}")]
+    Parquet(#[from] parquet::errors::ParquetError),
+    /// A column of an unexpected type, or a batch of columns that don't line up
+    #[cfg(feature = "parquet")]
+    #[error("Arrow error: {0}")]
+    Arrow(#[from] arrow::error::ArrowError),
+}
+
+/// A way of applying records that some features can't go with, see
+/// `PaymentsError::Unsupported`
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum ProcessingMode {
+    /// `PaymentsEngine::process_parallel`, where shards apply records out of input
+    /// order
+    Parallel,
+    /// `PaymentsEngine::apply_batch`, where everything done has to be rolled back
+    Batch,
+    /// A store shared between instances, where a transaction that conflicts with
+    /// another instance's is rolled back and applied again
+    Shared,
+}
+
+impl fmt::Display for ProcessingMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mode = match self {
+            ProcessingMode::Parallel => "with more than one thread",
+            ProcessingMode::Batch => "in a batch",
+            ProcessingMode::Shared => "with a store shared between instances",
+        };
+        write!(f, "{}", mode)
+    }
+}
+
+impl PaymentsError {
+    /// Whether the error is down to the input, such as a malformed record, rather
+    /// than the engine or its stores, so that other inputs can still be applied
+    pub fn is_input_error(&self) -> bool {
+        use PaymentsError::*;
+        match self {
+            AtLine { source, .. } => source.is_input_error(),
+            Csv(_)
+            | Io(_)
+            | Json(_)
+            | InvalidUtf8(_)
+            | UnknownTransactionType(_)
+            | MissingField(_)
+            | MissingAmount(_)
+            | ParseError { .. }
+            | Rejected(..)
+            | Header(_)
+            | NonPositiveAmount(..)
+            | AmountOverMax(..)
+            | ExcessPrecision(..) => true,
+            #[cfg(feature = "parquet")]
+            Parquet(_) | Arrow(_) => true,
+            _ => false,
+        }
+    }
+
+    /// Whether another instance sharing the store changed what the transaction
+    /// read, so that it can be applied again
+    #[cfg(feature = "redis")]
+    pub(crate) fn is_store_conflict(&self) -> bool {
+        match self {
+            PaymentsError::AtLine { source, .. } => source.is_store_conflict(),
+            PaymentsError::StoreConflict(_) => true,
+            _ => false,
+        }
+    }
+}
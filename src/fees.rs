@@ -0,0 +1,188 @@
+use crate::{PaymentsError, TransactionType};
+use rust_decimal::Decimal;
+use std::str::FromStr;
+use toml_edit::{DocumentMut, Item, Value};
+
+/// What a deposit or withdrawal is charged, a flat amount plus a percentage of
+/// its amount
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct Fee {
+    pub flat: Decimal,
+    /// Of the transaction's amount, 1.5 for 1.5%
+    pub percent: Decimal,
+}
+
+impl Fee {
+    /// The fee for a transaction of `amount`, before rounding to the engine's precision
+    pub fn on(&self, amount: Decimal) -> Decimal {
+        self.flat + amount * self.percent / Decimal::ONE_HUNDRED
+    }
+}
+
+/// Where the fees charged are kept
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum FeeCollection {
+    /// Apart for each client and currency, written as a `fees` column of the accounts
+    #[default]
+    PerClient,
+    /// Pooled in a single house balance across every client, by currency
+    House,
+}
+
+impl FromStr for FeeCollection {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<FeeCollection, Self::Err> {
+        match s {
+            "client" => Ok(FeeCollection::PerClient),
+            "house" => Ok(FeeCollection::House),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Fees charged on deposits and withdrawals, see `PaymentsEngine::set_fee_schedule`.
+/// A deposit credits its amount less the fee, a withdrawal debits its amount plus
+/// the fee. Read from TOML such as:
+///
+/// ```toml
+/// collect = "house"
+///
+/// [deposit]
+/// percent = "1.5"
+///
+/// [withdrawal]
+/// flat = "0.50"
+/// percent = "0.25"
+/// ```
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct FeeSchedule {
+    pub deposit: Fee,
+    pub withdrawal: Fee,
+    pub collect: FeeCollection,
+}
+
+impl FeeSchedule {
+    /// Parses a schedule, where every key is optional and missing fees are zero.
+    /// Amounts may be given as strings, integers or floats.
+    pub fn from_toml(text: &str) -> Result<FeeSchedule, PaymentsError> {
+        let document: DocumentMut = text
+            .parse()
+            .map_err(|err| PaymentsError::FeeSchedule(format!("{}", err).trim().to_string()))?;
+        let mut schedule = FeeSchedule::default();
+        for (key, item) in document.iter() {
+            match key {
+                "collect" => {
+                    schedule.collect = item
+                        .as_str()
+                        .and_then(|collect| collect.parse().ok())
+                        .ok_or_else(|| invalid("collect, expected \"client\" or \"house\""))?;
+                }
+                "deposit" => schedule.deposit = fee(key, item)?,
+                "withdrawal" => schedule.withdrawal = fee(key, item)?,
+                _ => return Err(invalid(&format!("key {}", key))),
+            }
+        }
+        Ok(schedule)
+    }
+
+    /// The fee for the transaction type, zero for types without one
+    pub fn fee(&self, transaction_type: &TransactionType, amount: Decimal) -> Decimal {
+        match transaction_type {
+            TransactionType::Deposit => self.deposit.on(amount),
+            TransactionType::Withdrawal => self.withdrawal.on(amount),
+            _ => Decimal::ZERO,
+        }
+    }
+}
+
+fn invalid(what: &str) -> PaymentsError {
+    PaymentsError::FeeSchedule(format!("Invalid {}", what))
+}
+
+/// Reads the `[deposit]` or `[withdrawal]` table named `name`
+fn fee(name: &str, item: &Item) -> Result<Fee, PaymentsError> {
+    let table = item
+        .as_table_like()
+        .ok_or_else(|| invalid(&format!("{}, expected a table", name)))?;
+    let mut fee = Fee::default();
+    for (key, item) in table.iter() {
+        let amount = item
+            .as_value()
+            .and_then(decimal)
+            .filter(|amount| *amount >= Decimal::ZERO)
+            .ok_or_else(|| {
+                invalid(&format!(
+                    "{}.{}, expected an amount of zero or more",
+                    name, key
+                ))
+            })?;
+        match key {
+            "flat" => fee.flat = amount,
+            "percent" => fee.percent = amount,
+            _ => return Err(invalid(&format!("key {}.{}", name, key))),
+        }
+    }
+    Ok(fee)
+}
+
+/// An amount given as a string, integer or float
+pub(crate) fn decimal(value: &Value) -> Option<Decimal> {
+    match value {
+        Value::String(s) => Decimal::from_str(s.value().trim()).ok(),
+        Value::Integer(i) => Some(Decimal::from(*i.value())),
+        Value::Float(f) => Decimal::from_str(&f.value().to_string()).ok(),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn reads_a_schedule() {
+        let schedule = FeeSchedule::from_toml(
+            "collect = \"house\"\n\
+             [deposit]\n\
+             percent = 1.5\n\
+             [withdrawal]\n\
+             flat = \"0.50\"\n\
+             percent = 1\n",
+        )
+        .unwrap();
+        assert_eq!(schedule.collect, FeeCollection::House);
+        assert_eq!(
+            schedule.fee(&TransactionType::Deposit, dec!(10)),
+            dec!(0.15)
+        );
+        assert_eq!(
+            schedule.fee(&TransactionType::Withdrawal, dec!(10)),
+            dec!(0.60)
+        );
+        assert_eq!(
+            schedule.fee(&TransactionType::Transfer, dec!(10)),
+            Decimal::ZERO
+        );
+
+        assert_eq!(FeeSchedule::from_toml("").unwrap(), FeeSchedule::default());
+        for bad in [
+            "collect = \"bank\"",
+            "refund = 1",
+            "deposit = 1",
+            "[deposit]\nflat = -1",
+            "[deposit]\nfixed = 1",
+            "[deposit",
+        ] {
+            assert!(
+                matches!(
+                    FeeSchedule::from_toml(bad),
+                    Err(PaymentsError::FeeSchedule(_))
+                ),
+                "{}",
+                bad
+            );
+        }
+    }
+}
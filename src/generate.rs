@@ -0,0 +1,183 @@
+use crate::{ClientId, TxId, PRECISION};
+use rust_decimal::Decimal;
+use std::io;
+
+/// Writes random but internally consistent csv feeds, for load testing and for
+/// exercising the engine at scale. Every well formed row applies cleanly with the
+/// default settings: deposits come before the withdrawals they fund, disputes refer
+/// to the client's own deposits, and resolves and chargebacks to open disputes.
+/// A client charged back is locked, so it's left alone from then on and a new client
+/// takes its place.
+#[derive(Debug, Clone)]
+pub struct Generator {
+    /// Clients picked from at random, starting with 1 to `clients`
+    pub clients: ClientId,
+    /// Rows written, malformed ones included, unless client ids run out
+    pub transactions: u64,
+    /// The same seed always gives the same feed
+    pub seed: u64,
+    /// Share of rows, from 0 to 1, that fail to parse
+    pub malformed_rate: f64,
+}
+
+/// Where a generated client's funds stand, so every row keeps them consistent
+#[derive(Debug, Default)]
+struct Account {
+    available: Decimal,
+    /// Deposits that can still be disputed
+    deposits: Vec<(TxId, Decimal)>,
+    disputed: Vec<(TxId, Decimal)>,
+}
+
+impl Generator {
+    pub fn write<W: io::Write>(&self, mut writer: W) -> io::Result<()> {
+        let mut rng = SplitMix64(self.seed);
+        // Clients not locked yet, with their accounts
+        let mut active: Vec<(ClientId, Account)> = (1..=self.clients)
+            .map(|client| (client, Account::default()))
+            .collect();
+        let mut next_client = self.clients.checked_add(1);
+        writeln!(writer, "type, client, tx, amount")?;
+        let mut tx: TxId = 0;
+        for _ in 0..self.transactions {
+            if active.is_empty() {
+                break;
+            }
+            tx += 1;
+            let slot = rng.below(active.len() as u64) as usize;
+            let (client, account) = &mut active[slot];
+            let client = *client;
+            if rng.chance(self.malformed_rate) {
+                let row = match rng.below(4) {
+                    0 => format!("deposit, {}, {}, abc", client, tx),
+                    1 => format!("refund, {}, {}, 1.0", client, tx),
+                    2 => format!("withdrawal, {}, {}", client, tx),
+                    _ => format!("deposit, x{}, {}, 1.0", client, tx),
+                };
+                writeln!(writer, "{}", row)?;
+                continue;
+            }
+            let roll = rng.below(100);
+            if roll < 10 && !account.disputed.is_empty() {
+                let picked = rng.below(account.disputed.len() as u64) as usize;
+                let (id, amount) = account.disputed.swap_remove(picked);
+                if rng.chance(0.02) {
+                    writeln!(writer, "chargeback, {}, {}", client, id)?;
+                    match next_client {
+                        Some(id) => {
+                            active[slot] = (id, Account::default());
+                            next_client = id.checked_add(1);
+                        }
+                        None => {
+                            active.swap_remove(slot);
+                        }
+                    }
+                } else {
+                    writeln!(writer, "resolve, {}, {}", client, id)?;
+                    account.available += amount;
+                }
+                continue;
+            }
+            if roll < 20 {
+                // Only deposits the available funds still cover can be disputed
+                let picked = rng.below(account.deposits.len().max(1) as u64) as usize;
+                if let Some(&(id, amount)) = account.deposits.get(picked) {
+                    if amount <= account.available {
+                        account.deposits.swap_remove(picked);
+                        account.available -= amount;
+                        account.disputed.push((id, amount));
+                        writeln!(writer, "dispute, {}, {}", client, id)?;
+                        continue;
+                    }
+                }
+            }
+            if roll < 45 && account.available > Decimal::ZERO {
+                let amount = rng.amount(account.available);
+                account.available -= amount;
+                writeln!(writer, "withdrawal, {}, {}, {}", client, tx, amount)?;
+                continue;
+            }
+            let amount = rng.amount(Decimal::new(1000, 0));
+            account.available += amount;
+            account.deposits.push((tx, amount));
+            writeln!(writer, "deposit, {}, {}, {}", client, tx, amount)?;
+        }
+        writer.flush()
+    }
+}
+
+/// Small, fast generator whose output for a seed never changes, unlike a library's
+/// that may between versions
+pub(crate) struct SplitMix64(pub(crate) u64);
+
+impl SplitMix64 {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// From 0 up to but not including `n`
+    pub(crate) fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+
+    pub(crate) fn chance(&mut self, p: f64) -> bool {
+        ((self.next() >> 11) as f64 / (1u64 << 53) as f64) < p
+    }
+
+    /// From the smallest amount up to `max`, at the engine's precision
+    fn amount(&mut self, max: Decimal) -> Decimal {
+        let units = u64::try_from((max * Decimal::new(10i64.pow(PRECISION), 0)).trunc())
+            .unwrap_or(u64::MAX)
+            .max(1);
+        Decimal::new((self.below(units) + 1) as i64, PRECISION)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Outcome, PaymentsEngine};
+    use csv::StringRecord;
+
+    fn feed(seed: u64, malformed_rate: f64) -> String {
+        let generator = Generator {
+            clients: 20,
+            transactions: 2000,
+            seed,
+            malformed_rate,
+        };
+        let mut bytes = Vec::new();
+        generator.write(&mut bytes).unwrap();
+        String::from_utf8(bytes).unwrap()
+    }
+
+    #[test]
+    fn seed_decides_the_feed() {
+        assert_eq!(feed(42, 0.1), feed(42, 0.1));
+        assert_ne!(feed(42, 0.1), feed(43, 0.1));
+    }
+
+    #[test]
+    fn every_well_formed_row_applies() {
+        let input = feed(7, 0.05);
+        let mut engine = PaymentsEngine::new();
+        let mut malformed = 0;
+        let mut types = std::collections::HashSet::new();
+        for line in input.lines().skip(1) {
+            let record: StringRecord = line.split(',').map(str::trim).collect();
+            types.insert(record[0].to_string());
+            match engine.process_record(record) {
+                Ok(outcome) => assert_eq!(outcome, Outcome::Applied, "{}", line),
+                Err(_) => malformed += 1,
+            }
+        }
+        assert!(malformed > 50 && malformed < 150, "{}", malformed);
+        for kind in ["deposit", "withdrawal", "dispute", "resolve", "chargeback"] {
+            assert!(types.contains(kind), "no {}", kind);
+        }
+    }
+}
@@ -0,0 +1,236 @@
+use crate::{
+    AccountStatus, AccountStore, Client, ClientId, DisputePolicy, OutputOrder, PaymentsEngine,
+    TransactionStore,
+};
+use proto::payments_server::{Payments, PaymentsServer};
+use serde_json::{Map, Value};
+use std::error::Error;
+use std::pin::Pin;
+use std::thread;
+use tokio::net::TcpListener;
+use tokio::sync::{mpsc, oneshot};
+use tokio_stream::wrappers::TcpListenerStream;
+use tokio_stream::{Stream, StreamExt};
+use tonic::transport::Server;
+use tonic::{Request, Response, Status, Streaming};
+
+/// Messages of proto/payments.proto, with its `Payments` server and client
+pub mod proto {
+    tonic::include_proto!("toy_payments");
+}
+
+/// A stream of replies to a streaming call
+type ReplyStream<R> = Pin<Box<dyn Stream<Item = Result<R, Status>> + Send>>;
+
+/// A call on the service, handed to the thread holding the engine along with
+/// where to send the answer
+enum Call {
+    Submit(
+        proto::Transaction,
+        oneshot::Sender<Result<proto::SubmitReply, Status>>,
+    ),
+    Account(u32, oneshot::Sender<Result<proto::Account, Status>>),
+    Accounts(oneshot::Sender<Vec<proto::Account>>),
+}
+
+/// Serves the engine over gRPC on `addr`, such as "127.0.0.1:50051", until the
+/// process is stopped, with the `Payments` service of proto/payments.proto. The
+/// server runs on a thread of its own while the calls are answered on this one,
+/// one at a time in the order they come in, as with `serve`.
+pub fn serve_grpc<T, A, P>(
+    engine: &mut PaymentsEngine<T, A, P>,
+    addr: &str,
+) -> Result<(), Box<dyn Error + Send + Sync>>
+where
+    T: TransactionStore,
+    A: AccountStore,
+    P: DisputePolicy,
+{
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    let listener = runtime.block_on(TcpListener::bind(addr))?;
+    let (calls, mut incoming) = mpsc::unbounded_channel();
+    let server = thread::spawn(move || {
+        runtime.block_on(
+            Server::builder()
+                .add_service(PaymentsServer::new(Service { calls }))
+                .serve_with_incoming(TcpListenerStream::new(listener)),
+        )
+    });
+    while let Some(call) = incoming.blocking_recv() {
+        answer(engine, call);
+    }
+    // The calls only stop coming once the server is gone
+    match server.join() {
+        Ok(served) => Ok(served?),
+        Err(_) => Err(From::from("The gRPC server panicked")),
+    }
+}
+
+/// Answers a single call. A caller that hung up in the meantime doesn't get one.
+fn answer<T, A, P>(engine: &mut PaymentsEngine<T, A, P>, call: Call)
+where
+    T: TransactionStore,
+    A: AccountStore,
+    P: DisputePolicy,
+{
+    match call {
+        Call::Submit(transaction, reply) => {
+            let _ = reply.send(submit(engine, transaction));
+        }
+        Call::Account(id, reply) => {
+            let account = ClientId::try_from(id)
+                .ok()
+                .and_then(|id| engine.client(id))
+                .map(account)
+                .ok_or_else(|| Status::not_found(format!("Unknown client {}", id)));
+            let _ = reply.send(account);
+        }
+        Call::Accounts(reply) => {
+            let accounts = engine.clients(OutputOrder::ClientId);
+            let _ = reply.send(accounts.into_iter().map(account).collect());
+        }
+    }
+}
+
+/// Applies a transaction as `POST /transactions` does, by way of its json
+fn submit<T, A, P>(
+    engine: &mut PaymentsEngine<T, A, P>,
+    transaction: proto::Transaction,
+) -> Result<proto::SubmitReply, Status>
+where
+    T: TransactionStore,
+    A: AccountStore,
+    P: DisputePolicy,
+{
+    let mut json = Map::new();
+    json.insert("type".to_string(), transaction.r#type.into());
+    json.insert("client".to_string(), transaction.client.into());
+    json.insert("tx".to_string(), transaction.tx.into());
+    if !transaction.amount.is_empty() {
+        json.insert("amount".to_string(), transaction.amount.into());
+    }
+    if let Some(to_client) = transaction.to_client {
+        json.insert("to_client".to_string(), to_client.into());
+    }
+    if let Some(currency) = transaction.currency {
+        json.insert("currency".to_string(), currency.into());
+    }
+    if let Some(reference) = transaction.reference {
+        json.insert("reference".to_string(), reference.into());
+    }
+    if let Some(timestamp) = transaction.timestamp {
+        json.insert("timestamp".to_string(), timestamp.into());
+    }
+    let row = engine
+        .transaction_from_json(&Value::Object(json).to_string())
+        .map_err(|err| Status::invalid_argument(err.to_string()))?;
+    let tx = row.id;
+    match engine.process_transaction(row) {
+        Ok(outcome) => Ok(proto::SubmitReply {
+            tx,
+            disposition: outcome.disposition().to_string(),
+        }),
+        Err(err) => Err(Status::internal(err.to_string())),
+    }
+}
+
+/// An account as sent over the wire, its amounts formatted as in the accounts output
+fn account(client: &Client) -> proto::Account {
+    let status = match client.status() {
+        AccountStatus::Active => "active",
+        AccountStatus::Locked => "locked",
+        AccountStatus::Closed => "closed",
+    };
+    let currencies = client
+        .currencies()
+        .iter()
+        .map(|(currency, balance)| proto::Balance {
+            currency: currency.clone(),
+            available: balance.available.to_string(),
+            held: balance.held.to_string(),
+            total: balance.total.to_string(),
+        })
+        .collect();
+    proto::Account {
+        client: client.id().into(),
+        available: client.available().to_string(),
+        held: client.held().to_string(),
+        total: client.total().to_string(),
+        locked: client.locked(),
+        status: status.to_string(),
+        currencies,
+    }
+}
+
+/// The `Payments` service, passing each call on to the thread holding the engine
+#[derive(Clone)]
+struct Service {
+    calls: mpsc::UnboundedSender<Call>,
+}
+
+impl Service {
+    async fn call<R>(&self, call: impl FnOnce(oneshot::Sender<R>) -> Call) -> Result<R, Status> {
+        let (reply, answered) = oneshot::channel();
+        self.calls
+            .send(call(reply))
+            .map_err(|_| Status::unavailable("The engine stopped"))?;
+        answered
+            .await
+            .map_err(|_| Status::unavailable("The engine stopped"))
+    }
+}
+
+#[tonic::async_trait]
+impl Payments for Service {
+    async fn submit_transaction(
+        &self,
+        request: Request<proto::Transaction>,
+    ) -> Result<Response<proto::SubmitReply>, Status> {
+        let transaction = request.into_inner();
+        let reply = self
+            .call(|reply| Call::Submit(transaction, reply))
+            .await??;
+        Ok(Response::new(reply))
+    }
+
+    type SubmitTransactionsStream = ReplyStream<proto::SubmitReply>;
+
+    async fn submit_transactions(
+        &self,
+        request: Request<Streaming<proto::Transaction>>,
+    ) -> Result<Response<Self::SubmitTransactionsStream>, Status> {
+        let service = self.clone();
+        let replies = request.into_inner().then(move |transaction| {
+            let service = service.clone();
+            async move {
+                let transaction = transaction?;
+                service
+                    .call(|reply| Call::Submit(transaction, reply))
+                    .await?
+            }
+        });
+        Ok(Response::new(Box::pin(replies)))
+    }
+
+    async fn get_account(
+        &self,
+        request: Request<proto::AccountRequest>,
+    ) -> Result<Response<proto::Account>, Status> {
+        let id = request.into_inner().client;
+        let account = self.call(|reply| Call::Account(id, reply)).await??;
+        Ok(Response::new(account))
+    }
+
+    type StreamAccountsStream = ReplyStream<proto::Account>;
+
+    async fn stream_accounts(
+        &self,
+        _request: Request<proto::StreamAccountsRequest>,
+    ) -> Result<Response<Self::StreamAccountsStream>, Status> {
+        let accounts = self.call(Call::Accounts).await?;
+        let accounts = tokio_stream::iter(accounts.into_iter().map(Ok));
+        Ok(Response::new(Box::pin(accounts)))
+    }
+}
@@ -0,0 +1,117 @@
+use crate::{Balance, Client};
+use rust_decimal::Decimal;
+use std::fmt;
+use std::str::FromStr;
+
+/// A property every account's balances keep after each applied transaction
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Invariant {
+    /// total == available + held
+    TotalIsAvailablePlusHeld,
+    HeldNotNegative,
+    /// Only checked on request, as a dispute of funds already withdrawn takes
+    /// available below zero
+    AvailableNotNegative,
+}
+
+impl fmt::Display for Invariant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let invariant = match self {
+            Invariant::TotalIsAvailablePlusHeld => "total == available + held",
+            Invariant::HeldNotNegative => "held >= 0",
+            Invariant::AvailableNotNegative => "available >= 0",
+        };
+        write!(f, "{}", invariant)
+    }
+}
+
+/// What the engine does when a transaction leaves an account breaking an invariant
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum InvariantCheck {
+    Off,
+    /// Log every broken invariant and carry on
+    Log,
+    /// Fail processing at the first broken invariant
+    Abort,
+}
+
+impl FromStr for InvariantCheck {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<InvariantCheck, Self::Err> {
+        match s {
+            "off" => Ok(InvariantCheck::Off),
+            "log" => Ok(InvariantCheck::Log),
+            "abort" => Ok(InvariantCheck::Abort),
+            _ => Err(()),
+        }
+    }
+}
+
+/// The invariants the client's balances break, with the currency they're broken
+/// in, `None` for funds without one
+pub(crate) fn broken(
+    client: &Client,
+    available_not_negative: bool,
+) -> Vec<(Option<&str>, Invariant)> {
+    let mut broken = Vec::new();
+    let funds = client.balance(None);
+    let currencies = client
+        .currencies()
+        .iter()
+        .map(|(currency, balance)| (Some(currency.as_str()), balance));
+    for (currency, balance) in [(None, &funds)].into_iter().chain(currencies) {
+        for invariant in broken_by(balance, available_not_negative) {
+            broken.push((currency, invariant));
+        }
+    }
+    broken
+}
+
+fn broken_by(balance: &Balance, available_not_negative: bool) -> Vec<Invariant> {
+    let mut broken = Vec::new();
+    if balance.total != balance.available + balance.held {
+        broken.push(Invariant::TotalIsAvailablePlusHeld);
+    }
+    if balance.held < Decimal::ZERO {
+        broken.push(Invariant::HeldNotNegative);
+    }
+    if available_not_negative && balance.available < Decimal::ZERO {
+        broken.push(Invariant::AvailableNotNegative);
+    }
+    broken
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn finds_broken_invariants_in_every_currency() {
+        let mut client = Client::new(1);
+        client.available = dec!(-1.0);
+        client.total = dec!(-1.0);
+        assert!(broken(&client, false).is_empty());
+        assert_eq!(
+            broken(&client, true),
+            vec![(None, Invariant::AvailableNotNegative)]
+        );
+
+        client.currencies.insert(
+            "USD".to_string(),
+            Balance {
+                available: dec!(1.0),
+                held: dec!(-2.0),
+                total: dec!(1.0),
+            },
+        );
+        assert_eq!(
+            broken(&client, false),
+            vec![
+                (Some("USD"), Invariant::TotalIsAvailablePlusHeld),
+                (Some("USD"), Invariant::HeldNotNegative),
+            ]
+        );
+    }
+}
@@ -0,0 +1,64 @@
+use crate::{AccountStore, DisputePolicy, PaymentsEngine, TransactionStore};
+use kafka::consumer::{Consumer, FetchOffset, GroupOffsetStorage};
+use std::error::Error;
+use std::time::{Duration, Instant};
+use tracing::debug;
+
+/// Where `consume` reads transactions from
+#[derive(Debug, Clone)]
+pub struct KafkaSource {
+    /// Bootstrap brokers, such as "localhost:9092"
+    pub brokers: Vec<String>,
+    pub topic: String,
+    /// Consumer group the offsets are committed for
+    pub group: String,
+    /// How often `on_snapshot` is handed the engine
+    pub snapshot_every: Duration,
+}
+
+/// Applies every message on the topic until the process is stopped. Each message
+/// holds one transaction, either a csv line without a header or a json object as
+/// read by `--input-format json`. Malformed messages are skipped as they would be
+/// in a file.
+///
+/// Offsets are only committed once every message of a poll has been applied, so a
+/// run that fails part way reads the uncommitted messages again when restarted.
+/// Every `snapshot_every` the engine is handed to `on_snapshot`, e.g. to write the
+/// accounts out. Buffered disputes are never applied, as the input never finishes.
+pub fn consume<T, A, P, F>(
+    engine: &mut PaymentsEngine<T, A, P>,
+    source: &KafkaSource,
+    mut on_snapshot: F,
+) -> Result<(), Box<dyn Error + Send + Sync>>
+where
+    T: TransactionStore,
+    A: AccountStore,
+    P: DisputePolicy,
+    F: FnMut(&PaymentsEngine<T, A, P>) -> Result<(), Box<dyn Error + Send + Sync>>,
+{
+    let mut consumer = Consumer::from_hosts(source.brokers.clone())
+        .with_topic(source.topic.clone())
+        .with_group(source.group.clone())
+        .with_fallback_offset(FetchOffset::Earliest)
+        .with_offset_storage(Some(GroupOffsetStorage::Kafka))
+        .create()?;
+    let mut last_snapshot = Instant::now();
+    loop {
+        let message_sets = consumer.poll()?;
+        for message_set in message_sets.iter() {
+            for message in message_set.messages() {
+                let parsed = engine.transaction_from_payload(message.value);
+                engine.process_parsed(parsed)?;
+            }
+            consumer.consume_messageset(message_set)?;
+        }
+        if !message_sets.is_empty() {
+            consumer.commit_consumed()?;
+            debug!("Committed offsets for {}", source.topic);
+        }
+        if last_snapshot.elapsed() >= source.snapshot_every {
+            on_snapshot(engine)?;
+            last_snapshot = Instant::now();
+        }
+    }
+}
@@ -0,0 +1,125 @@
+//! Mock payment engine: maintains client accounts while applying deposits,
+//! withdrawals, disputes, resolves and chargebacks.
+
+#[cfg(feature = "async")]
+mod async_engine;
+#[cfg(feature = "native")]
+mod bench;
+#[cfg(feature = "chaos")]
+mod chaos;
+mod client;
+mod columns;
+#[cfg(feature = "native")]
+mod compression;
+#[cfg(all(unix, feature = "native"))]
+mod control;
+mod demo;
+mod diff;
+mod dispute_policy;
+mod engine;
+mod error;
+mod fees;
+mod generate;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod invariants;
+#[cfg(feature = "kafka")]
+mod kafka;
+#[cfg(all(unix, feature = "native"))]
+mod listen;
+#[cfg(feature = "native")]
+mod manifest;
+mod metrics;
+#[cfg(feature = "native")]
+mod mmap;
+mod profile;
+mod rules;
+#[cfg(feature = "s3")]
+mod s3;
+#[cfg(feature = "server")]
+mod server;
+#[cfg(feature = "native")]
+mod shared;
+#[cfg(all(unix, feature = "native"))]
+mod shutdown;
+mod store;
+mod transaction;
+#[cfg(feature = "wasm")]
+mod wasm;
+#[cfg(feature = "native")]
+mod watch;
+
+#[cfg(feature = "async")]
+pub use async_engine::AsyncPaymentsEngine;
+#[cfg(feature = "native")]
+pub use bench::{Benchmark, BenchmarkResult, Workload};
+#[cfg(feature = "chaos")]
+pub use chaos::Chaos;
+pub use client::{
+    AccountRow, AccountStatus, Balance, Client, Outcome, OverdraftPolicy, SkipReason,
+};
+pub use columns::{ColumnMap, FixedWidths};
+#[cfg(feature = "native")]
+pub use compression::Compression;
+#[cfg(all(unix, feature = "native"))]
+pub use control::{serve_control, ControlState};
+pub use demo::process_csv;
+pub use diff::{diff_snapshots, AccountDelta};
+pub use dispute_policy::{DisputePolicy, StandardDisputePolicy};
+#[cfg(feature = "parquet")]
+pub use engine::write_parquet;
+pub use engine::{
+    upgrade_state, Alert, AlertHandler, AlertSink, Alerts, AuditFormat, BatchOutcome, Checkpoint,
+    CheckpointSink, Discrepancy, EngineEvent, EventHandler, EvictionPolicy, InputFormat, Issue,
+    LedgerTotals, OpenDispute, OutputOrder, PaymentsEngine, Progress, ProgressSink, Reconciliation,
+    Report, CHECKPOINT_VERSION, STATE_VERSION,
+};
+pub use error::{PaymentsError, ProcessingMode};
+pub use fees::{Fee, FeeCollection, FeeSchedule};
+pub use generate::Generator;
+#[cfg(feature = "grpc")]
+pub use grpc::{proto, serve_grpc};
+pub use invariants::{Invariant, InvariantCheck};
+#[cfg(feature = "kafka")]
+pub use kafka::{consume, KafkaSource};
+#[cfg(all(unix, feature = "native"))]
+pub use listen::listen;
+#[cfg(feature = "native")]
+pub use manifest::{DigestReader, FileDigest, Manifest, RecordCounts, RunConfig, StreamDigest};
+#[cfg(feature = "native")]
+pub use mmap::MappedFile;
+pub use profile::Profile;
+pub use rules::{Rule, Rules};
+#[cfg(feature = "s3")]
+pub use s3::{S3Reader, S3Uri, S3};
+#[cfg(feature = "server")]
+pub use server::{serve, serve_metrics};
+#[cfg(feature = "native")]
+pub use shared::SharedPaymentsEngine;
+#[cfg(all(unix, feature = "native"))]
+pub use shutdown::stop_on_signals;
+#[cfg(feature = "redis")]
+pub use store::RedisStore;
+#[cfg(feature = "sqlite")]
+pub use store::SqliteStore;
+pub use store::{AccountStore, MemoryStore, TransactionStore, TxKey};
+pub use transaction::{
+    AmountFormat, DisputeState, RedisputePolicy, RoundingPolicy, Transaction, TransactionType,
+    TxIdScope,
+};
+#[cfg(feature = "native")]
+pub use watch::watch;
+
+pub type TxId = u32;
+pub type ClientId = u16;
+/// Currency code such as "USD", as given in the input
+pub type Currency = String;
+/// When a transaction happened, in seconds since the Unix epoch, as given in the input
+pub type Timestamp = u64;
+
+/// Number of decimal places amounts are stored and displayed with, unless set
+/// otherwise with `PaymentsEngine::set_precision`
+pub const PRECISION: u32 = 4;
+/// Most decimal places `PaymentsEngine::set_precision` takes, leaving room for
+/// amounts in the billions
+pub const MAX_PRECISION: u32 = 18;
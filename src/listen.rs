@@ -0,0 +1,191 @@
+use crate::{AccountStore, DisputePolicy, PaymentsEngine, TransactionStore};
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixListener;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tracing::{debug, info};
+
+/// Control line asking for a snapshot, as SIGHUP does
+const FLUSH: &str = "FLUSH";
+/// How long to wait for input before looking for a SIGHUP again, in milliseconds
+const POLL_MS: libc::c_int = 200;
+
+/// Set by the SIGHUP handler, taken by the loop reading the input
+static HANGUP: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_hangup(_: libc::c_int) {
+    HANGUP.store(true, Ordering::SeqCst);
+}
+
+/// Applies transactions streamed to `path` until the process is stopped, one per
+/// line, either a csv record without a header or a json object as read by
+/// `--input-format json`. A named pipe at `path` is read from, otherwise a Unix
+/// domain socket is bound there, replacing a stale one, and its connections are
+/// read one after the other.
+///
+/// A `FLUSH` line, or a SIGHUP, applies any buffered disputes and hands the engine
+/// to `on_flush`, e.g. to write the accounts out. Malformed lines are skipped as
+/// they would be in a file, and csv header lines are ignored.
+pub fn listen<T, A, P, F>(
+    engine: &mut PaymentsEngine<T, A, P>,
+    path: &Path,
+    mut on_flush: F,
+) -> Result<(), Box<dyn Error + Send + Sync>>
+where
+    T: TransactionStore,
+    A: AccountStore,
+    P: DisputePolicy,
+    F: FnMut(&PaymentsEngine<T, A, P>) -> Result<(), Box<dyn Error + Send + Sync>>,
+{
+    // SAFETY: the handler only stores to an atomic, which is async-signal-safe
+    unsafe {
+        let handler: extern "C" fn(libc::c_int) = on_hangup;
+        libc::signal(libc::SIGHUP, handler as libc::sighandler_t);
+    }
+    let file_type = fs::metadata(path).map(|metadata| metadata.file_type()).ok();
+    if file_type.is_some_and(|file_type| file_type.is_fifo()) {
+        // Opened for writing too, so the pipe stays open from one producer to the next
+        let mut pipe = OpenOptions::new().read(true).write(true).open(path)?;
+        info!("Reading transactions from pipe {}", path.display());
+        return read_lines(engine, &mut pipe, &mut on_flush);
+    }
+    if file_type.is_some_and(|file_type| file_type.is_socket()) {
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)
+        .map_err(|err| format!("Failed to listen on {} {}", path.display(), err))?;
+    listener.set_nonblocking(true)?;
+    info!("Listening for transactions on {}", path.display());
+    loop {
+        match listener.accept() {
+            Ok((mut stream, _)) => {
+                debug!("Producer connected");
+                stream.set_nonblocking(false)?;
+                read_lines(engine, &mut stream, &mut on_flush)?;
+                debug!("Producer disconnected");
+            }
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                flush_on_hangup(engine, &mut on_flush)?;
+                wait_readable(&listener)?;
+            }
+            Err(err) => return Err(err.into()),
+        }
+    }
+}
+
+/// Applies every line read from `input` until it's closed
+fn read_lines<T, A, P, F, R>(
+    engine: &mut PaymentsEngine<T, A, P>,
+    input: &mut R,
+    on_flush: &mut F,
+) -> Result<(), Box<dyn Error + Send + Sync>>
+where
+    T: TransactionStore,
+    A: AccountStore,
+    P: DisputePolicy,
+    F: FnMut(&PaymentsEngine<T, A, P>) -> Result<(), Box<dyn Error + Send + Sync>>,
+    R: Read + AsRawFd,
+{
+    let mut pending = Vec::new();
+    let mut chunk = [0; 8192];
+    loop {
+        flush_on_hangup(engine, on_flush)?;
+        if !wait_readable(input)? {
+            continue;
+        }
+        let read = match input.read(&mut chunk) {
+            Ok(0) => return Ok(()),
+            Ok(read) => read,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err.into()),
+        };
+        pending.extend_from_slice(&chunk[..read]);
+        while let Some(end) = pending.iter().position(|byte| *byte == b'\n') {
+            let line: Vec<u8> = pending.drain(..=end).collect();
+            apply_line(engine, &line, on_flush)?;
+        }
+    }
+}
+
+fn apply_line<T, A, P, F>(
+    engine: &mut PaymentsEngine<T, A, P>,
+    line: &[u8],
+    on_flush: &mut F,
+) -> Result<(), Box<dyn Error + Send + Sync>>
+where
+    T: TransactionStore,
+    A: AccountStore,
+    P: DisputePolicy,
+    F: FnMut(&PaymentsEngine<T, A, P>) -> Result<(), Box<dyn Error + Send + Sync>>,
+{
+    let text = String::from_utf8_lossy(line);
+    let text = text.trim();
+    if text.is_empty()
+        || text
+            .split(',')
+            .next()
+            .is_some_and(|field| field.trim() == "type")
+    {
+        return Ok(());
+    }
+    if text == FLUSH {
+        return flush(engine, on_flush);
+    }
+    let parsed = engine.transaction_from_payload(text.as_bytes());
+    engine.process_parsed(parsed)?;
+    Ok(())
+}
+
+fn flush_on_hangup<T, A, P, F>(
+    engine: &mut PaymentsEngine<T, A, P>,
+    on_flush: &mut F,
+) -> Result<(), Box<dyn Error + Send + Sync>>
+where
+    T: TransactionStore,
+    A: AccountStore,
+    P: DisputePolicy,
+    F: FnMut(&PaymentsEngine<T, A, P>) -> Result<(), Box<dyn Error + Send + Sync>>,
+{
+    if HANGUP.swap(false, Ordering::SeqCst) {
+        debug!("SIGHUP received");
+        flush(engine, on_flush)?;
+    }
+    Ok(())
+}
+
+fn flush<T, A, P, F>(
+    engine: &mut PaymentsEngine<T, A, P>,
+    on_flush: &mut F,
+) -> Result<(), Box<dyn Error + Send + Sync>>
+where
+    T: TransactionStore,
+    A: AccountStore,
+    P: DisputePolicy,
+    F: FnMut(&PaymentsEngine<T, A, P>) -> Result<(), Box<dyn Error + Send + Sync>>,
+{
+    engine.apply_deferred()?;
+    on_flush(engine)
+}
+
+/// Waits up to `POLL_MS` for `fd` to have something to read, returning whether it
+/// does. A signal arriving cuts the wait short.
+fn wait_readable<R: AsRawFd>(fd: &R) -> io::Result<bool> {
+    let mut poll = libc::pollfd {
+        fd: fd.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    // SAFETY: a single pollfd that outlives the call
+    match unsafe { libc::poll(&mut poll, 1, POLL_MS) } {
+        -1 => match io::Error::last_os_error() {
+            err if err.kind() == io::ErrorKind::Interrupted => Ok(false),
+            err => Err(err),
+        },
+        0 => Ok(false),
+        _ => Ok(true),
+    }
+}
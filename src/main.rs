@@ -1,328 +1,2213 @@
-use core::borrow::Borrow;
-use core::hash::Hash;
-use core::hash::Hasher;
-use csv::StringRecord;
-use rust_decimal::prelude::*;
+use clap::{Args, Parser, Subcommand};
+use log::LevelFilter;
 use rust_decimal::Decimal;
-use serde::{Deserialize, Serialize};
-use std::collections::HashSet;
 use std::error::Error;
-use std::ffi::OsString;
-use std::fs::File;
-use std::io::{self};
-use std::{env, process};
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+#[cfg(unix)]
+use toy_payments::ControlState;
+use toy_payments::{
+    AccountRow, AccountStore, AlertSink, Alerts, AmountFormat, AuditFormat, Benchmark,
+    BenchmarkResult, Checkpoint, ClientId, ColumnMap, Compression, DigestReader, Discrepancy,
+    EvictionPolicy, FeeCollection, FeeSchedule, FileDigest, Generator, InputFormat, InvariantCheck,
+    Manifest, MappedFile, MemoryStore, OutputOrder, OverdraftPolicy, PaymentsEngine, PaymentsError,
+    Profile, Progress, RedisputePolicy, RoundingPolicy, Rules, RunConfig, StandardDisputePolicy,
+    TransactionStore, TxId, TxIdScope, Workload, MAX_PRECISION, STATE_VERSION,
+};
 
-type TxId = u32;
-type ClientId = u16;
+/// Exit code for failures while processing the transactions
+const EXIT_FAILURE: i32 = 1;
+/// Exit code for invalid command line usage, such as an unknown option
+const EXIT_USAGE: i32 = 2;
+/// Exit code for a run stopped by SIGINT or SIGTERM part way through its input
+const EXIT_INTERRUPTED: i32 = 3;
+/// How often `--progress` writes to stderr
+const PROGRESS_EVERY: Duration = Duration::from_secs(1);
+/// For `--dispute-window-days`
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+/// What `--store redis:<addr>` starts its keys with
+#[cfg(feature = "redis")]
+const REDIS_PREFIX: &str = "toy-payments";
 
-#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
-enum TransactionType {
-    Deposit,
-    Withdrawal,
-    Dispute,
-    Resolve,
-    Chargeback,
+/// The engine, with its stores picked by `--store`
+type Engine = PaymentsEngine<Box<dyn TransactionStore>, Box<dyn AccountStore>>;
+
+/// How the accounts are written out
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum OutputFormat {
+    Csv,
+    /// A single json array of accounts
+    Json,
+    /// One json account per line
+    JsonLines,
+    /// A Parquet file, with the `parquet` feature
+    Parquet,
 }
 
-impl FromStr for TransactionType {
+impl FromStr for OutputFormat {
     type Err = ();
 
-    fn from_str(s: &str) -> Result<TransactionType, Self::Err> {
+    fn from_str(s: &str) -> Result<OutputFormat, Self::Err> {
         match s {
-            "deposit" => Ok(TransactionType::Deposit),
-            "withdrawal" => Ok(TransactionType::Withdrawal),
-            "dispute" => Ok(TransactionType::Dispute),
-            "resolve" => Ok(TransactionType::Resolve),
-            "chargeback" => Ok(TransactionType::Chargeback),
+            "csv" => Ok(OutputFormat::Csv),
+            "json" => Ok(OutputFormat::Json),
+            "jsonl" => Ok(OutputFormat::JsonLines),
+            "parquet" => Ok(OutputFormat::Parquet),
             _ => Err(()),
         }
     }
 }
 
-#[derive(Eq, Serialize, Deserialize, Debug, Clone)]
-struct Transaction {
-    id: TxId,
-    transaction_type: TransactionType,
-    client_id: ClientId,
-    amount: Decimal,
+/// What a continuous run writes each time it writes the accounts out
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum OutputMode {
+    /// Every account
+    Full,
+    /// Only the accounts changed since the last time, each row with the sequence
+    /// number of the delta
+    Delta,
 }
 
-impl PartialEq for Transaction {
-    fn eq(&self, other: &Transaction) -> bool {
-        self.id == other.id
+impl FromStr for OutputMode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<OutputMode, Self::Err> {
+        match s {
+            "full" => Ok(OutputMode::Full),
+            "delta" => Ok(OutputMode::Delta),
+            _ => Err(()),
+        }
     }
 }
 
-impl Hash for Transaction {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        self.id.hash(state);
-    }
+/// Where transactions are read from
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Source {
+    /// The files given as args, or stdin
+    File,
+    /// A Kafka topic, with the `kafka` feature
+    Kafka,
 }
 
-impl Borrow<TxId> for Transaction {
-    fn borrow(&self) -> &TxId {
-        &self.id
+impl FromStr for Source {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Source, Self::Err> {
+        match s {
+            "file" => Ok(Source::File),
+            "kafka" => Ok(Source::Kafka),
+            _ => Err(()),
+        }
     }
 }
 
-#[derive(Eq, Clone, Debug, Serialize)]
-struct Client {
-    #[serde(rename(serialize = "client"))]
-    id: ClientId,
-    available: Decimal,
-    held: Decimal,
-    total: Decimal,
-    locked: bool,
-    #[serde(skip_serializing)]
-    disputes: HashSet<TxId>,
+/// How log lines are written to stderr
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum LogFormat {
+    Text,
+    /// One json object per line, for log pipelines
+    Json,
 }
 
-impl PartialEq for Client {
-    fn eq(&self, other: &Client) -> bool {
-        self.id == other.id
+impl FromStr for LogFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<LogFormat, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(()),
+        }
     }
 }
 
-impl Hash for Client {
-    fn hash<H: Hasher>(&self, state: &mut H) {
-        self.id.hash(state);
+/// Parses an option with the `FromStr` of one of the enums above, whose errors
+/// carry nothing
+fn parse_value<T: FromStr<Err = ()>>(s: &str) -> Result<T, String> {
+    s.parse().map_err(|_| format!("unknown value {:?}", s))
+}
+
+/// Parses `--input-format`, telling apart parquet in a build without the feature
+fn parse_input_format(s: &str) -> Result<InputFormat, String> {
+    if cfg!(not(feature = "parquet")) && s == "parquet" {
+        return Err("can't read parquet, built without the parquet feature".to_string());
     }
+    parse_value(s)
+}
+
+/// Applies a csv file of transactions and writes the resulting accounts to stdout.
+/// Without a subcommand the args are taken as for `process`.
+#[derive(Parser)]
+#[command(version, about, args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Log at this level and above: off, error, warn, info, debug or trace, in
+    /// place of `RUST_LOG`
+    #[arg(long, global = true, value_name = "LEVEL", value_parser = parse_log_level)]
+    log_level: Option<LevelFilter>,
+    /// text, or json for one json object per log line
+    #[arg(long, global = true, value_name = "FORMAT", default_value = "text", value_parser = parse_value::<LogFormat>)]
+    log_format: LogFormat,
+    #[command(flatten)]
+    process: ProcessArgs,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Applies the transactions and writes the accounts
+    Process(ProcessArgs),
+    /// Applies the transactions and writes a summary to stderr instead of the accounts
+    Validate(ProcessArgs),
+    /// Applies the transactions and writes the system wide totals as json
+    Report(ReportArgs),
+    /// Keeps the engine running behind an http api, with the `server` feature, or
+    /// gRPC with `--grpc` and the `grpc` feature
+    Serve(ServeArgs),
+    /// Applies every file dropped into a directory as it arrives, writing the
+    /// accounts after each one
+    Watch(WatchArgs),
+    /// Writes a random but internally consistent csv feed, for load testing
+    #[command(visible_alias = "fuzz")]
+    Generate(GenerateArgs),
+    /// Applies a synthetic workload in memory and writes its throughput and peak
+    /// memory
+    Bench(BenchArgs),
+    /// Writes the transactions applied to a client, as recorded in a state file with
+    /// `--history`, as one json object per line
+    History(HistoryArgs),
+    /// Compares two accounts csvs, writing how each changed client's balances moved
+    /// and listing newly locked accounts on stderr
+    Diff(DiffArgs),
+    /// Works on state files
+    #[command(subcommand)]
+    State(StateCommand),
+}
+
+#[derive(Subcommand)]
+enum StateCommand {
+    /// Migrates a state file saved by an earlier version in place
+    Upgrade {
+        /// State file to upgrade
+        file: OsString,
+    },
+}
+
+/// Options for how the engine applies transactions, common to every subcommand
+#[derive(Args)]
+struct EngineArgs {
+    /// TOML file of partner profiles, for `--profile`
+    #[arg(long, value_name = "PATH", requires = "profile")]
+    config: Option<OsString>,
+    /// Apply the named profile from `--config` before the other options, which
+    /// override its settings
+    #[arg(long, value_name = "NAME", requires = "config")]
+    profile: Option<String>,
+    /// Fail on the first record that can't be parsed or is a partner error, such as
+    /// an unknown tx, naming its line
+    #[arg(long)]
+    strict: bool,
+    /// Skip and count such records instead, listing them on stderr once done. The default.
+    #[arg(long, conflicts_with = "strict")]
+    lenient: bool,
+    /// csv, json for one json transaction per line, fixed-width:<widths> for
+    /// fields at fixed widths, such as fixed-width:type=10,client=6,_=2,tx=10,amount=16
+    /// where _ is filler, or parquet with the parquet feature
+    #[arg(long, value_name = "FORMAT", value_parser = parse_input_format)]
+    input_format: Option<InputFormat>,
+    /// What csv fields are separated by, such as | for pipe delimited input, or \t
+    /// for a tab [default: ,]
+    #[arg(long, value_name = "CHAR", value_parser = parse_delimiter)]
+    delimiter: Option<u8>,
+    /// How csv amounts are written: plain, en for $1,234.56 or eu for 1.234,56 €
+    #[arg(long, value_name = "FORMAT", value_parser = parse_value::<AmountFormat>)]
+    amount_format: Option<AmountFormat>,
+    /// Match csv columns by the header names in this TOML map instead of by position
+    #[arg(long, value_name = "PATH")]
+    column_map: Option<OsString>,
+    /// How amounts with more than `--precision` decimal places are rounded, and
+    /// balances in the output
+    #[arg(long, value_name = "POLICY", value_parser = parse_value::<RoundingPolicy>)]
+    rounding: Option<RoundingPolicy>,
+    /// Decimal places amounts are read and balances written with [default: 4]
+    #[arg(
+        long,
+        value_name = "PLACES",
+        value_parser = clap::value_parser!(u32).range(0..=i64::from(MAX_PRECISION))
+    )]
+    precision: Option<u32>,
+    /// Apply disputes, resolves and chargebacks only after every other record
+    #[arg(long)]
+    buffered: bool,
+    /// Which disputes are let through: standard, any-tx to dispute withdrawals too,
+    /// or lenient to also work disputes on locked accounts and dispute resolved
+    /// transactions again. The flags below turn each of those on over it.
+    #[arg(long, value_name = "POLICY", value_parser = parse_value::<StandardDisputePolicy>)]
+    dispute_policy: Option<StandardDisputePolicy>,
+    /// Let withdrawals be disputed as well as deposits
+    #[arg(long)]
+    dispute_withdrawals: bool,
+    /// Reject disputes filed more than this many days after their tx, going by the
+    /// timestamp column
+    #[arg(long, value_name = "DAYS")]
+    dispute_window_days: Option<u64>,
+    /// Still apply disputes and resolves to locked accounts
+    #[arg(long)]
+    disputes_on_locked: bool,
+    /// Whether a resolved transaction can be disputed again
+    #[arg(long, value_name = "POLICY", value_parser = parse_value::<RedisputePolicy>)]
+    redispute: Option<RedisputePolicy>,
+    /// Whether tx ids are unique across clients or only per client: global or client
+    #[arg(long, value_name = "SCOPE", value_parser = parse_value::<TxIdScope>)]
+    tx_id_scope: Option<TxIdScope>,
+    /// How far withdrawals and transfers may take available funds below zero:
+    /// reject, allow-negative or allow-up-to:<limit>
+    #[arg(long, value_name = "POLICY", value_parser = parse_value::<OverdraftPolicy>)]
+    overdraft: Option<OverdraftPolicy>,
+    /// The overdraft policy for one client instead, such as 3=allow-negative, may be
+    /// repeated
+    #[arg(long, value_name = "CLIENT=POLICY", value_parser = parse_client_overdraft)]
+    client_overdraft: Vec<(ClientId, OverdraftPolicy)>,
+    /// Reject deposits, withdrawals and transfers over this amount
+    #[arg(long, value_name = "AMOUNT")]
+    max_amount: Option<Decimal>,
+    /// Charge deposits and withdrawals the fees in this TOML schedule
+    #[arg(long, value_name = "PATH")]
+    fees: Option<OsString>,
+    /// Reject deposits, withdrawals and transfers breaking the limits in this TOML file
+    #[arg(long, value_name = "PATH")]
+    rules: Option<OsString>,
+    /// Alert on available funds going below zero and on locked accounts: stderr, or
+    /// an http(s) url to post each alert to as json with the webhook feature
+    #[arg(long, value_name = "SINK")]
+    alerts: Option<String>,
+    /// Also alert when a dispute takes a client's held funds over this amount
+    #[arg(long, value_name = "AMOUNT", requires = "alerts")]
+    alert_held_over: Option<Decimal>,
+    /// Delay, reorder and duplicate records before they're applied, such as
+    /// seed=7,reorder=16,duplicate=0.01,delay=0.001,max-delay-ms=200. Needs the chaos
+    /// feature.
+    #[arg(long, value_name = "SETTINGS")]
+    chaos: Option<String>,
+    /// Keep only each client's latest n transactions for disputes
+    #[arg(long, value_name = "N")]
+    max_tx_per_client: Option<usize>,
+    /// How kept transactions are dropped: keep-all, settled, window:<n> or per-client:<n>
+    #[arg(long, value_name = "POLICY", value_parser = parse_value::<EvictionPolicy>)]
+    evict: Option<EvictionPolicy>,
+    /// Where kept transactions are held: memory, sqlite:<path> or redis:<addr>
+    #[arg(long, value_name = "STORE")]
+    store: Option<String>,
+    /// Append every parsed record to this trail
+    #[arg(long, value_name = "PATH")]
+    audit: Option<OsString>,
+    /// csv, or jsonl for one json record per line
+    #[arg(long, value_name = "FORMAT", default_value = "csv", value_parser = parse_value::<AuditFormat>)]
+    audit_format: AuditFormat,
+    /// Write a double entry journal of the applied transactions to this csv file
+    #[arg(long, value_name = "PATH")]
+    export_ledger: Option<OsString>,
+    /// Load the engine state from this file, if it exists, and save it once done
+    #[arg(long, value_name = "PATH")]
+    state_file: Option<OsString>,
+    /// Unlock the client before processing, may be repeated
+    #[arg(long, value_name = "CLIENT")]
+    unlock: Vec<ClientId>,
+    /// Keep every client's applied transactions in the state file, for `history`
+    #[arg(long)]
+    history: bool,
+    /// Check every account a transaction touched once it's applied, and log or
+    /// abort on broken invariants: off, log or abort
+    #[arg(long, value_name = "MODE", default_value = "off", value_parser = parse_value::<InvariantCheck>)]
+    check_invariants: InvariantCheck,
+    /// Also count available funds below zero as a broken invariant
+    #[arg(long)]
+    check_available: bool,
+}
+
+#[derive(Args)]
+struct ProcessArgs {
+    /// Files to read in order as one input, `-` for stdin. Quoted globs are expanded.
+    #[arg(value_name = "FILE")]
+    file_paths: Vec<OsString>,
+    #[command(flatten)]
+    engine: EngineArgs,
+    /// Worker threads to shard clients over
+    #[arg(long, default_value_t = 1)]
+    threads: usize,
+    /// Threads to parse csv records on, while the main thread reads and applies them
+    #[arg(long, value_name = "N", default_value_t = 1)]
+    parse_threads: usize,
+    /// Memory-map the input files and parse csv records in place, for large files
+    #[arg(long)]
+    mmap: bool,
+    /// Write the accounts to this file instead of stdout, replacing it only once
+    /// they're all written
+    #[arg(long, value_name = "PATH")]
+    output: Option<OsString>,
+    /// csv, json for a single array, jsonl for one account per line, or parquet
+    /// with the parquet feature
+    #[arg(long, value_name = "FORMAT", default_value = "csv", value_parser = parse_value::<OutputFormat>)]
+    output_format: OutputFormat,
+    /// client-id, or first-seen for the order clients appeared in the input
+    #[arg(long, value_name = "ORDER", default_value = "client-id", value_parser = parse_value::<OutputOrder>)]
+    order: OutputOrder,
+    /// Only write the accounts of these clients, such as 3 or 3,7, may be repeated
+    #[arg(long = "client", value_name = "ID", value_delimiter = ',')]
+    clients: Vec<ClientId>,
+    /// Only write accounts whose total is at least this
+    #[arg(long, value_name = "AMOUNT")]
+    min_total: Option<Decimal>,
+    /// Only write locked accounts
+    #[arg(long)]
+    locked_only: bool,
+    /// Same as the `validate` subcommand
+    #[arg(long)]
+    validate: bool,
+    /// Check once done that every account's total is what the deposits,
+    /// withdrawals, chargebacks and fees applied to it add up to
+    #[arg(long)]
+    reconcile: bool,
+    /// Also write the system wide totals to stderr as json
+    #[arg(long)]
+    report: bool,
+    /// Also write counts by transaction type and skip reason, and the totals, to stderr
+    #[arg(long)]
+    summary: bool,
+    /// Write the `--summary` to this file instead of stderr
+    #[arg(long, value_name = "PATH")]
+    summary_file: Option<OsString>,
+    /// Also write every transaction still under dispute to this file as csv, with
+    /// its age in days, for chasing partners
+    #[arg(long, value_name = "PATH")]
+    disputes_output: Option<OsString>,
+    /// Write a checkpoint to this file every `--checkpoint-every` records, removed
+    /// once the run is done
+    #[arg(long, value_name = "PATH")]
+    checkpoint: Option<OsString>,
+    /// Records read between checkpoints
+    #[arg(long, value_name = "N", default_value_t = 10_000)]
+    checkpoint_every: usize,
+    /// Carry on from the `--checkpoint` an interrupted run left, if there is one,
+    /// instead of from the state file
+    #[arg(long, requires = "checkpoint")]
+    resume: bool,
+    /// Write how much of the input was read, and how fast, to stderr every second
+    #[arg(long)]
+    progress: bool,
+    /// Once done, write a json manifest of the run to this file: the inputs' and
+    /// output's sha-256, the record counts, rejections by reason, the version, and
+    /// the args along with the config files they name
+    #[arg(long, value_name = "PATH")]
+    manifest: Option<OsString>,
+    /// Read transactions streamed to this Unix domain socket or named pipe instead
+    /// of files, writing the accounts on SIGHUP or a FLUSH line
+    #[arg(long, value_name = "PATH", conflicts_with = "file_paths")]
+    listen: Option<OsString>,
+    /// file, or kafka to consume a topic instead
+    #[arg(long, default_value = "file", value_parser = parse_value::<Source>)]
+    source: Source,
+    /// full to write every account each time they're written out while consuming
+    /// Kafka or listening, or delta for only those changed since the last time. The
+    /// watch subcommand takes it too, for the accounts written after each file
+    #[arg(long, value_name = "MODE", default_value = "full", value_parser = parse_value::<OutputMode>)]
+    output_mode: OutputMode,
+    /// With --output-mode delta, write every account every n times instead, starting
+    /// with the first
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    full_snapshot_every: Option<u64>,
+    /// Kafka bootstrap brokers, comma separated
+    #[arg(long, value_delimiter = ',', default_value = "localhost:9092")]
+    brokers: Vec<String>,
+    /// Kafka topic to consume
+    #[arg(long, required_if_eq("source", "kafka"))]
+    topic: Option<String>,
+    /// Kafka consumer group the offsets are committed for
+    #[arg(long, default_value = "toy-payments")]
+    group: String,
+    /// How often the accounts are written out while consuming Kafka
+    #[arg(long, value_name = "SECS", default_value_t = 60)]
+    snapshot_secs: u64,
+}
+
+#[derive(Args)]
+struct ServeArgs {
+    /// Address to serve on
+    #[arg(long, value_name = "ADDR", default_value = "127.0.0.1:8080")]
+    listen: String,
+    /// Serve the gRPC service of proto/payments.proto instead of the http api
+    #[arg(long)]
+    grpc: bool,
+    #[command(flatten)]
+    engine: EngineArgs,
+}
+
+#[derive(Args)]
+struct WatchArgs {
+    /// Directory to pick transaction files up from
+    #[arg(value_name = "DIR")]
+    dir: OsString,
+    #[command(flatten)]
+    engine: EngineArgs,
+    /// Look at the directory for new files this often, for file systems that don't
+    /// report changes such as network mounts, instead of waiting to be told
+    #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    poll_secs: Option<u64>,
+    /// Serve Prometheus metrics at /metrics on this address, such as 127.0.0.1:9100,
+    /// updated after each input file. Needs the server feature.
+    #[arg(long, value_name = "ADDR")]
+    metrics_addr: Option<String>,
+    /// Answer GET <client>, SNAPSHOT <path> and STATS commands on a Unix socket bound
+    /// here, as of the last input file
+    #[arg(long, value_name = "PATH")]
+    control: Option<OsString>,
+    /// Write the accounts to this file instead of stdout, replacing it after each
+    /// input file
+    #[arg(long, value_name = "PATH")]
+    output: Option<OsString>,
+    /// csv, json for a single array, jsonl for one account per line, or parquet
+    /// with the parquet feature
+    #[arg(long, value_name = "FORMAT", default_value = "csv", value_parser = parse_value::<OutputFormat>)]
+    output_format: OutputFormat,
+    /// client-id, or first-seen for the order clients appeared in the input
+    #[arg(long, value_name = "ORDER", default_value = "client-id", value_parser = parse_value::<OutputOrder>)]
+    order: OutputOrder,
+    /// full to write every account after each input file, or delta for only those
+    /// the file changed
+    #[arg(long, value_name = "MODE", default_value = "full", value_parser = parse_value::<OutputMode>)]
+    output_mode: OutputMode,
+    /// With --output-mode delta, write every account every n files instead, starting
+    /// with the first
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    full_snapshot_every: Option<u64>,
+}
+
+#[derive(Args)]
+struct GenerateArgs {
+    /// Clients to spread the transactions over
+    #[arg(long, default_value_t = 100)]
+    clients: ClientId,
+    /// Rows to write, such as 1000 or 1e6
+    #[arg(long, value_name = "N", default_value = "1000", value_parser = parse_count)]
+    txns: u64,
+    /// The same seed always gives the same feed
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+    /// Share of rows, from 0 to 1, that fail to parse
+    #[arg(long, value_name = "RATE", default_value_t = 0.0)]
+    malformed_rate: f64,
+    /// Write the feed to this file instead of stdout
+    #[arg(long, value_name = "PATH")]
+    output: Option<OsString>,
 }
 
-impl Borrow<ClientId> for Client {
-    fn borrow(&self) -> &ClientId {
-        &self.id
+#[derive(Args)]
+struct BenchArgs {
+    /// deposit-heavy, dispute-heavy, or many-clients for every client id
+    #[arg(long, value_name = "PROFILE", default_value = "deposit-heavy", value_parser = parse_value::<Workload>)]
+    profile: Workload,
+    /// Transactions to apply, such as 1000 or 1e6
+    #[arg(long, value_name = "N", default_value = "1e6", value_parser = parse_count)]
+    txns: u64,
+    /// The same seed always gives the same workload
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+}
+
+fn parse_log_level(s: &str) -> Result<LevelFilter, String> {
+    s.parse().map_err(|_| format!("unknown value {:?}", s))
+}
+
+fn parse_client_overdraft(s: &str) -> Result<(ClientId, OverdraftPolicy), String> {
+    let (client, policy) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected CLIENT=POLICY, not {:?}", s))?;
+    let client = client
+        .parse()
+        .map_err(|_| format!("invalid client id {:?}", client))?;
+    Ok((client, parse_value(policy)?))
+}
+
+fn parse_delimiter(s: &str) -> Result<u8, String> {
+    match s.as_bytes() {
+        b"\\t" => Ok(b'\t'),
+        [byte] if byte.is_ascii() && !matches!(byte, b'"' | b'\n' | b'\r') => Ok(*byte),
+        _ => Err(format!("expected a single character, not {:?}", s)),
     }
 }
 
-impl Client {
-    fn new(id: ClientId) -> Client {
-        Client {
-            id: id,
-            available: Decimal::from_str("0.0000").unwrap(),
-            held: Decimal::from_str("0.0000").unwrap(),
-            locked: false,
-            total: Decimal::from_str("0.0000").unwrap(),
-            disputes: HashSet::<TxId>::new(),
-        }
+/// Parses a count given whole or in scientific notation, such as 1e6
+fn parse_count(s: &str) -> Result<u64, String> {
+    if let Ok(count) = s.parse() {
+        return Ok(count);
     }
-    fn handle_transaction(
-        &mut self,
-        transaction_type: &TransactionType,
-        transaction: &Transaction,
-    ) {
-        // Client is locked, no further handling should occur (far as I understand)
-        if self.locked {
-            return;
-        }
-        use TransactionType::*;
-        match transaction_type {
-            Deposit => self.deposit(transaction.amount),
-            Withdrawal => self.withdrawal(transaction.amount),
-            Dispute => self.dispute(
-                transaction.id,
-                &transaction.transaction_type,
-                transaction.amount,
-            ),
-            Resolve => self.resolve(transaction.id, transaction.amount),
-            Chargeback => self.chargeback(transaction.id, transaction.amount),
+    match s.parse::<f64>() {
+        Ok(count) if count >= 0.0 && count.fract() == 0.0 && count <= u64::MAX as f64 => {
+            Ok(count as u64)
         }
-        self.calculate_total();
+        _ => Err(format!("expected a whole number, not {:?}", s)),
     }
+}
+
+/// Without a subcommand the args are taken as for `process`
+#[derive(Args)]
+#[command(args_conflicts_with_subcommands = true)]
+struct ReportArgs {
+    #[command(subcommand)]
+    query: Option<ReportCommand>,
+    #[command(flatten)]
+    process: ProcessArgs,
+}
+
+#[derive(Subcommand)]
+enum ReportCommand {
+    /// Writes a client's account as it stood right after a tx posted, replayed from
+    /// the history in a state file saved by a run with `--history`
+    BalanceAt(BalanceAtArgs),
+}
+
+#[derive(Args)]
+struct BalanceAtArgs {
+    client: ClientId,
+    tx: TxId,
+    /// State file saved by a run with `--history`
+    #[arg(long, value_name = "PATH")]
+    state_file: OsString,
+    /// The fee schedule of the runs, for the fees charged along the way
+    #[arg(long, value_name = "PATH")]
+    fees: Option<OsString>,
+}
 
-    fn deposit(&mut self, amount: Decimal) {
-        self.available = self.available + amount;
+#[derive(Args)]
+struct HistoryArgs {
+    client: ClientId,
+    /// State file saved by a run with `--history`
+    #[arg(long, value_name = "PATH")]
+    state_file: OsString,
+}
+
+#[derive(Args)]
+struct DiffArgs {
+    /// Accounts csv of the earlier run
+    before: OsString,
+    /// Accounts csv of the later run
+    after: OsString,
+    /// Write the deltas to this file instead of stdout
+    #[arg(long, value_name = "PATH")]
+    output: Option<OsString>,
+    /// csv, json for a single array, or jsonl for one delta per line
+    #[arg(long, value_name = "FORMAT", default_value = "csv", value_parser = parse_value::<OutputFormat>)]
+    output_format: OutputFormat,
+}
+
+/// Options for `--source kafka`
+#[cfg_attr(not(feature = "kafka"), allow(dead_code))]
+struct KafkaOptions {
+    brokers: Vec<String>,
+    topic: String,
+    group: String,
+    /// How often the accounts are written out
+    snapshot_every: Duration,
+}
+
+/// An audit trail built up in a local file, with the `s3://` uri it's uploaded to
+type AuditUpload = (PathBuf, String);
+
+/// Options for `--manifest`
+struct ManifestOptions {
+    path: OsString,
+    /// Files the settings were read from, such as the `--config` profiles
+    config_files: Vec<OsString>,
+}
+
+/// What a run writes once the input is processed
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+enum Display {
+    Accounts,
+    /// A summary to stderr, for `validate`
+    Summary,
+    /// The system wide `Report` as json, for `report`
+    Report,
+}
+
+/// Command line options that decide what is displayed, rather than how the engine runs
+struct Options {
+    /// Files to read in order as one input, `-` for stdin. Stdin when empty.
+    file_paths: Vec<OsString>,
+    display: Display,
+    /// Also write the system wide `Report` to stderr as json
+    report: bool,
+    /// Also write the processing summary, to stderr for `Some(None)`
+    summary: Option<Option<OsString>>,
+    /// Where the open disputes are written, if anywhere
+    disputes_output: Option<OsString>,
+    output_order: OutputOrder,
+    /// Which accounts are written
+    output_filter: OutputFilter,
+    output_format: OutputFormat,
+    /// Where the accounts are written, stdout when `None`
+    output: Option<OsString>,
+    /// Whether continuous runs write every account or only those changed
+    output_mode: OutputMode,
+    /// Every how many times a delta run writes every account instead
+    full_snapshot_every: Option<u64>,
+    /// Engine state loaded before processing, if the file exists, and saved after
+    state_file: Option<OsString>,
+    /// Where checkpoints are written along the way, with how many records apart
+    checkpoint: Option<(OsString, usize)>,
+    /// Carry on from the checkpoint, if there is one
+    resume: bool,
+    /// Write progress through the input to stderr
+    progress: bool,
+    /// Clients to unlock before processing, once any saved state is loaded
+    unlock: Vec<ClientId>,
+    /// Worker threads to shard clients over, processing serially when 1
+    threads: usize,
+    /// Whether to memory-map the input files, for `--mmap`
+    mmap: bool,
+    /// Address to serve the engine over http on, for the `serve` subcommand
+    serve: Option<String>,
+    /// Whether to serve it over gRPC instead, for `serve --grpc`
+    grpc: bool,
+    /// Directory to take input files from as they arrive, with how often to look if
+    /// polling, for the `watch` subcommand
+    watch: Option<(PathBuf, Option<Duration>)>,
+    /// Address to serve metrics on while watching, for `watch --metrics-addr`
+    metrics_addr: Option<String>,
+    /// Socket to answer control commands on while watching, for `watch --control`
+    control: Option<PathBuf>,
+    /// Client to write the history of, for the `history` subcommand
+    history: Option<ClientId>,
+    /// Client and tx to write the account as of, for `report balance-at`
+    balance_at: Option<(ClientId, TxId)>,
+    /// Feed to write instead of processing anything, for the `generate` subcommand
+    generate: Option<Generator>,
+    /// Workload to time instead of processing anything, for the `bench` subcommand
+    bench: Option<Benchmark>,
+    /// Accounts csvs to compare instead of processing anything, for the `diff`
+    /// subcommand
+    diff: Option<(OsString, OsString)>,
+    /// State file to migrate instead of processing anything, for `state upgrade`
+    upgrade: Option<OsString>,
+    /// Socket or named pipe to read transactions from instead of files, for `--listen`
+    listen: Option<PathBuf>,
+    /// Topic to consume instead of reading files, for `--source kafka`
+    kafka: Option<KafkaOptions>,
+    /// The audit trail to upload once the run is done, for `--audit s3://...`
+    audit_upload: Option<AuditUpload>,
+    /// Where the run manifest is written, if anywhere
+    manifest: Option<ManifestOptions>,
+}
+
+impl Options {
+    /// Options for the subcommands that don't read any input
+    fn without_input(state_file: Option<OsString>, unlock: Vec<ClientId>) -> Options {
+        Options {
+            file_paths: Vec::new(),
+            display: Display::Accounts,
+            report: false,
+            summary: None,
+            disputes_output: None,
+            output_order: OutputOrder::ClientId,
+            output_filter: OutputFilter::default(),
+            output_format: OutputFormat::Csv,
+            output: None,
+            output_mode: OutputMode::Full,
+            full_snapshot_every: None,
+            state_file,
+            checkpoint: None,
+            resume: false,
+            progress: false,
+            unlock,
+            threads: 1,
+            mmap: false,
+            serve: None,
+            grpc: false,
+            watch: None,
+            metrics_addr: None,
+            control: None,
+            history: None,
+            balance_at: None,
+            generate: None,
+            bench: None,
+            diff: None,
+            upgrade: None,
+            listen: None,
+            kafka: None,
+            audit_upload: None,
+            manifest: None,
+        }
     }
+}
 
-    fn calculate_total(&mut self) {
-        self.total = self.available + self.held;
+/// Which accounts are written, narrowed with `--client`, `--min-total` and
+/// `--locked-only`
+#[derive(Debug, Default)]
+struct OutputFilter {
+    /// Every client when empty
+    clients: Vec<ClientId>,
+    min_total: Option<Decimal>,
+    locked_only: bool,
+}
+
+impl OutputFilter {
+    /// Whether the account row is written, which for a client holding currencies is
+    /// decided row by row
+    fn keeps(&self, row: &AccountRow) -> bool {
+        (self.clients.is_empty() || self.clients.contains(&row.client))
+            && self.min_total.is_none_or(|min| row.total >= min)
+            && (!self.locked_only || row.locked)
     }
+}
+
+/// An input to process, mapped into memory with `--mmap`
+enum Input {
+    Read(Box<dyn io::Read>),
+    Mapped(MappedFile),
+    /// A Parquet file, read a row group at a time rather than loaded whole
+    #[cfg(feature = "parquet")]
+    Parquet(File),
+}
 
-    fn withdrawal(&mut self, amount: Decimal) {
-        if self.available >= amount {
-            self.available = self.available - amount;
+/// Sets the engine up as the args ask, returning the rest for `run`
+fn configure(engine: &mut Engine, cli: Cli) -> Result<Options, Box<dyn Error>> {
+    let (args, display) = match cli.command {
+        None => (cli.process, Display::Accounts),
+        Some(Command::Process(args)) => (args, Display::Accounts),
+        Some(Command::Validate(args)) => (args, Display::Summary),
+        Some(Command::Report(ReportArgs {
+            query: Some(ReportCommand::BalanceAt(args)),
+            ..
+        })) => {
+            if let Some(path) = &args.fees {
+                engine.set_fee_schedule(read_fee_schedule(path)?);
+            }
+            return Ok(Options {
+                balance_at: Some((args.client, args.tx)),
+                ..Options::without_input(Some(args.state_file), Vec::new())
+            });
+        }
+        Some(Command::Report(args)) => (args.process, Display::Report),
+        Some(Command::Serve(args)) => {
+            let EngineSetup {
+                state_file,
+                unlock,
+                audit_upload,
+            } = configure_engine(engine, args.engine)?;
+            if audit_upload.is_some() {
+                return Err(From::from(
+                    "Can't keep the audit trail in S3 with serve, it's only uploaded once the input is done",
+                ));
+            }
+            return Ok(Options {
+                serve: Some(args.listen),
+                grpc: args.grpc,
+                ..Options::without_input(state_file, unlock)
+            });
+        }
+        Some(Command::Watch(args)) => {
+            let EngineSetup {
+                state_file,
+                unlock,
+                audit_upload,
+            } = configure_engine(engine, args.engine)?;
+            if audit_upload.is_some() {
+                return Err(From::from(
+                    "Can't keep the audit trail in S3 with watch, it's only uploaded once the input is done",
+                ));
+            }
+            track_changes(engine, args.output_mode, args.full_snapshot_every)?;
+            return Ok(Options {
+                output_order: args.order,
+                output_format: args.output_format,
+                output: args.output,
+                output_mode: args.output_mode,
+                full_snapshot_every: args.full_snapshot_every,
+                watch: Some((
+                    PathBuf::from(args.dir),
+                    args.poll_secs.map(Duration::from_secs),
+                )),
+                metrics_addr: args.metrics_addr,
+                control: args.control.map(PathBuf::from),
+                ..Options::without_input(state_file, unlock)
+            });
+        }
+        Some(Command::Generate(args)) => {
+            if !(0.0..=1.0).contains(&args.malformed_rate) {
+                return Err(From::from("Expected --malformed-rate from 0 to 1"));
+            }
+            return Ok(Options {
+                output: args.output,
+                generate: Some(Generator {
+                    clients: args.clients,
+                    transactions: args.txns,
+                    seed: args.seed,
+                    malformed_rate: args.malformed_rate,
+                }),
+                ..Options::without_input(None, Vec::new())
+            });
+        }
+        Some(Command::Bench(args)) => {
+            return Ok(Options {
+                bench: Some(Benchmark {
+                    workload: args.profile,
+                    transactions: args.txns,
+                    seed: args.seed,
+                }),
+                ..Options::without_input(None, Vec::new())
+            });
+        }
+        Some(Command::Diff(args)) => {
+            return Ok(Options {
+                output: args.output,
+                output_format: args.output_format,
+                diff: Some((args.before, args.after)),
+                ..Options::without_input(None, Vec::new())
+            });
+        }
+        Some(Command::State(StateCommand::Upgrade { file })) => {
+            return Ok(Options {
+                upgrade: Some(file),
+                ..Options::without_input(None, Vec::new())
+            });
+        }
+        Some(Command::History(args)) => {
+            return Ok(Options {
+                history: Some(args.client),
+                ..Options::without_input(Some(args.state_file), Vec::new())
+            });
         }
+    };
+    let display = if args.validate {
+        Display::Summary
+    } else {
+        display
+    };
+    let manifest = args.manifest.map(|path| ManifestOptions {
+        path,
+        config_files: [
+            &args.engine.config,
+            &args.engine.column_map,
+            &args.engine.fees,
+            &args.engine.rules,
+        ]
+        .into_iter()
+        .flatten()
+        .cloned()
+        .collect(),
+    });
+    let EngineSetup {
+        state_file,
+        unlock,
+        audit_upload,
+    } = configure_engine(engine, args.engine)?;
+    engine.set_record_issues(display == Display::Summary);
+    engine.set_reconcile(args.reconcile);
+    engine.set_parse_threads(args.parse_threads);
+    let mut file_paths = Vec::new();
+    for arg in args.file_paths {
+        file_paths.extend(expand_glob(arg)?);
     }
+    if args.output_mode == OutputMode::Delta
+        && args.source != Source::Kafka
+        && args.listen.is_none()
+    {
+        return Err(From::from(
+            "Expected --source kafka, --listen or the watch subcommand with --output-mode delta, the accounts are only written once otherwise",
+        ));
+    }
+    track_changes(engine, args.output_mode, args.full_snapshot_every)?;
+    let kafka = match args.source {
+        Source::File => None,
+        Source::Kafka => Some(KafkaOptions {
+            brokers: args.brokers,
+            topic: args.topic.ok_or("Expected --topic with --source kafka")?,
+            group: args.group,
+            snapshot_every: Duration::from_secs(args.snapshot_secs),
+        }),
+    };
+    Ok(Options {
+        file_paths,
+        display,
+        report: args.report,
+        summary: (args.summary || args.summary_file.is_some()).then_some(args.summary_file),
+        disputes_output: args.disputes_output,
+        output_order: args.order,
+        output_filter: OutputFilter {
+            clients: args.clients,
+            min_total: args.min_total,
+            locked_only: args.locked_only,
+        },
+        output_format: args.output_format,
+        output: args.output,
+        output_mode: args.output_mode,
+        full_snapshot_every: args.full_snapshot_every,
+        state_file,
+        checkpoint: args
+            .checkpoint
+            .map(|checkpoint| (checkpoint, args.checkpoint_every)),
+        resume: args.resume,
+        progress: args.progress,
+        unlock,
+        threads: args.threads,
+        mmap: args.mmap,
+        serve: None,
+        grpc: false,
+        watch: None,
+        metrics_addr: None,
+        control: None,
+        history: None,
+        balance_at: None,
+        generate: None,
+        bench: None,
+        diff: None,
+        upgrade: None,
+        listen: args.listen.map(PathBuf::from),
+        kafka,
+        audit_upload,
+        manifest,
+    })
+}
 
-    fn dispute(&mut self, tx_id: TxId, transaction_type: &TransactionType, amount: Decimal) {
-        if transaction_type == &TransactionType::Deposit {
-            self.disputes.insert(tx_id);
-            self.available -= amount;
-            self.held += amount;
-        }
+/// Has the engine keep track of which clients change for `--output-mode delta`
+fn track_changes(
+    engine: &mut Engine,
+    output_mode: OutputMode,
+    full_snapshot_every: Option<u64>,
+) -> Result<(), Box<dyn Error>> {
+    if full_snapshot_every.is_some() && output_mode != OutputMode::Delta {
+        return Err(From::from(
+            "Expected --output-mode delta with --full-snapshot-every",
+        ));
     }
+    engine.set_track_changes(output_mode == OutputMode::Delta);
+    Ok(())
+}
+
+/// What of the engine args `run` handles rather than the engine
+struct EngineSetup {
+    state_file: Option<OsString>,
+    unlock: Vec<ClientId>,
+    /// The audit trail to upload once the run is done
+    audit_upload: Option<AuditUpload>,
+}
 
-    fn resolve(&mut self, tx_id: TxId, amount: Decimal) {
-        if self.disputes.contains(&tx_id) {
-            self.disputes.remove(&tx_id);
-            self.available += amount;
-            self.held -= amount;
+/// Applies the engine args, returning the rest for `run`
+fn configure_engine(engine: &mut Engine, args: EngineArgs) -> Result<EngineSetup, Box<dyn Error>> {
+    if let (Some(path), Some(name)) = (&args.config, &args.profile) {
+        let profiles =
+            fs::read_to_string(path).map_err(|err| format!("Failed to read {:?} {}", path, err))?;
+        Profile::from_toml(&profiles, name)?.apply(engine);
+    }
+    // Flags only ever turn a setting on, or off for `--lenient`, over the profile's
+    if args.strict || args.lenient {
+        engine.set_strict(args.strict);
+    }
+    engine.set_record_history(args.history);
+    engine.set_invariant_check(args.check_invariants, args.check_available);
+    if args.buffered {
+        engine.set_buffered(true);
+    }
+    if let Some(dispute_policy) = args.dispute_policy {
+        engine.set_dispute_policy(dispute_policy);
+    }
+    if args.dispute_withdrawals {
+        engine.set_withdrawal_disputes(true);
+    }
+    if args.disputes_on_locked {
+        engine.set_disputes_on_locked_accounts(true);
+    }
+    engine.set_dispute_window(
+        args.dispute_window_days
+            .map(|days| Duration::from_secs(days.saturating_mul(SECS_PER_DAY))),
+    );
+    if let Some(input_format) = args.input_format {
+        engine.set_input_format(input_format);
+    }
+    if let Some(delimiter) = args.delimiter {
+        engine.set_delimiter(delimiter);
+    }
+    if let Some(amount_format) = args.amount_format {
+        engine.set_amount_format(amount_format);
+    }
+    if let Some(path) = &args.column_map {
+        let map =
+            fs::read_to_string(path).map_err(|err| format!("Failed to read {:?} {}", path, err))?;
+        engine.set_column_map(ColumnMap::from_toml(&map)?);
+    }
+    if let Some(rounding_policy) = args.rounding {
+        engine.set_rounding_policy(rounding_policy);
+    }
+    if let Some(precision) = args.precision {
+        engine.set_precision(precision);
+    }
+    if let Some(redispute_policy) = args.redispute {
+        engine.set_redispute_policy(redispute_policy);
+    }
+    if let Some(tx_id_scope) = args.tx_id_scope {
+        engine.set_tx_id_scope(tx_id_scope);
+    }
+    if let Some(overdraft_policy) = args.overdraft {
+        engine.set_overdraft_policy(overdraft_policy);
+    }
+    for &(client, overdraft_policy) in &args.client_overdraft {
+        engine.set_client_overdraft_policy(client, overdraft_policy);
+    }
+    if args.max_amount.is_some() {
+        engine.set_max_amount(args.max_amount);
+    }
+    if let Some(path) = &args.fees {
+        engine.set_fee_schedule(read_fee_schedule(path)?);
+    }
+    if let Some(path) = &args.rules {
+        let rules =
+            fs::read_to_string(path).map_err(|err| format!("Failed to read {:?} {}", path, err))?;
+        engine.set_rules(Rules::from_toml(&rules)?);
+    }
+    if let Some(sink) = &args.alerts {
+        engine.set_alerts(Some(Alerts {
+            held_over: args.alert_held_over,
+            ..Alerts::new(alert_sink(sink)?)
+        }));
+    }
+    if let Some(chaos) = &args.chaos {
+        set_chaos(engine, chaos)?;
+    }
+    if args.max_tx_per_client.is_some() {
+        engine.set_max_transactions_per_client(args.max_tx_per_client);
+    }
+    if let Some(eviction_policy) = args.evict {
+        engine.set_eviction_policy(eviction_policy);
+    }
+    if let Some(store) = &args.store {
+        set_store(engine, store)?;
+    }
+    let mut audit_upload = None;
+    if let Some(mut path) = args.audit {
+        if is_s3(&path) {
+            // S3 can't append to an object, so the trail is built up in a copy of it
+            // and uploaded once the run is done
+            let staged = staging_path("audit");
+            download_s3(&path.to_string_lossy(), &staged)?;
+            audit_upload = Some((staged.clone(), path.to_string_lossy().into_owned()));
+            path = staged.into_os_string();
         }
+        // The trail is only ever appended to, across runs
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let append = file.metadata()?.len() > 0;
+        engine.set_audit_sink(Box::new(file), args.audit_format, append);
     }
+    if let Some(path) = args.export_ledger {
+        engine.set_journal_sink(Box::new(File::create(path)?));
+    }
+    Ok(EngineSetup {
+        state_file: args.state_file,
+        unlock: args.unlock,
+        audit_upload,
+    })
+}
 
-    fn chargeback(&mut self, tx_id: TxId, amount: Decimal) {
-        if self.disputes.contains(&tx_id) {
-            self.disputes.remove(&tx_id);
-            self.held -= amount;
-            self.locked = true;
-        }
+#[cfg(feature = "chaos")]
+fn set_chaos(engine: &mut Engine, value: &str) -> Result<(), Box<dyn Error>> {
+    let chaos = value
+        .parse::<toy_payments::Chaos>()
+        .map_err(|_| format!("Invalid chaos settings {:?}", value))?;
+    engine.set_chaos(chaos);
+    Ok(())
+}
+
+#[cfg(not(feature = "chaos"))]
+fn set_chaos(_engine: &mut Engine, _value: &str) -> Result<(), Box<dyn Error>> {
+    Err(From::from(
+        "Can't inject chaos, built without the chaos feature",
+    ))
+}
+
+/// Alerts go to stderr for "stderr", or are posted to an http(s) url with the
+/// webhook feature
+fn alert_sink(value: &str) -> Result<AlertSink, Box<dyn Error>> {
+    if value == "stderr" {
+        return Ok(AlertSink::Stderr);
+    }
+    if !(value.starts_with("http://") || value.starts_with("https://")) {
+        return Err(From::from(format!(
+            "Invalid alert sink {:?}, expected stderr or an http(s) url",
+            value
+        )));
     }
+    webhook(value)
+}
+
+#[cfg(feature = "webhook")]
+fn webhook(url: &str) -> Result<AlertSink, Box<dyn Error>> {
+    Ok(AlertSink::Webhook(url.to_string()))
 }
 
-struct ToyProgram {
-    clients: HashSet<Client>,
-    transactions: HashSet<Transaction>,
+#[cfg(not(feature = "webhook"))]
+fn webhook(url: &str) -> Result<AlertSink, Box<dyn Error>> {
+    Err(From::from(format!(
+        "Can't post alerts to {}, built without the webhook feature",
+        url
+    )))
 }
 
-impl ToyProgram {
-    fn new() -> ToyProgram {
-        let clients = HashSet::<Client>::new();
-        let transactions = HashSet::<Transaction>::new();
-        ToyProgram {
-            clients,
-            transactions,
+/// Keeps transactions in memory for "memory", or in a SQLite database for
+/// "sqlite:<path>" with the sqlite feature. "redis:<addr>" with the redis feature
+/// keeps the accounts there too, shared with other instances.
+fn set_store(engine: &mut Engine, value: &str) -> Result<(), Box<dyn Error>> {
+    match value.split_once(':') {
+        None if value == "memory" => engine.set_transaction_store(Box::new(MemoryStore::new()))?,
+        #[cfg(feature = "sqlite")]
+        Some(("sqlite", path)) => {
+            let store = toy_payments::SqliteStore::create(path)
+                .map_err(|err| format!("Failed to open store {} {}", path, err))?;
+            engine.set_transaction_store(Box::new(store))?;
         }
+        #[cfg(not(feature = "sqlite"))]
+        Some(("sqlite", path)) => {
+            return Err(From::from(format!(
+                "Can't store in {}, built without the sqlite feature",
+                path
+            )))
+        }
+        #[cfg(feature = "redis")]
+        Some(("redis", addr)) => {
+            let store = toy_payments::RedisStore::connect(addr, REDIS_PREFIX)
+                .map_err(|err| format!("Failed to open store {} {}", addr, err))?;
+            engine.set_transaction_store(Box::new(store.clone()))?;
+            engine.set_account_store(Box::new(store));
+        }
+        #[cfg(not(feature = "redis"))]
+        Some(("redis", addr)) => {
+            return Err(From::from(format!(
+                "Can't store in {}, built without the redis feature",
+                addr
+            )))
+        }
+        _ => return Err(From::from(format!("Unknown store {:?}", value))),
     }
+    Ok(())
+}
 
-    pub fn process(&mut self) -> Result<(), Box<dyn Error>> {
-        let file_path = self.get_from_env()?;
-        let file = File::open(file_path)?;
-        let mut reader = csv::ReaderBuilder::new().flexible(true).from_reader(file);
+/// Expands a quoted glob pattern to the files it matches, in alphabetical order.
+/// Anything else, including a pattern matching nothing, is kept as the path itself.
+fn expand_glob(arg: OsString) -> Result<Vec<OsString>, Box<dyn Error>> {
+    let pattern = match arg.to_str() {
+        Some(pattern) if pattern.contains(['*', '?', '[']) => pattern,
+        _ => return Ok(vec![arg]),
+    };
+    let mut paths = Vec::new();
+    for path in glob::glob(pattern).map_err(|err| format!("Invalid pattern {} {}", pattern, err))? {
+        paths.push(path?.into_os_string());
+    }
+    if paths.is_empty() {
+        paths.push(arg);
+    }
+    Ok(paths)
+}
 
-        for result in reader.records().skip(1) {
-            use TransactionType::*;
-            let record = result.unwrap_or_else(|err| {
-                panic!("Could not parse csv result to StringResult: {}", err)
-            });
-            let (transaction_type, transaction) = self.transaction_from_record(record)?;
+fn run(engine: &mut Engine, options: &Options) -> Result<(), Box<dyn Error>> {
+    if let Some(generator) = &options.generate {
+        return match &options.output {
+            Some(path) => write_atomically(Path::new(path), |writer| Ok(generator.write(writer)?)),
+            None => Ok(generator.write(io::BufWriter::new(io::stdout().lock()))?),
+        };
+    }
+    if let Some(benchmark) = &options.bench {
+        println!("{}", describe_benchmark(benchmark, &benchmark.run()?));
+        return Ok(());
+    }
+    if let Some((before, after)) = &options.diff {
+        return display_diff(before, after, options);
+    }
+    if let Some(path) = &options.upgrade {
+        return upgrade_state_file(Path::new(path));
+    }
+    let resumed = match &options.checkpoint {
+        Some((path, _)) if options.resume && Path::new(path).exists() => {
+            let file = File::open(path)?;
+            let checkpoint = Checkpoint::read(io::BufReader::new(file))
+                .map_err(|err| format!("Failed to read checkpoint {:?} {}", path, err))?;
+            engine.resume(checkpoint)?;
+            true
+        }
+        _ => false,
+    };
+    // A checkpoint already has the saved state loaded and the clients unlocked
+    if let (Some(state_file), false) = (&options.state_file, resumed) {
+        if Path::new(state_file).exists() {
+            let file = File::open(state_file)?;
+            engine
+                .load_state(io::BufReader::new(file))
+                .map_err(|err| format!("Failed to load state from {:?} {}", state_file, err))?;
+        }
+    }
+    if !resumed {
+        for id in &options.unlock {
+            engine.unlock_client(*id, true)?;
+        }
+    }
+    if let Some((path, every)) = &options.checkpoint {
+        let path = PathBuf::from(path);
+        engine.set_checkpoints(
+            *every,
+            Box::new(move |checkpoint| {
+                write_atomically(&path, |writer| Ok(checkpoint.write(writer)?))
+                    .map_err(|err| PaymentsError::Io(io::Error::other(err.to_string())))
+            }),
+        );
+    }
+    if let Some(id) = options.history {
+        return display_history(engine, id);
+    }
+    if let Some((id, tx)) = options.balance_at {
+        return display_balance_at(engine, id, tx);
+    }
+    if let Some(addr) = &options.serve {
+        if options.grpc {
+            return serve_grpc(engine, addr);
+        }
+        #[cfg(feature = "server")]
+        return toy_payments::serve(engine, addr).map_err(|err| err as Box<dyn Error>);
+        #[cfg(not(feature = "server"))]
+        return Err(From::from(format!(
+            "Can't serve on {}, built without the server feature",
+            addr
+        )));
+    }
+    if let Some(kafka) = &options.kafka {
+        return consume_kafka(engine, kafka, options);
+    }
+    if let Some(path) = &options.listen {
+        return listen(engine, path, options);
+    }
+    if let Some((dir, poll_every)) = &options.watch {
+        let metrics = match &options.metrics_addr {
+            Some(addr) => Some(serve_metrics(engine, addr)?),
+            None => None,
+        };
+        let control = match &options.control {
+            Some(path) => Some(serve_control(engine, path)?),
+            None => None,
+        };
+        let mut emitted = Emitted::default();
+        return toy_payments::watch(engine, dir, *poll_every, |engine, _| {
+            if let (Some(latest), Some(text)) = (&metrics, engine.metrics()) {
+                *latest.lock().map_err(|_| "Metrics server stopped")? = text;
+            }
+            if let Some(publish) = &control {
+                publish(engine)?;
+            }
+            emit_clients(engine, options, &mut emitted).map_err(|err| err.to_string())?;
+            if let Some(state_file) = &options.state_file {
+                save_state(engine, Path::new(state_file)).map_err(|err| err.to_string())?;
+            }
+            Ok(())
+        })
+        .map_err(|err| err as Box<dyn Error>);
+    }
+    // Each input is named so an error can say which one its line is in
+    let mut inputs: Vec<(String, Input)> = Vec::new();
+    // The inputs' digests for the manifest, once they're read
+    let mut digests: Vec<Box<dyn Fn() -> FileDigest>> = Vec::new();
+    // Progress is measured against the files' sizes, which compressed ones don't give
+    let mut compressed = false;
+    // Streams can only be read once, so are hashed as they're processed
+    let hashed = options.manifest.is_some();
+    let stream = |digests: &mut Vec<_>, name: String, reader: Box<dyn io::Read>| -> Input {
+        if !hashed {
+            return Input::Read(reader);
+        }
+        let (reader, digest) = DigestReader::new(reader, name);
+        digests.push(Box::new(move || digest.digest()) as Box<dyn Fn() -> FileDigest>);
+        Input::Read(Box::new(reader))
+    };
+    if options.file_paths.is_empty() {
+        let input = stream(
+            &mut digests,
+            "stdin".to_string(),
+            Box::new(io::stdin().lock()),
+        );
+        inputs.push(("stdin".to_string(), input));
+    }
+    for file_path in &options.file_paths {
+        if file_path == "-" {
+            let input = stream(
+                &mut digests,
+                "stdin".to_string(),
+                Box::new(io::stdin().lock()),
+            );
+            inputs.push(("stdin".to_string(), input));
+            continue;
+        }
+        let name = file_path.to_string_lossy().into_owned();
+        if is_s3(file_path) {
+            let input = stream(&mut digests, name.clone(), open_s3(&name)?);
+            inputs.push((name, input));
+            continue;
+        }
+        if hashed {
+            let digest = FileDigest::of_file(Path::new(file_path))
+                .map_err(|err| format!("Failed to hash {:?} {}", file_path, err))?;
+            digests.push(Box::new(move || digest.clone()));
+        }
+        let open = |err| format!("Failed to open {:?} {}", file_path, err);
+        let mut file = File::open(file_path).map_err(open)?;
+        let compression = Compression::detect(Path::new(file_path), &mut file).map_err(open)?;
+        compressed |= compression != Compression::None;
+        #[cfg(feature = "parquet")]
+        if compression == Compression::None && *engine.input_format() == InputFormat::Parquet {
+            inputs.push((name, Input::Parquet(file)));
+            continue;
+        }
+        // A compressed file can't be parsed in place, so is always streamed
+        let input = if options.mmap && compression == Compression::None {
+            let map = MappedFile::open(&file)
+                .map_err(|err| format!("Failed to map {:?} {}", file_path, err))?;
+            Input::Mapped(map)
+        } else {
+            Input::Read(compression.decompress(file).map_err(open)?)
+        };
+        inputs.push((file_path.to_string_lossy().into_owned(), input));
+    }
+    if options.progress {
+        // Stdin has no size to measure progress against
+        let sizes: Option<Vec<u64>> = options
+            .file_paths
+            .iter()
+            .map(|path| match path.to_str() {
+                Some("-") => None,
+                _ => fs::metadata(path).ok().map(|metadata| metadata.len()),
+            })
+            .collect();
+        let total = sizes
+            .filter(|_| !options.file_paths.is_empty() && !compressed)
+            .map(|sizes| sizes.iter().sum());
+        engine.set_progress(
+            PROGRESS_EVERY,
+            Box::new(move |progress| eprintln!("{}", describe_progress(progress, total))),
+        );
+    }
+    #[cfg(unix)]
+    engine.set_stop_flag(toy_payments::stop_on_signals());
+    if options.threads > 1 {
+        let inputs = inputs.into_iter().map(|(_, input)| match input {
+            Input::Read(reader) => reader,
+            Input::Mapped(map) => Box::new(io::Cursor::new(map)),
+            #[cfg(feature = "parquet")]
+            Input::Parquet(file) => Box::new(file),
+        });
+        match engine.process_parallel(inputs, options.threads) {
+            Err(PaymentsError::Interrupted) => return interrupted(engine, options),
+            result => result?,
+        }
+    } else {
+        for (name, input) in inputs {
+            match match input {
+                Input::Read(reader) => engine.process_part(reader),
+                Input::Mapped(map) => engine.process_mapped_part(&map),
+                #[cfg(feature = "parquet")]
+                Input::Parquet(file) => engine.process_parquet_file(file),
+            } {
+                Err(PaymentsError::Interrupted) => return interrupted(engine, options),
+                result => result.map_err(|err| format!("{}: {}", name, err))?,
+            }
+        }
+        engine.finish()?;
+    }
+    if let Some(reconciliation) = engine.reconcile() {
+        for discrepancy in &reconciliation.discrepancies {
+            eprintln!("{}", describe_discrepancy(discrepancy));
+        }
+        if !reconciliation.is_balanced() {
+            return Err(From::from(format!(
+                "Reconciliation failed for {} accounts",
+                reconciliation.discrepancies.len()
+            )));
+        }
+    }
+    // Only an interrupted run needs its checkpoint
+    if let Some((path, _)) = &options.checkpoint {
+        if Path::new(path).exists() {
+            fs::remove_file(path)?;
+        }
+    }
+    // A validation run leaves the saved state as it was
+    if let (Some(state_file), false) = (&options.state_file, options.display == Display::Summary) {
+        save_state(engine, Path::new(state_file))?;
+    }
+    match options.display {
+        Display::Summary => {
+            write_issues(engine, &mut io::stdout().lock())?;
+            display_summary(engine);
+            if !engine.issues().is_empty() {
+                return Err(From::from(format!(
+                    "{} records failed validation",
+                    engine.issues().len()
+                )));
+            }
+        }
+        Display::Report => {
+            let write = |writer: &mut dyn Write| -> Result<(), Box<dyn Error>> {
+                serde_json::to_writer(&mut *writer, &engine.report())?;
+                writeln!(writer)?;
+                Ok(())
+            };
+            match &options.output {
+                Some(path) => write_atomically(Path::new(path), |writer| write(writer))?,
+                None => write(&mut io::stdout().lock())?,
+            }
+        }
+        Display::Accounts => {
+            display_clients(engine, options)?;
+            display_house_fees(engine);
+            // The summary lists the skipped records too
+            if options.summary.is_none() {
+                display_skipped(engine);
+            }
+        }
+    }
+    if options.report {
+        eprintln!("{}", serde_json::to_string(&engine.report())?);
+    }
+    if let Some(path) = &options.disputes_output {
+        write_atomically(Path::new(path), |writer| write_disputes(engine, writer))?;
+    }
+    match &options.summary {
+        Some(Some(path)) => {
+            write_atomically(Path::new(path), |writer| Ok(write_summary(engine, writer)?))?
+        }
+        Some(None) => write_summary(engine, &mut io::stderr().lock())?,
+        None => (),
+    }
+    if let Some(manifest) = &options.manifest {
+        let inputs = digests.iter().map(|digest| digest()).collect();
+        write_manifest(engine, options, manifest, inputs)?;
+    }
+    Ok(())
+}
 
-            match (&transaction_type, &transaction) {
-                (Deposit | Withdrawal, None) => {
-                    panic!("Deposits and withdrawals require a transaction")
-                }
-                // No matching transaction, assume partner error
-                (Dispute | Resolve | Chargeback, None) => (),
-                (Deposit | Withdrawal, Some(t)) => {
-                    let unique = self.ensure_globally_unique_transaction(transaction.clone())?;
-                    // If no result assume partner error
-                    if unique {
-                        self.transactions.insert(transaction.clone().unwrap());
-
-                        let mut client = match self.clients.get(&t.client_id) {
-                            Some(c) => {
-                                let client = c.clone();
-                                self.clients.remove(&client);
-                                client
-                            }
-                            None => Client::new(t.client_id),
-                        };
-                        client.handle_transaction(&transaction_type, &t);
-                        self.clients.insert(client);
-                    }
+/// Writes what the run read, did and wrote for `--manifest`, the output hashed as
+/// it was written
+fn write_manifest(
+    engine: &Engine,
+    options: &Options,
+    manifest: &ManifestOptions,
+    inputs: Vec<FileDigest>,
+) -> Result<(), Box<dyn Error>> {
+    let mut config = RunConfig {
+        args: std::env::args_os()
+            .skip(1)
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect(),
+        files: Vec::new(),
+    };
+    for path in &manifest.config_files {
+        config.files.push(
+            FileDigest::of_file(Path::new(path))
+                .map_err(|err| format!("Failed to hash {:?} {}", path, err))?,
+        );
+    }
+    let mut output = Vec::new();
+    match options.display {
+        Display::Accounts => write_clients(
+            engine,
+            options.output_order,
+            &options.output_filter,
+            options.output_format,
+            &mut output,
+        )?,
+        Display::Report => {
+            serde_json::to_writer(&mut output, &engine.report())?;
+            writeln!(output)?;
+        }
+        Display::Summary => (),
+    }
+    let output = (options.display != Display::Summary).then(|| {
+        let path = options.output.as_ref().map_or_else(
+            || "stdout".to_string(),
+            |path| path.to_string_lossy().into_owned(),
+        );
+        FileDigest::of_bytes(path, &output)
+    });
+    let manifest_path = Path::new(&manifest.path);
+    write_atomically(manifest_path, |writer| {
+        Ok(Manifest::of(engine, inputs, config, output).write(writer)?)
+    })
+}
+
+fn display_diff(before: &OsStr, after: &OsStr, options: &Options) -> Result<(), Box<dyn Error>> {
+    let open = |path: &OsStr| {
+        File::open(path)
+            .map(io::BufReader::new)
+            .map_err(|err| format!("Failed to open {:?} {}", path, err))
+    };
+    let deltas = toy_payments::diff_snapshots(open(before)?, open(after)?)?;
+    let write = |writer: &mut dyn Write| -> Result<(), Box<dyn Error>> {
+        match options.output_format {
+            OutputFormat::Csv => {
+                let mut writer = csv::Writer::from_writer(writer);
+                for delta in &deltas {
+                    writer.serialize(delta)?;
                 }
-                (Dispute | Resolve | Chargeback, Some(t)) => {
-                    match self.clients.get(&t.client_id) {
-                        Some(c) => {
-                            let mut client = c.clone();
-                            if client.id == t.client_id {
-                                client.handle_transaction(&transaction_type, &t);
-                                self.clients.remove(&client.id);
-                                self.clients.insert(client);
-                            }
-                        }
-                        None => (),
-                    };
+                writer.flush()?;
+            }
+            OutputFormat::Json => {
+                serde_json::to_writer(&mut *writer, &deltas)?;
+                writeln!(writer)?;
+            }
+            OutputFormat::JsonLines => {
+                for delta in &deltas {
+                    serde_json::to_writer(&mut *writer, delta)?;
+                    writeln!(writer)?;
                 }
             }
+            OutputFormat::Parquet => {
+                return Err(From::from("Can't write deltas as parquet, only accounts"))
+            }
         }
-        self.display_clients()?;
         Ok(())
+    };
+    match &options.output {
+        Some(path) => write_atomically(Path::new(path), |writer| write(writer))?,
+        None => write(&mut io::stdout().lock())?,
     }
+    let mut locked: Vec<ClientId> = deltas
+        .iter()
+        .filter(|delta| delta.newly_locked())
+        .map(|delta| delta.client)
+        .collect();
+    locked.dedup();
+    eprintln!("newly locked accounts: {} {:?}", locked.len(), locked);
+    Ok(())
+}
 
-    pub fn display_clients(&self) -> Result<(), Box<dyn Error>> {
-        let mut writer = csv::Writer::from_writer(io::stdout());
-        for client in &self.clients {
-            writer.serialize(client)?;
+/// e.g. "read 512.0 of 1024.0 MiB (50%), 1000000 records at 250000 records/s"
+fn describe_progress(progress: &Progress, total: Option<u64>) -> String {
+    let mib = |bytes: u64| bytes as f64 / (1024.0 * 1024.0);
+    let read = match total {
+        Some(total) if total > 0 => format!(
+            "{:.1} of {:.1} MiB ({:.0}%)",
+            mib(progress.bytes),
+            mib(total),
+            progress.bytes as f64 * 100.0 / total as f64
+        ),
+        _ => format!("{:.1} MiB", mib(progress.bytes)),
+    };
+    format!(
+        "read {}, {} records at {:.0} records/s",
+        read,
+        progress.records,
+        progress.records_per_sec()
+    )
+}
+
+fn describe_benchmark(benchmark: &Benchmark, result: &BenchmarkResult) -> String {
+    let peak_memory = match result.peak_memory {
+        Some(bytes) => format!("{:.1} MiB", bytes as f64 / (1024.0 * 1024.0)),
+        None => "unknown".to_string(),
+    };
+    format!(
+        "{}: {} transactions in {:.3}s, {:.0} txns/sec, peak memory {}",
+        benchmark.workload,
+        result.transactions,
+        result.elapsed.as_secs_f64(),
+        result.transactions_per_sec(),
+        peak_memory
+    )
+}
+
+#[cfg(feature = "kafka")]
+fn consume_kafka(
+    engine: &mut Engine,
+    kafka: &KafkaOptions,
+    options: &Options,
+) -> Result<(), Box<dyn Error>> {
+    let source = toy_payments::KafkaSource {
+        brokers: kafka.brokers.clone(),
+        topic: kafka.topic.clone(),
+        group: kafka.group.clone(),
+        snapshot_every: kafka.snapshot_every,
+    };
+    let mut emitted = Emitted::default();
+    toy_payments::consume(engine, &source, |engine| {
+        emit_clients(engine, options, &mut emitted).map_err(|err| err.to_string())?;
+        if let Some(state_file) = &options.state_file {
+            save_state(engine, Path::new(state_file)).map_err(|err| err.to_string())?;
         }
         Ok(())
+    })
+    .map_err(|err| err as Box<dyn Error>)
+}
+
+#[cfg(not(feature = "kafka"))]
+fn consume_kafka(
+    _engine: &mut Engine,
+    kafka: &KafkaOptions,
+    _options: &Options,
+) -> Result<(), Box<dyn Error>> {
+    Err(From::from(format!(
+        "Can't consume {}, built without the kafka feature",
+        kafka.topic
+    )))
+}
+
+/// Starts serving the engine's metrics on `addr`, returning where to put them as
+/// they change
+#[cfg(feature = "server")]
+fn serve_metrics(engine: &mut Engine, addr: &str) -> Result<Arc<Mutex<String>>, Box<dyn Error>> {
+    engine.enable_metrics();
+    let latest = Arc::new(Mutex::new(engine.metrics().unwrap_or_default()));
+    toy_payments::serve_metrics(addr, Arc::clone(&latest))
+        .map_err(|err| format!("Failed to serve metrics on {} {}", addr, err))?;
+    Ok(latest)
+}
+
+#[cfg(not(feature = "server"))]
+fn serve_metrics(_engine: &mut Engine, addr: &str) -> Result<Arc<Mutex<String>>, Box<dyn Error>> {
+    Err(From::from(format!(
+        "Can't serve metrics on {}, built without the server feature",
+        addr
+    )))
+}
+
+/// Serves the engine over gRPC on `addr` until the process is stopped
+#[cfg(feature = "grpc")]
+fn serve_grpc(engine: &mut Engine, addr: &str) -> Result<(), Box<dyn Error>> {
+    toy_payments::serve_grpc(engine, addr)
+        .map_err(|err| From::from(format!("Failed to serve gRPC on {} {}", addr, err)))
+}
+
+#[cfg(not(feature = "grpc"))]
+fn serve_grpc(_engine: &mut Engine, addr: &str) -> Result<(), Box<dyn Error>> {
+    Err(From::from(format!(
+        "Can't serve gRPC on {}, built without the grpc feature",
+        addr
+    )))
+}
+
+/// What brings the control socket's state up to date with the engine
+type PublishControl = Box<dyn Fn(&Engine) -> Result<(), Box<dyn Error + Send + Sync>>>;
+
+/// Starts answering control commands on `path`, returning what to hand the engine
+/// to as it changes
+#[cfg(unix)]
+fn serve_control(engine: &Engine, path: &Path) -> Result<PublishControl, Box<dyn Error>> {
+    let latest = Arc::new(Mutex::new(ControlState::of(engine)));
+    toy_payments::serve_control(path, Arc::clone(&latest)).map_err(|err| {
+        format!(
+            "Failed to answer control commands on {} {}",
+            path.display(),
+            err
+        )
+    })?;
+    Ok(Box::new(move |engine| {
+        *latest.lock().map_err(|_| "Control socket stopped")? = ControlState::of(engine);
+        Ok(())
+    }))
+}
+
+#[cfg(not(unix))]
+fn serve_control(_engine: &Engine, path: &Path) -> Result<PublishControl, Box<dyn Error>> {
+    Err(From::from(format!(
+        "Can't answer control commands on {}, sockets are only supported on Unix",
+        path.display()
+    )))
+}
+
+#[cfg(unix)]
+fn listen(engine: &mut Engine, path: &Path, options: &Options) -> Result<(), Box<dyn Error>> {
+    let mut emitted = Emitted::default();
+    toy_payments::listen(engine, path, |engine| {
+        emit_clients(engine, options, &mut emitted).map_err(|err| err.to_string())?;
+        if let Some(state_file) = &options.state_file {
+            save_state(engine, Path::new(state_file)).map_err(|err| err.to_string())?;
+        }
+        Ok(())
+    })
+    .map_err(|err| err as Box<dyn Error>)
+}
+
+#[cfg(not(unix))]
+fn listen(_engine: &mut Engine, path: &Path, _options: &Options) -> Result<(), Box<dyn Error>> {
+    Err(From::from(format!(
+        "Can't listen on {}, sockets and named pipes are only supported on Unix",
+        path.display()
+    )))
+}
+
+/// Writes the file next to `path` first and then moves it into place, so a failed
+/// or interrupted write can't leave a truncated file behind. An `s3://` uri is
+/// written to a temporary file and then uploaded, replacing the object only once
+/// it's all in.
+fn write_atomically<F>(path: &Path, write: F) -> Result<(), Box<dyn Error>>
+where
+    F: FnOnce(&mut io::BufWriter<File>) -> Result<(), Box<dyn Error>>,
+{
+    if is_s3(path.as_os_str()) {
+        let staged = staging_path("output");
+        let written = write_atomically(&staged, write)
+            .and_then(|()| upload_s3(&staged, &path.to_string_lossy()));
+        // The staged file is only a copy, whether or not it made it up
+        let _ = fs::remove_file(&staged);
+        return written;
+    }
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    let file = File::create(&temp).map_err(|err| format!("Failed to create {:?} {}", temp, err))?;
+    let mut writer = io::BufWriter::new(file);
+    write(&mut writer)?;
+    writer.flush()?;
+    fs::rename(&temp, path)?;
+    Ok(())
+}
+
+/// Whether the path is an `s3://bucket/key` uri rather than a local file
+fn is_s3(path: &OsStr) -> bool {
+    path.to_str().is_some_and(|path| path.starts_with("s3://"))
+}
+
+/// A file in the temp dir to stage an upload to S3 in
+fn staging_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("toy-payments-{}-{}", process::id(), name))
+}
+
+#[cfg(feature = "s3")]
+fn open_s3(uri: &str) -> Result<Box<dyn io::Read + Send>, Box<dyn Error>> {
+    let uri: toy_payments::S3Uri = uri.parse()?;
+    Ok(Box::new(toy_payments::S3::connect()?.open(&uri)?))
+}
+
+/// Copies the object to `path`, leaving it empty if there's no such object yet
+#[cfg(feature = "s3")]
+fn download_s3(uri: &str, path: &Path) -> Result<(), Box<dyn Error>> {
+    let uri: toy_payments::S3Uri = uri.parse()?;
+    let s3 = toy_payments::S3::connect()?;
+    let mut file = File::create(path)?;
+    if s3.exists(&uri)? {
+        io::copy(&mut s3.open(&uri)?, &mut file)?;
+    }
+    Ok(())
+}
+
+#[cfg(feature = "s3")]
+fn upload_s3(path: &Path, uri: &str) -> Result<(), Box<dyn Error>> {
+    let uri: toy_payments::S3Uri = uri.parse()?;
+    let file = File::open(path)?;
+    Ok(toy_payments::S3::connect()?.upload(&uri, io::BufReader::new(file))?)
+}
+
+#[cfg(not(feature = "s3"))]
+fn open_s3(uri: &str) -> Result<Box<dyn io::Read + Send>, Box<dyn Error>> {
+    Err(From::from(format!(
+        "Can't read {}, built without the s3 feature",
+        uri
+    )))
+}
+
+#[cfg(not(feature = "s3"))]
+fn download_s3(uri: &str, _path: &Path) -> Result<(), Box<dyn Error>> {
+    Err(From::from(format!(
+        "Can't read {}, built without the s3 feature",
+        uri
+    )))
+}
+
+#[cfg(not(feature = "s3"))]
+fn upload_s3(_path: &Path, uri: &str) -> Result<(), Box<dyn Error>> {
+    Err(From::from(format!(
+        "Can't write {}, built without the s3 feature",
+        uri
+    )))
+}
+
+fn save_state(engine: &Engine, path: &Path) -> Result<(), Box<dyn Error>> {
+    write_atomically(path, |writer| Ok(engine.save_state(writer)?))
+}
+
+/// Migrates the state file in place, noting on stderr which version it was
+fn upgrade_state_file(path: &Path) -> Result<(), Box<dyn Error>> {
+    let file = File::open(path).map_err(|err| format!("Failed to open {:?} {}", path, err))?;
+    let mut upgraded = Vec::new();
+    let from = toy_payments::upgrade_state(io::BufReader::new(file), &mut upgraded)
+        .map_err(|err| format!("Failed to upgrade {:?} {}", path, err))?;
+    write_atomically(path, |writer| Ok(writer.write_all(&upgraded)?))?;
+    if from == STATE_VERSION {
+        eprintln!("state already at version {}", STATE_VERSION);
+    } else {
+        eprintln!("state upgraded from version {} to {}", from, STATE_VERSION);
+    }
+    Ok(())
+}
+
+/// Writes the accounts to the `--output` file, or stdout without one
+/// Writes the accounts as they stood when a signal stopped the run, along with a
+/// checkpoint to carry on from if there's one, rather than the saved state, which
+/// only a finished run updates
+fn interrupted(engine: &Engine, options: &Options) -> Result<(), Box<dyn Error>> {
+    if let Some((path, _)) = &options.checkpoint {
+        let checkpoint = engine.checkpoint()?;
+        write_atomically(Path::new(path), |writer| Ok(checkpoint.write(writer)?))?;
+    }
+    display_clients(engine, options)?;
+    Err(Box::new(PaymentsError::Interrupted))
+}
+
+fn display_clients(engine: &Engine, options: &Options) -> Result<(), Box<dyn Error>> {
+    display_rows(
+        engine.account_rows(options.output_order),
+        engine.precision(),
+        options,
+    )
+}
+
+/// Where `--output-mode delta` is up to
+#[derive(Default)]
+struct Emitted {
+    /// Times the accounts were due to be written out
+    times: u64,
+    /// Sequence number of the last delta written
+    sequence: u64,
+    /// The engine's change stamp as of then
+    stamp: u64,
+}
+
+/// Writes the accounts out in a continuous run: every one of them, or with
+/// `--output-mode delta` the rows of the clients changed since the last delta, with
+/// its sequence number. Nothing is written, and no number taken, when nothing
+/// changed, other than every `--full-snapshot-every` times when every account is.
+fn emit_clients(
+    engine: &Engine,
+    options: &Options,
+    emitted: &mut Emitted,
+) -> Result<(), Box<dyn Error>> {
+    if options.output_mode == OutputMode::Full {
+        return display_clients(engine, options);
+    }
+    let full = options
+        .full_snapshot_every
+        .is_some_and(|every| emitted.times.is_multiple_of(every));
+    emitted.times += 1;
+    let mut rows = if full {
+        engine.account_rows(options.output_order)
+    } else {
+        engine.account_rows_of(&engine.changed_since(emitted.stamp))
+    };
+    emitted.stamp = engine.change_stamp();
+    if rows.is_empty() && !full {
+        return Ok(());
+    }
+    emitted.sequence += 1;
+    for row in &mut rows {
+        row.sequence = Some(emitted.sequence);
+    }
+    display_rows(rows, engine.precision(), options)
+}
+
+fn display_rows(
+    rows: Vec<AccountRow>,
+    precision: u32,
+    options: &Options,
+) -> Result<(), Box<dyn Error>> {
+    let write = |writer: &mut dyn Write| {
+        write_rows(
+            rows,
+            &options.output_filter,
+            options.output_format,
+            precision,
+            writer,
+        )
+    };
+    match &options.output {
+        Some(path) => write_atomically(Path::new(path), |writer| write(writer)),
+        None => write(&mut io::stdout().lock()),
     }
+}
 
-    fn ensure_globally_unique_transaction(
-        &self,
-        transaction: Option<Transaction>,
-    ) -> Result<bool, Box<dyn Error>> {
-        match transaction {
-            None => Err(From::from("Transaction doesn't exist")),
-            Some(t) => match self.transactions.get(&t.id) {
-                None => Ok(true),
-                _ => Ok(false),
-            },
-        }
-    }
-
-    fn transaction_from_record(
-        &self,
-        record: StringRecord,
-    ) -> Result<(TransactionType, Option<Transaction>), Box<dyn Error>> {
-        use TransactionType::*;
-        let transaction_type = record[0]
-            .parse::<TransactionType>()
-            .unwrap_or_else(|err| panic!("{:?}", err));
-        let client_id = record[1]
-            .trim()
-            .parse::<ClientId>()
-            .unwrap_or_else(|err| panic!("Failed to set client_id from {} {}", &record[1], err));
-        let tx = record[2]
-            .trim()
-            .parse::<TxId>()
-            .unwrap_or_else(|err| panic!("Failed to set tx from {} {}", &record[2], err));
-        match transaction_type {
-            Deposit | Withdrawal => {
-                let mut amount = Decimal::from_str(&record[3].trim()).unwrap_or_else(|err| {
-                    panic!("Failed to set amount from {} {}", &record[3], err)
-                });
-                amount.rescale(4);
-                let transaction = Transaction {
-                    id: tx,
-                    transaction_type: transaction_type.clone(),
-                    client_id: client_id,
-                    amount: amount,
-                };
-
-                return Ok((transaction_type, Some(transaction)));
+fn write_clients<W: Write>(
+    engine: &Engine,
+    order: OutputOrder,
+    filter: &OutputFilter,
+    format: OutputFormat,
+    writer: W,
+) -> Result<(), Box<dyn Error>> {
+    write_rows(
+        engine.account_rows(order),
+        filter,
+        format,
+        engine.precision(),
+        writer,
+    )
+}
+
+fn write_rows<W: Write>(
+    mut rows: Vec<AccountRow>,
+    filter: &OutputFilter,
+    format: OutputFormat,
+    precision: u32,
+    mut writer: W,
+) -> Result<(), Box<dyn Error>> {
+    rows.retain(|row| filter.keeps(row));
+    match format {
+        OutputFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(&mut writer);
+            for row in rows {
+                writer.serialize(row)?;
             }
-            Dispute | Resolve | Chargeback => {
-                match self.transactions.get(&tx) {
-                    Some(t) => {
-                        // Client must own transaction, else record is in error
-                        if &t.client_id == &client_id {
-                            Ok((transaction_type, Some(t.clone())))
-                        } else {
-                            // Matching tx id is not relative to client
-                            Ok((transaction_type, None))
-                        }
-                    }
-                    None => return Ok((transaction_type, None)),
-                }
+            writer.flush()?;
+        }
+        OutputFormat::Json => {
+            serde_json::to_writer(&mut writer, &rows)?;
+            writeln!(writer)?;
+        }
+        OutputFormat::JsonLines => {
+            for row in rows {
+                serde_json::to_writer(&mut writer, &row)?;
+                writeln!(writer)?;
             }
         }
+        OutputFormat::Parquet => write_parquet(&rows, precision, &mut writer)?,
     }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Writes the accounts as a Parquet file, built up in memory as the writer isn't
+/// always `Send`
+#[cfg(feature = "parquet")]
+fn write_parquet(
+    rows: &[AccountRow],
+    precision: u32,
+    writer: &mut dyn Write,
+) -> Result<(), Box<dyn Error>> {
+    let mut file = Vec::new();
+    toy_payments::write_parquet(rows, precision, &mut file)?;
+    writer.write_all(&file)?;
+    Ok(())
+}
 
-    fn get_from_env(&self) -> Result<OsString, Box<dyn Error>> {
-        match env::args_os().nth(1) {
-            None => Err(From::from(
-                "Expected 1 argument for transaction csv, but got none",
-            )),
-            Some(file_path) => Ok(file_path),
+#[cfg(not(feature = "parquet"))]
+fn write_parquet(
+    _rows: &[AccountRow],
+    _precision: u32,
+    _writer: &mut dyn Write,
+) -> Result<(), Box<dyn Error>> {
+    Err(From::from(
+        "Can't write parquet, built without the parquet feature",
+    ))
+}
+
+fn write_disputes<W: Write>(engine: &Engine, writer: W) -> Result<(), Box<dyn Error>> {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    let mut writer = csv::Writer::from_writer(writer);
+    let disputes = engine.open_disputes(now)?;
+    if disputes.is_empty() {
+        writer.write_record(["client", "tx", "type", "amount", "age"])?;
+    }
+    for dispute in disputes {
+        writer.serialize(dispute)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Writes the fees pooled in the house balance to stderr, when collecting them there
+fn display_house_fees(engine: &Engine) {
+    if engine
+        .fee_schedule()
+        .is_none_or(|schedule| schedule.collect != FeeCollection::House)
+    {
+        return;
+    }
+    let report = engine.report();
+    if let Some(fees) = report.fees {
+        eprintln!("house fees: {}", fees);
+    }
+    for (currency, fees) in report.currency_fees {
+        eprintln!("house fees {}: {}", currency, fees);
+    }
+}
+
+fn read_fee_schedule(path: &OsString) -> Result<FeeSchedule, Box<dyn Error>> {
+    let schedule =
+        fs::read_to_string(path).map_err(|err| format!("Failed to read {:?} {}", path, err))?;
+    Ok(FeeSchedule::from_toml(&schedule)?)
+}
+
+/// Writes the client's account as of the tx as accounts csv
+fn display_balance_at(engine: &Engine, id: ClientId, tx: TxId) -> Result<(), Box<dyn Error>> {
+    let client = engine
+        .balance_at(id, tx)
+        .ok_or_else(|| format!("No tx {} in the history of client {}", tx, id))?;
+    let mut writer = csv::Writer::from_writer(io::stdout().lock());
+    for row in client.rows() {
+        writer.serialize(row)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+fn display_history(engine: &Engine, id: ClientId) -> Result<(), Box<dyn Error>> {
+    if engine.client(id).is_none() {
+        return Err(From::from(format!("Unknown client {}", id)));
+    }
+    let mut stdout = io::stdout().lock();
+    for transaction in engine.history(id) {
+        serde_json::to_writer(&mut stdout, transaction)?;
+        writeln!(stdout)?;
+    }
+    Ok(())
+}
+
+/// Lists the records skipped along the way on stderr, if any
+fn display_skipped(engine: &Engine) {
+    if engine.invalid_records() > 0 {
+        eprintln!(
+            "Skipped {} records that failed to parse",
+            engine.invalid_records()
+        );
+    }
+    if engine.invariant_violations() > 0 {
+        eprintln!("{} broken invariants", engine.invariant_violations());
+    }
+    let skipped: usize = engine.skipped().values().sum();
+    if skipped > 0 {
+        eprintln!("Skipped {} records:", skipped);
+        for (reason, count) in engine.skipped() {
+            eprintln!("  {}: {}", reason, count);
         }
     }
 }
 
+/// Writes what was done with the records, by transaction type and skip reason, and
+/// the system wide totals
+fn write_summary(engine: &Engine, writer: &mut dyn Write) -> io::Result<()> {
+    writeln!(writer, "transactions: {}", engine.valid_records())?;
+    for (transaction_type, count) in engine.transaction_counts() {
+        writeln!(writer, "  {}: {}", transaction_type, count)?;
+    }
+    let skipped: usize = engine.skipped().values().sum();
+    writeln!(writer, "applied: {}", engine.applied_records())?;
+    writeln!(writer, "rejected: {}", skipped)?;
+    for (reason, count) in engine.skipped() {
+        writeln!(writer, "  {}: {}", reason, count)?;
+    }
+    writeln!(writer, "invalid records: {}", engine.invalid_records())?;
+    if engine.invariant_violations() > 0 {
+        writeln!(
+            writer,
+            "broken invariants: {}",
+            engine.invariant_violations()
+        )?;
+    }
+    let report = engine.report();
+    writeln!(writer, "locked accounts: {}", report.locked_accounts)?;
+    writeln!(writer, "available: {}", report.available)?;
+    writeln!(writer, "held: {}", report.held)?;
+    writeln!(writer, "total: {}", report.total)?;
+    for (currency, balance) in &report.currencies {
+        writeln!(
+            writer,
+            "{}: available {}, held {}, total {}",
+            currency, balance.available, balance.held, balance.total
+        )?;
+    }
+    Ok(())
+}
+
+/// One line naming an account that didn't reconcile
+fn describe_discrepancy(discrepancy: &Discrepancy) -> String {
+    let currency = discrepancy
+        .currency
+        .as_ref()
+        .map(|currency| format!(" in {}", currency))
+        .unwrap_or_default();
+    format!(
+        "Client {}{}: total {}, expected {}",
+        discrepancy.client, currency, discrepancy.actual, discrepancy.expected
+    )
+}
+
+/// Writes the records that failed validation as json lines, for tools to pick up
+fn write_issues(engine: &Engine, writer: &mut dyn Write) -> Result<(), Box<dyn Error>> {
+    for issue in engine.issues() {
+        serde_json::to_writer(&mut *writer, issue)?;
+        writeln!(writer)?;
+    }
+    Ok(())
+}
+
+/// Writes what processing did, or would do, to stderr so stdout stays free for results
+fn display_summary(engine: &Engine) {
+    let skipped: usize = engine.skipped().values().sum();
+    eprintln!("valid records: {}", engine.valid_records());
+    eprintln!("invalid records: {}", engine.invalid_records());
+    eprintln!("skipped records: {}", skipped);
+    for (reason, count) in engine.skipped() {
+        eprintln!("  {}: {}", reason, count);
+    }
+    let locked: Vec<ClientId> = engine
+        .clients(OutputOrder::ClientId)
+        .into_iter()
+        .filter(|c| c.locked())
+        .map(|c| c.id())
+        .collect();
+    eprintln!("locked accounts: {} {:?}", locked.len(), locked);
+}
+
+/// Logs to stderr at `RUST_LOG`'s levels, or at `level` for every module if given
+fn init_logging(level: Option<LevelFilter>, format: LogFormat) {
+    let mut builder = env_logger::Builder::from_default_env();
+    if let Some(level) = level {
+        builder.filter_level(level);
+    }
+    if format == LogFormat::Json {
+        builder.format(|buf, record| {
+            let line = serde_json::json!({
+                "timestamp": buf.timestamp_millis().to_string(),
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "message": record.args().to_string(),
+            });
+            writeln!(buf, "{}", line)
+        });
+    }
+    builder.init();
+}
+
 fn main() {
-    let mut service = ToyProgram::new();
-    if let Err(err) = service.process() {
-        println!("{}", err);
-        process::exit(1);
+    let cli = Cli::parse();
+    init_logging(cli.log_level, cli.log_format);
+    let mut engine: Engine =
+        PaymentsEngine::with_stores(Box::new(MemoryStore::new()), Box::new(MemoryStore::new()));
+    let options = match configure(&mut engine, cli) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("{}", err);
+            process::exit(EXIT_USAGE);
+        }
+    };
+    let mut result = run(&mut engine, &options);
+    // The trail holds every record read, so is uploaded even when the run failed
+    if let Some((staged, uri)) = &options.audit_upload {
+        let uploaded = upload_s3(staged, uri);
+        let _ = fs::remove_file(staged);
+        result = result.and(uploaded);
+    }
+    if let Err(err) = result {
+        eprintln!("{}", err);
+        match err.downcast_ref::<PaymentsError>() {
+            Some(PaymentsError::Interrupted) => process::exit(EXIT_INTERRUPTED),
+            _ => process::exit(EXIT_FAILURE),
+        }
     }
     process::exit(0);
 }
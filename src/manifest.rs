@@ -0,0 +1,227 @@
+use crate::{AccountStore, DisputePolicy, PaymentsEngine, TransactionStore, TransactionType};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+/// What a run did, for pipelines to verify and archive: the inputs it read, what
+/// became of their records, how it was configured and the output it wrote. Written
+/// as json once the run is done.
+#[derive(Debug, Clone, Serialize)]
+pub struct Manifest {
+    pub engine_version: &'static str,
+    pub inputs: Vec<FileDigest>,
+    pub records: RecordCounts,
+    /// Records skipped, by their disposition in the audit trail
+    pub rejected: BTreeMap<&'static str, usize>,
+    pub config: RunConfig,
+    /// The accounts output, `None` if nothing was written
+    pub output: Option<FileDigest>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RecordCounts {
+    /// Records that parsed, whether they were applied or skipped
+    pub valid: usize,
+    /// Records that failed to parse
+    pub invalid: usize,
+    pub applied: usize,
+    /// Records that parsed, by transaction type
+    pub by_type: BTreeMap<TransactionType, usize>,
+}
+
+/// How the run was set up: its command line, and the files it named that the
+/// settings were read from
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct RunConfig {
+    pub args: Vec<String>,
+    pub files: Vec<FileDigest>,
+}
+
+/// The size and sha-256 of a file, or of whatever was read from a stream
+#[derive(Debug, Clone, Eq, PartialEq, Serialize)]
+pub struct FileDigest {
+    pub path: String,
+    pub bytes: u64,
+    /// In lowercase hex
+    pub sha256: String,
+}
+
+impl FileDigest {
+    /// Reads the whole file to hash it, as it is on disk
+    pub fn of_file(path: &Path) -> io::Result<FileDigest> {
+        let mut hasher = Hasher::default();
+        io::copy(&mut File::open(path)?, &mut hasher)?;
+        Ok(hasher.digest(path.to_string_lossy().into_owned()))
+    }
+
+    pub fn of_bytes(path: String, bytes: &[u8]) -> FileDigest {
+        let mut hasher = Hasher::default();
+        hasher.update(bytes);
+        hasher.digest(path)
+    }
+}
+
+impl Manifest {
+    /// The manifest of a run that's done with the engine's inputs
+    pub fn of<T: TransactionStore, A: AccountStore, P: DisputePolicy>(
+        engine: &PaymentsEngine<T, A, P>,
+        inputs: Vec<FileDigest>,
+        config: RunConfig,
+        output: Option<FileDigest>,
+    ) -> Manifest {
+        let mut rejected = BTreeMap::new();
+        for (reason, count) in engine.skipped() {
+            *rejected.entry(reason.disposition()).or_insert(0) += count;
+        }
+        Manifest {
+            engine_version: env!("CARGO_PKG_VERSION"),
+            inputs,
+            records: RecordCounts {
+                valid: engine.valid_records(),
+                invalid: engine.invalid_records(),
+                applied: engine.applied_records(),
+                by_type: engine.transaction_counts().clone(),
+            },
+            rejected,
+            config,
+            output,
+        }
+    }
+
+    /// Writes the manifest as pretty printed json
+    pub fn write<W: Write>(&self, mut writer: W) -> io::Result<()> {
+        serde_json::to_writer_pretty(&mut writer, self)?;
+        writeln!(writer)
+    }
+}
+
+/// Hashes everything written to it
+#[derive(Default)]
+struct Hasher {
+    sha256: Sha256,
+    bytes: u64,
+}
+
+impl Hasher {
+    fn update(&mut self, bytes: &[u8]) {
+        self.sha256.update(bytes);
+        self.bytes += bytes.len() as u64;
+    }
+
+    fn digest(&self, path: String) -> FileDigest {
+        let sha256 = self.sha256.clone().finalize();
+        FileDigest {
+            path,
+            bytes: self.bytes,
+            sha256: sha256.iter().map(|byte| format!("{:02x}", byte)).collect(),
+        }
+    }
+}
+
+impl Write for Hasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Hashes a stream as it's read, such as stdin, which can't be read again once
+/// it's processed. Take its `digest` once whatever it was handed to is done with it.
+pub struct DigestReader<R> {
+    inner: R,
+    hasher: Arc<Mutex<Hasher>>,
+}
+
+/// What a `DigestReader` has read so far
+#[derive(Clone)]
+pub struct StreamDigest {
+    path: String,
+    hasher: Arc<Mutex<Hasher>>,
+}
+
+impl<R: Read> DigestReader<R> {
+    /// The reader, and where to get the digest of what it reads, named `path`
+    pub fn new(inner: R, path: String) -> (DigestReader<R>, StreamDigest) {
+        let hasher = Arc::new(Mutex::new(Hasher::default()));
+        let digest = StreamDigest {
+            path,
+            hasher: Arc::clone(&hasher),
+        };
+        (DigestReader { inner, hasher }, digest)
+    }
+}
+
+impl<R: Read> Read for DigestReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        if let Ok(mut hasher) = self.hasher.lock() {
+            hasher.update(&buf[..read]);
+        }
+        Ok(read)
+    }
+}
+
+impl StreamDigest {
+    pub fn digest(&self) -> FileDigest {
+        match self.hasher.lock() {
+            Ok(hasher) => hasher.digest(self.path.clone()),
+            Err(poisoned) => poisoned.into_inner().digest(self.path.clone()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hashes_streams_as_they_are_read() {
+        let (mut reader, digest) = DigestReader::new("abc".as_bytes(), "stdin".to_string());
+        io::copy(&mut reader, &mut io::sink()).unwrap();
+        let expected = FileDigest {
+            path: "stdin".to_string(),
+            bytes: 3,
+            sha256: "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad".to_string(),
+        };
+        assert_eq!(digest.digest(), expected);
+        assert_eq!(FileDigest::of_bytes("stdin".to_string(), b"abc"), expected);
+    }
+
+    #[test]
+    fn counts_records_and_rejections() {
+        let mut engine = PaymentsEngine::new();
+        engine
+            .process_part(
+                "type, client, tx, amount\n\
+                 deposit, 1, 1, 1.0\n\
+                 deposit, 1, 1, 1.0\n\
+                 withdrawal, 1, 2, 5.0\n\
+                 dispute, 1, 3\n\
+                 deposit, x, 4, 1.0\n"
+                    .as_bytes(),
+            )
+            .unwrap();
+
+        let manifest = Manifest::of(&engine, Vec::new(), RunConfig::default(), None);
+        assert_eq!(manifest.records.valid, 4);
+        assert_eq!(manifest.records.invalid, 1);
+        assert_eq!(manifest.records.applied, 1);
+        assert_eq!(manifest.records.by_type[&TransactionType::Deposit], 2);
+        assert_eq!(
+            manifest.rejected.into_iter().collect::<Vec<_>>(),
+            [
+                ("ignored-unknown-tx", 1),
+                ("rejected-duplicate", 1),
+                ("rejected-insufficient-funds", 1)
+            ]
+        );
+    }
+}
@@ -0,0 +1,156 @@
+use crate::{Report, TransactionType};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::time::Duration;
+
+/// Upper bounds of the latency histogram buckets, in seconds
+const LATENCY_BUCKETS: [f64; 10] = [
+    0.000_001, 0.000_005, 0.000_01, 0.000_05, 0.000_1, 0.000_5, 0.001, 0.01, 0.1, 1.0,
+];
+
+/// Counts and timings of the transactions the engine applied, for Prometheus. The
+/// balances are taken from the engine's `Report` when rendering.
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub(crate) struct Metrics {
+    /// Transactions applied or skipped, by type and disposition, see
+    /// `SkipReason::disposition`
+    processed: BTreeMap<(TransactionType, &'static str), u64>,
+    /// Count of transactions at or under each of `LATENCY_BUCKETS`
+    latency_buckets: [u64; LATENCY_BUCKETS.len()],
+    latency_count: u64,
+    latency_sum: Duration,
+}
+
+impl Metrics {
+    pub(crate) fn record(
+        &mut self,
+        transaction_type: &TransactionType,
+        disposition: &'static str,
+        latency: Duration,
+    ) {
+        *self
+            .processed
+            .entry((transaction_type.clone(), disposition))
+            .or_insert(0) += 1;
+        let secs = latency.as_secs_f64();
+        for (bucket, bound) in self.latency_buckets.iter_mut().zip(LATENCY_BUCKETS) {
+            if secs <= bound {
+                *bucket += 1;
+            }
+        }
+        self.latency_count += 1;
+        self.latency_sum += latency;
+    }
+
+    /// Adds another engine's counts and timings, such as a shard's
+    #[cfg(feature = "native")]
+    pub(crate) fn merge(&mut self, other: Metrics) {
+        for (key, count) in other.processed {
+            *self.processed.entry(key).or_insert(0) += count;
+        }
+        for (bucket, count) in self.latency_buckets.iter_mut().zip(other.latency_buckets) {
+            *bucket += count;
+        }
+        self.latency_count += other.latency_count;
+        self.latency_sum += other.latency_sum;
+    }
+
+    /// The metrics in the Prometheus text exposition format
+    pub(crate) fn render(&self, report: &Report) -> String {
+        let mut text = String::new();
+        // Writing to a String can't fail
+        let _ = self.write(&mut text, report);
+        text
+    }
+
+    fn write(&self, text: &mut String, report: &Report) -> std::fmt::Result {
+        writeln!(
+            text,
+            "# HELP payments_transactions_total Transactions applied or skipped, by type and disposition"
+        )?;
+        writeln!(text, "# TYPE payments_transactions_total counter")?;
+        for ((transaction_type, disposition), count) in &self.processed {
+            writeln!(
+                text,
+                "payments_transactions_total{{type=\"{}\",disposition=\"{}\"}} {}",
+                transaction_type, disposition, count
+            )?;
+        }
+        let funds = [
+            ("available", "Funds clients can use"),
+            ("held", "Funds held for open disputes"),
+            ("total", "Funds held and available"),
+        ];
+        for (name, help) in funds {
+            writeln!(text, "# HELP payments_{}_funds {}, by currency", name, help)?;
+            writeln!(text, "# TYPE payments_{}_funds gauge", name)?;
+            let amount = match name {
+                "available" => report.available,
+                "held" => report.held,
+                _ => report.total,
+            };
+            writeln!(text, "payments_{}_funds{{currency=\"\"}} {}", name, amount)?;
+            for (currency, balance) in &report.currencies {
+                let amount = match name {
+                    "available" => balance.available,
+                    "held" => balance.held,
+                    _ => balance.total,
+                };
+                writeln!(
+                    text,
+                    "payments_{}_funds{{currency=\"{}\"}} {}",
+                    name,
+                    escape(currency),
+                    amount
+                )?;
+            }
+        }
+        writeln!(
+            text,
+            "# HELP payments_locked_accounts Accounts locked by a chargeback"
+        )?;
+        writeln!(text, "# TYPE payments_locked_accounts gauge")?;
+        writeln!(text, "payments_locked_accounts {}", report.locked_accounts)?;
+        writeln!(
+            text,
+            "# HELP payments_open_disputes Disputes not yet resolved or charged back"
+        )?;
+        writeln!(text, "# TYPE payments_open_disputes gauge")?;
+        writeln!(text, "payments_open_disputes {}", report.open_disputes)?;
+        writeln!(
+            text,
+            "# HELP payments_transaction_seconds Time taken to apply a transaction"
+        )?;
+        writeln!(text, "# TYPE payments_transaction_seconds histogram")?;
+        for (bound, count) in LATENCY_BUCKETS.iter().zip(self.latency_buckets) {
+            writeln!(
+                text,
+                "payments_transaction_seconds_bucket{{le=\"{}\"}} {}",
+                bound, count
+            )?;
+        }
+        writeln!(
+            text,
+            "payments_transaction_seconds_bucket{{le=\"+Inf\"}} {}",
+            self.latency_count
+        )?;
+        writeln!(
+            text,
+            "payments_transaction_seconds_sum {}",
+            self.latency_sum.as_secs_f64()
+        )?;
+        writeln!(
+            text,
+            "payments_transaction_seconds_count {}",
+            self.latency_count
+        )
+    }
+}
+
+/// Escapes a label value as the text format expects
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
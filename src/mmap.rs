@@ -0,0 +1,96 @@
+use memmap2::Mmap;
+use std::fs::File;
+use std::io;
+use std::ops::Deref;
+
+/// A file's contents mapped read only into memory, for reading a large input in
+/// place with `PaymentsEngine::process_mapped_part`.
+///
+/// The file must not be changed while it's mapped, as a mapped file's bytes change
+/// along with it.
+pub struct MappedFile {
+    map: Mmap,
+}
+
+impl MappedFile {
+    pub fn open(file: &File) -> io::Result<MappedFile> {
+        // SAFETY: the mapping is read only, and callers are told above not to change
+        // the file while it's mapped
+        let map = unsafe { Mmap::map(file)? };
+        Ok(MappedFile { map })
+    }
+}
+
+impl Deref for MappedFile {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.map
+    }
+}
+
+impl AsRef<[u8]> for MappedFile {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Generator, OutputOrder, PaymentsEngine};
+
+    #[test]
+    fn mapped_input_applies_the_same_as_read_input() {
+        let path =
+            std::env::temp_dir().join(format!("toy-payments-mmap-{}.csv", std::process::id()));
+        let mut feed = Vec::new();
+        Generator {
+            clients: 20,
+            transactions: 2_000,
+            seed: 7,
+            malformed_rate: 0.02,
+        }
+        .write(&mut feed)
+        .unwrap();
+        // Not utf-8
+        feed.extend_from_slice(b"deposit,1,99999,\xff\n");
+        std::fs::write(&path, &feed).unwrap();
+
+        let map = MappedFile::open(&File::open(&path).unwrap()).unwrap();
+        assert_eq!(&map[..], &feed[..]);
+        let mut mapped = PaymentsEngine::new();
+        mapped.set_record_issues(true);
+        mapped.process_mapped_part(&map).unwrap();
+        mapped.finish().unwrap();
+        let mut read = PaymentsEngine::new();
+        read.set_record_issues(true);
+        read.process(&feed[..]).unwrap();
+        assert_eq!(
+            mapped.clients(OutputOrder::FirstSeen),
+            read.clients(OutputOrder::FirstSeen)
+        );
+        assert_eq!(mapped.skipped(), read.skipped());
+        assert_eq!(mapped.invalid_records(), read.invalid_records());
+        assert_eq!(mapped.issues().len(), read.issues().len());
+        assert_eq!(
+            mapped.issues().last().unwrap().message,
+            "Invalid utf-8 in record: invalid utf-8 sequence of 1 bytes from index 13"
+        );
+        drop(map);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn maps_an_empty_file() {
+        let path = std::env::temp_dir().join(format!(
+            "toy-payments-mmap-empty-{}.csv",
+            std::process::id()
+        ));
+        std::fs::write(&path, "").unwrap();
+        assert!(MappedFile::open(&File::open(&path).unwrap())
+            .unwrap()
+            .is_empty());
+        std::fs::remove_file(path).unwrap();
+    }
+}
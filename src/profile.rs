@@ -0,0 +1,248 @@
+use crate::fees::decimal;
+use crate::{
+    AccountStore, AmountFormat, ColumnMap, DisputePolicy, InputFormat, OverdraftPolicy,
+    PaymentsEngine, PaymentsError, RedisputePolicy, RoundingPolicy, TransactionStore, TxIdScope,
+    MAX_PRECISION,
+};
+use rust_decimal::Decimal;
+use std::str::FromStr;
+use toml_edit::{DocumentMut, Item};
+
+/// How one partner's feed is read and applied, bundling the engine settings it
+/// needs. Settings left out keep the engine's own. Read from a TOML file of named
+/// profiles such as:
+///
+/// ```toml
+/// [acme]
+/// precision = 2
+/// rounding = "half-even"
+/// strict = true
+/// dispute_withdrawals = true
+///
+/// [acme.columns]
+/// type = "txn_type"
+/// client = "cust"
+/// ```
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Profile {
+    pub input_format: Option<InputFormat>,
+    /// What csv fields are separated by
+    pub delimiter: Option<u8>,
+    pub amount_format: Option<AmountFormat>,
+    /// Read from a `columns` table, see `ColumnMap::from_toml`
+    pub column_map: Option<ColumnMap>,
+    pub rounding: Option<RoundingPolicy>,
+    pub precision: Option<u32>,
+    pub strict: Option<bool>,
+    pub buffered: Option<bool>,
+    pub dispute_withdrawals: Option<bool>,
+    pub disputes_on_locked: Option<bool>,
+    pub redispute: Option<RedisputePolicy>,
+    pub tx_id_scope: Option<TxIdScope>,
+    pub overdraft: Option<OverdraftPolicy>,
+    pub max_amount: Option<Decimal>,
+}
+
+impl Profile {
+    /// Reads the profile `name` from a file of profiles, each a table of settings
+    /// named as the command line options are, with `_` for `-`
+    pub fn from_toml(text: &str, name: &str) -> Result<Profile, PaymentsError> {
+        let document: DocumentMut = text
+            .parse()
+            .map_err(|err| PaymentsError::Profile(format!("{}", err).trim().to_string()))?;
+        let table = document
+            .get(name)
+            .ok_or_else(|| PaymentsError::Profile(format!("No profile {}", name)))?
+            .as_table_like()
+            .ok_or_else(|| invalid(&format!("{}, expected a table", name)))?;
+        let mut profile = Profile::default();
+        for (key, item) in table.iter() {
+            let what = format!("{}.{}", name, key);
+            match key {
+                "input_format" => profile.input_format = Some(parsed(item, &what)?),
+                "delimiter" => {
+                    let delimiter = item
+                        .as_str()
+                        .and_then(delimiter)
+                        .ok_or_else(|| invalid(&format!("{}, expected one character", what)))?;
+                    profile.delimiter = Some(delimiter);
+                }
+                "amount_format" => profile.amount_format = Some(parsed(item, &what)?),
+                "columns" => {
+                    let columns = item
+                        .as_table_like()
+                        .ok_or_else(|| invalid(&format!("{}, expected a table", what)))?;
+                    profile.column_map = Some(ColumnMap::from_table(columns)?);
+                }
+                "rounding" => profile.rounding = Some(parsed(item, &what)?),
+                "precision" => {
+                    let precision = item
+                        .as_integer()
+                        .and_then(|places| u32::try_from(places).ok())
+                        .filter(|places| *places <= MAX_PRECISION)
+                        .ok_or_else(|| {
+                            invalid(&format!("{}, expected 0 to {}", what, MAX_PRECISION))
+                        })?;
+                    profile.precision = Some(precision);
+                }
+                "strict" => profile.strict = Some(flag(item, &what)?),
+                "buffered" => profile.buffered = Some(flag(item, &what)?),
+                "dispute_withdrawals" => profile.dispute_withdrawals = Some(flag(item, &what)?),
+                "disputes_on_locked" => profile.disputes_on_locked = Some(flag(item, &what)?),
+                "redispute" => profile.redispute = Some(parsed(item, &what)?),
+                "tx_id_scope" => profile.tx_id_scope = Some(parsed(item, &what)?),
+                "overdraft" => profile.overdraft = Some(parsed(item, &what)?),
+                "max_amount" => {
+                    let amount = item
+                        .as_value()
+                        .and_then(decimal)
+                        .filter(|amount| *amount > Decimal::ZERO)
+                        .ok_or_else(|| invalid(&format!("{}, expected an amount", what)))?;
+                    profile.max_amount = Some(amount);
+                }
+                _ => return Err(invalid(&format!("key {}", what))),
+            }
+        }
+        Ok(profile)
+    }
+
+    /// Sets the engine up with the settings the profile has, those of its
+    /// `StandardDisputePolicy` included
+    pub fn apply<T: TransactionStore, A: AccountStore>(&self, engine: &mut PaymentsEngine<T, A>) {
+        self.apply_settings(engine);
+        if let Some(dispute_withdrawals) = self.dispute_withdrawals {
+            engine.set_withdrawal_disputes(dispute_withdrawals);
+        }
+        if let Some(disputes_on_locked) = self.disputes_on_locked {
+            engine.set_disputes_on_locked_accounts(disputes_on_locked);
+        }
+        if let Some(redispute) = self.redispute {
+            engine.set_redispute_policy(redispute);
+        }
+    }
+
+    /// Sets up an engine with a dispute policy of its own, leaving out the settings
+    /// of the `StandardDisputePolicy`, which are then up to that policy
+    pub fn apply_settings<T: TransactionStore, A: AccountStore, P: DisputePolicy>(
+        &self,
+        engine: &mut PaymentsEngine<T, A, P>,
+    ) {
+        if let Some(input_format) = &self.input_format {
+            engine.set_input_format(input_format.clone());
+        }
+        if let Some(delimiter) = self.delimiter {
+            engine.set_delimiter(delimiter);
+        }
+        if let Some(amount_format) = self.amount_format {
+            engine.set_amount_format(amount_format);
+        }
+        if let Some(column_map) = &self.column_map {
+            engine.set_column_map(column_map.clone());
+        }
+        if let Some(rounding) = self.rounding {
+            engine.set_rounding_policy(rounding);
+        }
+        if let Some(precision) = self.precision {
+            engine.set_precision(precision);
+        }
+        if let Some(strict) = self.strict {
+            engine.set_strict(strict);
+        }
+        if let Some(buffered) = self.buffered {
+            engine.set_buffered(buffered);
+        }
+        if let Some(tx_id_scope) = self.tx_id_scope {
+            engine.set_tx_id_scope(tx_id_scope);
+        }
+        if let Some(overdraft) = self.overdraft {
+            engine.set_overdraft_policy(overdraft);
+        }
+        if self.max_amount.is_some() {
+            engine.set_max_amount(self.max_amount);
+        }
+    }
+}
+
+fn invalid(what: &str) -> PaymentsError {
+    PaymentsError::Profile(format!("Invalid {}", what))
+}
+
+/// A setting given as a string the option's values parse from
+fn parsed<T: FromStr>(item: &Item, what: &str) -> Result<T, PaymentsError> {
+    item.as_str()
+        .and_then(|value| value.parse().ok())
+        .ok_or_else(|| invalid(what))
+}
+
+/// A single ascii character other than a quote or line break, as csv fields can
+/// be separated by
+fn delimiter(value: &str) -> Option<u8> {
+    match value.as_bytes() {
+        [byte] if byte.is_ascii() && !matches!(byte, b'"' | b'\n' | b'\r') => Some(*byte),
+        _ => None,
+    }
+}
+
+fn flag(item: &Item, what: &str) -> Result<bool, PaymentsError> {
+    item.as_bool()
+        .ok_or_else(|| invalid(&format!("{}, expected true or false", what)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    const PROFILES: &str = "[acme]\n\
+                            precision = 2\n\
+                            rounding = \"half-even\"\n\
+                            strict = true\n\
+                            max_amount = 5000\n\
+                            [acme.columns]\n\
+                            type = \"txn_type\"\n\
+                            [globex]\n\
+                            input_format = \"json\"\n\
+                            delimiter = \"|\"\n\
+                            tx_id_scope = \"client\"\n\
+                            dispute_withdrawals = false\n";
+
+    #[test]
+    fn reads_a_named_profile() {
+        let acme = Profile::from_toml(PROFILES, "acme").unwrap();
+        assert_eq!(acme.precision, Some(2));
+        assert_eq!(acme.rounding, Some(RoundingPolicy::HalfEven));
+        assert_eq!((acme.strict, acme.buffered), (Some(true), None));
+        assert_eq!(acme.max_amount, Some(dec!(5000)));
+        assert_eq!(acme.column_map.unwrap().name("type"), Some("txn_type"));
+        let globex = Profile::from_toml(PROFILES, "globex").unwrap();
+        assert_eq!(globex.input_format, Some(InputFormat::Json));
+        assert_eq!(globex.delimiter, Some(b'|'));
+        assert_eq!(globex.dispute_withdrawals, Some(false));
+        assert_eq!(globex.column_map, None);
+        assert_eq!(globex.tx_id_scope, Some(TxIdScope::Client));
+
+        for (bad, name) in [
+            (PROFILES, "initech"),
+            ("acme = 1", "acme"),
+            ("[acme]\nprecision = 40", "acme"),
+            ("[acme]\nstrict = \"yes\"", "acme"),
+            ("[acme]\nrounding = \"sideways\"", "acme"),
+            ("[acme]\nthreads = 4", "acme"),
+            ("[acme]\ndelimiter = \"||\"", "acme"),
+            ("[acme", "acme"),
+        ] {
+            assert!(
+                matches!(
+                    Profile::from_toml(bad, name),
+                    Err(PaymentsError::Profile(_))
+                ),
+                "{}",
+                bad
+            );
+        }
+        assert!(matches!(
+            Profile::from_toml("[acme.columns]\naccount = \"acct\"", "acme"),
+            Err(PaymentsError::ColumnMap(_))
+        ));
+    }
+}
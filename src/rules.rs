@@ -0,0 +1,133 @@
+use crate::fees::decimal;
+use crate::PaymentsError;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use toml_edit::DocumentMut;
+
+/// A limit a transaction broke, rejecting it with `SkipReason::RejectedByRule`
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Ord, PartialOrd, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Rule {
+    /// A withdrawal taking the client's withdrawals that day over the limit
+    MaxDailyWithdrawal,
+    /// A deposit, withdrawal or transfer past the client's limit for the input file
+    #[serde(rename = "max_tx_per_client")]
+    MaxTransactionsPerClient,
+    /// A deposit over the limit
+    MaxDeposit,
+}
+
+impl Rule {
+    /// The rule's name, as its key in the rules file
+    pub fn name(&self) -> &'static str {
+        match self {
+            Rule::MaxDailyWithdrawal => "max_daily_withdrawal",
+            Rule::MaxTransactionsPerClient => "max_tx_per_client",
+            Rule::MaxDeposit => "max_deposit",
+        }
+    }
+
+    /// Label for the audit trail of a record the rule rejected
+    pub(crate) fn disposition(&self) -> &'static str {
+        match self {
+            Rule::MaxDailyWithdrawal => "rejected-by-rule:max_daily_withdrawal",
+            Rule::MaxTransactionsPerClient => "rejected-by-rule:max_tx_per_client",
+            Rule::MaxDeposit => "rejected-by-rule:max_deposit",
+        }
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+/// Limits checked before a deposit, withdrawal or transfer is accepted, see
+/// `PaymentsEngine::set_rules`. Limits left out aren't checked. Read from TOML
+/// such as:
+///
+/// ```toml
+/// max_daily_withdrawal = "1000"
+/// max_tx_per_client = 500
+/// max_deposit = "10000"
+/// ```
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Rules {
+    /// Total a client may withdraw per day, by the records' timestamps. Withdrawals
+    /// without a timestamp count as a day of their own.
+    pub max_daily_withdrawal: Option<Decimal>,
+    /// Deposits, withdrawals and transfers a client may make per input file
+    pub max_transactions_per_client: Option<usize>,
+    pub max_deposit: Option<Decimal>,
+}
+
+impl Rules {
+    /// Parses rules, where every key is optional. Amounts may be given as strings,
+    /// integers or floats.
+    pub fn from_toml(text: &str) -> Result<Rules, PaymentsError> {
+        let document: DocumentMut = text
+            .parse()
+            .map_err(|err| PaymentsError::Rules(format!("{}", err).trim().to_string()))?;
+        let mut rules = Rules::default();
+        for (key, item) in document.iter() {
+            let amount = || {
+                item.as_value()
+                    .and_then(decimal)
+                    .filter(|amount| *amount > Decimal::ZERO)
+                    .ok_or_else(|| invalid(&format!("{}, expected an amount", key)))
+            };
+            match key {
+                "max_daily_withdrawal" => rules.max_daily_withdrawal = Some(amount()?),
+                "max_tx_per_client" => {
+                    let count = item
+                        .as_integer()
+                        .and_then(|count| usize::try_from(count).ok())
+                        .ok_or_else(|| invalid(&format!("{}, expected a count", key)))?;
+                    rules.max_transactions_per_client = Some(count);
+                }
+                "max_deposit" => rules.max_deposit = Some(amount()?),
+                _ => return Err(invalid(&format!("key {}", key))),
+            }
+        }
+        Ok(rules)
+    }
+}
+
+fn invalid(what: &str) -> PaymentsError {
+    PaymentsError::Rules(format!("Invalid {}", what))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn parses_rules_and_refuses_invalid_ones() {
+        let rules = Rules::from_toml(
+            "max_daily_withdrawal = \"1000\"\nmax_tx_per_client = 500\nmax_deposit = 10000.5\n",
+        )
+        .unwrap();
+        assert_eq!(rules.max_daily_withdrawal, Some(dec!(1000)));
+        assert_eq!(rules.max_transactions_per_client, Some(500));
+        assert_eq!(rules.max_deposit, Some(dec!(10000.5)));
+        assert_eq!(Rules::from_toml("").unwrap(), Rules::default());
+
+        for bad in [
+            "max_deposit = 0",
+            "max_deposit = \"lots\"",
+            "max_tx_per_client = -1",
+            "max_tx_per_client = \"5\"",
+            "max_withdrawal = 5",
+            "max_deposit = ",
+        ] {
+            assert!(
+                matches!(Rules::from_toml(bad), Err(PaymentsError::Rules(_))),
+                "{}",
+                bad
+            );
+        }
+    }
+}
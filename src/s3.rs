@@ -0,0 +1,250 @@
+use crate::PaymentsError;
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::types::{CompletedMultipartUpload, CompletedPart};
+use aws_sdk_s3::Client;
+use std::fmt;
+use std::io::{self, Read};
+use std::str::FromStr;
+use tokio::runtime::{Builder, Runtime};
+use tracing::debug;
+
+/// Bytes uploaded per part of a multipart upload, over S3's 5 MiB minimum
+const PART_SIZE: usize = 8 * 1024 * 1024;
+
+/// An object named by a `s3://bucket/key` uri
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct S3Uri {
+    pub bucket: String,
+    pub key: String,
+}
+
+impl FromStr for S3Uri {
+    type Err = PaymentsError;
+
+    fn from_str(s: &str) -> Result<S3Uri, Self::Err> {
+        let invalid = || PaymentsError::S3(format!("Expected s3://bucket/key, not {:?}", s));
+        let (bucket, key) = s
+            .strip_prefix("s3://")
+            .and_then(|rest| rest.split_once('/'))
+            .ok_or_else(invalid)?;
+        if bucket.is_empty() || key.is_empty() {
+            return Err(invalid());
+        }
+        Ok(S3Uri {
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+        })
+    }
+}
+
+impl fmt::Display for S3Uri {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "s3://{}/{}", self.bucket, self.key)
+    }
+}
+
+/// Reads and writes objects through the AWS SDK, with credentials and region taken
+/// from the environment and config files the same way as the `aws` cli. The SDK is
+/// async, so each call is run to completion on a runtime of its own.
+pub struct S3 {
+    client: Client,
+    runtime: Runtime,
+}
+
+impl S3 {
+    pub fn connect() -> Result<S3, PaymentsError> {
+        let runtime = Builder::new_current_thread().enable_all().build()?;
+        let config = runtime.block_on(aws_config::load_defaults(
+            aws_config::BehaviorVersion::latest(),
+        ));
+        Ok(S3 {
+            client: Client::new(&config),
+            runtime,
+        })
+    }
+
+    /// Streams the object, fetching its body a chunk at a time as it's read
+    pub fn open(self, uri: &S3Uri) -> Result<S3Reader, PaymentsError> {
+        let object = self
+            .runtime
+            .block_on(
+                self.client
+                    .get_object()
+                    .bucket(&uri.bucket)
+                    .key(&uri.key)
+                    .send(),
+            )
+            .map_err(|err| PaymentsError::S3(format!("Failed to get {} {}", uri, err)))?;
+        Ok(S3Reader {
+            body: object.body,
+            runtime: self.runtime,
+            chunk: Vec::new(),
+            position: 0,
+        })
+    }
+
+    /// Whether the object is there, for appending to it
+    pub fn exists(&self, uri: &S3Uri) -> Result<bool, PaymentsError> {
+        let head = self.runtime.block_on(
+            self.client
+                .head_object()
+                .bucket(&uri.bucket)
+                .key(&uri.key)
+                .send(),
+        );
+        match head {
+            Ok(_) => Ok(true),
+            Err(err) if err.as_service_error().is_some_and(|err| err.is_not_found()) => Ok(false),
+            Err(err) => Err(PaymentsError::S3(format!("Failed to find {} {}", uri, err))),
+        }
+    }
+
+    /// Writes everything read from `input` to the object, in parts of 8 MiB so it's
+    /// never all held in memory. The object is only replaced once every part is in.
+    pub fn upload<R: Read>(&self, uri: &S3Uri, mut input: R) -> Result<(), PaymentsError> {
+        let first = read_part(&mut input)?;
+        if first.len() < PART_SIZE {
+            self.runtime
+                .block_on(
+                    self.client
+                        .put_object()
+                        .bucket(&uri.bucket)
+                        .key(&uri.key)
+                        .body(ByteStream::from(first))
+                        .send(),
+                )
+                .map_err(|err| PaymentsError::S3(format!("Failed to put {} {}", uri, err)))?;
+            return Ok(());
+        }
+        let upload = self
+            .runtime
+            .block_on(
+                self.client
+                    .create_multipart_upload()
+                    .bucket(&uri.bucket)
+                    .key(&uri.key)
+                    .send(),
+            )
+            .map_err(|err| PaymentsError::S3(format!("Failed to upload {} {}", uri, err)))?;
+        let upload_id = upload.upload_id().unwrap_or_default();
+        let result = self.upload_parts(uri, upload_id, first, input);
+        if result.is_err() {
+            // Parts of an upload that's never completed are kept, and charged for,
+            // until it's aborted
+            let abort = self.runtime.block_on(
+                self.client
+                    .abort_multipart_upload()
+                    .bucket(&uri.bucket)
+                    .key(&uri.key)
+                    .upload_id(upload_id)
+                    .send(),
+            );
+            if let Err(err) = abort {
+                debug!("Failed to abort the upload to {} {}", uri, err);
+            }
+        }
+        result
+    }
+
+    fn upload_parts<R: Read>(
+        &self,
+        uri: &S3Uri,
+        upload_id: &str,
+        first: Vec<u8>,
+        mut input: R,
+    ) -> Result<(), PaymentsError> {
+        let failed = |err| PaymentsError::S3(format!("Failed to upload {} {}", uri, err));
+        let mut parts = Vec::new();
+        let mut part = first;
+        while !part.is_empty() {
+            let number = parts.len() as i32 + 1;
+            let uploaded = self
+                .runtime
+                .block_on(
+                    self.client
+                        .upload_part()
+                        .bucket(&uri.bucket)
+                        .key(&uri.key)
+                        .upload_id(upload_id)
+                        .part_number(number)
+                        .body(ByteStream::from(part))
+                        .send(),
+                )
+                .map_err(|err| failed(err.to_string()))?;
+            debug!("Uploaded part {} of {}", number, uri);
+            parts.push(
+                CompletedPart::builder()
+                    .set_e_tag(uploaded.e_tag)
+                    .part_number(number)
+                    .build(),
+            );
+            part = read_part(&mut input)?;
+        }
+        self.runtime
+            .block_on(
+                self.client
+                    .complete_multipart_upload()
+                    .bucket(&uri.bucket)
+                    .key(&uri.key)
+                    .upload_id(upload_id)
+                    .multipart_upload(
+                        CompletedMultipartUpload::builder()
+                            .set_parts(Some(parts))
+                            .build(),
+                    )
+                    .send(),
+            )
+            .map_err(|err| failed(err.to_string()))?;
+        Ok(())
+    }
+}
+
+/// Reads up to a part's worth of `input`, less only at its end
+fn read_part<R: Read>(input: &mut R) -> io::Result<Vec<u8>> {
+    let mut part = Vec::with_capacity(PART_SIZE);
+    input.take(PART_SIZE as u64).read_to_end(&mut part)?;
+    Ok(part)
+}
+
+/// An object's body, read as it's downloaded
+pub struct S3Reader {
+    body: ByteStream,
+    runtime: Runtime,
+    /// The chunk being read, up to `position`
+    chunk: Vec<u8>,
+    position: usize,
+}
+
+impl Read for S3Reader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.chunk.len() {
+            match self.runtime.block_on(self.body.next()) {
+                Some(chunk) => {
+                    self.chunk = chunk.map_err(io::Error::other)?.to_vec();
+                    self.position = 0;
+                }
+                None => return Ok(0),
+            }
+        }
+        let read = (&self.chunk[self.position..]).read(buf)?;
+        self.position += read;
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_uris() {
+        let uri: S3Uri = "s3://feeds/2024/01/transactions.csv".parse().unwrap();
+        assert_eq!(uri.bucket, "feeds");
+        assert_eq!(uri.key, "2024/01/transactions.csv");
+        assert_eq!(uri.to_string(), "s3://feeds/2024/01/transactions.csv");
+        assert!("s3://feeds".parse::<S3Uri>().is_err());
+        assert!("s3://feeds/".parse::<S3Uri>().is_err());
+        assert!("s3:///key".parse::<S3Uri>().is_err());
+        assert!("feeds/key".parse::<S3Uri>().is_err());
+    }
+}
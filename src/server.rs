@@ -0,0 +1,202 @@
+use crate::{AccountStore, ClientId, DisputePolicy, OutputOrder, PaymentsEngine, TransactionStore};
+use serde_json::json;
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use tiny_http::{Header, Response, Server};
+use tracing::{debug, warn};
+
+/// Content type of the Prometheus text format
+pub(crate) const METRICS_CONTENT_TYPE: &str = "Content-Type: text/plain; version=0.0.4";
+
+/// Serves the engine over http on `addr`, such as "127.0.0.1:8080", until the
+/// process is stopped. Requests are handled one at a time, in the order they come in.
+///
+/// - `POST /transactions`: applies a json transaction, as read by `--input-format json`
+/// - `GET /accounts`: every account, sorted by client id
+/// - `GET /accounts/{client_id}`: a single account
+/// - `GET /metrics`: the engine's metrics in the Prometheus text format
+pub fn serve<T, A, P>(
+    engine: &mut PaymentsEngine<T, A, P>,
+    addr: &str,
+) -> Result<(), Box<dyn Error + Send + Sync>>
+where
+    T: TransactionStore,
+    A: AccountStore,
+    P: DisputePolicy,
+{
+    engine.enable_metrics();
+    let server = Server::http(addr)?;
+    let json_type: Header = "Content-Type: application/json".parse().unwrap();
+    let metrics_type: Header = METRICS_CONTENT_TYPE.parse().unwrap();
+    for mut request in server.incoming_requests() {
+        let mut body = String::new();
+        let (status, body) = match request.as_reader().read_to_string(&mut body) {
+            Ok(_) => respond(engine, request.method().as_str(), request.url(), &body),
+            Err(err) => (400, json!({ "error": err.to_string() }).to_string()),
+        };
+        debug!("{} {} {}", request.method(), request.url(), status);
+        let content_type = match (status, request.url()) {
+            (200, "/metrics") => metrics_type.clone(),
+            _ => json_type.clone(),
+        };
+        let response = Response::from_string(body)
+            .with_status_code(status)
+            .with_header(content_type);
+        if let Err(err) = request.respond(response) {
+            warn!("Failed to respond: {}", err);
+        }
+    }
+    Ok(())
+}
+
+/// Serves `GET /metrics` on `addr` from a thread of its own, answering with
+/// whatever `latest` holds, such as the engine's `metrics` as of the last file
+/// `watch` applied. Fails if `addr` can't be bound.
+pub fn serve_metrics(
+    addr: &str,
+    latest: Arc<Mutex<String>>,
+) -> Result<thread::JoinHandle<()>, Box<dyn Error + Send + Sync>> {
+    let server = Server::http(addr)?;
+    let metrics_type: Header = METRICS_CONTENT_TYPE.parse().unwrap();
+    Ok(thread::spawn(move || {
+        for request in server.incoming_requests() {
+            let response = match (request.method().as_str(), request.url()) {
+                ("GET", "/metrics") => {
+                    let metrics = latest.lock().map(|m| m.clone()).unwrap_or_default();
+                    Response::from_string(metrics).with_header(metrics_type.clone())
+                }
+                (_, path) => {
+                    Response::from_string(format!("No route for {}", path)).with_status_code(404)
+                }
+            };
+            if let Err(err) = request.respond(response) {
+                warn!("Failed to respond: {}", err);
+            }
+        }
+    }))
+}
+
+/// Answers a single request with a status code and a json body, or the metrics text
+fn respond<T, A, P>(
+    engine: &mut PaymentsEngine<T, A, P>,
+    method: &str,
+    path: &str,
+    body: &str,
+) -> (u16, String)
+where
+    T: TransactionStore,
+    A: AccountStore,
+    P: DisputePolicy,
+{
+    let error = |status, message: String| (status, json!({ "error": message }).to_string());
+    match (method, path) {
+        ("POST", "/transactions") => match engine.transaction_from_json(body) {
+            Ok(row) => {
+                let tx = row.id;
+                match engine.process_transaction(row) {
+                    Ok(_) => (202, json!({ "tx": tx }).to_string()),
+                    Err(err) => error(500, err.to_string()),
+                }
+            }
+            Err(err) => error(400, err.to_string()),
+        },
+        ("GET", "/accounts") => match serde_json::to_string(&engine.clients(OutputOrder::ClientId))
+        {
+            Ok(accounts) => (200, accounts),
+            Err(err) => error(500, err.to_string()),
+        },
+        ("GET", path) if path.starts_with("/accounts/") => {
+            let id = &path["/accounts/".len()..];
+            let client = id
+                .parse::<ClientId>()
+                .map_err(|err| error(400, format!("Failed to set client_id from {} {}", id, err)))
+                .and_then(|id| {
+                    engine
+                        .client(id)
+                        .ok_or_else(|| error(404, format!("Unknown client {}", id)))
+                });
+            match client.map(serde_json::to_string) {
+                Ok(Ok(account)) => (200, account),
+                Ok(Err(err)) => error(500, err.to_string()),
+                Err(response) => response,
+            }
+        }
+        ("GET", "/metrics") => match engine.metrics() {
+            Some(metrics) => (200, metrics),
+            None => error(404, "Metrics are off".to_string()),
+        },
+        (_, "/transactions" | "/accounts" | "/metrics") => {
+            error(405, format!("{} not allowed", method))
+        }
+        _ => error(404, format!("No route for {}", path)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn routes() {
+        let mut engine = PaymentsEngine::new();
+        let deposit = r#"{"type":"deposit","client":2,"tx":1,"amount":"1.5"}"#;
+        assert_eq!(
+            respond(&mut engine, "POST", "/transactions", deposit),
+            (202, r#"{"tx":1}"#.to_string())
+        );
+        let (status, _) = respond(&mut engine, "POST", "/transactions", "{}");
+        assert_eq!(status, 400);
+
+        let account =
+            r#"{"client":2,"available":"1.5000","held":"0.0000","total":"1.5000","locked":false}"#;
+        assert_eq!(
+            respond(&mut engine, "GET", "/accounts/2", ""),
+            (200, account.to_string())
+        );
+        assert_eq!(
+            respond(&mut engine, "GET", "/accounts", ""),
+            (200, format!("[{}]", account))
+        );
+        assert_eq!(respond(&mut engine, "GET", "/accounts/3", "").0, 404);
+        assert_eq!(respond(&mut engine, "GET", "/accounts/x", "").0, 400);
+        assert_eq!(respond(&mut engine, "DELETE", "/accounts", "").0, 405);
+        assert_eq!(respond(&mut engine, "GET", "/", "").0, 404);
+        assert_eq!(respond(&mut engine, "GET", "/metrics", "").0, 404);
+    }
+
+    #[test]
+    fn metrics() {
+        let mut engine = PaymentsEngine::new();
+        engine.enable_metrics();
+        for body in [
+            r#"{"type":"deposit","client":1,"tx":1,"amount":"2.5"}"#,
+            r#"{"type":"deposit","client":1,"tx":1,"amount":"2.5"}"#,
+            r#"{"type":"dispute","client":1,"tx":1}"#,
+        ] {
+            respond(&mut engine, "POST", "/transactions", body);
+        }
+
+        let (status, metrics) = respond(&mut engine, "GET", "/metrics", "");
+        assert_eq!(status, 200);
+        for line in [
+            r#"payments_transactions_total{type="deposit",disposition="applied"} 1"#,
+            r#"payments_transactions_total{type="deposit",disposition="rejected-duplicate"} 1"#,
+            r#"payments_transactions_total{type="dispute",disposition="applied"} 1"#,
+            r#"payments_held_funds{currency=""} 2.5000"#,
+            r#"payments_available_funds{currency=""} 0.0000"#,
+            "payments_locked_accounts 0",
+            "payments_open_disputes 1",
+            r#"payments_transaction_seconds_bucket{le="+Inf"} 3"#,
+            "payments_transaction_seconds_count 3",
+        ] {
+            assert!(
+                metrics.lines().any(|l| l == line),
+                "{} in {}",
+                line,
+                metrics
+            );
+        }
+        assert_eq!(respond(&mut engine, "POST", "/metrics", "").0, 405);
+    }
+}
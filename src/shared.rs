@@ -0,0 +1,161 @@
+use crate::{
+    AccountStore, Client, ClientId, DisputePolicy, MemoryStore, Outcome, PaymentsEngine,
+    PaymentsError, Report, StandardDisputePolicy, Transaction, TransactionStore,
+};
+use std::sync::mpsc;
+use std::thread;
+
+/// A closure to call with the engine
+type Call<T, A, P> = Box<dyn FnOnce(&mut PaymentsEngine<T, A, P>) + Send>;
+
+/// A call for the engine's thread to make
+enum Request<T, A, P> {
+    Run(Call<T, A, P>),
+    /// Finish the engine and hand it back, stopping the thread
+    Finish(mpsc::Sender<Result<PaymentsEngine<T, A, P>, PaymentsError>>),
+}
+
+/// A `PaymentsEngine` on a thread of its own, behind a handle that can be cloned
+/// and used from any number of threads. Calls are queued and made one at a time in
+/// the order they arrive, each waiting for its answer, so callers don't need a
+/// lock around the engine.
+pub struct SharedPaymentsEngine<T = MemoryStore, A = MemoryStore, P = StandardDisputePolicy> {
+    sender: mpsc::Sender<Request<T, A, P>>,
+}
+
+impl<T, A, P> Clone for SharedPaymentsEngine<T, A, P> {
+    fn clone(&self) -> Self {
+        SharedPaymentsEngine {
+            sender: self.sender.clone(),
+        }
+    }
+}
+
+impl<T, A, P> SharedPaymentsEngine<T, A, P>
+where
+    T: TransactionStore + 'static,
+    A: AccountStore + 'static,
+    P: DisputePolicy,
+{
+    /// Moves the engine, with its settings, onto a new thread, which stops once
+    /// every handle is dropped or `finish` is called
+    pub fn new(mut engine: PaymentsEngine<T, A, P>) -> SharedPaymentsEngine<T, A, P> {
+        let (sender, receiver) = mpsc::channel::<Request<T, A, P>>();
+        thread::spawn(move || {
+            for request in receiver {
+                match request {
+                    Request::Run(call) => call(&mut engine),
+                    Request::Finish(reply) => {
+                        let _ = reply.send(engine.finish().map(|()| engine));
+                        return;
+                    }
+                }
+            }
+        });
+        SharedPaymentsEngine { sender }
+    }
+
+    /// Calls `f` with the engine on its thread once the calls queued before it are
+    /// done, and returns what it does
+    pub fn with<R, F>(&self, f: F) -> Result<R, PaymentsError>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut PaymentsEngine<T, A, P>) -> R + Send + 'static,
+    {
+        let (reply, answer) = mpsc::channel();
+        let call: Call<T, A, P> = Box::new(move |engine| {
+            let _ = reply.send(f(engine));
+        });
+        self.sender
+            .send(Request::Run(call))
+            .map_err(|_| PaymentsError::EngineStopped)?;
+        // No answer when the call panicked, which stops the engine
+        answer.recv().map_err(|_| PaymentsError::EngineStopped)
+    }
+
+    pub fn process_transaction(&self, row: Transaction) -> Result<Outcome, PaymentsError> {
+        self.with(move |engine| engine.process_transaction(row))?
+    }
+
+    /// The client's account as it stands, `None` if it hasn't been seen
+    pub fn client(&self, id: ClientId) -> Result<Option<Client>, PaymentsError> {
+        self.with(move |engine| engine.client(id).cloned())
+    }
+
+    pub fn report(&self) -> Result<Report, PaymentsError> {
+        self.with(|engine| engine.report())
+    }
+
+    /// Applies anything held back in buffered mode once the calls queued before are
+    /// done, and hands the engine back. Calls through other handles fail with
+    /// `PaymentsError::EngineStopped` from then on.
+    pub fn finish(self) -> Result<PaymentsEngine<T, A, P>, PaymentsError> {
+        let (reply, answer) = mpsc::channel();
+        self.sender
+            .send(Request::Finish(reply))
+            .map_err(|_| PaymentsError::EngineStopped)?;
+        answer.recv().map_err(|_| PaymentsError::EngineStopped)?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DisputeState, TransactionType, TxId};
+    use rust_decimal::Decimal;
+    use rust_decimal_macros::dec;
+
+    fn transaction(
+        id: TxId,
+        transaction_type: TransactionType,
+        client_id: ClientId,
+        amount: Decimal,
+    ) -> Transaction {
+        Transaction {
+            id,
+            transaction_type,
+            client_id,
+            amount,
+            to_client_id: None,
+            reference: None,
+            currency: None,
+            timestamp: None,
+            dispute_state: DisputeState::Undisputed,
+        }
+    }
+
+    #[test]
+    fn handles_on_many_threads_share_one_engine() {
+        use TransactionType::*;
+        let engine = SharedPaymentsEngine::new(PaymentsEngine::new());
+        let threads: Vec<_> = (1..=4)
+            .map(|client_id: ClientId| {
+                let engine = engine.clone();
+                thread::spawn(move || {
+                    for n in 0..100 {
+                        let id = TxId::from(client_id) * 1000 + n;
+                        let row = transaction(id, Deposit, client_id, dec!(1.0000));
+                        engine.process_transaction(row).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let disputed = transaction(1000, Dispute, 1, Decimal::ZERO);
+        assert_eq!(
+            engine.process_transaction(disputed).unwrap(),
+            Outcome::Applied
+        );
+        let client = engine.client(1).unwrap().unwrap();
+        assert_eq!(client.available(), dec!(99.0000));
+        assert_eq!(client.held(), dec!(1.0000));
+        assert_eq!(engine.report().unwrap().total, dec!(400.0000));
+
+        let other = engine.clone();
+        let engine = engine.finish().unwrap();
+        assert_eq!(engine.valid_records(), 401);
+        assert!(matches!(other.client(1), Err(PaymentsError::EngineStopped)));
+    }
+}
@@ -0,0 +1,31 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock};
+
+/// Set by the SIGINT and SIGTERM handlers, once they're installed
+static STOP: OnceLock<Arc<AtomicBool>> = OnceLock::new();
+
+extern "C" fn on_stop(signal: libc::c_int) {
+    if let Some(stop) = STOP.get() {
+        stop.store(true, Ordering::SeqCst);
+    }
+    // SAFETY: signal is async-signal-safe. Put back as it was, so a second
+    // signal stops the process straight away.
+    unsafe {
+        libc::signal(signal, libc::SIG_DFL);
+    }
+}
+
+/// Installs SIGINT and SIGTERM handlers setting the flag this returns, for
+/// `PaymentsEngine::set_stop_flag`, rather than stopping the process. A second
+/// signal stops the process as usual.
+pub fn stop_on_signals() -> Arc<AtomicBool> {
+    let stop = STOP.get_or_init(|| Arc::new(AtomicBool::new(false)));
+    // SAFETY: the handler only stores to an atomic and resets itself, which are
+    // async-signal-safe
+    unsafe {
+        let handler: extern "C" fn(libc::c_int) = on_stop;
+        libc::signal(libc::SIGINT, handler as libc::sighandler_t);
+        libc::signal(libc::SIGTERM, handler as libc::sighandler_t);
+    }
+    Arc::clone(stop)
+}
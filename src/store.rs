@@ -0,0 +1,163 @@
+use crate::{Client, ClientId, PaymentsError, Transaction, TxId};
+use std::collections::HashMap;
+use std::fmt;
+
+#[cfg(feature = "redis")]
+mod redis;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+
+#[cfg(feature = "redis")]
+pub use redis::RedisStore;
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteStore;
+
+/// What a kept transaction is found by: its tx id, along with its client's when tx
+/// ids are only unique per client, see `TxIdScope`
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub struct TxKey {
+    pub client: Option<ClientId>,
+    pub tx: TxId,
+}
+
+impl From<TxId> for TxKey {
+    /// The key of a tx id unique across every client
+    fn from(tx: TxId) -> TxKey {
+        TxKey { client: None, tx }
+    }
+}
+
+impl fmt::Display for TxKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.client {
+            Some(client) => write!(f, "{} of client {}", self.tx, client),
+            None => write!(f, "{}", self.tx),
+        }
+    }
+}
+
+/// Where the engine keeps the deposits, withdrawals and transfers it may still need
+/// for disputes, and to catch repeated tx ids
+pub trait TransactionStore: Send {
+    fn get_tx(&self, key: TxKey) -> Result<Option<Transaction>, PaymentsError>;
+
+    /// Adds the transaction under `key`, replacing any kept under the same key
+    fn put_tx(&mut self, key: TxKey, transaction: Transaction) -> Result<(), PaymentsError>;
+
+    fn remove_tx(&mut self, key: TxKey) -> Result<Option<Transaction>, PaymentsError>;
+
+    /// Every transaction kept, for saving the engine state or sharding it over threads
+    fn transactions(&self) -> Result<Vec<Transaction>, PaymentsError>;
+
+    /// Makes everything put so far durable, for stores that batch their writes
+    fn flush(&mut self) -> Result<(), PaymentsError> {
+        Ok(())
+    }
+}
+
+/// Where accounts are read from the first time the engine sees their client, and
+/// written back to once the input is done, or as each transaction is applied for
+/// stores that write through. Accounts are few enough, at most one per client id,
+/// that the engine works on them in memory in between.
+pub trait AccountStore: Send {
+    fn get_client(&self, id: ClientId) -> Result<Option<Client>, PaymentsError>;
+
+    /// Adds the client, or replaces it with its latest balances
+    fn put_client(&mut self, client: &Client) -> Result<(), PaymentsError>;
+
+    /// Makes everything put so far durable, for stores that batch their writes
+    fn flush(&mut self) -> Result<(), PaymentsError> {
+        Ok(())
+    }
+
+    /// Whether the engine puts the accounts each transaction touched, and flushes
+    /// both stores, as soon as the transaction is applied, for stores shared with
+    /// other instances that commit a transaction and its accounts together
+    fn writes_through(&self) -> bool {
+        false
+    }
+}
+
+impl<S: TransactionStore + ?Sized> TransactionStore for Box<S> {
+    fn get_tx(&self, key: TxKey) -> Result<Option<Transaction>, PaymentsError> {
+        (**self).get_tx(key)
+    }
+
+    fn put_tx(&mut self, key: TxKey, transaction: Transaction) -> Result<(), PaymentsError> {
+        (**self).put_tx(key, transaction)
+    }
+
+    fn remove_tx(&mut self, key: TxKey) -> Result<Option<Transaction>, PaymentsError> {
+        (**self).remove_tx(key)
+    }
+
+    fn transactions(&self) -> Result<Vec<Transaction>, PaymentsError> {
+        (**self).transactions()
+    }
+
+    fn flush(&mut self) -> Result<(), PaymentsError> {
+        TransactionStore::flush(&mut **self)
+    }
+}
+
+impl<S: AccountStore + ?Sized> AccountStore for Box<S> {
+    fn get_client(&self, id: ClientId) -> Result<Option<Client>, PaymentsError> {
+        (**self).get_client(id)
+    }
+
+    fn put_client(&mut self, client: &Client) -> Result<(), PaymentsError> {
+        (**self).put_client(client)
+    }
+
+    fn flush(&mut self) -> Result<(), PaymentsError> {
+        AccountStore::flush(&mut **self)
+    }
+
+    fn writes_through(&self) -> bool {
+        (**self).writes_through()
+    }
+}
+
+/// Keeps everything in memory, the engine's default for both transactions and
+/// accounts
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    transactions: HashMap<TxKey, Transaction>,
+    clients: HashMap<ClientId, Client>,
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+}
+
+impl TransactionStore for MemoryStore {
+    fn get_tx(&self, key: TxKey) -> Result<Option<Transaction>, PaymentsError> {
+        Ok(self.transactions.get(&key).cloned())
+    }
+
+    fn put_tx(&mut self, key: TxKey, transaction: Transaction) -> Result<(), PaymentsError> {
+        self.transactions.insert(key, transaction);
+        Ok(())
+    }
+
+    fn remove_tx(&mut self, key: TxKey) -> Result<Option<Transaction>, PaymentsError> {
+        Ok(self.transactions.remove(&key))
+    }
+
+    fn transactions(&self) -> Result<Vec<Transaction>, PaymentsError> {
+        Ok(self.transactions.values().cloned().collect())
+    }
+}
+
+impl AccountStore for MemoryStore {
+    fn get_client(&self, id: ClientId) -> Result<Option<Client>, PaymentsError> {
+        Ok(self.clients.get(&id).cloned())
+    }
+
+    fn put_client(&mut self, client: &Client) -> Result<(), PaymentsError> {
+        self.clients.insert(client.id, client.clone());
+        Ok(())
+    }
+}
@@ -0,0 +1,497 @@
+use super::{AccountStore, TransactionStore, TxKey};
+use crate::engine::SavedClient;
+use crate::{Client, ClientId, PaymentsError, Transaction};
+use redis::Connection;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::mem;
+use std::sync::{Arc, Mutex};
+
+/// Keys asked for per `SCAN` while listing the kept transactions
+const SCAN_COUNT: usize = 1000;
+
+/// Keeps transactions and accounts in Redis, shared by several engine instances
+/// that split the clients between them, such as behind a load balancer. Rows are
+/// stored as json under `<prefix>:tx:<tx>`, or `<prefix>:tx:<client>:<tx>` with
+/// client scoped tx ids, and `<prefix>:client:<id>`.
+///
+/// The store writes through: the engine flushes it after every transaction it
+/// applies, and the transaction's rows and every account it touched are committed
+/// together in one MULTI/EXEC, or not at all. Each key is watched, and only written
+/// if it's still as this store last read or wrote it, or still missing if it
+/// wasn't there. Otherwise the flush fails with `PaymentsError::StoreConflict` and
+/// nothing of the transaction is written, so instances can't overwrite each other's
+/// changes, or leave transactions behind for accounts they never wrote. The engine
+/// then reads the keys afresh and applies the transaction again.
+///
+/// Clones share the connection and the writes waiting to be committed, so a store
+/// and its clone make up the engine's transaction and account stores.
+#[derive(Clone)]
+pub struct RedisStore {
+    shared: Arc<Mutex<Shared>>,
+    prefix: String,
+}
+
+struct Shared {
+    connection: Connection,
+    /// Each key as last read or committed, `None` if it wasn't there
+    seen: HashMap<Key, Option<Vec<u8>>>,
+    /// Writes waiting for the next flush, `None` to delete the key
+    pending: BTreeMap<Key, Option<Vec<u8>>>,
+}
+
+/// What a row is kept under
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Ord, PartialOrd)]
+enum Key {
+    Tx(TxKey),
+    Client(ClientId),
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Key::Tx(key) => write!(f, "Tx {}", key),
+            Key::Client(id) => write!(f, "Client {}", id),
+        }
+    }
+}
+
+impl RedisStore {
+    /// Connects to the Redis server at `addr`, such as `localhost:6379`, keeping
+    /// everything under keys starting with `prefix`
+    pub fn connect(addr: &str, prefix: &str) -> Result<RedisStore, PaymentsError> {
+        let connection = redis::Client::open(format!("redis://{}", addr))?.get_connection()?;
+        Ok(RedisStore {
+            shared: Arc::new(Mutex::new(Shared {
+                connection,
+                seen: HashMap::new(),
+                pending: BTreeMap::new(),
+            })),
+            prefix: prefix.to_string(),
+        })
+    }
+
+    fn redis_key(&self, key: Key) -> String {
+        match key {
+            Key::Tx(TxKey {
+                client: Some(client),
+                tx,
+            }) => format!("{}:tx:{}:{}", self.prefix, client, tx),
+            Key::Tx(TxKey { client: None, tx }) => format!("{}:tx:{}", self.prefix, tx),
+            Key::Client(id) => format!("{}:client:{}", self.prefix, id),
+        }
+    }
+
+    /// The row under the key as waiting to be committed, or else as Redis has it
+    fn get(&self, key: Key) -> Result<Option<Vec<u8>>, PaymentsError> {
+        let shared = &mut *self.shared.lock().unwrap();
+        if let Some(pending) = shared.pending.get(&key) {
+            return Ok(pending.clone());
+        }
+        let body: Option<Vec<u8>> = redis::cmd("GET")
+            .arg(self.redis_key(key))
+            .query(&mut shared.connection)?;
+        shared.seen.insert(key, body.clone());
+        Ok(body)
+    }
+
+    /// Holds the write back for the next flush
+    fn put(&self, key: Key, body: Option<Vec<u8>>) {
+        self.shared.lock().unwrap().pending.insert(key, body);
+    }
+
+    /// Commits the writes waiting, all of them or, if another instance wrote one
+    /// of their keys since this store read it, none
+    fn commit(&self) -> Result<(), PaymentsError> {
+        let shared = &mut *self.shared.lock().unwrap();
+        if shared.pending.is_empty() {
+            return Ok(());
+        }
+        let pending = mem::take(&mut shared.pending);
+        let keys: Vec<String> = pending.keys().map(|key| self.redis_key(*key)).collect();
+        // Any write to the keys from here on makes the EXEC fail
+        redis::cmd("WATCH")
+            .arg(&keys)
+            .exec(&mut shared.connection)?;
+        let current: Vec<Option<Vec<u8>>> = redis::cmd("MGET")
+            .arg(&keys)
+            .query(&mut shared.connection)?;
+        let changed = pending
+            .keys()
+            .zip(&current)
+            .find(|(key, current)| shared.seen.get(key).is_some_and(|seen| seen != *current));
+        if let Some((key, _)) = changed {
+            redis::cmd("UNWATCH").exec(&mut shared.connection)?;
+            return Err(PaymentsError::StoreConflict(key.to_string()));
+        }
+        let mut transaction = redis::pipe();
+        transaction.atomic();
+        for (redis_key, body) in keys.iter().zip(pending.values()) {
+            match body {
+                Some(body) => transaction.set(redis_key, body).ignore(),
+                None => transaction.del(redis_key).ignore(),
+            };
+        }
+        let committed: Option<()> = transaction.query(&mut shared.connection)?;
+        if committed.is_none() {
+            // Written in between the check and the EXEC
+            let key = pending.keys().next().copied().unwrap_or(Key::Client(0));
+            return Err(PaymentsError::StoreConflict(key.to_string()));
+        }
+        shared.seen.extend(pending);
+        Ok(())
+    }
+}
+
+impl TransactionStore for RedisStore {
+    fn get_tx(&self, key: TxKey) -> Result<Option<Transaction>, PaymentsError> {
+        let body = self.get(Key::Tx(key))?;
+        Ok(body.map(|body| serde_json::from_slice(&body)).transpose()?)
+    }
+
+    fn put_tx(&mut self, key: TxKey, transaction: Transaction) -> Result<(), PaymentsError> {
+        self.put(Key::Tx(key), Some(serde_json::to_vec(&transaction)?));
+        Ok(())
+    }
+
+    fn remove_tx(&mut self, key: TxKey) -> Result<Option<Transaction>, PaymentsError> {
+        let transaction = self.get_tx(key)?;
+        if transaction.is_some() {
+            self.put(Key::Tx(key), None);
+        }
+        Ok(transaction)
+    }
+
+    /// Every instance's transactions, as they share the keys, along with those
+    /// waiting to be committed
+    fn transactions(&self) -> Result<Vec<Transaction>, PaymentsError> {
+        let shared = &mut *self.shared.lock().unwrap();
+        let mut scan = redis::cmd("SCAN");
+        scan.cursor_arg(0)
+            .arg("MATCH")
+            .arg(format!("{}:tx:*", self.prefix))
+            .arg("COUNT")
+            .arg(SCAN_COUNT);
+        let listed: Vec<String> = scan
+            .iter(&mut shared.connection)?
+            .collect::<Result<_, _>>()?;
+        let mut bodies = HashMap::new();
+        for key in listed {
+            let body: Option<Vec<u8>> =
+                redis::cmd("GET").arg(&key).query(&mut shared.connection)?;
+            // Removed since it was listed
+            if let Some(body) = body {
+                bodies.insert(key, body);
+            }
+        }
+        for (key, body) in &shared.pending {
+            if let Key::Tx(_) = key {
+                match body {
+                    Some(body) => bodies.insert(self.redis_key(*key), body.clone()),
+                    None => bodies.remove(&self.redis_key(*key)),
+                };
+            }
+        }
+        let mut transactions = bodies
+            .values()
+            .map(|body| serde_json::from_slice(body))
+            .collect::<Result<Vec<Transaction>, _>>()?;
+        transactions.sort_by_key(|transaction| (transaction.id, transaction.client_id));
+        Ok(transactions)
+    }
+
+    fn flush(&mut self) -> Result<(), PaymentsError> {
+        self.commit()
+    }
+}
+
+impl AccountStore for RedisStore {
+    fn get_client(&self, id: ClientId) -> Result<Option<Client>, PaymentsError> {
+        let body = self.get(Key::Client(id))?;
+        let saved: Option<SavedClient> =
+            body.map(|body| serde_json::from_slice(&body)).transpose()?;
+        Ok(saved.map(Client::from))
+    }
+
+    fn put_client(&mut self, client: &Client) -> Result<(), PaymentsError> {
+        let body = serde_json::to_vec(&SavedClient::from(client))?;
+        self.put(Key::Client(client.id), Some(body));
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), PaymentsError> {
+        self.commit()
+    }
+
+    fn writes_through(&self) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DisputeState, PaymentsEngine, TransactionType};
+    use redis::{Parser, Value};
+    use rust_decimal_macros::dec;
+    use std::io::Write;
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    /// Each key's value and how many times it was written, for WATCH
+    type Keys = Arc<Mutex<HashMap<Vec<u8>, (Option<Vec<u8>>, u64)>>>;
+
+    /// Serves the commands the store sends, as Redis would, on a local port
+    fn fake_redis() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let keys = Keys::default();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let keys = keys.clone();
+                thread::spawn(move || serve(stream.unwrap(), keys));
+            }
+        });
+        addr
+    }
+
+    fn serve(mut stream: TcpStream, keys: Keys) {
+        let reader = stream.try_clone().unwrap();
+        let mut parser = Parser::new();
+        let mut watched = HashMap::new();
+        let mut queued: Option<Vec<Vec<Vec<u8>>>> = None;
+        while let Ok(Value::Array(request)) = parser.parse_value(&reader) {
+            let args: Vec<Vec<u8>> = request
+                .into_iter()
+                .map(|arg| match arg {
+                    Value::BulkString(arg) => arg,
+                    other => panic!("Unexpected argument {:?}", other),
+                })
+                .collect();
+            let reply = match (args[0].as_slice(), &mut queued) {
+                (b"EXEC", queued) => {
+                    let keys = &mut keys.lock().unwrap();
+                    let commands = queued.take().unwrap();
+                    let changed = watched
+                        .drain()
+                        .any(|(key, version)| keys.get(&key).map_or(0, |entry| entry.1) != version);
+                    if changed {
+                        Value::Nil
+                    } else {
+                        Value::Array(commands.iter().map(|args| run(keys, args)).collect())
+                    }
+                }
+                (_, Some(queued)) => {
+                    queued.push(args);
+                    Value::SimpleString("QUEUED".to_string())
+                }
+                (b"MULTI", queued) => {
+                    *queued = Some(Vec::new());
+                    Value::Okay
+                }
+                (b"WATCH", _) => {
+                    let keys = keys.lock().unwrap();
+                    for key in &args[1..] {
+                        let version = keys.get(key).map_or(0, |entry| entry.1);
+                        watched.insert(key.clone(), version);
+                    }
+                    Value::Okay
+                }
+                (b"UNWATCH", _) => {
+                    watched.clear();
+                    Value::Okay
+                }
+                _ => run(&mut keys.lock().unwrap(), &args),
+            };
+            stream.write_all(&encode(&reply)).unwrap();
+        }
+    }
+
+    fn run(keys: &mut HashMap<Vec<u8>, (Option<Vec<u8>>, u64)>, args: &[Vec<u8>]) -> Value {
+        let get = |key: &Vec<u8>| match keys.get(key).and_then(|entry| entry.0.clone()) {
+            Some(body) => Value::BulkString(body),
+            None => Value::Nil,
+        };
+        match args[0].as_slice() {
+            b"GET" => get(&args[1]),
+            b"MGET" => Value::Array(args[1..].iter().map(get).collect()),
+            b"SET" | b"DEL" => {
+                let entry = keys.entry(args[1].clone()).or_default();
+                entry.0 = args.get(2).cloned();
+                entry.1 += 1;
+                Value::Okay
+            }
+            b"SCAN" => {
+                let prefix = args[3].strip_suffix(b"*").unwrap();
+                let matching = keys
+                    .iter()
+                    .filter(|(key, entry)| key.starts_with(prefix) && entry.0.is_some())
+                    .map(|(key, _)| Value::BulkString(key.clone()))
+                    .collect();
+                Value::Array(vec![
+                    Value::BulkString(b"0".to_vec()),
+                    Value::Array(matching),
+                ])
+            }
+            // Such as the CLIENT SETINFO sent on connecting
+            _ => Value::Okay,
+        }
+    }
+
+    fn encode(reply: &Value) -> Vec<u8> {
+        match reply {
+            Value::Okay => b"+OK\r\n".to_vec(),
+            Value::SimpleString(status) => format!("+{}\r\n", status).into_bytes(),
+            Value::Nil => b"$-1\r\n".to_vec(),
+            Value::BulkString(bulk) => {
+                let mut encoded = format!("${}\r\n", bulk.len()).into_bytes();
+                encoded.extend_from_slice(bulk);
+                encoded.extend_from_slice(b"\r\n");
+                encoded
+            }
+            Value::Array(items) => {
+                let mut encoded = format!("*{}\r\n", items.len()).into_bytes();
+                for item in items {
+                    encoded.extend(encode(item));
+                }
+                encoded
+            }
+            other => panic!("Unexpected reply {:?}", other),
+        }
+    }
+
+    #[test]
+    fn round_trips_transactions_and_clients() {
+        let addr = fake_redis();
+        let mut store = RedisStore::connect(&addr, "test").unwrap();
+        let deposit = Transaction {
+            id: 1,
+            transaction_type: TransactionType::Deposit,
+            client_id: 2,
+            amount: dec!(1.5),
+            to_client_id: None,
+            reference: None,
+            currency: None,
+            timestamp: None,
+            dispute_state: DisputeState::Undisputed,
+        };
+        store.put_tx(1.into(), deposit.clone()).unwrap();
+        // Read back before and after it's committed
+        assert_eq!(store.get_tx(1.into()).unwrap(), Some(deposit.clone()));
+        assert_eq!(store.transactions().unwrap(), vec![deposit.clone()]);
+        TransactionStore::flush(&mut store).unwrap();
+        let other = RedisStore::connect(&addr, "test").unwrap();
+        assert_eq!(other.get_tx(1.into()).unwrap(), Some(deposit.clone()));
+        let scoped = TxKey {
+            client: Some(2),
+            tx: 1,
+        };
+        assert_eq!(store.get_tx(scoped).unwrap(), None);
+        assert_eq!(store.transactions().unwrap(), vec![deposit.clone()]);
+        assert_eq!(store.remove_tx(1.into()).unwrap(), Some(deposit));
+        assert_eq!(store.get_tx(1.into()).unwrap(), None);
+        assert_eq!(store.transactions().unwrap(), vec![]);
+        TransactionStore::flush(&mut store).unwrap();
+        assert_eq!(other.transactions().unwrap(), vec![]);
+
+        let mut client = Client::new(2);
+        client.available = dec!(1.5);
+        client.total = dec!(1.5);
+        client.disputes.insert(7);
+        assert_eq!(store.get_client(2).unwrap(), None);
+        store.put_client(&client).unwrap();
+        AccountStore::flush(&mut store).unwrap();
+        assert_eq!(other.get_client(2).unwrap(), Some(client.clone()));
+        // Written again by the same instance
+        client.available = dec!(2);
+        client.total = dec!(2);
+        store.put_client(&client).unwrap();
+        AccountStore::flush(&mut store).unwrap();
+        assert_eq!(other.get_client(2).unwrap(), Some(client));
+    }
+
+    #[test]
+    fn commits_each_transaction_and_applies_conflicting_ones_again() {
+        let addr = fake_redis();
+        let engine = || {
+            let store = RedisStore::connect(&addr, "test").unwrap();
+            PaymentsEngine::with_stores(store.clone(), store)
+        };
+        let mut first = engine();
+        let mut second = engine();
+        first
+            .process_part(
+                "type, client, tx, amount\ndeposit, 1, 1, 2.0\ndeposit, 2, 2, 1.0\n".as_bytes(),
+            )
+            .unwrap();
+        // Committed as applied, so the second instance carries on from there
+        second
+            .process_part("type, client, tx, amount\ndeposit, 2, 3, 1.0\n".as_bytes())
+            .unwrap();
+        assert_eq!(second.client(2).unwrap().available(), dec!(2));
+
+        // The first still holds client 2 as it was before the second's deposit, so
+        // its transfer to client 2 conflicts, and is applied again to the client as
+        // the second left it
+        first
+            .process_part("type, client, tx, amount\ntransfer, 1, 4, 0.5, 2\n".as_bytes())
+            .unwrap();
+        assert_eq!(first.client(2).unwrap().available(), dec!(2.5));
+        assert_eq!(first.valid_records(), 3);
+
+        let mut third = engine();
+        third
+            .process_part("type, client, tx, amount\ndeposit, 1, 4, 1.0\ndispute, 1, 1\nwithdrawal, 2, 5, 0.5\n".as_bytes())
+            .unwrap();
+        third.finish().unwrap();
+        assert_eq!(third.duplicate_transactions(), [4]);
+        assert_eq!(third.client(1).unwrap().available(), dec!(-0.5));
+        assert_eq!(third.client(1).unwrap().held(), dec!(2));
+        assert_eq!(third.client(2).unwrap().available(), dec!(2));
+
+        // Events can't be taken back from a conflicting try
+        let mut fourth = engine();
+        fourth.on_event(|_| ());
+        let err = fourth
+            .process_part("type, client, tx, amount\ndeposit, 3, 6, 1.0\n".as_bytes())
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .ends_with("Event handlers isn't supported with a store shared between instances"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn writes_nothing_changed_by_another_instance_since_it_was_read() {
+        let addr = fake_redis();
+        let mut first = RedisStore::connect(&addr, "test").unwrap();
+        let mut second = RedisStore::connect(&addr, "test").unwrap();
+        let mut client = Client::new(2);
+        assert_eq!(first.get_client(2).unwrap(), None);
+        assert_eq!(second.get_client(2).unwrap(), None);
+        client.available = dec!(1);
+        second.put_client(&client).unwrap();
+        AccountStore::flush(&mut second).unwrap();
+
+        client.available = dec!(2);
+        first.put_client(&client).unwrap();
+        let deposit = Transaction {
+            id: 1,
+            transaction_type: TransactionType::Deposit,
+            client_id: 2,
+            amount: dec!(1),
+            to_client_id: None,
+            reference: None,
+            currency: None,
+            timestamp: None,
+            dispute_state: DisputeState::Undisputed,
+        };
+        first.put_tx(1.into(), deposit).unwrap();
+        let err = AccountStore::flush(&mut first).unwrap_err();
+        assert_eq!(err.to_string(), "Client 2 was changed by another instance");
+        assert_eq!(second.get_tx(1.into()).unwrap(), None);
+        assert_eq!(first.get_client(2).unwrap().unwrap().available(), dec!(1));
+    }
+}
@@ -0,0 +1,217 @@
+use super::{AccountStore, TransactionStore, TxKey};
+use crate::engine::SavedClient;
+use crate::{Client, ClientId, PaymentsError, Transaction};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::path::Path;
+
+/// Writes committed together, as a commit per write is far too slow for large files
+const WRITES_PER_COMMIT: usize = 10_000;
+
+/// Keeps transactions and accounts in a SQLite database, so the transactions kept
+/// for disputes don't have to fit in memory. Rows are stored as json, the same as
+/// in a saved engine state.
+pub struct SqliteStore {
+    connection: Connection,
+    /// Writes since the last commit
+    pending: usize,
+}
+
+impl SqliteStore {
+    /// Starts a new store at `path`, replacing whatever an earlier run left there.
+    /// State carries over from one run to the next with `save_state` instead.
+    pub fn create<P: AsRef<Path>>(path: P) -> Result<SqliteStore, PaymentsError> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(
+            "DROP TABLE IF EXISTS transactions;
+             DROP TABLE IF EXISTS clients;
+             CREATE TABLE transactions (
+                 client INTEGER NOT NULL,
+                 tx INTEGER NOT NULL,
+                 body TEXT NOT NULL,
+                 PRIMARY KEY (client, tx)
+             );
+             CREATE TABLE clients (client INTEGER PRIMARY KEY, body TEXT NOT NULL);
+             BEGIN;",
+        )?;
+        Ok(SqliteStore {
+            connection,
+            pending: 0,
+        })
+    }
+
+    /// Commits once enough writes have built up, starting the next batch
+    fn written(&mut self) -> Result<(), PaymentsError> {
+        self.pending += 1;
+        if self.pending >= WRITES_PER_COMMIT {
+            self.commit()?;
+        }
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<(), PaymentsError> {
+        self.connection.execute_batch("COMMIT; BEGIN;")?;
+        self.pending = 0;
+        Ok(())
+    }
+}
+
+impl TransactionStore for SqliteStore {
+    fn get_tx(&self, key: TxKey) -> Result<Option<Transaction>, PaymentsError> {
+        let body: Option<String> = self
+            .connection
+            .prepare_cached("SELECT body FROM transactions WHERE client = ?1 AND tx = ?2")?
+            .query_row(params![client_column(key), key.tx], |row| row.get(0))
+            .optional()?;
+        Ok(body.map(|body| serde_json::from_str(&body)).transpose()?)
+    }
+
+    fn put_tx(&mut self, key: TxKey, transaction: Transaction) -> Result<(), PaymentsError> {
+        let body = serde_json::to_string(&transaction)?;
+        self.connection
+            .prepare_cached(
+                "INSERT OR REPLACE INTO transactions (client, tx, body) VALUES (?1, ?2, ?3)",
+            )?
+            .execute(params![client_column(key), key.tx, body])?;
+        self.written()
+    }
+
+    fn remove_tx(&mut self, key: TxKey) -> Result<Option<Transaction>, PaymentsError> {
+        let transaction = self.get_tx(key)?;
+        if transaction.is_some() {
+            self.connection
+                .prepare_cached("DELETE FROM transactions WHERE client = ?1 AND tx = ?2")?
+                .execute(params![client_column(key), key.tx])?;
+            self.written()?;
+        }
+        Ok(transaction)
+    }
+
+    fn transactions(&self) -> Result<Vec<Transaction>, PaymentsError> {
+        let mut statement = self
+            .connection
+            .prepare_cached("SELECT body FROM transactions ORDER BY tx, client")?;
+        let mut rows = statement.query([])?;
+        let mut transactions = Vec::new();
+        while let Some(row) = rows.next()? {
+            let body: String = row.get(0)?;
+            transactions.push(serde_json::from_str(&body)?);
+        }
+        Ok(transactions)
+    }
+
+    fn flush(&mut self) -> Result<(), PaymentsError> {
+        self.commit()
+    }
+}
+
+/// The key's client as stored, -1 for a tx id unique across clients, as a null
+/// wouldn't match itself in the primary key
+fn client_column(key: TxKey) -> i64 {
+    key.client.map_or(-1, i64::from)
+}
+
+impl AccountStore for SqliteStore {
+    fn get_client(&self, id: ClientId) -> Result<Option<Client>, PaymentsError> {
+        let body: Option<String> = self
+            .connection
+            .prepare_cached("SELECT body FROM clients WHERE client = ?1")?
+            .query_row(params![id], |row| row.get(0))
+            .optional()?;
+        let saved: Option<SavedClient> =
+            body.map(|body| serde_json::from_str(&body)).transpose()?;
+        Ok(saved.map(Client::from))
+    }
+
+    fn put_client(&mut self, client: &Client) -> Result<(), PaymentsError> {
+        let body = serde_json::to_string(&SavedClient::from(client))?;
+        self.connection
+            .prepare_cached("INSERT OR REPLACE INTO clients (client, body) VALUES (?1, ?2)")?
+            .execute(params![client.id, body])?;
+        self.written()
+    }
+
+    fn flush(&mut self) -> Result<(), PaymentsError> {
+        self.commit()
+    }
+}
+
+impl Drop for SqliteStore {
+    fn drop(&mut self) {
+        // Nothing can be reported from here, `flush` is where commit errors surface
+        let _ = self.connection.execute_batch("COMMIT");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DisputeState, PaymentsEngine, TransactionType};
+    use rust_decimal_macros::dec;
+
+    #[test]
+    fn round_trips_transactions_and_clients() {
+        let dir = std::env::temp_dir().join(format!("toy-payments-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut store = SqliteStore::create(dir.join("store.db")).unwrap();
+        let deposit = Transaction {
+            id: 1,
+            transaction_type: TransactionType::Deposit,
+            client_id: 2,
+            amount: dec!(1.5),
+            to_client_id: None,
+            reference: None,
+            currency: None,
+            timestamp: None,
+            dispute_state: DisputeState::Undisputed,
+        };
+        store.put_tx(1.into(), deposit.clone()).unwrap();
+        assert_eq!(store.get_tx(1.into()).unwrap(), Some(deposit.clone()));
+        let scoped = TxKey {
+            client: Some(2),
+            tx: 1,
+        };
+        assert_eq!(store.get_tx(scoped).unwrap(), None);
+        assert_eq!(store.transactions().unwrap(), vec![deposit.clone()]);
+        assert_eq!(store.remove_tx(1.into()).unwrap(), Some(deposit));
+        assert_eq!(store.get_tx(1.into()).unwrap(), None);
+
+        let mut client = Client::new(2);
+        client.available = dec!(1.5);
+        client.total = dec!(1.5);
+        client.disputes.insert(7);
+        store.put_client(&client).unwrap();
+        store.commit().unwrap();
+        assert_eq!(store.get_client(2).unwrap(), Some(client));
+        assert_eq!(store.get_client(3).unwrap(), None);
+        drop(store);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn engine_matches_memory_store() {
+        let input = "type, client, tx, amount\n\
+                     deposit, 1, 1, 1.0\n\
+                     deposit, 1, 2, 5.0\n\
+                     deposit, 2, 3, 3.0\n\
+                     deposit, 2, 3, 3.0\n\
+                     dispute, 1, 2\n\
+                     withdrawal, 2, 4, 1.0\n\
+                     chargeback, 1, 2\n";
+        let dir = std::env::temp_dir().join(format!("toy-payments-engine-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut memory = PaymentsEngine::new();
+        memory.process(input.as_bytes()).unwrap();
+        let mut sqlite = PaymentsEngine::with_stores(
+            SqliteStore::create(dir.join("transactions.db")).unwrap(),
+            SqliteStore::create(dir.join("accounts.db")).unwrap(),
+        );
+        sqlite.process(input.as_bytes()).unwrap();
+
+        assert_eq!(sqlite.accounts(), memory.accounts());
+        assert_eq!(sqlite.skipped(), memory.skipped());
+        let (_, accounts) = sqlite.into_stores();
+        assert_eq!(accounts.get_client(1).unwrap().as_ref(), memory.client(1));
+        drop(accounts);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,309 @@
+use crate::{ClientId, Currency, SkipReason, Timestamp, TxId};
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Eq, PartialEq, Hash, Ord, PartialOrd)]
+#[serde(rename_all = "lowercase")]
+pub enum TransactionType {
+    Deposit,
+    Withdrawal,
+    Dispute,
+    Resolve,
+    Chargeback,
+    /// Moves funds from the row's client to another client
+    Transfer,
+    /// Opens a new account for the row's client, with the amount if there is one
+    Open,
+    /// Closes the client's account, turning away its transactions until reactivated
+    Close,
+    /// Takes transactions for a closed account again
+    Reactivate,
+    /// A back-office correction of the client's available funds by a positive or
+    /// negative amount, applied even to a locked account. Can't be disputed.
+    Adjustment,
+}
+
+impl TransactionType {
+    /// Whether the transaction opens, closes or reactivates an account rather than
+    /// moving funds
+    pub fn is_lifecycle(&self) -> bool {
+        matches!(
+            self,
+            TransactionType::Open | TransactionType::Close | TransactionType::Reactivate
+        )
+    }
+}
+
+impl FromStr for TransactionType {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<TransactionType, Self::Err> {
+        match s {
+            "deposit" => Ok(TransactionType::Deposit),
+            "withdrawal" => Ok(TransactionType::Withdrawal),
+            "dispute" => Ok(TransactionType::Dispute),
+            "resolve" => Ok(TransactionType::Resolve),
+            "chargeback" => Ok(TransactionType::Chargeback),
+            "transfer" => Ok(TransactionType::Transfer),
+            "open" => Ok(TransactionType::Open),
+            "close" => Ok(TransactionType::Close),
+            "reactivate" => Ok(TransactionType::Reactivate),
+            "adjustment" => Ok(TransactionType::Adjustment),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for TransactionType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            TransactionType::Deposit => "deposit",
+            TransactionType::Withdrawal => "withdrawal",
+            TransactionType::Dispute => "dispute",
+            TransactionType::Resolve => "resolve",
+            TransactionType::Chargeback => "chargeback",
+            TransactionType::Transfer => "transfer",
+            TransactionType::Open => "open",
+            TransactionType::Close => "close",
+            TransactionType::Reactivate => "reactivate",
+            TransactionType::Adjustment => "adjustment",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Where a deposit, withdrawal or transfer is in its dispute lifecycle
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, Eq, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum DisputeState {
+    #[default]
+    Undisputed,
+    Disputed,
+    Resolved,
+    /// Final, a charged back transaction can't be disputed again
+    ChargedBack,
+}
+
+impl DisputeState {
+    pub fn is_undisputed(&self) -> bool {
+        *self == DisputeState::Undisputed
+    }
+
+    /// The state a dispute, resolve or chargeback of the transaction moves it to, or
+    /// why that isn't a valid transition
+    pub(crate) fn next(
+        self,
+        transaction_type: &TransactionType,
+        redisputable: bool,
+    ) -> Result<DisputeState, SkipReason> {
+        use DisputeState::*;
+        match (transaction_type, self) {
+            (TransactionType::Dispute, Undisputed) => Ok(Disputed),
+            (TransactionType::Dispute, Resolved) if redisputable => Ok(Disputed),
+            (TransactionType::Dispute, Disputed) => Err(SkipReason::AlreadyDisputed),
+            (TransactionType::Dispute, _) => Err(SkipReason::DisputeClosed),
+            (TransactionType::Resolve, Disputed) => Ok(Resolved),
+            (TransactionType::Chargeback, Disputed) => Ok(ChargedBack),
+            (_, Resolved | ChargedBack) => Err(SkipReason::DisputeSettled),
+            _ => Err(SkipReason::NotDisputed),
+        }
+    }
+}
+
+/// Whether a transaction can be disputed again once its dispute is resolved
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum RedisputePolicy {
+    /// A dispute is settled for good by its resolve or chargeback
+    Never,
+    /// A resolved transaction can be disputed again, a charged back one still can't
+    AfterResolve,
+}
+
+impl FromStr for RedisputePolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<RedisputePolicy, Self::Err> {
+        match s {
+            "never" => Ok(RedisputePolicy::Never),
+            "after-resolve" => Ok(RedisputePolicy::AfterResolve),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Across what tx ids are unique, which is what transactions are kept by for
+/// catching duplicates and looking up disputes
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum TxIdScope {
+    /// Every client's transactions share one set of tx ids
+    #[default]
+    Global,
+    /// Each client has tx ids of its own, so a tx id is only a duplicate if the same
+    /// client used it before
+    Client,
+}
+
+impl FromStr for TxIdScope {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<TxIdScope, Self::Err> {
+        match s {
+            "global" => Ok(TxIdScope::Global),
+            "client" => Ok(TxIdScope::Client),
+            _ => Err(()),
+        }
+    }
+}
+
+/// How amounts with more decimal places than the engine's precision, `PRECISION` by
+/// default, are brought down to it
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum RoundingPolicy {
+    /// Round to nearest, ties to even (banker's rounding)
+    HalfEven,
+    /// Round to nearest, ties away from zero
+    HalfUp,
+    /// Drop the extra digits, rounding toward zero
+    Truncate,
+    /// Refuse the record instead of altering the amount
+    Reject,
+}
+
+impl FromStr for RoundingPolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<RoundingPolicy, Self::Err> {
+        match s {
+            "half-even" => Ok(RoundingPolicy::HalfEven),
+            "half-up" => Ok(RoundingPolicy::HalfUp),
+            "truncate" => Ok(RoundingPolicy::Truncate),
+            "reject" => Ok(RoundingPolicy::Reject),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Currency symbols an amount may start or end with, outside the plain format
+const CURRENCY_SYMBOLS: [char; 4] = ['$', '€', '£', '¥'];
+
+/// How amounts are written in csv input. Outside the plain format a currency symbol
+/// before or after the amount is dropped, as are thousands separators between
+/// groups of 3 digits.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum AmountFormat {
+    /// Digits with a `.` before the decimals only, anything else is malformed
+    #[default]
+    Plain,
+    /// Thousands separated by `,` and a `.` before the decimals, such as `$1,234.56`
+    En,
+    /// Thousands separated by `.` and a `,` before the decimals, such as `1.234,56 €`
+    Eu,
+}
+
+impl FromStr for AmountFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<AmountFormat, Self::Err> {
+        match s {
+            "plain" => Ok(AmountFormat::Plain),
+            "en" => Ok(AmountFormat::En),
+            "eu" => Ok(AmountFormat::Eu),
+            _ => Err(()),
+        }
+    }
+}
+
+impl AmountFormat {
+    /// The amount in the plain format, ready to parse. One that doesn't fit the
+    /// format, such as with a misplaced separator, is left as it is, to fail parsing.
+    pub fn normalize(self, amount: &str) -> Cow<'_, str> {
+        let (thousands, decimals) = match self {
+            AmountFormat::Plain => return Cow::Borrowed(amount),
+            AmountFormat::En => (',', '.'),
+            AmountFormat::Eu => ('.', ','),
+        };
+        let symbol = |c: char| CURRENCY_SYMBOLS.contains(&c) || c.is_whitespace();
+        let trimmed = amount.trim_matches(symbol);
+        // The sign may come before the symbol too, as in -$5
+        let (sign, digits) = match trimmed.strip_prefix(['-', '+']) {
+            Some(unsigned) => (&trimmed[..1], unsigned.trim_start_matches(symbol)),
+            None => ("", trimmed),
+        };
+        let (whole, fraction) = match digits.split_once(decimals) {
+            Some((whole, fraction)) => (whole, Some(fraction)),
+            None => (digits, None),
+        };
+        let mut groups = whole.split(thousands);
+        let grouped = groups
+            .next()
+            .is_some_and(|first| (1..=3).contains(&first.len()))
+            && groups.all(|group| group.len() == 3);
+        if whole.contains(thousands) && !grouped {
+            return Cow::Borrowed(amount);
+        }
+        let mut plain = format!("{}{}", sign, whole.replace(thousands, ""));
+        if let Some(fraction) = fraction {
+            plain.push('.');
+            plain.push_str(fraction);
+        }
+        Cow::Owned(plain)
+    }
+}
+
+/// A single record of the input. Serialized with the csv column names, so a json
+/// line reads like `{"type":"deposit","client":1,"tx":1,"amount":"1.0"}`.
+#[derive(Eq, PartialEq, Serialize, Deserialize, Debug, Clone)]
+pub struct Transaction {
+    #[serde(rename = "tx")]
+    pub id: TxId,
+    #[serde(rename = "type")]
+    pub transaction_type: TransactionType,
+    #[serde(rename = "client")]
+    pub client_id: ClientId,
+    /// Zero for disputes, resolves and chargebacks, which refer to another tx's amount
+    #[serde(default)]
+    pub amount: Decimal,
+    /// Receiving client of a transfer, `None` for every other type
+    #[serde(default, rename = "to_client", skip_serializing_if = "Option::is_none")]
+    pub to_client_id: Option<ClientId>,
+    /// Operator reference of an adjustment, `None` for every other type
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reference: Option<String>,
+    /// `None` for input without a currency. Disputes, resolves and chargebacks take
+    /// their tx's currency, and are rejected if they name another one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub currency: Option<Currency>,
+    /// `None` for input without timestamps, in which case no dispute window applies
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timestamp: Option<Timestamp>,
+    /// Kept with the transaction once applied, always undisputed on the way in
+    #[serde(default, skip_serializing_if = "DisputeState::is_undisputed")]
+    pub dispute_state: DisputeState,
+}
+
+impl Transaction {
+    /// Client whose funds are held while the transaction is disputed, which for a
+    /// transfer is the receiving client
+    pub fn disputed_client_id(&self) -> ClientId {
+        self.to_client_id.unwrap_or(self.client_id)
+    }
+
+    /// Checksum of what the record says, leaving out where its dispute stands and
+    /// when it was sent, so a resubmitted transaction can be told apart from another
+    /// one reusing its tx id
+    pub fn fingerprint(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.id.hash(&mut hasher);
+        self.transaction_type.hash(&mut hasher);
+        self.client_id.hash(&mut hasher);
+        self.amount.normalize().hash(&mut hasher);
+        self.to_client_id.hash(&mut hasher);
+        self.currency.hash(&mut hasher);
+        hasher.finish()
+    }
+}
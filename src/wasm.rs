@@ -0,0 +1,8 @@
+use wasm_bindgen::prelude::*;
+
+/// `process_csv` for JavaScript: the csv pasted in, as a string, to the accounts
+/// csv, throwing an `Error` with the message of anything that fails the run
+#[wasm_bindgen(js_name = process_csv)]
+pub fn process_csv_js(input: &str) -> Result<String, JsError> {
+    crate::process_csv(input.as_bytes()).map_err(|err| JsError::new(&err.to_string()))
+}
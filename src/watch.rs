@@ -0,0 +1,126 @@
+use crate::{AccountStore, DisputePolicy, PaymentsEngine, TransactionStore};
+use notify::{PollWatcher, RecursiveMode, Watcher};
+use std::collections::HashSet;
+use std::error::Error;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+/// Applies every file dropped into `dir` until the process is stopped, picking new
+/// ones up as the file system reports them, or by looking every `poll_every` if
+/// given, for file systems that don't report changes such as network mounts.
+/// Files already there are applied first. Each file is a whole input in the
+/// engine's input format, applied in order of file name, and `on_file` is handed
+/// the engine and the file once it's done, e.g. to write the accounts out.
+///
+/// Files are picked up as soon as they're seen, so they should be moved into `dir`
+/// once complete rather than written there. Hidden files and `.tmp` files are left
+/// alone. Buffered disputes are applied at the end of each file. A file that can't
+/// be read, or a malformed record that stops one, is logged and the rest of it
+/// skipped; the records before it stay applied.
+pub fn watch<T, A, P, F>(
+    engine: &mut PaymentsEngine<T, A, P>,
+    dir: &Path,
+    poll_every: Option<Duration>,
+    mut on_file: F,
+) -> Result<(), Box<dyn Error + Send + Sync>>
+where
+    T: TransactionStore,
+    A: AccountStore,
+    P: DisputePolicy,
+    F: FnMut(&PaymentsEngine<T, A, P>, &Path) -> Result<(), Box<dyn Error + Send + Sync>>,
+{
+    let (sender, events) = mpsc::channel();
+    let mut watcher: Box<dyn Watcher> = match poll_every {
+        Some(every) => Box::new(PollWatcher::new(
+            sender,
+            notify::Config::default().with_poll_interval(every),
+        )?),
+        None => Box::new(notify::recommended_watcher(sender)?),
+    };
+    // Watched before the first look, so nothing dropped in between is missed
+    watcher
+        .watch(dir, RecursiveMode::NonRecursive)
+        .map_err(|err| format!("Failed to watch {} {}", dir.display(), err))?;
+    let mut seen = HashSet::new();
+    loop {
+        for path in new_files(dir, &mut seen)? {
+            info!("Processing {}", path.display());
+            let file = match File::open(&path) {
+                Ok(file) => file,
+                Err(err) => {
+                    warn!("Skipped {} {}", path.display(), err);
+                    continue;
+                }
+            };
+            match engine.process_part(file) {
+                Ok(()) => (),
+                Err(err) if err.is_input_error() => {
+                    warn!("Skipped the rest of {} {}", path.display(), err);
+                }
+                Err(err) => return Err(format!("{}: {}", path.display(), err).into()),
+            }
+            engine.apply_deferred()?;
+            on_file(engine, &path)?;
+        }
+        // Whatever changed, the directory is looked at again for new files
+        match events.recv() {
+            Ok(Ok(event)) => debug!("{:?}", event),
+            Ok(Err(err)) => warn!("Watching {} {}", dir.display(), err),
+            Err(_) => return Err(From::from("The directory watcher stopped")),
+        }
+        while events.try_recv().is_ok() {}
+    }
+}
+
+/// Files in `dir` that aren't in `seen` yet, sorted by name, adding them to it
+fn new_files(
+    dir: &Path,
+    seen: &mut HashSet<OsString>,
+) -> Result<Vec<PathBuf>, Box<dyn Error + Send + Sync>> {
+    let mut files = Vec::new();
+    for entry in
+        fs::read_dir(dir).map_err(|err| format!("Failed to read {} {}", dir.display(), err))?
+    {
+        let entry = entry?;
+        let name = entry.file_name();
+        let ignored = name.to_string_lossy().starts_with('.')
+            || Path::new(&name)
+                .extension()
+                .is_some_and(|extension| extension == "tmp");
+        if ignored || !entry.file_type()?.is_file() || seen.contains(&name) {
+            continue;
+        }
+        debug!("Found {:?}", name);
+        seen.insert(name);
+        files.push(entry.path());
+    }
+    files.sort();
+    Ok(files)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_each_new_file_once_in_name_order() {
+        let dir = std::env::temp_dir().join(format!("toy-payments-watch-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("b.csv"), "").unwrap();
+        fs::write(dir.join("a.csv"), "").unwrap();
+        fs::write(dir.join(".hidden.csv"), "").unwrap();
+        fs::write(dir.join("accounts.csv.tmp"), "").unwrap();
+        let mut seen = HashSet::new();
+
+        let found = new_files(&dir, &mut seen).unwrap();
+        assert_eq!(found, vec![dir.join("a.csv"), dir.join("b.csv")]);
+        fs::write(dir.join("c.csv"), "").unwrap();
+        assert_eq!(new_files(&dir, &mut seen).unwrap(), vec![dir.join("c.csv")]);
+        assert!(new_files(&dir, &mut seen).unwrap().is_empty());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,2061 @@
+use assert_cmd::Command;
+
+#[test]
+fn missing_option_value_is_a_usage_error() {
+    let output = Command::cargo_bin("toy_payments")
+        .unwrap()
+        .arg("--order")
+        .assert()
+        .code(2)
+        .stdout("")
+        .get_output()
+        .clone();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("'--order <ORDER>'"), "{}", stderr);
+}
+
+#[test]
+fn subcommands_process_validate_and_report() {
+    let plain = Command::cargo_bin("toy_payments")
+        .unwrap()
+        .arg("inputs/long.csv")
+        .output()
+        .unwrap();
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .args(["process", "inputs/long.csv"])
+        .assert()
+        .success()
+        .stdout(plain.stdout);
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .args(["validate", "inputs/invalid_amount.csv"])
+        .assert()
+        .code(1)
+        .stdout(
+            "{\"line\":4,\"kind\":\"invalid\",\"message\":\"Failed to set amount from abc Invalid decimal: unknown character\"}\n",
+        );
+    let input = "type, client, tx, amount\n\
+                 deposit, 1, 1, 1.0\n\
+                 deposit, 2, 2, 2.0\n\
+                 withdrawal, 2, 3, 0.5\n";
+    let output = Command::cargo_bin("toy_payments")
+        .unwrap()
+        .arg("report")
+        .write_stdin(input)
+        .assert()
+        .success()
+        .get_output()
+        .clone();
+    let report: serde_json::Value = serde_json::from_slice(&output.stdout).unwrap();
+    assert_eq!(report["total"], "2.5000");
+}
+
+#[test]
+fn strict_fails_on_a_malformed_record() {
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .args(["--strict", "inputs/invalid_amount.csv"])
+        .assert()
+        .code(1)
+        .stdout("")
+        .stderr(
+            "inputs/invalid_amount.csv: Line 4: \
+             Failed to set amount from abc Invalid decimal: unknown character\n",
+        );
+}
+
+#[test]
+fn lenient_lists_skipped_records() {
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .args(["--lenient", "inputs/invalid_amount.csv"])
+        .assert()
+        .success()
+        .stderr(
+            "Skipped 1 records that failed to parse\n\
+             Skipped 1 records:\n  \
+             insufficient funds: 1\n",
+        );
+}
+
+#[test]
+fn summary_goes_to_stderr_or_a_file() {
+    let summary = "transactions: 5\n  \
+                   deposit: 2\n  \
+                   withdrawal: 1\n  \
+                   dispute: 1\n  \
+                   chargeback: 1\n\
+                   applied: 4\n\
+                   rejected: 1\n  \
+                   insufficient funds: 1\n\
+                   invalid records: 1\n\
+                   locked accounts: 1\n\
+                   available: 1.0000\n\
+                   held: 0.0000\n\
+                   total: 1.0000\n";
+    let accounts = "client,available,held,total,locked\n\
+                    1,1.0000,0.0000,1.0000,true\n";
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .args(["--summary", "inputs/invalid_amount.csv"])
+        .assert()
+        .success()
+        .stdout(accounts)
+        .stderr(summary);
+
+    let path = std::env::temp_dir().join(format!("toy-payments-summary-{}", std::process::id()));
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .arg("inputs/invalid_amount.csv")
+        .arg("--summary-file")
+        .arg(&path)
+        .assert()
+        .success()
+        .stdout(accounts)
+        .stderr("");
+    assert_eq!(std::fs::read_to_string(&path).unwrap(), summary);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn manifest_records_what_the_run_read_did_and_wrote() {
+    let dir = std::env::temp_dir();
+    let manifest = dir.join(format!("toy-payments-manifest-{}.json", std::process::id()));
+    let output = dir.join(format!("toy-payments-manifest-{}.csv", std::process::id()));
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .arg("inputs/invalid_amount.csv")
+        .arg("--output")
+        .arg(&output)
+        .arg("--manifest")
+        .arg(&manifest)
+        .assert()
+        .success();
+    let written: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(&manifest).unwrap()).unwrap();
+    let input = &written["inputs"][0];
+    assert_eq!(input["path"], "inputs/invalid_amount.csv");
+    assert_eq!(
+        input["bytes"],
+        std::fs::metadata("inputs/invalid_amount.csv")
+            .unwrap()
+            .len()
+    );
+    assert_eq!(input["sha256"].as_str().unwrap().len(), 64);
+    assert_eq!(written["records"]["valid"], 5);
+    assert_eq!(written["records"]["invalid"], 1);
+    assert_eq!(written["records"]["applied"], 4);
+    assert_eq!(written["records"]["by_type"]["deposit"], 2);
+    assert_eq!(written["rejected"]["rejected-insufficient-funds"], 1);
+    assert_eq!(written["engine_version"], env!("CARGO_PKG_VERSION"));
+    assert_eq!(written["config"]["args"][0], "inputs/invalid_amount.csv");
+    assert_eq!(
+        written["output"]["bytes"],
+        std::fs::metadata(&output).unwrap().len()
+    );
+
+    // Stdin is hashed as it's read, and the accounts as they're written to stdout
+    let input = "type, client, tx, amount\ndeposit, 1, 1, 1.0\n";
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .arg("--manifest")
+        .arg(&manifest)
+        .write_stdin(input)
+        .assert()
+        .success();
+    let written: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(&manifest).unwrap()).unwrap();
+    assert_eq!(written["inputs"][0]["path"], "stdin");
+    assert_eq!(written["inputs"][0]["bytes"], input.len());
+    assert_eq!(written["output"]["path"], "stdout");
+    std::fs::remove_file(manifest).unwrap();
+    std::fs::remove_file(output).unwrap();
+}
+
+#[test]
+fn output_writes_accounts_to_a_file() {
+    let path = std::env::temp_dir().join(format!("toy-payments-out-{}.csv", std::process::id()));
+    let stdout = Command::cargo_bin("toy_payments")
+        .unwrap()
+        .arg("inputs/long.csv")
+        .output()
+        .unwrap()
+        .stdout;
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .arg("inputs/long.csv")
+        .arg("--output")
+        .arg(&path)
+        .assert()
+        .success()
+        .stdout("");
+    assert_eq!(std::fs::read(&path).unwrap(), stdout);
+    let mut temp = path.clone().into_os_string();
+    temp.push(".tmp");
+    assert!(!std::path::Path::new(&temp).exists());
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn resume_carries_on_from_the_checkpoint_of_a_failed_run() {
+    let path = std::env::temp_dir().join(format!("toy-payments-{}.checkpoint", std::process::id()));
+    let lenient = Command::cargo_bin("toy_payments")
+        .unwrap()
+        .arg("inputs/invalid_amount.csv")
+        .output()
+        .unwrap()
+        .stdout;
+    // Strict mode stops at the malformed record, leaving the checkpoint before it
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .args([
+            "inputs/invalid_amount.csv",
+            "--strict",
+            "--checkpoint-every",
+            "1",
+        ])
+        .arg("--checkpoint")
+        .arg(&path)
+        .assert()
+        .failure();
+    assert!(path.exists());
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .args([
+            "inputs/invalid_amount.csv",
+            "--resume",
+            "--checkpoint-every",
+            "1",
+        ])
+        .arg("--checkpoint")
+        .arg(&path)
+        .assert()
+        .success()
+        .stdout(lenient);
+    assert!(!path.exists());
+}
+
+#[test]
+fn reads_stdin_without_a_path_or_with_dash() {
+    let input = "type, client, tx, amount\n\
+                 deposit, 1, 1, 1.0\n\
+                 deposit, 2, 2, 2.0\n\
+                 deposit, 1, 3, 2.0\n";
+    let output = "client,available,held,total,locked\n\
+                  1,3.0000,0.0000,3.0000,false\n\
+                  2,2.0000,0.0000,2.0000,false\n";
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .write_stdin(input)
+        .assert()
+        .success()
+        .stdout(output);
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .arg("-")
+        .write_stdin(input)
+        .assert()
+        .success()
+        .stdout(output);
+}
+
+#[test]
+fn unreadable_file_is_a_processing_error() {
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .arg("inputs/does_not_exist.csv")
+        .assert()
+        .code(1)
+        .stdout("");
+}
+
+#[test]
+fn validate_reports_summary_instead_of_accounts() {
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .args(["--validate", "inputs/invalid_amount.csv"])
+        .assert()
+        .code(1)
+        .stdout(
+            "{\"line\":4,\"kind\":\"invalid\",\"message\":\"Failed to set amount from abc Invalid decimal: unknown character\"}\n",
+        )
+        .stderr(
+            "valid records: 5\n\
+             invalid records: 1\n\
+             skipped records: 1\n  \
+             insufficient funds: 1\n\
+             locked accounts: 1 [1]\n\
+             1 records failed validation\n",
+        );
+}
+
+#[test]
+fn json_output_formats() {
+    let input = "type, client, tx, amount\n\
+                 deposit, 1, 1, 1.0\n\
+                 deposit, 2, 2, 2.0\n\
+                 deposit, 1, 3, 2.0\n";
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .args(["--output-format", "json"])
+        .write_stdin(input)
+        .assert()
+        .success()
+        .stdout(
+            "[{\"client\":1,\"available\":\"3.0000\",\"held\":\"0.0000\",\"total\":\"3.0000\",\"locked\":false},\
+             {\"client\":2,\"available\":\"2.0000\",\"held\":\"0.0000\",\"total\":\"2.0000\",\"locked\":false}]\n",
+        );
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .args(["--output-format", "jsonl"])
+        .write_stdin(input)
+        .assert()
+        .success()
+        .stdout(
+            "{\"client\":1,\"available\":\"3.0000\",\"held\":\"0.0000\",\"total\":\"3.0000\",\"locked\":false}\n\
+             {\"client\":2,\"available\":\"2.0000\",\"held\":\"0.0000\",\"total\":\"2.0000\",\"locked\":false}\n",
+        );
+}
+
+#[test]
+fn accounts_are_written_sorted_by_client_id() {
+    let input = "type, client, tx, amount\n\
+                 deposit, 3, 2, 1.0\n\
+                 deposit, 7, 3, 1.0\n\
+                 deposit, 1, 4, 1.0\n\
+                 deposit, 5, 5, 1.0\n";
+    let output = "client,available,held,total,locked\n\
+                  1,1.0000,0.0000,1.0000,false\n\
+                  3,1.0000,0.0000,1.0000,false\n\
+                  5,1.0000,0.0000,1.0000,false\n\
+                  7,1.0000,0.0000,1.0000,false\n";
+    // Repeated runs, as hash map iteration order differs between processes
+    for _ in 0..5 {
+        Command::cargo_bin("toy_payments")
+            .unwrap()
+            .write_stdin(input)
+            .assert()
+            .success()
+            .stdout(output);
+    }
+}
+
+#[test]
+fn several_files_or_a_glob_are_one_input() {
+    // tx 3 is repeated across the files, so the second is rejected as a duplicate
+    let output = "client,available,held,total,locked\n\
+                  1,5.0000,0.0000,5.0000,false\n\
+                  2,1.5000,0.0000,1.5000,false\n";
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .args([
+            "inputs/daily/txns-2024-01-01.csv",
+            "inputs/daily/txns-2024-01-02.csv",
+        ])
+        .assert()
+        .success()
+        .stdout(output);
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .arg("inputs/daily/txns-2024-01-*.csv")
+        .assert()
+        .success()
+        .stdout(output);
+}
+
+#[test]
+fn threads_give_the_same_accounts() {
+    let serial = Command::cargo_bin("toy_payments")
+        .unwrap()
+        .arg("inputs/long.csv")
+        .output()
+        .unwrap();
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .args(["--threads", "4", "inputs/long.csv"])
+        .assert()
+        .success()
+        .stdout(serial.stdout);
+}
+
+#[test]
+fn currencies_get_a_row_each() {
+    let input = "type, client, tx, amount, currency\n\
+                 deposit, 1, 1, 1.0, USD\n\
+                 deposit, 1, 2, 2.0, EUR\n\
+                 deposit, 1, 3, 3.0, USD\n\
+                 deposit, 2, 4, 4.0\n";
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .write_stdin(input)
+        .assert()
+        .success()
+        .stdout(
+            "client,currency,available,held,total,locked\n\
+             1,EUR,2.0000,0.0000,2.0000,false\n\
+             1,USD,4.0000,0.0000,4.0000,false\n\
+             2,,4.0000,0.0000,4.0000,false\n",
+        );
+}
+
+#[test]
+fn history_reads_a_clients_transactions_from_the_state_file() {
+    let state =
+        std::env::temp_dir().join(format!("toy-payments-history-{}.json", std::process::id()));
+    let input = "type, client, tx, amount\n\
+                 deposit, 1, 1, 1.0\n\
+                 deposit, 1, 2, 2.0\n\
+                 deposit, 2, 3, 3.0\n\
+                 dispute, 1, 2\n";
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .arg("--history")
+        .arg("--state-file")
+        .arg(&state)
+        .write_stdin(input)
+        .assert()
+        .success();
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .args(["history", "1", "--state-file"])
+        .arg(&state)
+        .assert()
+        .success()
+        .stdout(
+            "{\"tx\":1,\"type\":\"deposit\",\"client\":1,\"amount\":\"1.0000\"}\n\
+             {\"tx\":2,\"type\":\"deposit\",\"client\":1,\"amount\":\"2.0000\",\"dispute_state\":\"disputed\"}\n\
+             {\"tx\":2,\"type\":\"dispute\",\"client\":1,\"amount\":\"0\"}\n",
+        );
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .args(["history", "9", "--state-file"])
+        .arg(&state)
+        .assert()
+        .code(1)
+        .stderr("Unknown client 9\n");
+    std::fs::remove_file(state).unwrap();
+}
+
+#[test]
+fn report_balance_at_replays_a_clients_history() {
+    let state = std::env::temp_dir().join(format!(
+        "toy-payments-balance-at-{}.json",
+        std::process::id()
+    ));
+    let input = "type, client, tx, amount\n\
+                 deposit, 1, 1, 10.0\n\
+                 deposit, 1, 2, 2.0\n\
+                 withdrawal, 1, 3, 4.0\n\
+                 dispute, 1, 2\n\
+                 deposit, 1, 4, 1.0\n";
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .arg("--history")
+        .arg("--state-file")
+        .arg(&state)
+        .write_stdin(input)
+        .assert()
+        .success();
+    for (tx, account) in [
+        ("2", "1,12.0000,0.0000,12.0000,false\n"),
+        ("3", "1,8.0000,0.0000,8.0000,false\n"),
+        ("4", "1,7.0000,2.0000,9.0000,false\n"),
+    ] {
+        Command::cargo_bin("toy_payments")
+            .unwrap()
+            .args(["report", "balance-at", "1", tx, "--state-file"])
+            .arg(&state)
+            .assert()
+            .success()
+            .stdout(format!("client,available,held,total,locked\n{}", account));
+    }
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .args(["report", "balance-at", "1", "9", "--state-file"])
+        .arg(&state)
+        .assert()
+        .code(1)
+        .stderr("No tx 9 in the history of client 1\n");
+    std::fs::remove_file(state).unwrap();
+}
+
+#[test]
+fn state_upgrade_migrates_an_earlier_state_file_in_place() {
+    let state =
+        std::env::temp_dir().join(format!("toy-payments-upgrade-{}.json", std::process::id()));
+    std::fs::write(
+        &state,
+        "{\"clients\":[{\"id\":1,\"available\":\"0\",\"held\":\"2\",\"locked\":false,\"disputes\":[2]}],\
+          \"first_seen\":[1],\
+          \"transactions\":[{\"tx\":2,\"type\":\"deposit\",\"client\":1,\"amount\":\"2\"}],\
+          \"retained\":{}}",
+    )
+    .unwrap();
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .args(["state", "upgrade"])
+        .arg(&state)
+        .assert()
+        .success()
+        .stderr("state upgraded from version 1 to 2\n");
+    let upgraded: serde_json::Value =
+        serde_json::from_slice(&std::fs::read(&state).unwrap()).unwrap();
+    assert_eq!(upgraded["version"], 2);
+    assert_eq!(upgraded["transactions"][0]["dispute_state"], "disputed");
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .args(["state", "upgrade"])
+        .arg(&state)
+        .assert()
+        .success()
+        .stderr("state already at version 2\n");
+
+    std::fs::write(&state, "{\"version\":3}").unwrap();
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .args(["state", "upgrade"])
+        .arg(&state)
+        .assert()
+        .code(1)
+        .stderr(format!(
+            "Failed to upgrade {:?} Unsupported state version 3\n",
+            state
+        ));
+    std::fs::remove_file(state).unwrap();
+}
+
+#[test]
+fn watch_applies_each_dropped_file_and_rewrites_the_accounts() {
+    let dir = std::env::temp_dir().join(format!("toy-payments-drop-{}", std::process::id()));
+    let inbox = dir.join("inbox");
+    std::fs::create_dir_all(&inbox).unwrap();
+    let output = dir.join("accounts.csv");
+    std::fs::write(
+        inbox.join("1.csv"),
+        "type, client, tx, amount\ndeposit, 1, 1, 1.0\ndeposit, 1, 2, 2.0\n",
+    )
+    .unwrap();
+    let mut watcher = std::process::Command::new(assert_cmd::cargo::cargo_bin("toy_payments"))
+        .arg("watch")
+        .arg(&inbox)
+        .arg("--output")
+        .arg(&output)
+        .spawn()
+        .unwrap();
+    let wait_for = |expected: &str| {
+        for _ in 0..100 {
+            if std::fs::read_to_string(&output).is_ok_and(|accounts| accounts == expected) {
+                return true;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+        false
+    };
+
+    let first = wait_for(
+        "client,available,held,total,locked\n\
+         1,3.0000,0.0000,3.0000,false\n",
+    );
+    // Written elsewhere and moved in, as the drop folder expects
+    std::fs::write(
+        dir.join("2.csv"),
+        "type, client, tx, amount\ndeposit, 2, 3, 1.0\ndeposit, 2, 4, 4.0\n",
+    )
+    .unwrap();
+    std::fs::rename(dir.join("2.csv"), inbox.join("2.csv")).unwrap();
+    let second = wait_for(
+        "client,available,held,total,locked\n\
+         1,3.0000,0.0000,3.0000,false\n\
+         2,5.0000,0.0000,5.0000,false\n",
+    );
+    watcher.kill().unwrap();
+    watcher.wait().unwrap();
+    std::fs::remove_dir_all(dir).unwrap();
+    assert!(first && second);
+}
+
+#[test]
+fn watch_skips_the_rest_of_a_malformed_file_and_carries_on() {
+    let dir = std::env::temp_dir().join(format!("toy-payments-skip-{}", std::process::id()));
+    let inbox = dir.join("inbox");
+    std::fs::create_dir_all(&inbox).unwrap();
+    let output = dir.join("accounts.csv");
+    std::fs::write(
+        inbox.join("1.csv"),
+        "type, client, tx, amount\ndeposit, 1, 1, 1.0\ndeposit, 1, 2, oops\ndeposit, 1, 3, 9.0\n",
+    )
+    .unwrap();
+    let mut watcher = std::process::Command::new(assert_cmd::cargo::cargo_bin("toy_payments"))
+        .args(["watch", "--strict", "--poll-secs", "1"])
+        .arg(&inbox)
+        .arg("--output")
+        .arg(&output)
+        .spawn()
+        .unwrap();
+    let wait_for = |expected: &str| {
+        for _ in 0..100 {
+            if std::fs::read_to_string(&output).is_ok_and(|accounts| accounts == expected) {
+                return true;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+        false
+    };
+
+    // The records ahead of the malformed one stay applied
+    let first = wait_for(
+        "client,available,held,total,locked\n\
+         1,1.0000,0.0000,1.0000,false\n",
+    );
+    std::fs::write(
+        dir.join("2.csv"),
+        "type, client, tx, amount\ndeposit, 2, 4, 2.0\n",
+    )
+    .unwrap();
+    std::fs::rename(dir.join("2.csv"), inbox.join("2.csv")).unwrap();
+    let second = wait_for(
+        "client,available,held,total,locked\n\
+         1,1.0000,0.0000,1.0000,false\n\
+         2,2.0000,0.0000,2.0000,false\n",
+    );
+    watcher.kill().unwrap();
+    watcher.wait().unwrap();
+    std::fs::remove_dir_all(dir).unwrap();
+    assert!(first && second);
+}
+
+#[test]
+fn watch_delta_output_writes_only_changed_accounts() {
+    let dir = std::env::temp_dir().join(format!("toy-payments-delta-{}", std::process::id()));
+    let inbox = dir.join("inbox");
+    std::fs::create_dir_all(&inbox).unwrap();
+    let output = dir.join("accounts.csv");
+    std::fs::write(
+        inbox.join("1.csv"),
+        "type, client, tx, amount\ndeposit, 1, 1, 1.0\ndeposit, 2, 2, 2.0\n",
+    )
+    .unwrap();
+    let mut watcher = std::process::Command::new(assert_cmd::cargo::cargo_bin("toy_payments"))
+        .args(["watch", "--output-mode", "delta", "--full-snapshot-every", "4"])
+        .arg(&inbox)
+        .arg("--output")
+        .arg(&output)
+        .spawn()
+        .unwrap();
+    let wait_for = |expected: &str| {
+        for _ in 0..100 {
+            if std::fs::read_to_string(&output).is_ok_and(|accounts| accounts == expected) {
+                return true;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+        false
+    };
+    let drop_file = |name: &str, input: &str| {
+        std::fs::write(dir.join(name), input).unwrap();
+        std::fs::rename(dir.join(name), inbox.join(name)).unwrap();
+    };
+
+    // The first is a full snapshot, then only the clients each file changed
+    let full = wait_for(
+        "sequence,client,available,held,total,locked\n\
+         1,1,1.0000,0.0000,1.0000,false\n\
+         1,2,2.0000,0.0000,2.0000,false\n",
+    );
+    drop_file("2.csv", "type, client, tx, amount\ndeposit, 2, 3, 1.0\n");
+    let delta = wait_for(
+        "sequence,client,available,held,total,locked\n\
+         2,2,3.0000,0.0000,3.0000,false\n",
+    );
+    // Nothing changed, so nothing is written and the sequence isn't taken
+    drop_file("3.csv", "type, client, tx, amount\nwithdrawal, 1, 4, 9.0\n");
+    drop_file("4.csv", "type, client, tx, amount\ndeposit, 3, 5, 1.0\n");
+    let after_nothing = wait_for(
+        "sequence,client,available,held,total,locked\n\
+         3,3,1.0000,0.0000,1.0000,false\n",
+    );
+    watcher.kill().unwrap();
+    watcher.wait().unwrap();
+    std::fs::remove_dir_all(dir).unwrap();
+    assert!(full && delta && after_nothing);
+
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .args(["--output-mode", "delta"])
+        .write_stdin("type, client, tx, amount\n")
+        .assert()
+        .code(2)
+        .stderr(
+            "Expected --source kafka, --listen or the watch subcommand with --output-mode delta, \
+             the accounts are only written once otherwise\n",
+        );
+}
+
+#[test]
+fn watch_delta_output_follows_on_stdout() {
+    use std::io::Read;
+    use std::sync::{Arc, Mutex};
+
+    let dir = std::env::temp_dir().join(format!("toy-payments-deltas-{}", std::process::id()));
+    let inbox = dir.join("inbox");
+    std::fs::create_dir_all(&inbox).unwrap();
+    std::fs::write(
+        inbox.join("1.csv"),
+        "type, client, tx, amount\ndeposit, 1, 1, 1.0\ndeposit, 2, 2, 2.0\n",
+    )
+    .unwrap();
+    let mut watcher = std::process::Command::new(assert_cmd::cargo::cargo_bin("toy_payments"))
+        .args(["watch", "--output-mode", "delta"])
+        .arg(&inbox)
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    let written = Arc::new(Mutex::new(String::new()));
+    let mut stdout = watcher.stdout.take().unwrap();
+    let reader = {
+        let written = Arc::clone(&written);
+        std::thread::spawn(move || {
+            let mut buf = [0; 1024];
+            while let Ok(n @ 1..) = stdout.read(&mut buf) {
+                written
+                    .lock()
+                    .unwrap()
+                    .push_str(&String::from_utf8_lossy(&buf[..n]));
+            }
+        })
+    };
+    let wait_for = |expected: &str| {
+        for _ in 0..100 {
+            if *written.lock().unwrap() == expected {
+                return true;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+        false
+    };
+
+    let first = "sequence,client,available,held,total,locked\n\
+                 1,1,1.0000,0.0000,1.0000,false\n\
+                 1,2,2.0000,0.0000,2.0000,false\n";
+    let full = wait_for(first);
+    std::fs::write(
+        dir.join("2.csv"),
+        "type, client, tx, amount\nwithdrawal, 1, 3, 0.5\n",
+    )
+    .unwrap();
+    std::fs::rename(dir.join("2.csv"), inbox.join("2.csv")).unwrap();
+    let delta = wait_for(&format!(
+        "{}sequence,client,available,held,total,locked\n\
+         2,1,0.5000,0.0000,0.5000,false\n",
+        first
+    ));
+    watcher.kill().unwrap();
+    watcher.wait().unwrap();
+    reader.join().unwrap();
+    std::fs::remove_dir_all(dir).unwrap();
+    assert!(full && delta, "{}", written.lock().unwrap());
+}
+
+#[cfg(unix)]
+#[test]
+fn listen_applies_streamed_lines_and_writes_the_accounts_on_flush_or_sighup() {
+    use std::io::Write;
+    use std::os::unix::net::UnixStream;
+
+    let dir = std::env::temp_dir().join(format!("toy-payments-listen-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let socket = dir.join("payments.sock");
+    let output = dir.join("accounts.csv");
+    let mut listener = std::process::Command::new(assert_cmd::cargo::cargo_bin("toy_payments"))
+        .arg("--listen")
+        .arg(&socket)
+        .arg("--output")
+        .arg(&output)
+        .spawn()
+        .unwrap();
+    let wait_for = |expected: &str| {
+        for _ in 0..100 {
+            if std::fs::read_to_string(&output).is_ok_and(|accounts| accounts == expected) {
+                return true;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+        false
+    };
+    let mut stream = None;
+    for _ in 0..100 {
+        if let Ok(connected) = UnixStream::connect(&socket) {
+            stream = Some(connected);
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+    let mut stream = stream.unwrap();
+
+    stream
+        .write_all(b"type, client, tx, amount\ndeposit, 1, 1, 1.0\ndeposit, 1, 2, 2.0\nFLUSH\n")
+        .unwrap();
+    let flushed = wait_for(
+        "client,available,held,total,locked\n\
+         1,3.0000,0.0000,3.0000,false\n",
+    );
+    stream
+        .write_all(b"{\"type\":\"deposit\",\"client\":2,\"tx\":3,\"amount\":\"4.0\"}\n")
+        .unwrap();
+    stream.write_all(b"withdrawal, 1, 4, 1.0\n").unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(300));
+    std::process::Command::new("kill")
+        .arg("-HUP")
+        .arg(listener.id().to_string())
+        .status()
+        .unwrap();
+    let hung_up = wait_for(
+        "client,available,held,total,locked\n\
+         1,2.0000,0.0000,2.0000,false\n\
+         2,4.0000,0.0000,4.0000,false\n",
+    );
+    listener.kill().unwrap();
+    listener.wait().unwrap();
+    std::fs::remove_dir_all(dir).unwrap();
+    assert!(flushed && hung_up);
+}
+
+#[cfg(unix)]
+#[test]
+fn interrupted_runs_write_the_accounts_so_far_and_a_checkpoint() {
+    use std::io::Write;
+
+    let path = std::env::temp_dir().join(format!(
+        "toy-payments-interrupted-{}.checkpoint",
+        std::process::id()
+    ));
+    let read = "type, client, tx, amount\n\
+                deposit, 1, 1, 1.0\n\
+                deposit, 2, 2, 2.0\n";
+    let unread = "deposit, 1, 3, 5.0\n";
+    let mut run = std::process::Command::new(assert_cmd::cargo::cargo_bin("toy_payments"))
+        .args(["--checkpoint-every", "1000"])
+        .arg("--checkpoint")
+        .arg(&path)
+        .env("RUST_LOG", "off")
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = run.stdin.take().unwrap();
+    stdin.write_all(read.as_bytes()).unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(500));
+    std::process::Command::new("kill")
+        .arg("-INT")
+        .arg(run.id().to_string())
+        .status()
+        .unwrap();
+    std::thread::sleep(std::time::Duration::from_millis(200));
+    // Read after the signal, so never applied
+    stdin.write_all(unread.as_bytes()).unwrap();
+    drop(stdin);
+    let output = run.wait_with_output().unwrap();
+    assert_eq!(output.status.code(), Some(3));
+    assert_eq!(
+        String::from_utf8(output.stdout).unwrap(),
+        "client,available,held,total,locked\n\
+         1,1.0000,0.0000,1.0000,false\n\
+         2,2.0000,0.0000,2.0000,false\n"
+    );
+    assert_eq!(
+        String::from_utf8(output.stderr).unwrap(),
+        "Interrupted before the end of the input\n"
+    );
+
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .args(["--resume", "--checkpoint"])
+        .arg(&path)
+        .write_stdin(format!("{}{}", read, unread))
+        .assert()
+        .success()
+        .stdout(
+            "client,available,held,total,locked\n\
+             1,6.0000,0.0000,6.0000,false\n\
+             2,2.0000,0.0000,2.0000,false\n",
+        );
+    assert!(!path.exists());
+}
+
+#[test]
+fn pipe_delimited_and_fixed_width_input() {
+    let accounts = "client,available,held,total,locked\n\
+                    1,1.5000,0.0000,1.5000,false\n";
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .args(["--delimiter", "|"])
+        .write_stdin("type|client|tx|amount\ndeposit|1|1|2.0\nwithdrawal|1|2|0.5\n")
+        .assert()
+        .success()
+        .stdout(accounts);
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .args([
+            "--input-format",
+            "fixed-width:type=10,client=4,tx=4,amount=8",
+        ])
+        .write_stdin("deposit      1   1     2.0\nwithdrawal   1   2     0.5\n")
+        .assert()
+        .success()
+        .stdout(accounts);
+    for bad in [
+        ["--delimiter", "||"],
+        ["--input-format", "fixed-width:type=10,amount=8"],
+    ] {
+        Command::cargo_bin("toy_payments")
+            .unwrap()
+            .args(bad)
+            .assert()
+            .code(2);
+    }
+}
+
+#[test]
+fn overdraft_lets_trusted_clients_go_negative() {
+    let input = "type, client, tx, amount\n\
+                 deposit, 1, 1, 1.0\n\
+                 withdrawal, 1, 2, 3.0\n\
+                 withdrawal, 2, 3, 3.0\n\
+                 withdrawal, 3, 4, 30.0\n";
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .args([
+            "--overdraft",
+            "allow-up-to:10",
+            "--client-overdraft",
+            "3=allow-negative",
+        ])
+        .write_stdin(input)
+        .assert()
+        .success()
+        .stdout(
+            "client,available,held,total,locked\n\
+             1,-2.0000,0.0000,-2.0000,false\n\
+             2,-3.0000,0.0000,-3.0000,false\n\
+             3,-30.0000,0.0000,-30.0000,false\n",
+        );
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .args(["--client-overdraft", "3"])
+        .assert()
+        .code(2);
+}
+
+#[test]
+fn alerts_on_risky_accounts_go_to_stderr() {
+    let input = "type, client, tx, amount\n\
+                 deposit, 1, 1, 2.0\n\
+                 withdrawal, 1, 2, 3.0\n\
+                 deposit, 2, 3, 5.0\n\
+                 dispute, 2, 3\n\
+                 chargeback, 2, 3\n";
+    let output = Command::cargo_bin("toy_payments")
+        .unwrap()
+        .args([
+            "--alerts",
+            "stderr",
+            "--alert-held-over",
+            "1",
+            "--overdraft",
+            "allow-negative",
+        ])
+        .write_stdin(input)
+        .assert()
+        .success()
+        .get_output()
+        .clone();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let alerts: Vec<_> = stderr
+        .lines()
+        .filter(|line| line.starts_with("Alert: "))
+        .collect();
+    assert_eq!(
+        alerts,
+        [
+            "Alert: Client 1's available funds went to -1.0000 with tx 2",
+            "Alert: Client 2's held funds went to 5.0000 with tx 3, over 1",
+            "Alert: Client 2 was locked by a chargeback of tx 3",
+        ]
+    );
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .args(["--alerts", "pager"])
+        .write_stdin(input)
+        .assert()
+        .code(2);
+}
+
+#[test]
+fn dispute_policy_presets_and_flags() {
+    let input = "type, client, tx, amount\n\
+                 deposit, 1, 1, 5.0\n\
+                 withdrawal, 1, 2, 2.0\n\
+                 dispute, 1, 2\n\
+                 resolve, 1, 2\n\
+                 dispute, 1, 2\n";
+    let accounts = |args: &[&str]| {
+        let output = Command::cargo_bin("toy_payments")
+            .unwrap()
+            .args(args)
+            .write_stdin(input)
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        String::from_utf8(output.stdout).unwrap()
+    };
+    assert_eq!(
+        accounts(&[]),
+        "client,available,held,total,locked\n1,3.0000,0.0000,3.0000,false\n"
+    );
+    assert_eq!(
+        accounts(&["--dispute-policy", "lenient"]),
+        "client,available,held,total,locked\n1,3.0000,2.0000,5.0000,false\n"
+    );
+    assert_eq!(
+        accounts(&[
+            "--dispute-policy",
+            "standard",
+            "--dispute-withdrawals",
+            "--redispute",
+            "after-resolve"
+        ]),
+        "client,available,held,total,locked\n1,3.0000,2.0000,5.0000,false\n"
+    );
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .args(["--dispute-policy", "anything"])
+        .assert()
+        .code(2);
+}
+
+#[test]
+fn log_level_and_json_log_format() {
+    let input = "type, client, tx, amount\n\
+                 deposit, 1, 1, 1.0\n\
+                 deposit, 1, 2, 1.0\n\
+                 dispute, 1, 2\n\
+                 chargeback, 1, 2\n";
+    let output = Command::cargo_bin("toy_payments")
+        .unwrap()
+        .args(["--log-level", "info", "--log-format", "json"])
+        .env_remove("RUST_LOG")
+        .write_stdin(input)
+        .assert()
+        .success()
+        .get_output()
+        .clone();
+    let lines: Vec<serde_json::Value> = String::from_utf8(output.stderr)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(lines.len(), 1);
+    assert_eq!(lines[0]["level"], "INFO");
+    assert_eq!(
+        lines[0]["message"],
+        "Account locked by chargeback client=1 tx=2"
+    );
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .args(["--log-level", "loud"])
+        .assert()
+        .code(2);
+}
+
+#[test]
+fn progress_reports_records_read_on_stderr() {
+    let input = "type, client, tx, amount\n\
+                 deposit, 1, 1, 1.0\n\
+                 deposit, 1, 2, 1.0\n\
+                 deposit, 1, 3, 1.0\n";
+    let output = Command::cargo_bin("toy_payments")
+        .unwrap()
+        .arg("--progress")
+        .write_stdin(input)
+        .assert()
+        .success()
+        .get_output()
+        .clone();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.starts_with("read 0.0 MiB, 3 records at "),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn diff_reports_deltas_and_newly_locked_accounts() {
+    let dir = std::env::temp_dir().join(format!("toy-payments-diff-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+        dir.join("monday.csv"),
+        "client,available,held,total,locked\n\
+         1,1.0000,0.0000,1.0000,false\n\
+         2,2.0000,0.0000,2.0000,false\n",
+    )
+    .unwrap();
+    std::fs::write(
+        dir.join("tuesday.csv"),
+        "client,available,held,total,locked\n\
+         1,1.0000,0.0000,1.0000,false\n\
+         2,0.5000,0.0000,0.5000,true\n\
+         3,4.0000,0.0000,4.0000,false\n",
+    )
+    .unwrap();
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .arg("diff")
+        .arg(dir.join("monday.csv"))
+        .arg(dir.join("tuesday.csv"))
+        .assert()
+        .success()
+        .stdout(
+            "client,available,held,total,locked_before,locked_after\n\
+             2,-1.5000,0.0000,-1.5000,false,true\n\
+             3,4.0000,0.0000,4.0000,false,false\n",
+        )
+        .stderr("newly locked accounts: 1 [2]\n");
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn generate_writes_the_same_feed_for_a_seed() {
+    let generate = |seed: &str| {
+        Command::cargo_bin("toy_payments")
+            .unwrap()
+            .args([
+                "generate",
+                "--clients",
+                "10",
+                "--txns",
+                "2e2",
+                "--seed",
+                seed,
+            ])
+            .output()
+            .unwrap()
+            .stdout
+    };
+    let feed = generate("42");
+    assert_eq!(feed, generate("42"));
+    assert_ne!(feed, generate("43"));
+    let feed = String::from_utf8(feed).unwrap();
+    assert_eq!(feed.lines().count(), 201);
+    assert!(feed.starts_with("type, client, tx, amount\n"));
+}
+
+#[test]
+fn check_invariants_logs_or_aborts() {
+    // The dispute takes available below zero, as the funds were withdrawn
+    let input = "type, client, tx, amount\n\
+                 deposit, 1, 1, 5.0\n\
+                 deposit, 1, 2, 5.0\n\
+                 withdrawal, 1, 3, 8.0\n\
+                 dispute, 1, 2\n";
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .args(["--check-invariants", "log", "--check-available"])
+        .env("RUST_LOG", "off")
+        .write_stdin(input)
+        .assert()
+        .success()
+        .stdout("client,available,held,total,locked\n1,-3.0000,5.0000,2.0000,false\n")
+        .stderr("1 broken invariants\n");
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .args(["--check-invariants", "abort", "--check-available"])
+        .write_stdin(input)
+        .assert()
+        .code(1)
+        .stdout("")
+        .stderr("stdin: Line 5: Invariant available >= 0 broken for client 1 by tx 2\n");
+}
+
+#[cfg(feature = "server")]
+#[test]
+fn watch_serves_metrics_updated_after_each_file() {
+    use std::io::{Read, Write};
+    let dir = std::env::temp_dir().join(format!("toy-payments-metrics-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    std::fs::write(
+        dir.join("1.csv"),
+        "type, client, tx, amount\ndeposit, 1, 2, 2.0\ndeposit, 1, 2, 2.0\n",
+    )
+    .unwrap();
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let mut watcher = std::process::Command::new(assert_cmd::cargo::cargo_bin("toy_payments"))
+        .args(["watch", "--metrics-addr", &format!("127.0.0.1:{}", port)])
+        .arg(&dir)
+        .stdout(std::process::Stdio::null())
+        .spawn()
+        .unwrap();
+    let get_metrics = || {
+        let mut stream = std::net::TcpStream::connect(("127.0.0.1", port)).ok()?;
+        stream
+            .write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+            .ok()?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).ok()?;
+        Some(response)
+    };
+    let mut metrics = None;
+    for _ in 0..100 {
+        metrics = get_metrics().filter(|response| response.contains("payments_total_funds"));
+        if metrics
+            .as_ref()
+            .is_some_and(|response| response.contains("disposition=\"applied\"} 1"))
+        {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+    watcher.kill().unwrap();
+    watcher.wait().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    let metrics = metrics.unwrap();
+    assert!(metrics.contains("text/plain; version=0.0.4"));
+    assert!(metrics
+        .contains("payments_transactions_total{type=\"deposit\",disposition=\"applied\"} 1\n"));
+    assert!(metrics.contains(
+        "payments_transactions_total{type=\"deposit\",disposition=\"rejected-duplicate\"} 1\n"
+    ));
+    assert!(metrics.contains("payments_total_funds{currency=\"\"} 2.0000\n"));
+}
+
+#[cfg(not(feature = "server"))]
+#[test]
+fn watch_metrics_need_the_server_feature() {
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .args(["watch", ".", "--metrics-addr", "127.0.0.1:9100"])
+        .assert()
+        .code(1)
+        .stderr("Can't serve metrics on 127.0.0.1:9100, built without the server feature\n");
+}
+
+#[cfg(feature = "grpc")]
+#[test]
+fn serve_grpc_streams_transactions_and_reads_balances() {
+    use toy_payments::proto::payments_client::PaymentsClient;
+    use toy_payments::proto::{AccountRequest, StreamAccountsRequest, Transaction};
+    let port = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let mut server = std::process::Command::new(assert_cmd::cargo::cargo_bin("toy_payments"))
+        .args([
+            "serve",
+            "--grpc",
+            "--listen",
+            &format!("127.0.0.1:{}", port),
+        ])
+        .spawn()
+        .unwrap();
+    let transaction = |kind: &str, client, tx, amount: &str| Transaction {
+        r#type: kind.to_string(),
+        client,
+        tx,
+        amount: amount.to_string(),
+        ..Transaction::default()
+    };
+    let transactions = vec![
+        transaction("deposit", 1, 1, "5.0"),
+        transaction("deposit", 2, 2, "3.0"),
+        transaction("withdrawal", 1, 3, "10.0"),
+        transaction("dispute", 2, 2, ""),
+        transaction("deposit", 1, 1, "5.0"),
+        Transaction {
+            currency: Some("EUR".to_string()),
+            timestamp: Some(1_700_000_000),
+            ..transaction("deposit", 2, 4, "2.5")
+        },
+    ];
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let (replies, accounts, account, unknown) = runtime.block_on(async {
+        let mut client = None;
+        for _ in 0..100 {
+            client = PaymentsClient::connect(format!("http://127.0.0.1:{}", port))
+                .await
+                .ok();
+            if client.is_some() {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+        let mut client = client.unwrap();
+        let mut replies = Vec::new();
+        let mut stream = client
+            .submit_transactions(tokio_stream::iter(transactions))
+            .await
+            .unwrap()
+            .into_inner();
+        while let Some(reply) = stream.message().await.unwrap() {
+            replies.push((reply.tx, reply.disposition));
+        }
+        let mut accounts = Vec::new();
+        let mut stream = client
+            .stream_accounts(StreamAccountsRequest {})
+            .await
+            .unwrap()
+            .into_inner();
+        while let Some(account) = stream.message().await.unwrap() {
+            accounts.push(account);
+        }
+        let account = client.get_account(AccountRequest { client: 2 }).await;
+        let unknown = client.get_account(AccountRequest { client: 9 }).await;
+        (replies, accounts, account.unwrap().into_inner(), unknown)
+    });
+    server.kill().unwrap();
+    server.wait().unwrap();
+
+    let replies: Vec<_> = replies.iter().map(|(tx, d)| (*tx, d.as_str())).collect();
+    assert_eq!(
+        replies,
+        [
+            (1, "applied"),
+            (2, "applied"),
+            (3, "rejected-insufficient-funds"),
+            (2, "applied"),
+            (1, "rejected-duplicate"),
+            (4, "applied"),
+        ]
+    );
+    let balances: Vec<_> = accounts
+        .iter()
+        .map(|a| (a.client, a.available.as_str(), a.held.as_str(), a.locked))
+        .collect();
+    assert_eq!(
+        balances,
+        [
+            (1, "5.0000", "0.0000", false),
+            (2, "0.0000", "3.0000", false)
+        ]
+    );
+    assert_eq!(account, accounts[1]);
+    assert_eq!(account.total, "3.0000");
+    assert_eq!(account.status, "active");
+    let currencies: Vec<_> = account
+        .currencies
+        .iter()
+        .map(|b| (b.currency.as_str(), b.available.as_str(), b.total.as_str()))
+        .collect();
+    assert_eq!(currencies, [("EUR", "2.5000", "2.5000")]);
+    let unknown = unknown.unwrap_err();
+    assert_eq!(unknown.code(), tonic::Code::NotFound);
+    assert_eq!(unknown.message(), "Unknown client 9");
+}
+
+#[cfg(not(feature = "grpc"))]
+#[test]
+fn serve_grpc_needs_the_grpc_feature() {
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .args(["serve", "--grpc"])
+        .assert()
+        .code(1)
+        .stderr("Can't serve gRPC on 127.0.0.1:8080, built without the grpc feature\n");
+}
+
+#[cfg(feature = "parquet")]
+#[test]
+fn output_format_parquet_writes_a_parquet_file() {
+    let output = Command::cargo_bin("toy_payments")
+        .unwrap()
+        .args(["--output-format", "parquet"])
+        .write_stdin("type, client, tx, amount\ndeposit, 1, 1, 5.0\n")
+        .output()
+        .unwrap();
+    assert!(output.status.success());
+    assert!(output.stdout.starts_with(b"PAR1"));
+    assert!(output.stdout.ends_with(b"PAR1"));
+}
+
+#[cfg(not(feature = "parquet"))]
+#[test]
+fn parquet_needs_the_parquet_feature() {
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .args(["--output-format", "parquet"])
+        .write_stdin("type, client, tx, amount\n")
+        .assert()
+        .code(1)
+        .stdout("")
+        .stderr("Can't write parquet, built without the parquet feature\n");
+    let output = Command::cargo_bin("toy_payments")
+        .unwrap()
+        .args(["--input-format", "parquet"])
+        .assert()
+        .code(2)
+        .get_output()
+        .clone();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("can't read parquet, built without the parquet feature"),
+        "{}",
+        stderr
+    );
+}
+
+#[test]
+fn summary_tells_apart_why_resolves_and_chargebacks_were_dropped() {
+    let input = "type, client, tx, amount\n\
+                 deposit, 1, 1, 5.0\n\
+                 resolve, 1, 1\n\
+                 resolve, 1, 2\n\
+                 chargeback, 2, 1\n\
+                 dispute, 1, 1\n\
+                 resolve, 1, 1\n\
+                 chargeback, 1, 1\n";
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .arg("validate")
+        .env("RUST_LOG", "off")
+        .write_stdin(input)
+        .assert()
+        .code(1)
+        .stdout(
+            "{\"line\":3,\"kind\":\"ignored-not-disputed\",\"client\":1,\"tx\":1,\"message\":\"tx not under dispute\"}\n\
+             {\"line\":4,\"kind\":\"ignored-unknown-tx\",\"client\":1,\"tx\":2,\"message\":\"unknown tx\"}\n\
+             {\"line\":5,\"kind\":\"rejected-ownership-mismatch\",\"client\":2,\"tx\":1,\"message\":\"tx belongs to another client\"}\n\
+             {\"line\":8,\"kind\":\"ignored-dispute-settled\",\"client\":1,\"tx\":1,\"message\":\"tx dispute already resolved or charged back\"}\n",
+        )
+        .stderr(
+            "valid records: 7\n\
+             invalid records: 0\n\
+             skipped records: 4\n  \
+             unknown tx: 1\n  \
+             tx belongs to another client: 1\n  \
+             tx not under dispute: 1\n  \
+             tx dispute already resolved or charged back: 1\n\
+             locked accounts: 0 []\n\
+             4 records failed validation\n",
+        );
+}
+
+#[test]
+fn dispute_window_days_rejects_late_disputes() {
+    let input = "type, client, tx, amount, currency, timestamp\n\
+                 deposit, 1, 1, 5.0, , 1700000000\n\
+                 deposit, 1, 2, 5.0, , 1700000000\n\
+                 dispute, 1, 1, , , 1700086400\n\
+                 dispute, 1, 2, , , 1700086401\n";
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .args(["--dispute-window-days", "1"])
+        .env("RUST_LOG", "off")
+        .write_stdin(input)
+        .assert()
+        .success()
+        .stdout("client,available,held,total,locked\n1,5.0000,5.0000,10.0000,false\n")
+        .stderr("Skipped 1 records:\n  dispute window passed: 1\n");
+}
+
+#[test]
+fn fees_are_charged_per_client_or_to_the_house() {
+    let dir = std::env::temp_dir().join(format!("toy-payments-fees-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let schedule = dir.join("fees.toml");
+    let input = "type, client, tx, amount\n\
+                 deposit, 1, 1, 10.0\n\
+                 withdrawal, 1, 2, 2.0\n\
+                 deposit, 2, 3, 5.0\n";
+    std::fs::write(
+        &schedule,
+        "[deposit]\npercent = 1\n[withdrawal]\nflat = \"0.5\"\n",
+    )
+    .unwrap();
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .arg("--fees")
+        .arg(&schedule)
+        .write_stdin(input)
+        .assert()
+        .success()
+        .stdout(
+            "client,available,held,total,locked,fees\n\
+             1,7.4000,0.0000,7.4000,false,0.6000\n\
+             2,4.9500,0.0000,4.9500,false,0.0500\n",
+        )
+        .stderr("");
+    std::fs::write(&schedule, "collect = \"house\"\n[deposit]\npercent = 1\n").unwrap();
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .arg("--fees")
+        .arg(&schedule)
+        .write_stdin(input)
+        .assert()
+        .success()
+        .stdout(
+            "client,available,held,total,locked\n\
+             1,7.9000,0.0000,7.9000,false\n\
+             2,4.9500,0.0000,4.9500,false\n",
+        )
+        .stderr("house fees: 0.1500\n");
+    std::fs::write(&schedule, "[deposit]\nflat = -1\n").unwrap();
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .arg("--fees")
+        .arg(&schedule)
+        .write_stdin(input)
+        .assert()
+        .code(2)
+        .stderr("Fee schedule: Invalid deposit.flat, expected an amount of zero or more\n");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn disputes_output_lists_open_disputes() {
+    let path =
+        std::env::temp_dir().join(format!("toy-payments-disputes-{}.csv", std::process::id()));
+    let input = "type, client, tx, amount\n\
+                 deposit, 1, 1, 5.0\n\
+                 deposit, 2, 2, 2.5\n\
+                 deposit, 2, 3, 1.0\n\
+                 dispute, 2, 2\n\
+                 dispute, 1, 1\n\
+                 dispute, 2, 3\n\
+                 resolve, 2, 3\n";
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .arg("--disputes-output")
+        .arg(&path)
+        .write_stdin(input)
+        .assert()
+        .success();
+    let disputes = std::fs::read_to_string(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(
+        disputes,
+        "client,tx,type,amount,age\n\
+         1,1,deposit,5.0000,\n\
+         2,2,deposit,2.5000,\n"
+    );
+}
+
+#[test]
+fn column_map_matches_columns_by_header_name() {
+    let dir = std::env::temp_dir().join(format!("toy-payments-columns-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let map = dir.join("columns.toml");
+    std::fs::write(
+        &map,
+        "type = \"txn_type\"\nclient = \"cust\"\ntx = \"txn_id\"\namount = \"value\"\n",
+    )
+    .unwrap();
+    let input = "txn_id,cust,branch,txn_type,value\n\
+                 1,1,x,deposit,10.0\n\
+                 2,1,y,withdrawal,2.5\n\
+                 1,1,x,dispute,\n";
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .arg("--column-map")
+        .arg(&map)
+        .write_stdin(input)
+        .assert()
+        .success()
+        .stdout(
+            "client,available,held,total,locked\n\
+             1,-2.5000,10.0000,7.5000,false\n",
+        );
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .arg("--column-map")
+        .arg(&map)
+        .write_stdin("type,client,tx,amount\ndeposit,1,1,1.0\n")
+        .assert()
+        .code(1)
+        .stderr("stdin: Column map: No type column \"txn_type\" in the header\n");
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn watch_answers_control_commands_as_of_the_last_file() {
+    use std::io::{BufRead, BufReader, Write};
+    let dir = std::env::temp_dir().join(format!("toy-payments-control-{}", std::process::id()));
+    let inbox = dir.join("inbox");
+    std::fs::create_dir_all(&inbox).unwrap();
+    let socket = dir.join("control.sock");
+    std::fs::write(
+        inbox.join("1.csv"),
+        "type, client, tx, amount\ndeposit, 1, 1, 2.0\n",
+    )
+    .unwrap();
+    let mut watcher = std::process::Command::new(assert_cmd::cargo::cargo_bin("toy_payments"))
+        .arg("watch")
+        .arg(&inbox)
+        .arg("--control")
+        .arg(&socket)
+        .stdout(std::process::Stdio::null())
+        .spawn()
+        .unwrap();
+    let get = || {
+        let stream = std::os::unix::net::UnixStream::connect(&socket).ok()?;
+        let mut writer = stream.try_clone().ok()?;
+        writer.write_all(b"GET 1\n").ok()?;
+        let mut answer = String::new();
+        let mut reader = BufReader::new(stream);
+        while !answer.ends_with("\n\n") {
+            if reader.read_line(&mut answer).ok()? == 0 {
+                return None;
+            }
+        }
+        Some(answer)
+    };
+    let mut answer = None;
+    for _ in 0..100 {
+        answer = get();
+        if answer
+            .as_ref()
+            .is_some_and(|answer| !answer.starts_with("ERR"))
+        {
+            break;
+        }
+        std::thread::sleep(std::time::Duration::from_millis(100));
+    }
+    watcher.kill().unwrap();
+    watcher.wait().unwrap();
+    std::fs::remove_dir_all(&dir).unwrap();
+
+    assert_eq!(
+        answer.unwrap(),
+        "client,available,held,total,locked\n1,2.0000,0.0000,2.0000,false\n\n"
+    );
+}
+
+#[test]
+fn amount_format_reads_separators_and_symbols() {
+    let input = "type,client,tx,amount\n\
+                 deposit,1,1,\"$1,234.56\"\n\
+                 withdrawal,1,2,34.56 $\n\
+                 deposit,2,3,\"1,5\"\n";
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .args(["--amount-format", "en"])
+        .write_stdin(input)
+        .assert()
+        .success()
+        .stdout(
+            "client,available,held,total,locked\n\
+             1,1200.0000,0.0000,1200.0000,false\n",
+        )
+        .stderr("Skipped 1 records that failed to parse\n");
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .write_stdin(input)
+        .assert()
+        .success()
+        .stdout("")
+        .stderr("Skipped 3 records that failed to parse\n");
+}
+
+#[cfg(feature = "chaos")]
+#[test]
+fn chaos_duplicates_records_before_they_apply() {
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .args(["--chaos", "seed=1,duplicate=1"])
+        .write_stdin(
+            "type, client, tx, amount\n\
+             deposit, 1, 1, 2.0\n\
+             withdrawal, 1, 2, 0.5\n",
+        )
+        .assert()
+        .success()
+        .stdout(
+            "client,available,held,total,locked\n\
+             1,1.5000,0.0000,1.5000,false\n",
+        )
+        .stderr("Skipped 2 records:\n  duplicate tx id: 2\n");
+}
+
+#[cfg(not(feature = "chaos"))]
+#[test]
+fn chaos_needs_the_chaos_feature() {
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .args(["--chaos", "seed=1"])
+        .write_stdin("")
+        .assert()
+        .code(2)
+        .stderr("Can't inject chaos, built without the chaos feature\n");
+}
+
+#[test]
+fn reconcile_passes_quietly_when_the_accounts_add_up() {
+    let plain = Command::cargo_bin("toy_payments")
+        .unwrap()
+        .arg("inputs/long.csv")
+        .output()
+        .unwrap();
+    for threads in ["1", "2"] {
+        Command::cargo_bin("toy_payments")
+            .unwrap()
+            .args(["--reconcile", "--threads", threads, "inputs/long.csv"])
+            .assert()
+            .success()
+            .stdout(plain.stdout.clone())
+            .stderr(plain.stderr.clone());
+    }
+}
+
+#[test]
+fn parse_threads_give_the_same_accounts() {
+    let plain = Command::cargo_bin("toy_payments")
+        .unwrap()
+        .arg("inputs/long.csv")
+        .output()
+        .unwrap();
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .args(["--parse-threads", "3", "inputs/long.csv"])
+        .assert()
+        .success()
+        .stdout(plain.stdout)
+        .stderr(plain.stderr);
+}
+
+#[test]
+fn mmap_gives_the_same_accounts() {
+    let plain = Command::cargo_bin("toy_payments")
+        .unwrap()
+        .args(["inputs/long.csv", "inputs/invalid_amount.csv"])
+        .output()
+        .unwrap();
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .args(["--mmap", "inputs/long.csv", "inputs/invalid_amount.csv"])
+        .assert()
+        .success()
+        .stdout(plain.stdout)
+        .stderr(plain.stderr);
+}
+
+#[test]
+fn profiles_bundle_a_partners_settings() {
+    let config =
+        std::env::temp_dir().join(format!("toy-payments-profiles-{}.toml", std::process::id()));
+    std::fs::write(
+        &config,
+        "[acme]\n\
+         precision = 2\n\
+         dispute_withdrawals = true\n\
+         [acme.columns]\n\
+         type = \"kind\"\n\
+         client = \"cust\"\n",
+    )
+    .unwrap();
+    let input = "cust,kind,tx,amount\n\
+                 1,deposit,1,5.0\n\
+                 1,withdrawal,2,2.005\n\
+                 1,dispute,2,\n";
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .args(["--profile", "acme", "--config"])
+        .arg(&config)
+        .write_stdin(input)
+        .assert()
+        .success()
+        .stdout(
+            "client,available,held,total,locked\n\
+             1,3.00,2.00,5.00,false\n",
+        );
+    // Options given as well override the profile
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .args(["--profile", "acme", "--precision", "3", "--config"])
+        .arg(&config)
+        .write_stdin(input)
+        .assert()
+        .success()
+        .stdout(
+            "client,available,held,total,locked\n\
+             1,2.995,2.005,5.000,false\n",
+        );
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .args(["--profile", "globex", "--config"])
+        .arg(&config)
+        .write_stdin(input)
+        .assert()
+        .code(2)
+        .stderr("Profile: No profile globex\n");
+    std::fs::remove_file(config).unwrap();
+}
+
+#[test]
+fn client_scoped_tx_ids_may_repeat_across_clients() {
+    let input = "type, client, tx, amount\n\
+                 deposit, 1, 1, 1.0\n\
+                 deposit, 2, 1, 2.0\n\
+                 dispute, 2, 1\n";
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .args(["--tx-id-scope", "client"])
+        .write_stdin(input)
+        .assert()
+        .success()
+        .stdout(
+            "client,available,held,total,locked\n\
+             1,1.0000,0.0000,1.0000,false\n\
+             2,0.0000,2.0000,2.0000,false\n",
+        )
+        .stderr("");
+}
+
+#[test]
+fn closed_accounts_show_their_status() {
+    let input = "type, client, tx, amount\n\
+                 open, 1, 1, 5.0\n\
+                 open, 2, 2,\n\
+                 deposit, 2, 3, 2.0\n\
+                 close, 2, 4\n\
+                 deposit, 2, 5, 1.0\n";
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .env("RUST_LOG", "off")
+        .write_stdin(input)
+        .assert()
+        .success()
+        .stdout(
+            "client,available,held,total,locked,status\n\
+             1,5.0000,0.0000,5.0000,false,active\n\
+             2,2.0000,0.0000,2.0000,false,closed\n",
+        )
+        .stderr("Skipped 1 records:\n  account closed: 1\n");
+}
+
+#[test]
+fn export_ledger_journals_each_applied_transaction() {
+    let path = std::env::temp_dir().join(format!("toy-payments-ledger-{}.csv", std::process::id()));
+    // The first record is skipped
+    let input = "type,client,tx,amount\n\
+                 deposit,1,1,5.0\n\
+                 withdrawal,1,2,1.5\n\
+                 withdrawal,1,3,9.0\n\
+                 dispute,1,1\n\
+                 chargeback,1,1\n";
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .arg("--export-ledger")
+        .arg(&path)
+        .write_stdin(input)
+        .assert()
+        .success();
+    assert_eq!(
+        std::fs::read_to_string(&path).unwrap(),
+        "entry,tx,type,account,debit,credit,currency\n\
+         1,1,deposit,settlement,5.0000,,\n\
+         1,1,deposit,clients:1:available,,5.0000,\n\
+         2,2,withdrawal,clients:1:available,1.5000,,\n\
+         2,2,withdrawal,settlement,,1.5000,\n\
+         3,1,dispute,clients:1:available,5.0000,,\n\
+         3,1,dispute,clients:1:held,,5.0000,\n\
+         4,1,chargeback,clients:1:held,5.0000,,\n\
+         4,1,chargeback,settlement,,5.0000,\n"
+    );
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .args(["--threads", "2", "inputs/long.csv", "--export-ledger"])
+        .arg(&path)
+        .assert()
+        .code(1)
+        .stderr("The ledger export isn't supported with more than one thread\n");
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn rules_reject_transactions_over_their_limits() {
+    let path = std::env::temp_dir().join(format!("toy-payments-rules-{}.toml", std::process::id()));
+    std::fs::write(&path, "max_deposit = 50\nmax_daily_withdrawal = \"10\"\n").unwrap();
+    // The first record is skipped
+    let input = "type,client,tx,amount\n\
+                 deposit,1,1,60\n\
+                 deposit,1,2,40\n\
+                 withdrawal,1,3,8\n\
+                 withdrawal,1,4,3\n";
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .arg("--rules")
+        .arg(&path)
+        .write_stdin(input)
+        .assert()
+        .success()
+        .stdout("client,available,held,total,locked\n1,32.0000,0.0000,32.0000,false\n")
+        .stderr(
+            "Skipped 2 records:\n  \
+             rejected by rule max_daily_withdrawal: 1\n  \
+             rejected by rule max_deposit: 1\n",
+        );
+
+    std::fs::write(&path, "max_withdrawal = 5\n").unwrap();
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .arg("--rules")
+        .arg(&path)
+        .write_stdin(input)
+        .assert()
+        .code(2)
+        .stderr("Rules: Invalid key max_withdrawal\n");
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn adjustments_correct_locked_accounts() {
+    // The first record is skipped
+    let input = "type,client,tx,amount\n\
+                 deposit,1,1,5.0\n\
+                 deposit,1,2,3.0\n\
+                 dispute,1,1\n\
+                 chargeback,1,1\n\
+                 adjustment,1,3,-0.5,ops-1042\n\
+                 dispute,1,3\n\
+                 adjustment,1,4,1.0\n";
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .env("RUST_LOG", "off")
+        .write_stdin(input)
+        .assert()
+        .success()
+        .stdout("client,available,held,total,locked\n1,2.5000,0.0000,2.5000,true\n")
+        .stderr(
+            "Skipped 1 records that failed to parse\n\
+             Skipped 1 records:\n  \
+             tx can't be disputed: 1\n",
+        );
+}
+
+#[test]
+fn gzipped_input_gives_the_same_accounts() {
+    let path = std::env::temp_dir().join(format!("toy-payments-{}.csv.gz", std::process::id()));
+    let gzip = std::process::Command::new("gzip")
+        .args(["-c", "inputs/long.csv"])
+        .output()
+        .unwrap();
+    std::fs::write(&path, gzip.stdout).unwrap();
+    let plain = Command::cargo_bin("toy_payments")
+        .unwrap()
+        .args(["--mmap", "inputs/long.csv"])
+        .output()
+        .unwrap();
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .arg("--mmap")
+        .arg(&path)
+        .assert()
+        .success()
+        .stdout(plain.stdout)
+        .stderr(plain.stderr);
+
+    // Found by its first bytes without the extension, and failing when truncated
+    let renamed = path.with_extension("");
+    std::fs::rename(&path, &renamed).unwrap();
+    let compressed = std::fs::read(&renamed).unwrap();
+    std::fs::write(&renamed, &compressed[..compressed.len() / 2]).unwrap();
+    let output = Command::cargo_bin("toy_payments")
+        .unwrap()
+        .arg(&renamed)
+        .assert()
+        .code(1)
+        .get_output()
+        .clone();
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("Invalid gzip: "), "{}", stderr);
+    std::fs::remove_file(renamed).unwrap();
+}
+
+#[test]
+fn bench_reports_throughput_and_peak_memory() {
+    let output = Command::cargo_bin("toy_payments")
+        .unwrap()
+        .args(["bench", "--profile", "dispute-heavy", "--txns", "1e3"])
+        .assert()
+        .success()
+        .stderr("")
+        .get_output()
+        .clone();
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(
+        stdout.starts_with("dispute-heavy: 1000 transactions in "),
+        "{}",
+        stdout
+    );
+    assert!(stdout.contains(" txns/sec, peak memory "), "{}", stdout);
+
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .args(["bench", "--profile", "withdrawal-heavy"])
+        .assert()
+        .code(2);
+}
+
+#[test]
+fn output_filters_narrow_the_accounts_written() {
+    // The first record is skipped
+    let input = "type,client,tx,amount\n\
+                 deposit,1,1,5.0\n\
+                 deposit,2,2,3.0\n\
+                 deposit,3,3,8.0\n\
+                 deposit,3,4,1.0\n\
+                 dispute,3,4\n\
+                 chargeback,3,4\n";
+    let run = |args: &[&str]| {
+        Command::cargo_bin("toy_payments")
+            .unwrap()
+            .args(args)
+            .write_stdin(input)
+            .assert()
+            .success()
+            .get_output()
+            .stdout
+            .clone()
+    };
+    let header = "client,available,held,total,locked\n";
+    assert_eq!(
+        String::from_utf8(run(&["--client", "3,1"])).unwrap(),
+        format!("{header}1,5.0000,0.0000,5.0000,false\n3,8.0000,0.0000,8.0000,true\n")
+    );
+    assert_eq!(
+        run(&["--client", "1", "--client", "2", "--min-total", "4"]),
+        format!("{header}1,5.0000,0.0000,5.0000,false\n").as_bytes()
+    );
+    assert_eq!(
+        run(&["--locked-only", "--output-format", "jsonl"]),
+        b"{\"client\":3,\"available\":\"8.0000\",\"held\":\"0.0000\",\"total\":\"8.0000\",\"locked\":true}\n"
+    );
+}
+
+#[cfg(not(feature = "s3"))]
+#[test]
+fn s3_uris_need_the_s3_feature() {
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .arg("s3://feeds/transactions.csv")
+        .assert()
+        .code(1)
+        .stderr("Can't read s3://feeds/transactions.csv, built without the s3 feature\n");
+    Command::cargo_bin("toy_payments")
+        .unwrap()
+        .args(["--output", "s3://feeds/accounts.csv"])
+        .write_stdin("")
+        .assert()
+        .code(1)
+        .stderr("Can't write s3://feeds/accounts.csv, built without the s3 feature\n");
+}